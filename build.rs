@@ -0,0 +1,51 @@
+// writes include/microps.h, the declarations of the C API in src/capi.rs
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let config = cbindgen::Config {
+        usize_is_size_t: true,
+        ..Default::default()
+    };
+    let mut header = vec![];
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(dir.join("src/capi.rs"))
+        .with_language(cbindgen::Language::C)
+        .with_header(HEADER)
+        .with_include_guard("MICROPS_H")
+        .with_no_includes()
+        .with_sys_include("poll.h")
+        .with_sys_include("stddef.h")
+        .with_sys_include("sys/socket.h")
+        .with_sys_include("sys/types.h")
+        .with_cpp_compat(true)
+        .with_style(cbindgen::Style::Tag)
+        .rename_item("Config", "mps_config")
+        .rename_item("pollfd", "struct pollfd")
+        .rename_item("sockaddr", "struct sockaddr")
+        .with_documentation(true)
+        .generate()
+        .expect("can not generate include/microps.h")
+        .write(&mut header);
+    // rewritten only when it changes, not to rebuild what includes it for nothing
+    let path = dir.join("include/microps.h");
+    if fs::read(&path).ok().as_ref() != Some(&header) {
+        fs::write(&path, header).expect("can not write include/microps.h");
+    }
+}
+
+const HEADER: &str = "\
+/*
+ * C API of microps-rs, exported from libmicrops_rs.so (src/capi.rs).
+ *
+ * mps_init brings up one stack on one ethernet device; the other calls mirror their
+ * socket(2) namesakes for UDP over IPv4. On failure they return -1 and set errno.
+ *
+ * Written by build.rs from the declarations in src/capi.rs; edit those instead.
+ */";
@@ -4,8 +4,9 @@ extern crate libc;
 extern crate microps_rs;
 extern crate nix;
 
-use microps_rs::{ethernet, ip, raw};
+use microps_rs::{error::Error, ethernet, ip, raw, stack::Stack};
 use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 lazy_static! {
@@ -17,37 +18,40 @@ extern "C" fn handle_sigint(signal: libc::c_int) {
     TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
 }
 
-fn main() {
-    let args: Vec<String> = ::std::env::args().into_iter().collect();
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
     let (ifname, mac_addr, ip_addr) = if args.len() == 3 {
         (args[1].clone(), None, args[2].clone())
     } else if args.len() == 4 {
         (args[1].clone(), Some(args[2].clone()), args[3].clone())
     } else {
-        panic!("USAGE: arp_test <interface> [mac_address] <ip_address>");
+        return Err(Error::Invalid(
+            "USAGE: arp_test <interface> [mac_address] <ip_address>".to_string(),
+        ));
     };
     let handler = SigHandler::Handler(handle_sigint);
-    unsafe { signal::signal(Signal::SIGINT, handler) }.unwrap();
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
 
     let mut device = ethernet::Device::open(
+        &stack,
         ifname.as_str(),
         match mac_addr {
             None => ethernet::ADDR_ANY,
-            Some(mac_addr) => ethernet::MacAddr::from_str(&mac_addr).unwrap(),
+            Some(mac_addr) => ethernet::MacAddr::from_str(&mac_addr)?,
         },
         raw::Type::Auto,
-    )
-    .unwrap();
+    )?;
     eprintln!("ip_addr: {}", ip_addr);
     let interface = ip::interface::Interface::new(
         device.clone(),
-        ip::Addr::from_str(&ip_addr).unwrap(),
+        ip::Addr::from_str(&ip_addr)?,
         ip::Addr::empty(),
         None,
     );
     device.add_interface(interface);
-    device.run().unwrap();
+    device.run()?;
     eprintln!("[{}]", ifname);
     while !TERMINATE.load(Ordering::SeqCst) {}
-    device.close().unwrap();
+    device.close()
 }
@@ -0,0 +1,80 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ethernet, ip, raw, stack::Stack};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let mut args: Vec<String> = ::std::env::args().collect();
+    let stp = args.len() > 1 && args[1] == "-s";
+    if stp {
+        args.remove(1);
+    }
+    if args.len() != 2 && args.len() != 4 {
+        return Err(Error::Invalid(
+            "USAGE: bridge_test [-s] <port>,<port>[,...] [ip_address netmask]".to_string(),
+        ));
+    }
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let mut bridge = ethernet::bridge::Bridge::new(&stack, "br0", ethernet::ADDR_ANY);
+    let mut devices = vec![];
+    for name in args[1].split(',') {
+        let mut device = ethernet::Device::open(&stack, name, ethernet::ADDR_ANY, raw::Type::Auto)?;
+        bridge.add_port(&device)?;
+        device.run()?;
+        eprintln!("[{}]", name);
+        devices.push(device);
+    }
+    if stp {
+        bridge.enable_stp();
+    }
+    if args.len() == 4 {
+        let ip_addr = ip::Addr::from_str(&args[2])?;
+        let netmask = ip::Addr::from_str(&args[3])?;
+        eprintln!("ip_addr: {}", ip_addr);
+        let interface = ip::interface::Interface::new(bridge.clone(), ip_addr, netmask, None);
+        bridge.add_interface(interface);
+    }
+
+    while !TERMINATE.load(Ordering::SeqCst) {}
+
+    for entry in bridge.fdb() {
+        eprintln!(
+            "{} on {} ({}s)",
+            entry.mac_addr,
+            entry.port,
+            entry.age.num_seconds()
+        );
+    }
+    for (name, stats) in bridge.port_stats() {
+        eprintln!("{}: {:?}", name, stats);
+    }
+    if stp {
+        eprintln!("root: {}", bridge.root_id());
+        for (name, port) in bridge.stp_ports() {
+            eprintln!("{}: {} {}", name, port.role, port.state);
+        }
+    }
+    for device in devices {
+        device.clone().close()?;
+        bridge.remove_port(&device)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+/*
+ * a UDP echo server on the C API:
+ *
+ *   cargo build
+ *   cc -Iinclude examples/c/udp_echo.c -Ltarget/debug -lmicrops_rs -o udp_echo
+ *   LD_LIBRARY_PATH=target/debug ./udp_echo <interface> <address> <netmask> <port> [count]
+ */
+
+#include <arpa/inet.h>
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "microps.h"
+
+int main(int argc, char *argv[]) {
+    if (argc < 5) {
+        fprintf(stderr, "USAGE: %s <interface> <address> <netmask> <port> [count]\n", argv[0]);
+        return 1;
+    }
+    struct mps_config config = {argv[1], argv[2], argv[3], NULL};
+    int count = argc > 5 ? atoi(argv[5]) : -1;
+    if (mps_init(&config) == -1) {
+        perror("mps_init");
+        return 1;
+    }
+    int fd = mps_socket(AF_INET, SOCK_DGRAM | SOCK_NONBLOCK, 0);
+    if (fd == -1) {
+        perror("mps_socket");
+        return 1;
+    }
+    struct sockaddr_in addr;
+    memset(&addr, 0, sizeof(addr));
+    addr.sin_family = AF_INET;
+    addr.sin_addr.s_addr = htonl(INADDR_ANY);
+    addr.sin_port = htons(atoi(argv[4]));
+    if (mps_bind(fd, (struct sockaddr *)&addr, sizeof(addr)) == -1) {
+        perror("mps_bind");
+        return 1;
+    }
+    char buf[2048];
+    if (mps_recvfrom(fd, buf, sizeof(buf), 0, NULL, NULL) != -1 || errno != EAGAIN) {
+        fprintf(stderr, "expected EAGAIN from a non-blocking socket\n");
+        return 1;
+    }
+    fprintf(stderr, "listening on %s:%s\n", argv[2], argv[4]);
+    while (count != 0) {
+        struct pollfd pfd = {fd, POLLIN, 0};
+        int ready = mps_poll(&pfd, 1, 1000);
+        if (ready == -1) {
+            perror("mps_poll");
+            return 1;
+        }
+        if (ready == 0) {
+            continue;
+        }
+        struct sockaddr_in peer;
+        socklen_t peer_len = sizeof(peer);
+        ssize_t n = mps_recvfrom(fd, buf, sizeof(buf), 0, (struct sockaddr *)&peer, &peer_len);
+        if (n == -1) {
+            perror("mps_recvfrom");
+            return 1;
+        }
+        fprintf(stderr, "%zd bytes from %s:%d\n", n, inet_ntoa(peer.sin_addr), ntohs(peer.sin_port));
+        if (mps_sendto(fd, buf, n, 0, (struct sockaddr *)&peer, peer_len) == -1) {
+            perror("mps_sendto");
+            return 1;
+        }
+        if (count > 0) {
+            count--;
+        }
+    }
+    mps_close(fd);
+    return 0;
+}
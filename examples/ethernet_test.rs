@@ -4,7 +4,7 @@ extern crate libc;
 extern crate microps_rs;
 extern crate nix;
 
-use microps_rs::{ethernet, raw};
+use microps_rs::{ethernet, raw, stack::Stack};
 use nix::sys::signal::{self, SigHandler, Signal};
 use std::sync::atomic::{AtomicBool, Ordering};
 
@@ -18,7 +18,8 @@ extern "C" fn handle_sigint(signal: libc::c_int) {
 }
 
 fn main() {
-    let args: Vec<String> = ::std::env::args().into_iter().collect();
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
     if args.len() != 2 {
         panic!("USAGE: ethernet_test <device>");
     }
@@ -27,8 +28,9 @@ fn main() {
     unsafe { signal::signal(Signal::SIGINT, handler) }.unwrap();
 
     let mut device = ethernet::Device::open(
+        &stack,
         args[1].as_str(),
-        ethernet::ADDR_ANY.clone(),
+        ethernet::ADDR_ANY,
         raw::Type::Auto,
     )
     .unwrap();
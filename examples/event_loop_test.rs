@@ -0,0 +1,66 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ethernet, event_loop::EventLoop, ip, raw, stack::Stack, stats};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+fn threads() -> usize {
+    ::std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+// answers ARP and ping on one thread, stepping the loop by hand
+fn main() -> Result<(), Error> {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 4 {
+        return Err(Error::Invalid(
+            "USAGE: event_loop_test <interface> <ip_address> <netmask>".to_string(),
+        ));
+    }
+    let ip_addr = ip::Addr::from_str(&args[2])?;
+    let netmask = ip::Addr::from_str(&args[3])?;
+
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let stack = Stack::new();
+    let mut event_loop = EventLoop::new(&stack)?;
+    let mut device =
+        ethernet::Device::open(&stack, &args[1], ethernet::ADDR_ANY, raw::Type::Auto)?;
+    let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface);
+    event_loop.add(&device)?;
+    eprintln!("[{}] {}", args[1], ip_addr);
+
+    let mut handled = 0;
+    let mut threads_max = threads();
+    while !TERMINATE.load(Ordering::SeqCst) {
+        handled += event_loop.poll_once(Some(Duration::from_millis(100)))?;
+        threads_max = threads_max.max(threads());
+    }
+    drop(event_loop);
+    device.close()?;
+
+    // timers, address conflict detection included, ran on this thread too
+    eprintln!(
+        "{} frames and timers, at most {} threads",
+        handled, threads_max
+    );
+    let snapshot = stats::snapshot(&stack);
+    eprintln!("{:?}", snapshot.counters.icmp);
+    Ok(())
+}
@@ -0,0 +1,72 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{arp, config::Config, stack::Stack};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+// brings up the stack a file describes, see examples/config/*.toml; with `check`, only
+// reports whether the file is valid
+fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    let (path, check) = match args.len() {
+        2 => (&args[1], false),
+        3 if args[2] == "check" => (&args[1], true),
+        _ => panic!("USAGE: from_config <file> [check]"),
+    };
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            ::std::process::exit(1);
+        }
+    };
+    eprintln!(
+        "{}: {} devices, {} interfaces, {} routes, {} arp entries",
+        path,
+        config.devices.len(),
+        config.interfaces.len(),
+        config.routes.len(),
+        config.arp.len()
+    );
+    if check {
+        return;
+    }
+
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }.unwrap();
+
+    let stack = Stack::new();
+    let network = config.start(&stack).unwrap();
+    for (device, interface) in network.interfaces.iter() {
+        let interface = interface.0.lock().unwrap();
+        eprintln!(
+            "[{}] {}/{} mtu {}",
+            device,
+            interface.unicast,
+            interface.netmask,
+            interface.device.mtu()
+        );
+    }
+    for entry in arp::entries(&stack) {
+        eprintln!("arp: {} {} {:?}", entry.ip_addr, entry.mac_addr, entry.state);
+    }
+    while !TERMINATE.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    network.close().unwrap();
+}
@@ -4,8 +4,9 @@ extern crate libc;
 extern crate microps_rs;
 extern crate nix;
 
-use microps_rs::{ethernet, ip, raw};
+use microps_rs::{error::Error, ethernet, ip, raw, stack::Stack, stats};
 use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 lazy_static! {
@@ -17,43 +18,57 @@ extern "C" fn handle_sigint(signal: libc::c_int) {
     TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
 }
 
-fn main() {
-    let args: Vec<String> = ::std::env::args().into_iter().collect();
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
     let (ifname, mac_addr, ip_addr, netmask) = if args.len() == 4 {
         (
             args[1].clone(),
             None,
-            ip::Addr::from_str(&args[2]).unwrap(),
-            ip::Addr::from_str(&args[3]).unwrap(),
+            ip::Addr::from_str(&args[2])?,
+            ip::Addr::from_str(&args[3])?,
         )
     } else if args.len() == 5 {
         (
             args[1].clone(),
-            Some(ethernet::MacAddr::from_str(&args[2]).unwrap()),
-            ip::Addr::from_str(&args[3]).unwrap(),
-            ip::Addr::from_str(&args[4]).unwrap(),
+            Some(ethernet::MacAddr::from_str(&args[2])?),
+            ip::Addr::from_str(&args[3])?,
+            ip::Addr::from_str(&args[4])?,
         )
     } else {
-        panic!("USAGE: icmp_test <interface> [mac_address] <ip_address> <netmask>");
+        return Err(Error::Invalid(
+            "USAGE: icmp_test <interface> [mac_address] <ip_address> <netmask>".to_string(),
+        ));
     };
 
     let handler = SigHandler::Handler(handle_sigint);
-    unsafe { signal::signal(Signal::SIGINT, handler) }.unwrap();
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
 
     let mut device = ethernet::Device::open(
+        &stack,
         ifname.as_str(),
         match mac_addr {
             None => ethernet::ADDR_ANY,
             Some(mac_addr) => mac_addr,
         },
         raw::Type::Auto,
-    )
-    .unwrap();
+    )?;
     eprintln!("ip_addr: {}", ip_addr);
     let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
     device.add_interface(interface);
-    device.run().unwrap();
+    device.run()?;
     eprintln!("[{}]", ifname);
     while !TERMINATE.load(Ordering::SeqCst) {}
-    device.close().unwrap();
+    device.close()?;
+
+    let snapshot = stats::snapshot(&stack);
+    for (name, stats) in snapshot.devices {
+        eprintln!("{}: {:?}", name, stats);
+    }
+    eprintln!("{:?}", snapshot.counters.ip);
+    eprintln!("{:?}", snapshot.counters.icmp);
+    for (reason, count) in snapshot.counters.drops {
+        eprintln!("{}: {}", reason, count);
+    }
+    Ok(())
 }
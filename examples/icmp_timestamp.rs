@@ -0,0 +1,56 @@
+extern crate microps_rs;
+
+use microps_rs::{error::Error, ethernet, icmp, ip, raw, stack::Stack};
+use std::str::FromStr;
+use std::time::Duration;
+
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
+    let (ifname, mac_addr, ip_addr, netmask, target) = if args.len() == 5 {
+        (
+            args[1].clone(),
+            None,
+            ip::Addr::from_str(&args[2])?,
+            ip::Addr::from_str(&args[3])?,
+            ip::Addr::from_str(&args[4])?,
+        )
+    } else if args.len() == 6 {
+        (
+            args[1].clone(),
+            Some(ethernet::MacAddr::from_str(&args[2])?),
+            ip::Addr::from_str(&args[3])?,
+            ip::Addr::from_str(&args[4])?,
+            ip::Addr::from_str(&args[5])?,
+        )
+    } else {
+        return Err(Error::Invalid(
+            "USAGE: icmp_timestamp <interface> [mac_address] <ip_address> <netmask> <target>".to_string(),
+        ));
+    };
+
+    let mut device = ethernet::Device::open(
+        &stack,
+        ifname.as_str(),
+        match mac_addr {
+            None => ethernet::ADDR_ANY,
+            Some(mac_addr) => mac_addr,
+        },
+        raw::Type::Auto,
+    )?;
+    let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface.clone());
+    device.run()?;
+
+    match icmp::timestamp(&interface, &target, Duration::from_secs(3)) {
+        Ok(reply) => {
+            eprintln!("originate: {}", reply.originate);
+            eprintln!("receive  : {}", reply.receive);
+            eprintln!("transmit : {}", reply.transmit);
+            eprintln!("offset   : {} ms", reply.offset());
+            eprintln!("rtt      : {} ms", reply.round_trip());
+        }
+        Err(err) => eprintln!("err : {}", err),
+    }
+    device.close()
+}
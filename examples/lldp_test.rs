@@ -0,0 +1,64 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ethernet, ip, raw, stack::Stack};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 && args.len() != 5 {
+        return Err(Error::Invalid(
+            "USAGE: lldp_test <interface> <interval> [ip_address netmask]".to_string(),
+        ));
+    }
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let mut device = ethernet::Device::open(&stack, &args[1], ethernet::ADDR_ANY, raw::Type::Auto)?;
+    if args.len() == 5 {
+        let ip_addr = ip::Addr::from_str(&args[3])?;
+        let netmask = ip::Addr::from_str(&args[4])?;
+        eprintln!("ip_addr: {}", ip_addr);
+        let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
+        device.add_interface(interface);
+    }
+    device.run()?;
+    let config = ethernet::lldp::Config {
+        interval: args[2]
+            .parse()
+            .map_err(|_| Error::Invalid(format!("invalid interval: {}", args[2])))?,
+        ..Default::default()
+    };
+    ethernet::lldp::enable(&device, config)?;
+
+    while !TERMINATE.load(Ordering::SeqCst) {}
+
+    for neighbor in ethernet::lldp::neighbors(&stack) {
+        eprintln!(
+            "{}: {} port {} ttl {}s name {:?} addr {:?}",
+            neighbor.port,
+            neighbor.chassis_id,
+            neighbor.port_id,
+            neighbor.ttl,
+            neighbor.system_name,
+            neighbor.management_addr.map(|addr| format!("{}", addr))
+        );
+    }
+    ethernet::lldp::disable(&device);
+    device.close()
+}
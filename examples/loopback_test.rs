@@ -0,0 +1,25 @@
+extern crate microps_rs;
+
+use microps_rs::{icmp, ip, link, stack::Stack};
+use std::str::FromStr;
+use std::time::Duration;
+
+fn main() {
+    let stack = Stack::new();
+    let ip_addr = ip::Addr::from_str("127.0.0.1").unwrap();
+    let netmask = ip::Addr::from_str("255.0.0.0").unwrap();
+
+    let mut device = link::loopback::Device::open(&stack);
+    let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface.clone());
+    device.run().unwrap();
+
+    match icmp::timestamp(&interface, &ip_addr, Duration::from_secs(3)) {
+        Ok(reply) => {
+            eprintln!("offset   : {} ms", reply.offset());
+            eprintln!("rtt      : {} ms", reply.round_trip());
+        }
+        Err(err) => eprintln!("err : {}", err),
+    }
+    device.close().unwrap();
+}
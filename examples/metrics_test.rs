@@ -0,0 +1,51 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ethernet, ip, raw, stack::Stack, stats};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 5 {
+        return Err(Error::Invalid(
+            "USAGE: metrics_test <interface> <ip_address> <netmask> <listen_addr|file>".to_string(),
+        ));
+    }
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let mut device = ethernet::Device::open(&stack, &args[1], ethernet::ADDR_ANY, raw::Type::Auto)?;
+    let ip_addr = ip::Addr::from_str(&args[2])?;
+    let netmask = ip::Addr::from_str(&args[3])?;
+    eprintln!("ip_addr: {}", ip_addr);
+    let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface);
+    device.run()?;
+
+    // a path is written to periodically, anything else is an address to listen on
+    if args[4].contains('/') {
+        let path = ::std::path::PathBuf::from(&args[4]);
+        stats::prometheus::write_every(&stack, path, ::std::time::Duration::from_secs(5));
+    } else {
+        stats::prometheus::serve(&stack, &args[4])?;
+    }
+    eprintln!("[{}]", args[1]);
+
+    while !TERMINATE.load(Ordering::SeqCst) {}
+    device.close()
+}
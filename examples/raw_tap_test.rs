@@ -18,7 +18,7 @@ extern "C" fn handle_sigint(signal: libc::c_int) {
 }
 
 fn main() {
-    let args: Vec<String> = ::std::env::args().into_iter().collect();
+    let args: Vec<String> = ::std::env::args().collect();
     if args.len() != 2 {
         panic!("USAGE: raw_tap_test <device>");
     }
@@ -33,7 +33,7 @@ fn main() {
             .rx(
                 Box::new(|data: Buffer| {
                     println!("receive {} octets", data.0.len());
-                    Ok(None)
+                    Ok(())
                 }),
                 1000,
             )
@@ -18,7 +18,7 @@ extern "C" fn handle_sigint(signal: libc::c_int) {
 }
 
 fn main() {
-    let args: Vec<String> = ::std::env::args().into_iter().collect();
+    let args: Vec<String> = ::std::env::args().collect();
     if args.len() != 2 {
         panic!("USAGE: raw_socket_test <device>");
     }
@@ -34,7 +34,7 @@ fn main() {
                 Box::new(|data: Buffer| {
                     eprintln!("receive {} octets", data.0.len());
                     eprintln!("{}", data);
-                    Ok(None)
+                    Ok(())
                 }),
                 1000,
             )
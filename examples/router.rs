@@ -4,8 +4,9 @@ extern crate libc;
 extern crate microps_rs;
 extern crate nix;
 
-use microps_rs::{ethernet, ip, raw};
+use microps_rs::{ethernet, ip, raw, stack::Stack};
 use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 lazy_static! {
@@ -40,18 +41,20 @@ const INTERFACES: [InterfaceData; 2] = [
 ];
 
 fn main() {
-    ip::set_is_forwarding(true);
+    let stack = Stack::new();
+    ip::set_is_forwarding(&stack, true);
     for interface in INTERFACES.iter() {
         let mut device = ethernet::Device::open(
+            &stack,
             interface.name,
-            ethernet::MacAddr::from_str(&interface.mac_addr.to_string()).unwrap(),
+            ethernet::MacAddr::from_str(interface.mac_addr).unwrap(),
             raw::Type::Auto,
         )
         .unwrap();
         let interface = ip::interface::Interface::new(
             device.clone(),
-            ip::Addr::from_str(&interface.ip_addr.to_string()).unwrap(),
-            ip::Addr::from_str(&interface.netmask.to_string()).unwrap(),
+            ip::Addr::from_str(interface.ip_addr).unwrap(),
+            ip::Addr::from_str(interface.netmask).unwrap(),
             None,
         );
         device.add_interface(interface);
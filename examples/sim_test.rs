@@ -0,0 +1,104 @@
+extern crate microps_rs;
+
+use microps_rs::{
+    arp,
+    buffer::Buffer,
+    error::Error,
+    ip::{self, interface::Interface},
+    sim::{LinkConfig, Simulator},
+    stats, timer, udp,
+};
+use std::str::FromStr;
+use std::time::Duration;
+
+// two hosts over a lossy, reordering link; fragmented datagrams and ARP retransmissions
+// all run on simulated time, so the same seed gives the same trace every time
+fn run(seed: u64) -> Vec<String> {
+    let mut sim = Simulator::new(seed);
+    let start = sim.now();
+    let stack_a = sim.stack();
+    let stack_b = sim.stack();
+    let config = LinkConfig {
+        latency: Duration::from_millis(5),
+        bandwidth: 10_000_000,
+        loss: 0.05,
+        duplicate: 0.05,
+        reorder: 0.2,
+        reorder_delay: Duration::from_millis(20),
+    };
+    let (mut device_a, mut device_b) = sim.connect(&stack_a, &stack_b, config).unwrap();
+    let netmask = ip::Addr::from_str("255.255.255.0").unwrap();
+    let addr_a = ip::Addr::from_str("10.63.0.1").unwrap();
+    let addr_b = ip::Addr::from_str("10.63.0.2").unwrap();
+    let interface_a = Interface::new(device_a.clone(), addr_a, netmask, None);
+    device_a.add_interface(interface_a.clone());
+    let interface_b = Interface::new(device_b.clone(), addr_b, netmask, None);
+    device_b.add_interface(interface_b.clone());
+    // conflict detection probes and announces both addresses first
+    sim.run_for(Duration::from_secs(10));
+
+    let mut server = udp::open(&stack_b).unwrap();
+    server.bind_interface(interface_b, 7).unwrap();
+    let mut client = udp::open(&stack_a).unwrap();
+    client.bind_interface(interface_a, 0).unwrap();
+
+    let mut trace = vec![];
+    for (i, len) in [16, 4000, 64, 3000, 1200, 6000].iter().enumerate() {
+        client
+            .send_to(Buffer::from_vec(vec![i as u8; *len]), addr_b, 7)
+            .unwrap();
+        sim.run_for(Duration::from_secs(2));
+        loop {
+            match server.recv_from(0) {
+                Ok((_, _, buf)) => trace.push(format!(
+                    "{:>6} ms: {} bytes of #{}",
+                    (sim.now() - start).num_milliseconds(),
+                    buf.0.len(),
+                    buf.0[0]
+                )),
+                Err(Error::Timeout) => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+    }
+    for info in timer::timers(&stack_a) {
+        trace.push(format!(
+            "a: timer {:?} in {} ms",
+            info.name,
+            (info.deadline - sim.now()).num_milliseconds()
+        ));
+    }
+
+    // nothing is received from here on, yet entries still age and reassemblies time out
+    sim.run_for(Duration::from_secs(60));
+    for entry in arp::entries(&stack_a) {
+        assert_eq!(entry.state, arp::State::Stale, "{} did not age", entry.ip_addr);
+        trace.push(format!("a: {} is {}", entry.ip_addr, entry.state));
+    }
+    let counters = stats::snapshot(&stack_b).counters;
+    trace.push(format!("link: {:?}", sim.counters()));
+    trace.push(format!(
+        "b: reassembled {}, failed {}",
+        counters.ip.reasm_oks, counters.ip.reasm_fails
+    ));
+    trace
+}
+
+fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    let seed = match args.get(1) {
+        Some(seed) => seed.parse().unwrap(),
+        None => 1,
+    };
+    let trace = run(seed);
+    for line in trace.iter() {
+        eprintln!("{}", line);
+    }
+    assert_eq!(
+        trace,
+        run(seed),
+        "seed {} did not replay the same way",
+        seed
+    );
+    eprintln!("seed {} replayed the same way", seed);
+}
@@ -0,0 +1,54 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ip, link, slip, stack::Stack};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+// e.g. `socat -d -d pty,raw,echo=0 pty,raw,echo=0`, then run this on one end
+// and `slattach -p slip` with `ip addr add` on the other.
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 4 && args.len() != 5 {
+        return Err(Error::Invalid(
+            "USAGE: slip_test <tty> <ip_address> <netmask> [baud_rate]".to_string(),
+        ));
+    }
+    let mut config = slip::Config::default();
+    if args.len() == 5 {
+        config.baud_rate = args[4]
+            .parse()
+            .ok()
+            .and_then(slip::baud_rate)
+            .ok_or_else(|| Error::Invalid(format!("unknown baud rate: {}", args[4])))?;
+    }
+    let ip_addr = ip::Addr::from_str(&args[2])?;
+    let netmask = ip::Addr::from_str(&args[3])?;
+
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let raw = slip::Device::open(args[1].as_str(), &config)?;
+    let mut device = link::p2p::Device::open(&stack, raw, link::p2p::MTU)?;
+    eprintln!("ip_addr: {}", ip_addr);
+    let interface = ip::interface::Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface);
+    device.run()?;
+    eprintln!("[{}]", args[1]);
+    while !TERMINATE.load(Ordering::SeqCst) {}
+    device.close()
+}
@@ -0,0 +1,78 @@
+extern crate microps_rs;
+extern crate tokio;
+
+use microps_rs::{
+    error::Error,
+    ethernet,
+    event_loop::TokioEventLoop,
+    ip::{self, interface::Interface},
+    raw,
+    stack::Stack,
+    udp,
+};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// echoes UDP on port 7 for a while, with the devices, the timers and the socket all on
+// one tokio thread:
+//
+//   tokio_echo <interface> <ip_address> <netmask> <seconds>
+async fn echo(stack: Stack, interface: Interface, seconds: u64) -> Result<(), Error> {
+    let mut socket = udp::open(&stack)?.into_async();
+    socket.socket().bind_interface(interface, 7)?;
+    let until = Instant::now() + Duration::from_secs(seconds);
+    let mut echoed = 0;
+    while Instant::now() < until {
+        // a receive that times out is dropped and leaves nothing behind with the socket
+        match socket.recv_from_timeout(Duration::from_millis(200)).await {
+            Ok((peer_addr, peer_port, buf)) => {
+                socket.send_to(buf, peer_addr, peer_port).await?;
+                echoed += 1;
+            }
+            Err(Error::Timeout) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    socket.close()?;
+    eprintln!("echoed {} datagrams", echoed);
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 5 {
+        return Err(Error::Invalid(
+            "USAGE: tokio_echo <interface> <ip_address> <netmask> <seconds>".to_string(),
+        ));
+    }
+    let ip_addr = ip::Addr::from_str(&args[2])?;
+    let netmask = ip::Addr::from_str(&args[3])?;
+    let seconds = args[4]
+        .parse()
+        .map_err(|_| Error::Invalid(format!("invalid seconds: {}", args[4])))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let stack = Stack::new();
+        let mut device =
+            ethernet::Device::open(&stack, &args[1], ethernet::ADDR_ANY, raw::Type::Auto)?;
+        let interface = Interface::new(device.clone(), ip_addr, netmask, None);
+        device.add_interface(interface.clone());
+        let mut event_loop = TokioEventLoop::new(&stack)?;
+        event_loop.add(&device)?;
+        let event_loop = Arc::new(event_loop);
+        let event_loop_ = event_loop.clone();
+        let handle = tokio::spawn(async move { event_loop_.run().await });
+        eprintln!("[{}] {}", args[1], ip_addr);
+
+        let result = echo(stack.clone(), interface, seconds).await;
+        handle.abort();
+        let _ = handle.await;
+        drop(event_loop);
+        device.close()?;
+        result
+    })
+}
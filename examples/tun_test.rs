@@ -0,0 +1,50 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ip, link, raw, stack::Stack};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
+    let (ifname, ip_addr, netmask, packet_info) = if args.len() == 4 {
+        (args[1].clone(), args[2].clone(), args[3].clone(), false)
+    } else if args.len() == 5 && args[4] == "pi" {
+        (args[1].clone(), args[2].clone(), args[3].clone(), true)
+    } else {
+        return Err(Error::Invalid(
+            "USAGE: tun_test <interface> <ip_address> <netmask> [pi]".to_string(),
+        ));
+    };
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let raw = raw::tap::Device::open_tun(ifname.as_str(), packet_info)?;
+    let mut device = link::p2p::Device::open(&stack, raw, link::p2p::MTU)?;
+    eprintln!("ip_addr: {}", ip_addr);
+    let interface = ip::interface::Interface::new(
+        device.clone(),
+        ip::Addr::from_str(&ip_addr)?,
+        ip::Addr::from_str(&netmask)?,
+        None,
+    );
+    device.add_interface(interface);
+    device.run()?;
+    eprintln!("[{}]", ifname);
+    while !TERMINATE.load(Ordering::SeqCst) {}
+    device.close()
+}
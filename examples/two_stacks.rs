@@ -0,0 +1,69 @@
+extern crate microps_rs;
+
+use microps_rs::{
+    buffer::Buffer,
+    ethernet::{self, Device},
+    ip::{self, interface::Interface},
+    raw::Type,
+    stack::Stack,
+    udp,
+};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+// runs two hosts in one process, one on each end of a veth pair:
+//
+//   ip link add sta type veth peer name stb
+//   ip link set sta up; ip link set stb up
+//   two_stacks sta stb
+fn host(name: &str, ip_addr: &str) -> (Stack, Device, Interface) {
+    let stack = Stack::new();
+    let mut device = Device::open(&stack, name, ethernet::ADDR_ANY, Type::Auto).unwrap();
+    let ip_addr = ip::Addr::from_str(ip_addr).unwrap();
+    let netmask = ip::Addr::from_str("255.255.255.0").unwrap();
+    let interface = Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface.clone());
+    device.run().unwrap();
+    (stack, device, interface)
+}
+
+fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        panic!("USAGE: two_stacks <interface> <interface>");
+    }
+    let (stack_a, device_a, interface_a) = host(&args[1], "10.62.0.1");
+    let (stack_b, device_b, interface_b) = host(&args[2], "10.62.0.2");
+    // addresses are unusable until conflict detection has probed and announced them
+    thread::sleep(Duration::from_secs(8));
+
+    let mut server = udp::open(&stack_b).unwrap();
+    server.bind_interface(interface_b, 7).unwrap();
+    let echo = thread::spawn(move || {
+        let (peer_addr, peer_port, buf) = server.recv_from(5).unwrap();
+        eprintln!("b: {} bytes from {}:{}", buf.0.len(), peer_addr, peer_port);
+        server.send_to(buf, peer_addr, peer_port).unwrap();
+        server.close().unwrap();
+    });
+
+    let mut client = udp::open(&stack_a).unwrap();
+    client.bind_interface(interface_a, 0).unwrap();
+    let peer_addr = ip::Addr::from_str("10.62.0.2").unwrap();
+    client
+        .send_to(Buffer::from_vec(b"hello".to_vec()), peer_addr, 7)
+        .unwrap();
+    let (_, _, buf) = client.recv_from(5).unwrap();
+    eprintln!("a: echoed {:?}", String::from_utf8_lossy(&buf.to_vec()));
+    client.close().unwrap();
+    echo.join().unwrap();
+
+    // each stack only knows its own side
+    eprintln!(
+        "a: {} device(s), b: {} device(s)",
+        stack_a.devices().len(),
+        stack_b.devices().len()
+    );
+    device_a.close().unwrap();
+    device_b.close().unwrap();
+}
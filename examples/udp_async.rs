@@ -0,0 +1,138 @@
+extern crate microps_rs;
+
+use microps_rs::{
+    buffer::Buffer,
+    error::Error,
+    ethernet::{self, Device},
+    ip::{self, interface::Interface},
+    raw::Type,
+    stack::Stack,
+    udp,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+// serves several sockets of two hosts from the main thread alone, over a veth pair:
+//
+//   ip link add sta type veth peer name stb
+//   ip link set sta up; ip link set stb up
+//   udp_async sta stb
+fn host(name: &str, ip_addr: &str) -> (Stack, Device, Interface) {
+    let stack = Stack::new();
+    let mut device = Device::open(&stack, name, ethernet::ADDR_ANY, Type::Auto).unwrap();
+    let ip_addr = ip::Addr::from_str(ip_addr).unwrap();
+    let netmask = ip::Addr::from_str("255.255.255.0").unwrap();
+    let interface = Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface.clone());
+    device.run().unwrap();
+    (stack, device, interface)
+}
+
+// the smallest executor there is: poll whatever was woken, park while nothing was
+struct Task {
+    woken: AtomicBool,
+    thread: Thread,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+fn run_all(futures: Vec<Pin<Box<dyn Future<Output = ()>>>>) {
+    let mut tasks: Vec<_> = futures
+        .into_iter()
+        .map(|future| {
+            let task = Arc::new(Task {
+                woken: AtomicBool::new(true),
+                thread: thread::current(),
+            });
+            (task, Some(future))
+        })
+        .collect();
+    while tasks.iter().any(|(_, future)| future.is_some()) {
+        let mut polled = false;
+        for (task, future) in tasks.iter_mut() {
+            if future.is_none() || !task.woken.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+            polled = true;
+            let waker = Waker::from(task.clone());
+            let mut cx = Context::from_waker(&waker);
+            if let Poll::Ready(()) = future.as_mut().unwrap().as_mut().poll(&mut cx) {
+                *future = None;
+            }
+        }
+        if !polled {
+            thread::park();
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        panic!("USAGE: udp_async <interface> <interface>");
+    }
+    let (stack_a, device_a, interface_a) = host(&args[1], "10.62.0.1");
+    let (stack_b, device_b, interface_b) = host(&args[2], "10.62.0.2");
+    // addresses are unusable until conflict detection has probed and announced them
+    thread::sleep(Duration::from_secs(8));
+
+    let ports = [7, 8, 9];
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![];
+    for port in ports.iter().cloned() {
+        let mut server = udp::open(&stack_b).unwrap().into_async();
+        server
+            .socket()
+            .bind_interface(interface_b.clone(), port)
+            .unwrap();
+        futures.push(Box::pin(async move {
+            let (peer_addr, peer_port, buf) = server.recv_from().await.unwrap();
+            eprintln!(
+                "b:{}: {} bytes from {}:{}",
+                port,
+                buf.0.len(),
+                peer_addr,
+                peer_port
+            );
+            server.send_to(buf, peer_addr, peer_port).await.unwrap();
+            server.close().unwrap();
+        }));
+    }
+    let mut client = udp::open(&stack_a).unwrap().into_async();
+    client.socket().bind_interface(interface_a, 0).unwrap();
+    let peer_addr = ip::Addr::from_str("10.62.0.2").unwrap();
+    futures.push(Box::pin(async move {
+        for port in ports.iter().cloned() {
+            let message = format!("hello {}", port).into_bytes();
+            client
+                .send_to(Buffer::from_vec(message), peer_addr, port)
+                .await
+                .unwrap();
+            let (_, _, buf) = client
+                .recv_from_timeout(Duration::from_secs(5))
+                .await
+                .unwrap();
+            eprintln!("a: echoed {:?}", String::from_utf8_lossy(&buf.to_vec()));
+        }
+        // nobody sends any more
+        match client.recv_from_timeout(Duration::from_millis(500)).await {
+            Err(Error::Timeout) => eprintln!("a: timed out"),
+            other => panic!("expected a timeout: {:?}", other.map(|(addr, _, _)| addr)),
+        }
+        client.close().unwrap();
+    }));
+    run_all(futures);
+
+    device_a.close().unwrap();
+    device_b.close().unwrap();
+}
@@ -4,9 +4,11 @@ use microps_rs::{
     ethernet::{self, Device, MacAddr},
     ip::{self, interface::Interface},
     raw::Type,
+    stack::Stack,
     udp,
 };
 use std::collections::VecDeque;
+use std::str::FromStr;
 
 fn print_usage(name: &str) {
     eprintln!(
@@ -31,7 +33,7 @@ enum Args {
 }
 
 fn parse_args() -> Args {
-    let mut args: VecDeque<String> = ::std::env::args().into_iter().collect();
+    let mut args: VecDeque<String> = ::std::env::args().collect();
     let program_name = args.pop_front().unwrap();
 
     match (|| {
@@ -39,29 +41,29 @@ fn parse_args() -> Args {
         let mac_addr = if args[0] == "static" || args[0] == "dhcp" {
             None
         } else {
-            Some(MacAddr::from_str(&args.pop_front()?).unwrap())
+            Some(MacAddr::from_str(&args.pop_front()?).ok()?)
         };
         match args.pop_front()?.as_str() {
             "static" => {
-                let ip_addr = ip::Addr::from_str(&args.pop_front()?).unwrap();
-                let netmask = ip::Addr::from_str(&args.pop_front()?).unwrap();
+                let ip_addr = ip::Addr::from_str(&args.pop_front()?).ok()?;
+                let netmask = ip::Addr::from_str(&args.pop_front()?).ok()?;
                 let gateway = if args.is_empty() {
                     None
                 } else {
-                    let gateway = ip::Addr::from_str(&args.pop_front()?).unwrap();
+                    let gateway = ip::Addr::from_str(&args.pop_front()?).ok()?;
                     Some(gateway)
                 };
                 Some(Args::Static {
-                    interface: interface,
-                    mac_addr: mac_addr,
-                    ip_addr: ip_addr,
-                    netmask: netmask,
-                    gateway: gateway,
+                    interface,
+                    mac_addr,
+                    ip_addr,
+                    netmask,
+                    gateway,
                 })
             }
             "dhcp" => Some(Args::Dhcp {
-                interface: interface,
-                mac_addr: mac_addr,
+                interface,
+                mac_addr,
             }),
             _ => {
                 print_usage(&program_name);
@@ -78,6 +80,7 @@ fn parse_args() -> Args {
 }
 
 fn main() {
+    let stack = Stack::new();
     let interface = match parse_args() {
         Args::Static {
             interface,
@@ -87,6 +90,7 @@ fn main() {
             gateway,
         } => {
             let mut device = Device::open(
+                &stack,
                 interface.as_str(),
                 match mac_addr {
                     None => ethernet::ADDR_ANY,
@@ -105,6 +109,7 @@ fn main() {
             mac_addr,
         } => {
             let mut device = Device::open(
+                &stack,
                 interface.as_str(),
                 match mac_addr {
                     None => ethernet::ADDR_ANY,
@@ -113,8 +118,8 @@ fn main() {
                 Type::Auto,
             )
             .unwrap();
-            let ip_addr = ip::Addr::from_str(&"0.0.0.0".to_string()).unwrap();
-            let netmask = ip::Addr::from_str(&"0.0.0.0".to_string()).unwrap();
+            let ip_addr = ip::Addr::from_str("0.0.0.0").unwrap();
+            let netmask = ip::Addr::from_str("0.0.0.0").unwrap();
             let interface = Interface::new(device.clone(), ip_addr, netmask, None);
             device.add_interface(interface.clone());
             device.run().unwrap();
@@ -123,7 +128,7 @@ fn main() {
         }
     };
 
-    let mut socket = udp::open().unwrap();
+    let mut socket = udp::open(&stack).unwrap();
     socket.bind_interface(interface, 7).unwrap();
     eprintln!("waiting for message...");
     loop {
@@ -0,0 +1,105 @@
+extern crate microps_rs;
+
+use microps_rs::{
+    buffer::Buffer,
+    error::Error,
+    ethernet::{self, Device},
+    ip::{self, interface::Interface},
+    raw::Type,
+    stack::Stack,
+    udp::{self, Events, PollFd},
+};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// one thread serving three ports, over a veth pair:
+//
+//   ip link add sta type veth peer name stb
+//   ip link set sta up; ip link set stb up
+//   udp_poll sta stb
+fn host(name: &str, ip_addr: &str) -> (Stack, Device, Interface) {
+    let stack = Stack::new();
+    let mut device = Device::open(&stack, name, ethernet::ADDR_ANY, Type::Auto).unwrap();
+    let ip_addr = ip::Addr::from_str(ip_addr).unwrap();
+    let netmask = ip::Addr::from_str("255.255.255.0").unwrap();
+    let interface = Interface::new(device.clone(), ip_addr, netmask, None);
+    device.add_interface(interface.clone());
+    device.run().unwrap();
+    (stack, device, interface)
+}
+
+fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        panic!("USAGE: udp_poll <interface> <interface>");
+    }
+    let (stack_a, device_a, interface_a) = host(&args[1], "10.62.0.1");
+    let (stack_b, device_b, interface_b) = host(&args[2], "10.62.0.2");
+    // addresses are unusable until conflict detection has probed and announced them
+    thread::sleep(Duration::from_secs(8));
+
+    let ports = [53, 67, 123];
+    let mut servers: Vec<_> = ports
+        .iter()
+        .map(|port| {
+            let mut server = udp::open(&stack_b).unwrap();
+            server.bind_interface(interface_b.clone(), *port).unwrap();
+            server.set_nonblocking(true).unwrap();
+            server
+        })
+        .collect();
+    match servers[0].recv_from(-1) {
+        Err(Error::WouldBlock) => eprintln!("b: nothing to read yet"),
+        other => panic!("expected to be told it would block: {:?}", other.is_ok()),
+    }
+    let serve = thread::spawn(move || {
+        let mut served = 0;
+        while served < ports.len() {
+            let ready: Vec<usize> = {
+                let mut fds: Vec<_> = servers
+                    .iter()
+                    .map(|server| PollFd::new(server, Events::READABLE))
+                    .collect();
+                let started = Instant::now();
+                if udp::poll(&mut fds, 250).unwrap() == 0 {
+                    eprintln!("b: idle for {:?}", started.elapsed());
+                    continue;
+                }
+                (0..fds.len())
+                    .filter(|i| fds[*i].revents.contains(Events::READABLE))
+                    .collect()
+            };
+            for i in ready {
+                let server = &mut servers[i];
+                let (peer_addr, peer_port, buf) = server.recv_from(-1).unwrap();
+                eprintln!("b:{}: {} bytes", ports[i], buf.0.len());
+                server.send_to(buf, peer_addr, peer_port).unwrap();
+                served += 1;
+            }
+        }
+        for server in servers {
+            server.close().unwrap();
+        }
+    });
+
+    let mut client = udp::open(&stack_a).unwrap();
+    client.bind_interface(interface_a, 0).unwrap();
+    let peer_addr = ip::Addr::from_str("10.62.0.2").unwrap();
+    for port in ports.iter() {
+        thread::sleep(Duration::from_millis(400));
+        let message = format!("to {}", port).into_bytes();
+        client
+            .send_to(Buffer::from_vec(message), peer_addr, *port)
+            .unwrap();
+        let (_, _, buf) = client
+            .recv_from_timeout(Duration::from_millis(500))
+            .unwrap();
+        eprintln!("a: echoed {:?}", String::from_utf8_lossy(&buf.to_vec()));
+    }
+    serve.join().unwrap();
+    client.close().unwrap();
+
+    device_a.close().unwrap();
+    device_b.close().unwrap();
+}
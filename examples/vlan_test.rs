@@ -0,0 +1,62 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate microps_rs;
+extern crate nix;
+
+use microps_rs::{error::Error, ethernet, ip, raw, stack::Stack};
+use nix::sys::signal::{self, SigHandler, Signal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    static ref TERMINATE: AtomicBool = AtomicBool::new(false);
+}
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    let signal = Signal::from_c_int(signal).unwrap();
+    TERMINATE.store(signal == Signal::SIGINT, Ordering::Relaxed);
+}
+
+// `<vid>` is either `100` for 802.1Q or `10.100` for QinQ
+fn main() -> Result<(), Error> {
+    let stack = Stack::new();
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 5 && args.len() != 6 {
+        return Err(Error::Invalid(
+            "USAGE: vlan_test <interface> <vid> <ip_address> <netmask> [priority]".to_string(),
+        ));
+    }
+    let vids = args[2]
+        .split('.')
+        .map(|vid| vid.parse())
+        .collect::<Result<Vec<u16>, _>>()
+        .map_err(|_| Error::Invalid(format!("invalid vid: {}", args[2])))?;
+    let ip_addr = ip::Addr::from_str(&args[3])?;
+    let netmask = ip::Addr::from_str(&args[4])?;
+
+    let handler = SigHandler::Handler(handle_sigint);
+    unsafe { signal::signal(Signal::SIGINT, handler) }?;
+
+    let mut device =
+        ethernet::Device::open(&stack, args[1].as_str(), ethernet::ADDR_ANY, raw::Type::Auto)?;
+    let mut vlan = match vids.as_slice() {
+        [vid] => ethernet::vlan::Device::open(&device, *vid),
+        [s_vid, c_vid] => ethernet::vlan::Device::open_qinq(&device, *s_vid, *c_vid),
+        _ => Err(Error::Invalid("at most two vids can be stacked".to_string())),
+    }?;
+    if args.len() == 6 {
+        let priority = args[5]
+            .parse()
+            .map_err(|_| Error::Invalid(format!("invalid priority: {}", args[5])))?;
+        vlan.set_priority(priority)?;
+    }
+    eprintln!("ip_addr: {}", ip_addr);
+    let interface = ip::interface::Interface::new(vlan.clone(), ip_addr, netmask, None);
+    vlan.add_interface(interface);
+    device.run()?;
+    eprintln!("[{}.{}]", args[1], args[2]);
+    while !TERMINATE.load(Ordering::SeqCst) {}
+    vlan.close()?;
+    device.close()
+}
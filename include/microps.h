@@ -0,0 +1,106 @@
+/*
+ * C API of microps-rs, exported from libmicrops_rs.so (src/capi.rs).
+ *
+ * mps_init brings up one stack on one ethernet device; the other calls mirror their
+ * socket(2) namesakes for UDP over IPv4. On failure they return -1 and set errno.
+ *
+ * Written by build.rs from the declarations in src/capi.rs; edit those instead.
+ */
+
+#ifndef MICROPS_H
+#define MICROPS_H
+
+#include <poll.h>
+#include <stddef.h>
+#include <sys/socket.h>
+#include <sys/types.h>
+
+/**
+ * `struct mps_config`, what `mps_init` brings up.
+ */
+struct mps_config {
+  /**
+   * e.g. "eth0", or "tap0" for a tap device
+   */
+  const char *device;
+  /**
+   * dotted quads
+   */
+  const char *address;
+  const char *netmask;
+  /**
+   * may be NULL
+   */
+  const char *gateway;
+};
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * brings up the stack on `config.device`, returning once conflict detection has cleared its
+ * address, a few seconds later; fails with EADDRINUSE if another host has it.
+ *
+ * # Safety
+ * `config` and its strings must be valid, or the strings null where allowed.
+ */
+int mps_init(const struct mps_config *config);
+
+/**
+ * opens a UDP socket; `type_` may carry SOCK_NONBLOCK and SOCK_CLOEXEC.
+ */
+int mps_socket(int domain, int type_, int protocol);
+
+/**
+ * binds to a port of the stack's address, or of INADDR_ANY.
+ *
+ * # Safety
+ * `addr` must point to `len` readable bytes.
+ */
+int mps_bind(int fd, const struct sockaddr *addr, socklen_t len);
+
+/**
+ * sends `len` bytes of `buf` to `dst`.
+ *
+ * # Safety
+ * `buf` and `dst` must point to `len` and `dst_len` readable bytes.
+ */
+ssize_t mps_sendto(int fd,
+                   const void *buf,
+                   size_t len,
+                   int _flags,
+                   const struct sockaddr *dst,
+                   socklen_t dst_len);
+
+/**
+ * receives a datagram, truncated to `len`; MSG_DONTWAIT makes this call non-blocking.
+ *
+ * # Safety
+ * `buf` must point to `len` writable bytes, and `src` to `*src_len` unless null.
+ */
+ssize_t mps_recvfrom(int fd,
+                     void *buf,
+                     size_t len,
+                     int flags,
+                     struct sockaddr *src,
+                     socklen_t *src_len);
+
+/**
+ * closes a socket, waking whoever waits on it.
+ */
+int mps_close(int fd);
+
+/**
+ * poll(2) over sockets of the stack; other descriptors are reported as POLLNVAL.
+ *
+ * # Safety
+ * `fds` must point to `nfds` pollfds.
+ */
+int mps_poll(struct pollfd *fds, nfds_t nfds, int timeout);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MICROPS_H */
@@ -1,16 +1,40 @@
+mod acd;
 mod frame;
+mod guard;
 mod table;
 
-use std::error::Error;
-use std::thread::{self, JoinHandle};
+pub use acd::{is_conflicted, is_tentative, set_conflict_handler, Conflict, State as ConflictState};
+pub use guard::{incidents, set_guard, set_incident_handler, GuardConfig, Incident, IncidentKind};
+pub use table::State;
+pub(crate) use table::AGING_INTERVAL;
 
-use chrono::Utc;
-use nix::errno::{errno, Errno};
+use std::sync::Arc;
 
-use crate::{buffer::Buffer, ethernet, ip, packet::Packet, util::RuntimeError};
+use chrono::Duration;
+
+use crate::{
+    buffer::Buffer,
+    error::Error,
+    ethernet, icmp, ip,
+    link::LinkDevice,
+    packet::Packet,
+    stack::Stack,
+    timer::{self, TimerId},
+};
 
 const HARDWARE_TYPE_ETHERNET: u16 = 0x0001;
 
+#[derive(Default)]
+pub(crate) struct Tables {
+    table: table::Tables,
+    acd: acd::Tables,
+    guard: guard::Tables,
+}
+
+// the first retransmission waits this long (in milliseconds), doubling each time
+const RETRANSMIT_INTERVAL: u64 = 1000;
+const RETRANSMIT_MAX: usize = 2;
+
 #[repr(u16)]
 #[derive(Debug, PartialEq, Eq)]
 pub enum Op {
@@ -44,178 +68,802 @@ impl fmt::Display for Op {
     }
 }
 
-fn update_table(ip_addr: &ip::Addr, mac_addr: &ethernet::MacAddr) -> Result<(), Box<dyn Error>> {
-    let idx = table::lookup(ip_addr).ok_or(RuntimeError::new(format!(
-        "not found in table: {}",
-        ip_addr
-    )))?;
-    let (data, device, mac_addr) = {
-        let mut table = table::TABLE.lock().unwrap();
-        let ref mut entry = table.get_mut(idx).unwrap();
-        entry.mac_addr = mac_addr.clone();
-        entry.timestamp = Utc::now();
-        entry.cond.notify_all();
+// refreshes the entry for `ip_addr` and sends what was queued on it; false if there is none
+fn update_table(
+    interface: &ip::interface::Interface,
+    ip_addr: &ip::Addr,
+    mac_addr: &ethernet::MacAddr,
+    op: &Op,
+) -> bool {
+    let stack = interface.stack();
+    let (queue, device) = {
+        let mut table = stack.0.arp.table.entries.lock().unwrap();
+        let entry = match table
+            .iter_mut()
+            .find(|entry| entry.matches(interface, ip_addr))
+        {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if !guard::admit(&stack, entry, mac_addr, op) {
+            return true;
+        }
+        entry.mac_addr = *mac_addr;
+        entry.state = table::State::Reachable;
+        entry.timestamp = stack.now();
+        entry.requested = None;
+        if let Some(id) = entry.retransmit.take() {
+            timer::cancel(&stack, id);
+        }
         let device = entry.interface.0.lock().unwrap().device.clone();
-        (entry.data.clone(), device, entry.mac_addr.clone())
+        (entry.queue.drain(..).collect::<Vec<_>>(), device)
     };
-    if data.is_empty() {
-        device.tx(ethernet::Type::Ip, data, mac_addr)?;
-        table::remove(idx)?;
+    // the entry stays updated even if a queued datagram cannot be sent
+    for data in queue {
+        if let Err(err) = device.tx(ethernet::Type::Ip, data, *mac_addr) {
+            warn!("sending a datagram queued for {} failed: {}", ip_addr, err);
+        }
     }
-    Ok(())
+    true
 }
 
 fn send_request(
     interface: &ip::interface::Interface,
     ip_addr: &ip::Addr,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error> {
     let (src_mac_addr, src_ip_addr) = {
         let interface_inner = interface.0.lock().unwrap();
-        let device_inner = interface_inner.device.0.lock().unwrap();
-        (device_inner.addr.clone(), interface_inner.unicast.clone())
+        (interface_inner.device.addr(), interface_inner.unicast)
     };
     let request = frame::Frame {
         op: Op::Request,
-        src_mac_addr: src_mac_addr,
-        src_ip_addr: src_ip_addr,
+        src_mac_addr,
+        src_ip_addr,
         dst_mac_addr: ethernet::MacAddr::empty(),
-        dst_ip_addr: ip_addr.clone(),
+        dst_ip_addr: *ip_addr,
     };
     let device = {
         let interface_inner = interface.0.lock().unwrap();
         interface_inner.device.clone()
     };
 
-    if cfg!(debug_assertions) {
-        eprintln!(">>> arp request <<<");
-        request.dump();
-    }
+    log_packet!("request", request);
 
     device.tx(
         ethernet::Type::Arp,
         request.to_buffer(),
-        ethernet::ADDR_BROADCAST.clone(),
+        ethernet::ADDR_BROADCAST,
     )?;
     Ok(())
 }
 
 fn send_reply(
     interface: ip::interface::Interface,
+    src_ip_addr: ip::Addr,
     mac_addr: ethernet::MacAddr,
     ip_addr: ip::Addr,
     dst_addr: ethernet::MacAddr,
-) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
-    Ok(Some(thread::spawn(move || {
-        let (src_mac_addr, src_ip_addr, device) = {
-            let interface_inner = interface.0.lock().unwrap();
-            let addr = interface_inner.device.0.lock().unwrap().addr.clone();
-            (
-                addr,
-                interface_inner.unicast.clone(),
-                interface_inner.device.clone(),
-            )
-        };
-        let reply = frame::Frame {
-            op: Op::Reply,
-            src_mac_addr: src_mac_addr,
-            src_ip_addr: src_ip_addr,
-            dst_mac_addr: mac_addr,
-            dst_ip_addr: ip_addr,
+) -> Result<(), Error> {
+    let (src_mac_addr, device) = {
+        let interface_inner = interface.0.lock().unwrap();
+        (interface_inner.device.addr(), interface_inner.device.clone())
+    };
+    let reply = frame::Frame {
+        op: Op::Reply,
+        src_mac_addr,
+        src_ip_addr,
+        dst_mac_addr: mac_addr,
+        dst_ip_addr: ip_addr,
+    };
+    log_packet!("reply", reply);
+    device.tx(ethernet::Type::Arp, reply.to_buffer(), dst_addr)?;
+    Ok(())
+}
+
+/// starts RFC 5227 probing and announcement of the interface's address.
+pub fn probe(interface: &ip::interface::Interface) {
+    acd::start(interface)
+}
+
+/// sends a gratuitous ARP announcing the interface's address, e.g. after failover.
+pub fn announce(interface: &ip::interface::Interface) -> Result<(), Error> {
+    let ip_addr = interface.0.lock().unwrap().unicast;
+    acd::send(interface, ip_addr, ip_addr)
+}
+
+// schedules the `attempt`th retransmission of the request for an INCOMPLETE entry, or for a
+// STALE one being refreshed
+fn schedule_retransmit(
+    interface: &ip::interface::Interface,
+    ip_addr: ip::Addr,
+    attempt: usize,
+) -> TimerId {
+    let stack = interface.stack();
+    let interval = (RETRANSMIT_INTERVAL << attempt) as i64;
+    let interface = interface.clone();
+    timer::add(
+        &stack,
+        "arp: retransmit",
+        Duration::milliseconds(interval),
+        move |_| retransmit(&interface, ip_addr, attempt),
+    )
+}
+
+fn retransmit(interface: &ip::interface::Interface, ip_addr: ip::Addr, attempt: usize) {
+    let stack = interface.stack();
+    let queue = {
+        let mut table = stack.0.arp.table.entries.lock().unwrap();
+        let idx = match table.iter().position(|entry| {
+            entry.matches(interface, &ip_addr)
+                && (entry.state == table::State::Incomplete
+                    || (entry.state == table::State::Stale && entry.requested.is_some()))
+        }) {
+            Some(idx) => idx,
+            None => return,
         };
-        if cfg!(debug_assertions) {
-            eprintln!(">>> arp reply <<<");
-            reply.dump();
+        if attempt < RETRANSMIT_MAX {
+            table[idx].retransmit = Some(schedule_retransmit(interface, ip_addr, attempt + 1));
+            drop(table);
+            if let Err(err) = send_request(interface, &ip_addr) {
+                warn!("requesting {} failed: {}", ip_addr, err);
+            }
+            return;
         }
-        device
-            .tx(ethernet::Type::Arp, reply.to_buffer(), dst_addr)
-            .unwrap();
-        ()
-    })))
+        table.remove(idx).queue
+    };
+    for dgram in queue {
+        if let Err(err) = host_unreachable(interface, dgram) {
+            info!("{}", err);
+        }
+    }
 }
 
+fn host_unreachable(interface: &ip::interface::Interface, dgram: Buffer) -> Result<(), Error> {
+    if dgram.0.len() < ip::dgram::HEADER_MIN_SIZE {
+        return Ok(());
+    }
+    let src = ip::Addr([dgram.0[12], dgram.0[13], dgram.0[14], dgram.0[15]]);
+    let dst = ip::Addr([dgram.0[16], dgram.0[17], dgram.0[18], dgram.0[19]]);
+    if src == interface.0.lock().unwrap().unicast {
+        return Err(Error::Unresolved(dst));
+    }
+    icmp::tx(
+        interface,
+        icmp::Type::DestUnreach,
+        icmp::Code::Unreach(icmp::CodeUnreach::Host),
+        0,
+        icmp::quote(dgram),
+        &src,
+    )
+}
+
+/// Looks up the hardware address of `ip_addr`. While it is being resolved, a copy of `data`
+/// is queued to be sent on resolution and `Ok(None)` is returned.
 pub fn resolve(
     ip_interface: &ip::interface::Interface,
     ip_addr: ip::Addr,
-    data: Buffer,
-) -> Result<Option<ethernet::MacAddr>, Box<dyn Error>> {
-    match table::lookup(&ip_addr) {
-        Some(idx) => {
-            let (timeout, mac_addr) = {
-                let table = table::TABLE.lock().unwrap();
-                let ref entry = table.get(idx).unwrap();
-                if entry.mac_addr == ethernet::ADDR_ANY {
-                    send_request(ip_interface, &ip_addr)?;
-                    while {
-                        let _table = entry.cond.wait_timeout(
-                            table::TABLE.lock().unwrap(),
-                            ::std::time::Duration::from_secs(1),
-                        )?;
-                        errno() == Errno::EINTR as i32
-                    } {}
-                    if errno() == Errno::ETIMEDOUT as i32 {
-                        (Some(idx), entry.mac_addr.clone())
-                    } else {
-                        (None, entry.mac_addr.clone())
+    data: &Buffer,
+) -> Result<Option<ethernet::MacAddr>, Error> {
+    let stack = ip_interface.stack();
+    {
+        let mut table = stack.0.arp.table.entries.lock().unwrap();
+        if let Some(entry) = table
+            .iter_mut()
+            .find(|entry| entry.matches(ip_interface, &ip_addr))
+        {
+            match entry.state {
+                table::State::Incomplete => {
+                    entry.enqueue(data.clone());
+                    return Ok(None);
+                }
+                table::State::Reachable | table::State::Permanent => {
+                    return Ok(Some(entry.mac_addr))
+                }
+                table::State::Stale => {
+                    // keep using the stale address while asking for a fresh one
+                    let mac_addr = entry.mac_addr;
+                    if entry.requested.is_none() {
+                        entry.requested = Some(stack.now());
+                        // retried and finally dropped like an INCOMPLETE entry
+                        entry.retransmit = Some(schedule_retransmit(ip_interface, ip_addr, 0));
+                        drop(table);
+                        send_request(ip_interface, &ip_addr)?;
                     }
-                } else {
-                    (None, entry.mac_addr.clone())
+                    return Ok(Some(mac_addr));
                 }
-            };
-            if let Some(idx) = timeout {
-                table::remove(idx)?;
-                return Err(RuntimeError::new("timed out".to_string()));
             }
-            Ok(Some(mac_addr))
-        }
-        None => {
-            let mut new_entry = table::Entry::new(
-                ip_addr.clone(),
-                ethernet::MacAddr::empty(),
-                ip_interface.clone(),
-            );
-            new_entry.data = data;
-            table::TABLE.lock().unwrap().push(new_entry);
-            send_request(ip_interface, &ip_addr)?;
-            Ok(None)
         }
+        let mut new_entry = table::Entry::new(
+            ip_addr,
+            ethernet::MacAddr::empty(),
+            ip_interface.clone(),
+            stack.now(),
+        );
+        new_entry.enqueue(data.clone());
+        new_entry.requested = Some(stack.now());
+        new_entry.retransmit = Some(schedule_retransmit(ip_interface, ip_addr, 0));
+        table.push(new_entry);
     }
+    send_request(ip_interface, &ip_addr)?;
+    Ok(None)
 }
 
 pub fn rx(
     packet: Buffer,
-    device: &ethernet::Device,
-) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
-    let message = frame::Frame::from_buffer(packet).unwrap();
-
-    if cfg!(debug_assertions) {
-        eprintln!(">>> arp rx <<<");
-        message.dump();
-    }
-
-    table::patrol();
-    let marge = update_table(&message.src_ip_addr, &message.src_mac_addr).is_ok();
-    let device = device.0.lock().unwrap();
-    let interface = device.interface.as_ref().ok_or(RuntimeError::new(format!(
-        "device `{}` has not ip interface.",
-        device.name
-    )))?;
-    let src_ip_addr = interface.0.lock().unwrap().unicast.clone();
+    device: &dyn LinkDevice,
+) -> Result<(), Error> {
+    let message = frame::Frame::parse(packet)?;
+
+    log_packet!("rx", message);
+
+    let stack = device.stack();
+    let interface = &device
+        .interface()
+        .ok_or(Error::Dropped("arp: no interface"))?;
+    let marge = update_table(
+        interface,
+        &message.src_ip_addr,
+        &message.src_mac_addr,
+        &message.op,
+    );
+    acd::check(interface, &device.addr(), &message);
+    if acd::is_tentative(interface) || acd::is_conflicted(interface) {
+        return Ok(());
+    }
+    let src_ip_addr = interface.0.lock().unwrap().unicast;
     if src_ip_addr == message.dst_ip_addr {
         if !marge {
-            let mut table = table::TABLE.lock().unwrap();
+            let mut table = stack.0.arp.table.entries.lock().unwrap();
             table.push(table::Entry::new(
-                message.src_ip_addr.clone(),
-                message.src_mac_addr.clone(),
+                message.src_ip_addr,
+                message.src_mac_addr,
                 interface.clone(),
+                stack.now(),
             ));
         }
         if message.op == Op::Request {
             let interface = interface.clone();
             let src_mac_addr = message.src_mac_addr;
             let src_ip_addr = message.src_ip_addr;
-            let src_mac_addr_ = src_mac_addr.clone();
-            send_reply(interface, src_mac_addr, src_ip_addr, src_mac_addr_)?;
+            let src_mac_addr_ = src_mac_addr;
+            send_reply(
+                interface,
+                message.dst_ip_addr,
+                src_mac_addr,
+                src_ip_addr,
+                src_mac_addr_,
+            )?;
+        }
+    } else if message.op == Op::Request
+        && message.src_ip_addr != ip::Addr::empty()
+        && message.src_ip_addr != message.dst_ip_addr
+        && table::is_proxied(interface, &message.dst_ip_addr)
+    {
+        // answer only for hosts reached through another interface
+        let is_routed = ip::route::lookup(&stack, None, message.dst_ip_addr)
+            .map(|route| !Arc::ptr_eq(&route.interface.0, &interface.0))
+            .unwrap_or(false);
+        if is_routed {
+            send_reply(
+                interface.clone(),
+                message.dst_ip_addr,
+                message.src_mac_addr,
+                message.src_ip_addr,
+                message.src_mac_addr,
+            )?;
         }
     }
-    Ok(None)
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub ip_addr: ip::Addr,
+    pub mac_addr: ethernet::MacAddr,
+    pub interface: ip::interface::Interface,
+    pub age: Duration,
+    pub state: State,
+    // packets waiting for the entry to resolve
+    pub queued: usize,
+}
+
+/// returns a snapshot of the ARP table.
+pub fn entries(stack: &Stack) -> Vec<EntryInfo> {
+    let now = stack.now();
+    let table = stack.0.arp.table.entries.lock().unwrap();
+    table
+        .iter()
+        .map(|entry| EntryInfo {
+            ip_addr: entry.ip_addr,
+            mac_addr: entry.mac_addr,
+            interface: entry.interface.clone(),
+            age: now - entry.timestamp,
+            state: entry.state,
+            queued: entry.queue.len(),
+        })
+        .collect()
+}
+
+/// adds a permanent entry, which `patrol` never expires and received ARP never overwrites,
+/// and sends whatever was queued waiting for it.
+pub fn add_static(
+    interface: &ip::interface::Interface,
+    ip_addr: ip::Addr,
+    mac_addr: ethernet::MacAddr,
+) -> Result<(), Error> {
+    let stack = interface.stack();
+    let (queue, device) = {
+        let mut table = stack.0.arp.table.entries.lock().unwrap();
+        let queue = match table
+            .iter()
+            .position(|entry| entry.matches(interface, &ip_addr))
+        {
+            Some(idx) => table.remove(idx).queue,
+            None => Default::default(),
+        };
+        let mut entry = table::Entry::new(ip_addr, mac_addr, interface.clone(), stack.now());
+        entry.state = table::State::Permanent;
+        table.push(entry);
+        (queue, interface.0.lock().unwrap().device.clone())
+    };
+    // every queued datagram is tried; the first failure is returned
+    let mut result = Ok(());
+    for data in queue {
+        if let Err(err) = device.tx(ethernet::Type::Ip, data, mac_addr) {
+            warn!("sending a datagram queued for {} failed: {}", ip_addr, err);
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+/// makes an existing entry permanent so that received ARP can no longer change it.
+pub fn pin(stack: &Stack, ip_addr: &ip::Addr) -> Result<(), Error> {
+    let mut table = stack.0.arp.table.entries.lock().unwrap();
+    match table
+        .iter_mut()
+        .find(|entry| &entry.ip_addr == ip_addr && entry.state != table::State::Incomplete)
+    {
+        Some(entry) => {
+            entry.state = table::State::Permanent;
+            Ok(())
+        }
+        None => Err(Error::NotFound(format!("{} resolved in arp table", ip_addr))),
+    }
+}
+
+/// removes the entries for `ip_addr` on every interface.
+pub fn delete(stack: &Stack, ip_addr: &ip::Addr) -> Result<(), Error> {
+    let mut table = stack.0.arp.table.entries.lock().unwrap();
+    let len = table.len();
+    table.retain(|entry| {
+        let keep = &entry.ip_addr != ip_addr;
+        if let (false, Some(id)) = (keep, entry.retransmit) {
+            timer::cancel(stack, id);
+        }
+        keep
+    });
+    if table.len() == len {
+        return Err(Error::NotFound(format!("{} in arp table", ip_addr)));
+    }
+    Ok(())
+}
+
+/// removes all dynamic entries learned on `interface`.
+pub fn flush(interface: &ip::interface::Interface) {
+    let stack = interface.stack();
+    let mut table = stack.0.arp.table.entries.lock().unwrap();
+    table.retain(|entry| {
+        let keep = entry.state == table::State::Permanent
+            || !Arc::ptr_eq(&entry.interface.0, &interface.0);
+        if let (false, Some(id)) = (keep, entry.retransmit) {
+            timer::cancel(&stack, id);
+        }
+        keep
+    });
+}
+
+/// ages the table and the guard's history, run every `AGING_INTERVAL` by the stack's timer.
+pub(crate) fn patrol(stack: &Stack) {
+    table::patrol(stack);
+    guard::patrol(stack);
+}
+
+// forgets every entry, proxy and probed address, for `Stack::shutdown`
+pub(crate) fn clear(stack: &Stack) {
+    stack.0.arp.table.entries.lock().unwrap().clear();
+    stack.0.arp.table.proxies.lock().unwrap().clear();
+    acd::clear(stack);
+}
+
+/// makes `interface` answer ARP requests for `network`/`netmask` when it routes there.
+pub fn add_proxy(interface: &ip::interface::Interface, network: ip::Addr, netmask: ip::Addr) {
+    let stack = interface.stack();
+    let mut proxies = stack.0.arp.table.proxies.lock().unwrap();
+    proxies.push(table::Proxy {
+        network: network.apply_mask(&netmask),
+        netmask,
+        interface: interface.clone(),
+    });
+}
+
+pub fn delete_proxy(
+    interface: &ip::interface::Interface,
+    network: ip::Addr,
+    netmask: ip::Addr,
+) -> Result<(), Error> {
+    let network = network.apply_mask(&netmask);
+    let stack = interface.stack();
+    let mut proxies = stack.0.arp.table.proxies.lock().unwrap();
+    match proxies.iter().position(|proxy| {
+        Arc::ptr_eq(&proxy.interface.0, &interface.0)
+            && proxy.network == network
+            && proxy.netmask == netmask
+    }) {
+        Some(idx) => {
+            proxies.remove(idx);
+            Ok(())
+        }
+        None => Err(Error::NotFound(format!("proxy for {}/{}", network, netmask))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ip::interface::Interface,
+        sim::{LinkConfig, Simulator},
+        stats, udp,
+    };
+    use crate::link::{DeviceFlags, Resolution};
+    use std::time;
+
+    // 10.0.0.1 and 10.0.0.2 on one link, past conflict detection
+    fn pair(sim: &mut Simulator) -> (Interface, Interface) {
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (mut device, mut peer_device) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let mask = ip::Addr([255, 0, 0, 0]);
+        let interface = Interface::new(device.clone(), ip::Addr([10, 0, 0, 1]), mask, None);
+        device.add_interface(interface.clone());
+        let peer_interface = Interface::new(peer_device.clone(), ip::Addr([10, 0, 0, 2]), mask, None);
+        peer_device.add_interface(peer_interface.clone());
+        sim.run_for(time::Duration::from_secs(10));
+        (interface, peer_interface)
+    }
+
+    // a UDP datagram to port 7 carrying the single byte `n`, without a UDP checksum
+    fn datagram(src: ip::Addr, dst: ip::Addr, n: u8) -> Buffer {
+        let mut bytes = vec![0x45, 0, 0, 29, 0, n, 0, 0, 64, 17, 0, 0];
+        bytes.extend_from_slice(&src.0);
+        bytes.extend_from_slice(&dst.0);
+        bytes.extend_from_slice(&[0, 9, 0, 7, 0, 9, 0, 0, n]);
+        let sum = crate::util::calc_checksum(&bytes[..20], 20, 0);
+        let mut buf = Buffer::from_vec(bytes);
+        ip::dgram::Dgram::write_checksum(&mut buf, sum);
+        buf
+    }
+
+    fn entry(stack: &Stack, ip_addr: ip::Addr) -> Option<(State, usize)> {
+        entries(stack)
+            .into_iter()
+            .find(|entry| entry.ip_addr == ip_addr)
+            .map(|entry| (entry.state, entry.queued))
+    }
+
+    // frames sent by the stack's devices
+    fn sent(stack: &Stack) -> u64 {
+        stats::snapshot(stack)
+            .devices
+            .iter()
+            .map(|(_, device)| device.tx_frames)
+            .sum()
+    }
+
+    fn received(socket: &mut udp::Socket) -> Vec<u8> {
+        let mut received = vec![];
+        while let Ok((_, _, data)) = socket.recv_from(0) {
+            received.push(data.0[0]);
+        }
+        received
+    }
+
+    #[test]
+    fn datagrams_queued_while_resolving_go_out_on_the_reply() {
+        let mut sim = Simulator::new(1);
+        let (interface, peer_interface) = pair(&mut sim);
+        let (stack, peer) = (interface.stack(), peer_interface.stack());
+        let mut socket = udp::open(&peer).unwrap();
+        socket.bind_interface(peer_interface.clone(), 7).unwrap();
+        let (src, dst) = (ip::Addr([10, 0, 0, 1]), ip::Addr([10, 0, 0, 2]));
+
+        // one request, and only the newest few datagrams kept
+        let before = sent(&stack);
+        for n in 0..4 {
+            assert_eq!(resolve(&interface, dst, &datagram(src, dst, n)).unwrap(), None);
+        }
+        assert_eq!(sent(&stack) - before, 1);
+        assert_eq!(entry(&stack, dst), Some((State::Incomplete, table::QUEUE_MAX)));
+
+        sim.run_for(time::Duration::from_secs(1));
+        assert_eq!(entry(&stack, dst), Some((State::Reachable, 0)));
+        assert_eq!(received(&mut socket), vec![1, 2, 3]);
+        let mac_addr = peer_interface.0.lock().unwrap().device.addr();
+        assert_eq!(resolve(&interface, dst, &datagram(src, dst, 4)).unwrap(), Some(mac_addr));
+        // and nothing was asked again
+        assert_eq!(sent(&stack) - before, 4);
+        assert!(timer::timers(&stack).iter().all(|timer| timer.name != "arp: retransmit"));
+    }
+
+    #[test]
+    fn unanswered_requests_are_retried_then_given_up() {
+        let mut sim = Simulator::new(1);
+        let (interface, peer_interface) = pair(&mut sim);
+        let (stack, peer) = (interface.stack(), peer_interface.stack());
+        let nobody = ip::Addr([10, 0, 0, 9]);
+        let start = sent(&stack);
+        // one datagram from here, another passing through from the peer
+        let own = datagram(ip::Addr([10, 0, 0, 1]), nobody, 0);
+        let forwarded = datagram(ip::Addr([10, 0, 0, 2]), nobody, 1);
+        assert_eq!(resolve(&interface, nobody, &own).unwrap(), None);
+        assert_eq!(resolve(&interface, nobody, &forwarded).unwrap(), None);
+
+        // asked again after 1s, then after 2s more
+        for (wait, requests) in [(999, 1), (1, 2), (1999, 2), (1, 3)].iter() {
+            sim.run_for(time::Duration::from_millis(*wait));
+            assert_eq!(sent(&stack) - start, *requests);
+            assert_eq!(entry(&stack, nobody), Some((State::Incomplete, 2)));
+        }
+
+        // and given up on 4s after the last, telling only the peer
+        sim.run_for(time::Duration::from_millis(3999));
+        assert!(entry(&stack, nobody).is_some());
+        sim.run_for(time::Duration::from_millis(1));
+        assert_eq!(entry(&stack, nobody), None);
+        sim.run_for(time::Duration::from_secs(1));
+        let unreachable = icmp::Type::DestUnreach as u8;
+        let icmp = stats::snapshot(&peer).counters.icmp;
+        assert_eq!(icmp.in_types.get(&unreachable), Some(&1));
+        // past the three requests, the peer was resolved and sent the error
+        assert_eq!(sent(&stack) - start, 3 + 2);
+
+        // a later datagram starts over
+        assert_eq!(resolve(&interface, nobody, &own).unwrap(), None);
+        assert_eq!(entry(&stack, nobody), Some((State::Incomplete, 1)));
+    }
+
+    #[test]
+    fn a_stale_entry_is_used_while_it_is_asked_for_again() {
+        let mut sim = Simulator::new(1);
+        let (interface, peer_interface) = pair(&mut sim);
+        let stack = interface.stack();
+        let (src, dst) = (ip::Addr([10, 0, 0, 1]), ip::Addr([10, 0, 0, 2]));
+        let mac_addr = peer_interface.0.lock().unwrap().device.addr();
+        resolve(&interface, dst, &datagram(src, dst, 0)).unwrap();
+        sim.run_for(time::Duration::from_secs(1));
+        assert_eq!(entry(&stack, dst), Some((State::Reachable, 0)));
+
+        // aged out of REACHABLE by the patrol
+        sim.run_for(time::Duration::from_secs(40));
+        assert_eq!(entry(&stack, dst), Some((State::Stale, 0)));
+        let before = sent(&stack);
+        assert_eq!(resolve(&interface, dst, &datagram(src, dst, 1)).unwrap(), Some(mac_addr));
+        assert_eq!(resolve(&interface, dst, &datagram(src, dst, 2)).unwrap(), Some(mac_addr));
+        // asked once for both
+        assert_eq!(sent(&stack) - before, 1);
+        sim.run_for(time::Duration::from_secs(1));
+        assert_eq!(entry(&stack, dst), Some((State::Reachable, 0)));
+
+        // a stale entry nobody answers for is dropped like an unresolved one: a host
+        // that has since gone quiet introduces itself from the peer's link
+        let gone = ip::Addr([10, 0, 0, 9]);
+        let hello = frame::Frame {
+            op: Op::Request,
+            src_mac_addr: ethernet::MacAddr([2, 0, 0, 0, 0, 9]),
+            src_ip_addr: gone,
+            dst_mac_addr: ethernet::MacAddr::empty(),
+            dst_ip_addr: src,
+        };
+        let peer_device = peer_interface.0.lock().unwrap().device.clone();
+        peer_device
+            .tx(ethernet::Type::Arp, hello.to_buffer(), ethernet::ADDR_BROADCAST)
+            .unwrap();
+        sim.run_for(time::Duration::from_secs(40));
+        assert_eq!(entry(&stack, gone), Some((State::Stale, 0)));
+        resolve(&interface, gone, &datagram(src, gone, 3)).unwrap();
+        sim.run_for(time::Duration::from_millis(6999));
+        assert_eq!(entry(&stack, gone), Some((State::Stale, 0)));
+        sim.run_for(time::Duration::from_millis(1));
+        assert_eq!(entry(&stack, gone), None);
+    }
+
+    // a device that fails to send the datagram numbered 1 by `datagram`
+    #[derive(Debug)]
+    struct Flaky(ethernet::Device);
+
+    impl LinkDevice for Flaky {
+        fn name(&self) -> String {
+            self.0.name()
+        }
+        fn flags(&self) -> DeviceFlags {
+            self.0.flags()
+        }
+        fn mtu(&self) -> usize {
+            self.0.mtu()
+        }
+        fn header_len(&self) -> usize {
+            self.0.header_len()
+        }
+        fn resolution(&self) -> Resolution {
+            self.0.resolution()
+        }
+        fn addr(&self) -> ethernet::MacAddr {
+            self.0.addr()
+        }
+        fn broadcast_addr(&self) -> ethernet::MacAddr {
+            self.0.broadcast_addr()
+        }
+        fn interface(&self) -> Option<Interface> {
+            self.0.interface()
+        }
+        fn stack(&self) -> Stack {
+            self.0.stack()
+        }
+        fn tx(
+            &self,
+            type_: ethernet::Type,
+            payload: Buffer,
+            dst: ethernet::MacAddr,
+        ) -> Result<(), Error> {
+            if matches!(type_, ethernet::Type::Ip) && payload.0[5] == 1 {
+                return Err(Error::Dropped("flaky"));
+            }
+            self.0.tx(type_, payload, dst)
+        }
+    }
+
+    #[test]
+    fn a_static_entry_sends_everything_queued_for_it() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (mut device, mut peer_device) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let mask = ip::Addr([255, 0, 0, 0]);
+        let (src, dst) = (ip::Addr([10, 0, 0, 1]), ip::Addr([10, 0, 0, 2]));
+        let interface = Interface::new(Flaky(device.clone()), src, mask, None);
+        device.add_interface(interface.clone());
+        let peer_interface = Interface::new(peer_device.clone(), dst, mask, None);
+        peer_device.add_interface(peer_interface.clone());
+        sim.run_for(time::Duration::from_secs(10));
+        let mut socket = udp::open(&peer).unwrap();
+        socket.bind_interface(peer_interface, 7).unwrap();
+
+        for n in 0..3 {
+            resolve(&interface, dst, &datagram(src, dst, n)).unwrap();
+        }
+        // the one that cannot be sent does not hold back the others
+        let mac_addr = peer_device.addr();
+        assert!(matches!(add_static(&interface, dst, mac_addr), Err(Error::Dropped(_))));
+        assert_eq!(entry(&stack, dst), Some((State::Permanent, 0)));
+        sim.run_for(time::Duration::from_secs(1));
+        assert_eq!(received(&mut socket), vec![0, 2]);
+        assert!(timer::timers(&stack).iter().all(|timer| timer.name != "arp: retransmit"));
+    }
+
+    #[test]
+    fn static_entries_stay_until_deleted_everywhere() {
+        let mut sim = Simulator::new(1);
+        let (interface, peer_interface) = pair(&mut sim);
+        let stack = interface.stack();
+        let elsewhere = sim.stack();
+        let (mut other, _) = sim.connect(&stack, &elsewhere, LinkConfig::default()).unwrap();
+        let other_interface = Interface::new(
+            other.clone(),
+            ip::Addr([192, 168, 0, 1]),
+            ip::Addr([255, 255, 255, 0]),
+            None,
+        );
+        other.add_interface(other_interface.clone());
+
+        // the peer claims the address for itself
+        let claimed = ip::Addr([10, 0, 0, 7]);
+        let pinned = ethernet::MacAddr([2, 0, 0, 0, 0, 7]);
+        add_static(&interface, claimed, pinned).unwrap();
+        add_static(&other_interface, claimed, pinned).unwrap();
+        let claim = frame::Frame {
+            op: Op::Request,
+            src_mac_addr: peer_interface.0.lock().unwrap().device.addr(),
+            src_ip_addr: claimed,
+            dst_mac_addr: ethernet::MacAddr::empty(),
+            dst_ip_addr: ip::Addr([10, 0, 0, 1]),
+        };
+        let peer_device = peer_interface.0.lock().unwrap().device.clone();
+        peer_device
+            .tx(ethernet::Type::Arp, claim.to_buffer(), ethernet::ADDR_BROADCAST)
+            .unwrap();
+        // and the patrol runs long after any dynamic entry would have gone
+        sim.run_for(time::Duration::from_secs(400));
+        let pinned_entries = |stack: &Stack| {
+            entries(stack)
+                .into_iter()
+                .filter(|entry| entry.ip_addr == claimed)
+                .map(|entry| (entry.state, entry.mac_addr))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            pinned_entries(&stack),
+            vec![(State::Permanent, pinned), (State::Permanent, pinned)]
+        );
+
+        delete(&stack, &claimed).unwrap();
+        assert!(pinned_entries(&stack).is_empty());
+        assert!(matches!(delete(&stack, &claimed), Err(Error::NotFound(_))));
+    }
+
+    // a router between 10.0.0.0/16, where a host takes 10.0.0.0/8 to be on its link, and
+    // 10.1.0.0/16
+    fn routed(sim: &mut Simulator) -> (Interface, Interface) {
+        let (router, host, far) = (sim.stack(), sim.stack(), sim.stack());
+        let (mut near, mut host_device) = sim.connect(&router, &host, LinkConfig::default()).unwrap();
+        let (mut beyond, _) = sim.connect(&router, &far, LinkConfig::default()).unwrap();
+        let near_interface = Interface::new(
+            near.clone(),
+            ip::Addr([10, 0, 0, 1]),
+            ip::Addr([255, 255, 0, 0]),
+            None,
+        );
+        near.add_interface(near_interface.clone());
+        let beyond_interface = Interface::new(
+            beyond.clone(),
+            ip::Addr([10, 1, 0, 1]),
+            ip::Addr([255, 255, 0, 0]),
+            None,
+        );
+        beyond.add_interface(beyond_interface);
+        let host_interface = Interface::new(
+            host_device.clone(),
+            ip::Addr([10, 0, 0, 2]),
+            ip::Addr([255, 0, 0, 0]),
+            None,
+        );
+        host_device.add_interface(host_interface.clone());
+        sim.run_for(time::Duration::from_secs(10));
+        (near_interface, host_interface)
+    }
+
+    // asks from `host` for `ip_addr`, and what it learned
+    fn ask(sim: &mut Simulator, host: &Interface, ip_addr: ip::Addr) -> Option<ethernet::MacAddr> {
+        let src = host.0.lock().unwrap().unicast;
+        resolve(host, ip_addr, &datagram(src, ip_addr, 0)).unwrap();
+        sim.run_for(time::Duration::from_secs(1));
+        let learned = entries(&host.stack())
+            .into_iter()
+            .find(|entry| entry.ip_addr == ip_addr && entry.state == State::Reachable)
+            .map(|entry| entry.mac_addr);
+        delete(&host.stack(), &ip_addr).unwrap();
+        learned
+    }
+
+    #[test]
+    fn proxy_arp_answers_for_routed_hosts_only() {
+        let mut sim = Simulator::new(1);
+        let (near, host) = routed(&mut sim);
+        let far = ip::Addr([10, 1, 0, 5]);
+        assert_eq!(ask(&mut sim, &host, far), None);
+
+        add_proxy(&near, ip::Addr([10, 0, 0, 0]), ip::Addr([255, 0, 0, 0]));
+        let router_mac = near.0.lock().unwrap().device.addr();
+        assert_eq!(ask(&mut sim, &host, far), Some(router_mac));
+        // not for hosts on the link the request came from, nor where nothing routes
+        assert_eq!(ask(&mut sim, &host, ip::Addr([10, 0, 0, 9])), None);
+        assert_eq!(ask(&mut sim, &host, ip::Addr([10, 2, 0, 5])), None);
+
+        delete_proxy(&near, ip::Addr([10, 9, 9, 9]), ip::Addr([255, 0, 0, 0])).unwrap();
+        assert_eq!(ask(&mut sim, &host, far), None);
+        assert!(matches!(
+            delete_proxy(&near, ip::Addr([10, 0, 0, 0]), ip::Addr([255, 0, 0, 0])),
+            Err(Error::NotFound(_))
+        ));
+    }
 }
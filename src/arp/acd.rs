@@ -0,0 +1,266 @@
+// IPv4 Address Conflict Detection (RFC 5227)
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    arp::{frame, Op},
+    error::Error,
+    ethernet, ip,
+    link::Resolution,
+    packet::Packet,
+    stack::Stack,
+    timer,
+};
+
+// all in milliseconds
+const PROBE_WAIT: u64 = 1000;
+const PROBE_NUM: usize = 3;
+const PROBE_MIN: u64 = 1000;
+const PROBE_MAX: u64 = 2000;
+const ANNOUNCE_WAIT: u64 = 2000;
+const ANNOUNCE_NUM: usize = 2;
+const ANNOUNCE_INTERVAL: u64 = 2000;
+const DEFEND_INTERVAL: i64 = 10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Probing,
+    Announcing,
+    Defending,
+    // another host has the address; it stays unusable until reconfigured
+    Conflicted,
+}
+
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub interface: ip::interface::Interface,
+    pub ip_addr: ip::Addr,
+    pub mac_addr: ethernet::MacAddr,
+    pub state: State,
+}
+
+struct Entry {
+    interface: ip::interface::Interface,
+    ip_addr: ip::Addr,
+    state: State,
+    last_defense: Option<DateTime<Utc>>,
+}
+
+type Handler = Arc<dyn Fn(Conflict) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Tables {
+    entries: Mutex<Vec<Entry>>,
+    handler: Mutex<Option<Handler>>,
+}
+
+pub fn set_conflict_handler<F>(stack: &Stack, handler: F)
+where
+    F: Fn(Conflict) + Send + Sync + 'static,
+{
+    *stack.0.arp.acd.handler.lock().unwrap() = Some(Arc::new(handler));
+}
+
+fn report(stack: &Stack, conflict: Conflict) {
+    let handler = stack.0.arp.acd.handler.lock().unwrap().clone();
+    // may be called with the device locked, so let the handler run from a timer
+    timer::add(stack, "arp: conflict", Duration::zero(), move |_| match handler {
+        Some(ref handler) => handler(conflict.clone()),
+        None => warn!(
+            "{} is also used by {} ({:?})",
+            conflict.ip_addr, conflict.mac_addr, conflict.state
+        ),
+    });
+}
+
+fn position(entries: &[Entry], interface: &ip::interface::Interface) -> Option<usize> {
+    entries
+        .iter()
+        .position(|entry| Arc::ptr_eq(&entry.interface.0, &interface.0))
+}
+
+pub fn send(
+    interface: &ip::interface::Interface,
+    src_ip_addr: ip::Addr,
+    dst_ip_addr: ip::Addr,
+) -> Result<(), Error> {
+    let device = interface.0.lock().unwrap().device.clone();
+    let src_mac_addr = device.addr();
+    let request = frame::Frame {
+        op: Op::Request,
+        src_mac_addr,
+        src_ip_addr,
+        dst_mac_addr: ethernet::MacAddr::empty(),
+        dst_ip_addr,
+    };
+    log_packet!("arp probe/announce", request);
+    device.tx(
+        ethernet::Type::Arp,
+        request.to_buffer(),
+        ethernet::ADDR_BROADCAST,
+    )
+}
+
+fn is_state(interface: &ip::interface::Interface, ip_addr: ip::Addr, state: State) -> bool {
+    let stack = interface.stack();
+    let entries = stack.0.arp.acd.entries.lock().unwrap();
+    match position(&entries, interface) {
+        Some(idx) => entries[idx].ip_addr == ip_addr && entries[idx].state == state,
+        None => false,
+    }
+}
+
+fn transit(interface: &ip::interface::Interface, ip_addr: ip::Addr, from: State, to: State) -> bool {
+    let stack = interface.stack();
+    let mut entries = stack.0.arp.acd.entries.lock().unwrap();
+    match position(&entries, interface) {
+        Some(idx) if entries[idx].ip_addr == ip_addr && entries[idx].state == from => {
+            entries[idx].state = to;
+            true
+        }
+        _ => false,
+    }
+}
+
+// the `sent`th probe or announcement, each of which schedules the next
+fn step(interface: &ip::interface::Interface, ip_addr: ip::Addr, sent: usize) -> Result<(), Error> {
+    let stack = interface.stack();
+    if sent < PROBE_NUM {
+        // a conflict or a newer configuration ends this sequence
+        if !is_state(interface, ip_addr, State::Probing) {
+            return Ok(());
+        }
+        send(interface, ip::Addr::empty(), ip_addr)?;
+        let delay = if sent + 1 == PROBE_NUM {
+            ANNOUNCE_WAIT
+        } else {
+            stack.gen_range(PROBE_MIN, PROBE_MAX)
+        };
+        schedule(interface, ip_addr, sent + 1, delay);
+        return Ok(());
+    }
+    if sent == PROBE_NUM && !transit(interface, ip_addr, State::Probing, State::Announcing) {
+        return Ok(());
+    }
+    if !is_state(interface, ip_addr, State::Announcing) {
+        return Ok(());
+    }
+    send(interface, ip_addr, ip_addr)?;
+    if sent + 1 == PROBE_NUM + ANNOUNCE_NUM {
+        transit(interface, ip_addr, State::Announcing, State::Defending);
+    } else {
+        schedule(interface, ip_addr, sent + 1, ANNOUNCE_INTERVAL);
+    }
+    Ok(())
+}
+
+fn schedule(interface: &ip::interface::Interface, ip_addr: ip::Addr, sent: usize, delay: u64) {
+    let interface = interface.clone();
+    let delay = Duration::milliseconds(delay as i64);
+    timer::add(&interface.stack(), "arp: acd", delay, move |_| {
+        if let Err(err) = step(&interface, ip_addr, sent) {
+            error!("probing {} failed: {}", ip_addr, err);
+        }
+    });
+}
+
+/// starts probing for the interface's address, followed by announcing and defending it.
+pub fn start(interface: &ip::interface::Interface) {
+    let (ip_addr, resolution) = {
+        let interface = interface.0.lock().unwrap();
+        (interface.unicast, interface.device.resolution())
+    };
+    let stack = interface.stack();
+    let mut entries = stack.0.arp.acd.entries.lock().unwrap();
+    if let Some(idx) = position(&entries, interface) {
+        entries.remove(idx);
+    }
+    // nothing answers probes on NOARP, point-to-point or loopback links
+    if ip_addr == ip::Addr::empty() || resolution != Resolution::Arp {
+        return;
+    }
+    entries.push(Entry {
+        interface: interface.clone(),
+        ip_addr,
+        state: State::Probing,
+        last_defense: None,
+    });
+    drop(entries);
+    let delay = stack.gen_range(0, PROBE_WAIT);
+    schedule(interface, ip_addr, 0, delay);
+}
+
+pub(crate) fn clear(stack: &Stack) {
+    stack.0.arp.acd.entries.lock().unwrap().clear();
+}
+
+/// whether the interface's address is still being probed, and so unusable.
+pub fn is_tentative(interface: &ip::interface::Interface) -> bool {
+    state(interface) == Some(State::Probing)
+}
+
+/// whether the interface's address turned out to be another host's.
+pub fn is_conflicted(interface: &ip::interface::Interface) -> bool {
+    state(interface) == Some(State::Conflicted)
+}
+
+fn state(interface: &ip::interface::Interface) -> Option<State> {
+    let stack = interface.stack();
+    let entries = stack.0.arp.acd.entries.lock().unwrap();
+    position(&entries, interface).map(|idx| entries[idx].state)
+}
+
+/// checks a received ARP message against the address being probed or defended on `interface`.
+pub fn check(
+    interface: &ip::interface::Interface,
+    own_mac_addr: &ethernet::MacAddr,
+    message: &frame::Frame,
+) {
+    if &message.src_mac_addr == own_mac_addr {
+        return;
+    }
+    let stack = interface.stack();
+    let mut entries = stack.0.arp.acd.entries.lock().unwrap();
+    let idx = match position(&entries, interface) {
+        Some(idx) => idx,
+        None => return,
+    };
+    let ip_addr = entries[idx].ip_addr;
+    let state = entries[idx].state;
+    if state == State::Conflicted {
+        return;
+    }
+    let is_conflict = message.src_ip_addr == ip_addr
+        || (state == State::Probing
+            && message.op == Op::Request
+            && message.src_ip_addr == ip::Addr::empty()
+            && message.dst_ip_addr == ip_addr);
+    if !is_conflict {
+        return;
+    }
+    let now = stack.now();
+    let can_defend = state != State::Probing
+        && entries[idx]
+            .last_defense
+            .map(|last| now - last > Duration::milliseconds(DEFEND_INTERVAL))
+            .unwrap_or(true);
+    if can_defend {
+        entries[idx].last_defense = Some(now);
+        let interface = interface.clone();
+        timer::add(&stack, "arp: defend", Duration::zero(), move |_| {
+            if let Err(err) = send(&interface, ip_addr, ip_addr) {
+                warn!("defending {} failed: {}", ip_addr, err);
+            }
+        });
+    } else {
+        entries[idx].state = State::Conflicted;
+        report(&stack, Conflict {
+            interface: interface.clone(),
+            ip_addr,
+            mac_addr: message.src_mac_addr,
+            state,
+        });
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{arp::*, buffer::Buffer, ethernet, ip, packet, util::RuntimeError};
+use crate::{
+    arp::*,
+    buffer::Buffer,
+    error::{Error, Layer},
+    ethernet, ip, packet,
+};
 
 #[derive(Debug)]
 pub struct Frame {
@@ -12,50 +17,63 @@ pub struct Frame {
 const FRAME_SIZE: usize = 52;
 
 impl Frame {
-    pub fn dump(&self) {
-        eprintln!("op: {}", self.op);
-        eprintln!("src mac addr: {}", self.src_mac_addr);
-        eprintln!("src ip addr: {}", self.src_ip_addr);
-        eprintln!("dst mac addr: {}", self.dst_mac_addr);
-        eprintln!("dst ip addr: {}", self.dst_ip_addr);
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "op: {}", self.op);
+        let _ = writeln!(out, "src mac addr: {}", self.src_mac_addr);
+        let _ = writeln!(out, "src ip addr: {}", self.src_ip_addr);
+        let _ = writeln!(out, "dst mac addr: {}", self.dst_mac_addr);
+        let _ = writeln!(out, "dst ip addr: {}", self.dst_ip_addr);
+        out
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}) > {} ({})",
+            self.op, self.src_ip_addr, self.src_mac_addr, self.dst_ip_addr, self.dst_mac_addr
+        )
     }
 }
 
 impl packet::Packet<Frame> for Frame {
-    fn from_buffer(mut buffer: Buffer) -> Result<Self, Box<dyn Error>> {
+    const LAYER: Layer = Layer::Arp;
+
+    fn from_buffer(mut buffer: Buffer) -> Result<Self, Error> {
         let hardware_type = buffer.pop_u16("hardware type")?;
         if hardware_type != HARDWARE_TYPE_ETHERNET {
-            return Err(RuntimeError::new(format!(
-                "hardware type must be {}, but {}",
-                HARDWARE_TYPE_ETHERNET, hardware_type
-            )));
+            return Err(Error::parse(
+                "hardware type",
+                format!("must be {}, but {}", HARDWARE_TYPE_ETHERNET, hardware_type),
+            ));
         }
 
         let protocol = buffer.pop_u16("protocol type")?;
         if protocol != ethernet::Type::Ip as u16 {
-            return Err(RuntimeError::new(format!(
-                "protocol type must be {}, but {}",
-                ethernet::Type::Ip as u16,
-                protocol
-            )));
+            return Err(Error::parse(
+                "protocol type",
+                format!("must be {}, but {}", ethernet::Type::Ip as u16, protocol),
+            ));
         }
 
         let hardware_address_len = buffer.pop_u8("hardware address length")?;
         if hardware_address_len as usize != ethernet::ADDR_LEN {
-            return Err(RuntimeError::new(format!(
-                "hardware address length must be {}, but {}",
-                ethernet::ADDR_LEN,
-                hardware_address_len
-            )));
+            return Err(Error::parse(
+                "hardware address length",
+                format!("must be {}, but {}", ethernet::ADDR_LEN, hardware_address_len),
+            ));
         }
 
         let ip_address_len = buffer.pop_u8("ip address length")?;
         if ip_address_len as usize != ip::ADDR_LEN {
-            return Err(RuntimeError::new(format!(
-                "ip address length must be {}, but {}",
-                ip::ADDR_LEN,
-                ip_address_len
-            )));
+            return Err(Error::parse(
+                "ip address length",
+                format!("must be {}, but {}", ip::ADDR_LEN, ip_address_len),
+            ));
         }
 
         let op: u16 = buffer.pop_u16("operation")?;
@@ -64,7 +82,7 @@ impl packet::Packet<Frame> for Frame {
         } else if op == Op::Reply as u16 {
             Op::Reply
         } else {
-            return Err(RuntimeError::new(format!("invalid operation: {}", op)));
+            return Err(Error::parse("operation", format!("unknown: {}", op)));
         };
         let src_mac_addr = buffer.pop_mac_addr("src mac address")?;
         let src_ip_addr = buffer.pop_ip_addr("src ip address")?;
@@ -72,11 +90,11 @@ impl packet::Packet<Frame> for Frame {
         let dst_ip_addr = buffer.pop_ip_addr("dst ip address")?;
 
         Ok(Frame {
-            op: op,
-            src_mac_addr: src_mac_addr,
-            src_ip_addr: src_ip_addr,
-            dst_mac_addr: dst_mac_addr,
-            dst_ip_addr: dst_ip_addr,
+            op,
+            src_mac_addr,
+            src_ip_addr,
+            dst_mac_addr,
+            dst_ip_addr,
         })
     }
     fn to_buffer(self) -> Buffer {
@@ -0,0 +1,421 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    arp::{table, Op},
+    ethernet, ip,
+    stack::Stack,
+    timer,
+};
+
+// number of incidents kept for `incidents`
+const INCIDENTS_MAX: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct GuardConfig {
+    // this many MAC changes of one address within `flap_window` is reported as flapping
+    pub flap_threshold: usize,
+    pub flap_window: Duration,
+    // unsolicited replies accepted per sender address within `unsolicited_window`
+    pub unsolicited_limit: usize,
+    pub unsolicited_window: Duration,
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        GuardConfig {
+            flap_threshold: 3,
+            flap_window: Duration::seconds(60),
+            unsolicited_limit: 5,
+            unsolicited_window: Duration::seconds(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncidentKind {
+    MacChanged {
+        old: ethernet::MacAddr,
+        new: ethernet::MacAddr,
+    },
+    Flapping {
+        macs: Vec<ethernet::MacAddr>,
+    },
+    PinnedOverride {
+        pinned: ethernet::MacAddr,
+        claimed: ethernet::MacAddr,
+    },
+    UnsolicitedRateLimited {
+        mac: ethernet::MacAddr,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incident {
+    pub timestamp: DateTime<Utc>,
+    pub ip_addr: ip::Addr,
+    pub kind: IncidentKind,
+}
+
+impl fmt::Display for Incident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: ", self.timestamp, self.ip_addr)?;
+        match &self.kind {
+            IncidentKind::MacChanged { old, new } => write!(f, "mac changed {} -> {}", old, new),
+            IncidentKind::Flapping { macs } => {
+                write!(f, "flapping between")?;
+                for mac in macs {
+                    write!(f, " {}", mac)?;
+                }
+                Ok(())
+            }
+            IncidentKind::PinnedOverride { pinned, claimed } => {
+                write!(f, "refused {} for pinned {}", claimed, pinned)
+            }
+            IncidentKind::UnsolicitedRateLimited { mac } => {
+                write!(f, "rate limited unsolicited replies from {}", mac)
+            }
+        }
+    }
+}
+
+struct Guard {
+    config: GuardConfig,
+    changes: HashMap<ip::Addr, VecDeque<(DateTime<Utc>, ethernet::MacAddr)>>,
+    unsolicited: HashMap<ip::Addr, (DateTime<Utc>, usize)>,
+}
+
+type Handler = Arc<dyn Fn(Incident) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Tables {
+    active: Mutex<Option<Guard>>,
+    incidents: Mutex<VecDeque<Incident>>,
+    handler: Mutex<Option<Handler>>,
+}
+
+/// enables the guard with `config`, or disables it with `None`.
+pub fn set_guard(stack: &Stack, config: Option<GuardConfig>) {
+    *stack.0.arp.guard.active.lock().unwrap() = config.map(|config| Guard {
+        config,
+        changes: HashMap::new(),
+        unsolicited: HashMap::new(),
+    });
+}
+
+pub fn set_incident_handler<F>(stack: &Stack, handler: F)
+where
+    F: Fn(Incident) + Send + Sync + 'static,
+{
+    *stack.0.arp.guard.handler.lock().unwrap() = Some(Arc::new(handler));
+}
+
+/// returns the recorded incidents, oldest first.
+pub fn incidents(stack: &Stack) -> Vec<Incident> {
+    stack.0.arp.guard.incidents.lock().unwrap().iter().cloned().collect()
+}
+
+fn record(stack: &Stack, ip_addr: ip::Addr, kind: IncidentKind) {
+    let incident = Incident {
+        timestamp: stack.now(),
+        ip_addr,
+        kind,
+    };
+    {
+        let mut incidents = stack.0.arp.guard.incidents.lock().unwrap();
+        if incidents.len() >= INCIDENTS_MAX {
+            incidents.pop_front();
+        }
+        incidents.push_back(incident.clone());
+    }
+    let handler = stack.0.arp.guard.handler.lock().unwrap().clone();
+    // called with the ARP table locked, so let the handler run from a timer
+    timer::add(stack, "arp: incident", Duration::zero(), move |_| match handler {
+        Some(ref handler) => handler(incident.clone()),
+        None => warn!("{}", incident),
+    });
+}
+
+/// forgets changes and unsolicited replies older than their windows, run with the table's
+/// aging so that addresses heard from once are not remembered for ever.
+pub fn patrol(stack: &Stack) {
+    let now = stack.now();
+    let mut guard = stack.0.arp.guard.active.lock().unwrap();
+    let guard = match guard.as_mut() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let (flap_window, unsolicited_window) =
+        (guard.config.flap_window, guard.config.unsolicited_window);
+    guard.changes.retain(|_, changes| {
+        changes.retain(|(timestamp, _)| now - *timestamp <= flap_window);
+        !changes.is_empty()
+    });
+    guard
+        .unsolicited
+        .retain(|_, (start, _)| now - *start <= unsolicited_window);
+}
+
+/// Decides whether `entry` may take `mac_addr` from a received ARP message, recording
+/// an incident for anything suspicious. Pinned entries are never updated.
+pub fn admit(
+    stack: &Stack,
+    entry: &table::Entry,
+    mac_addr: &ethernet::MacAddr,
+    op: &Op,
+) -> bool {
+    let mut guard = stack.0.arp.guard.active.lock().unwrap();
+    let guard = match guard.as_mut() {
+        Some(guard) => guard,
+        None => return entry.state != table::State::Permanent,
+    };
+    let now = stack.now();
+
+    if entry.state == table::State::Permanent {
+        if &entry.mac_addr != mac_addr {
+            record(
+                stack,
+                entry.ip_addr,
+                IncidentKind::PinnedOverride {
+                    pinned: entry.mac_addr,
+                    claimed: *mac_addr,
+                },
+            );
+        }
+        return false;
+    }
+
+    let is_unsolicited = op == &Op::Reply
+        && entry.state != table::State::Incomplete
+        && entry.requested.is_none();
+    if is_unsolicited {
+        let window = guard.config.unsolicited_window;
+        let (start, count) = guard
+            .unsolicited
+            .entry(entry.ip_addr)
+            .or_insert((now, 0));
+        if now - *start > window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count > guard.config.unsolicited_limit {
+            // report once per window
+            if *count == guard.config.unsolicited_limit + 1 {
+                record(
+                    stack,
+                    entry.ip_addr,
+                    IncidentKind::UnsolicitedRateLimited { mac: *mac_addr },
+                );
+            }
+            return false;
+        }
+    }
+
+    if entry.state != table::State::Incomplete && &entry.mac_addr != mac_addr {
+        record(
+            stack,
+            entry.ip_addr,
+            IncidentKind::MacChanged {
+                old: entry.mac_addr,
+                new: *mac_addr,
+            },
+        );
+        let window = guard.config.flap_window;
+        let changes = guard
+            .changes
+            .entry(entry.ip_addr)
+            .or_insert(VecDeque::new());
+        changes.push_back((now, *mac_addr));
+        while changes
+            .front()
+            .map(|(timestamp, _)| now - *timestamp > window)
+            .unwrap_or(false)
+        {
+            changes.pop_front();
+        }
+        if changes.len() >= guard.config.flap_threshold {
+            let mut macs = vec![entry.mac_addr];
+            for (_, mac) in changes.iter() {
+                if !macs.contains(mac) {
+                    macs.push(*mac);
+                }
+            }
+            guard.changes.remove(&entry.ip_addr);
+            record(stack, entry.ip_addr, IncidentKind::Flapping { macs });
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        arp::{self, frame::Frame},
+        ip::interface::Interface,
+        link::LinkDevice,
+        packet::Packet,
+        sim::{LinkConfig, Simulator},
+    };
+    use std::time;
+
+    const CLAIMED: ip::Addr = ip::Addr([10, 0, 0, 7]);
+
+    fn mac(n: u8) -> ethernet::MacAddr {
+        ethernet::MacAddr([2, 0, 0, 0, 0, n])
+    }
+
+    // a guarded stack on 10.0.0.1 and its peer's device, past conflict detection
+    fn guarded(sim: &mut Simulator) -> (Stack, ethernet::Device) {
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (mut device, peer_device) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let mask = ip::Addr([255, 0, 0, 0]);
+        let interface = Interface::new(device.clone(), ip::Addr([10, 0, 0, 1]), mask, None);
+        device.add_interface(interface);
+        sim.run_for(time::Duration::from_secs(10));
+        set_guard(&stack, Some(GuardConfig::default()));
+        (stack, peer_device)
+    }
+
+    // has the peer claim `CLAIMED` for `mac_addr`
+    fn send(peer_device: &ethernet::Device, op: Op, mac_addr: ethernet::MacAddr) {
+        let frame = Frame {
+            op,
+            src_mac_addr: mac_addr,
+            src_ip_addr: CLAIMED,
+            dst_mac_addr: ethernet::MacAddr::empty(),
+            dst_ip_addr: ip::Addr([10, 0, 0, 1]),
+        };
+        peer_device
+            .tx(ethernet::Type::Arp, frame.to_buffer(), ethernet::ADDR_BROADCAST)
+            .unwrap();
+    }
+
+    // the same, and the claim heard
+    fn claim(sim: &mut Simulator, peer_device: &ethernet::Device, op: Op, mac_addr: ethernet::MacAddr) {
+        send(peer_device, op, mac_addr);
+        sim.run_for(time::Duration::from_millis(100));
+    }
+
+    fn learned(stack: &Stack) -> Option<ethernet::MacAddr> {
+        arp::entries(stack)
+            .into_iter()
+            .find(|entry| entry.ip_addr == CLAIMED)
+            .map(|entry| entry.mac_addr)
+    }
+
+    fn kinds(stack: &Stack) -> Vec<IncidentKind> {
+        incidents(stack).into_iter().map(|incident| incident.kind).collect()
+    }
+
+    // the addresses the guard is keeping history for
+    fn remembered(stack: &Stack) -> (usize, usize) {
+        let guard = stack.0.arp.guard.active.lock().unwrap();
+        let guard = guard.as_ref().unwrap();
+        (guard.changes.len(), guard.unsolicited.len())
+    }
+
+    #[test]
+    fn changes_are_reported_and_flapping_once_too_many() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer_device) = guarded(&mut sim);
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        assert_eq!(learned(&stack), Some(mac(1)));
+        assert!(incidents(&stack).is_empty());
+
+        // the same address again is no change
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        claim(&mut sim, &peer_device, Op::Request, mac(2));
+        assert_eq!(learned(&stack), Some(mac(2)));
+        assert_eq!(
+            kinds(&stack),
+            vec![IncidentKind::MacChanged { old: mac(1), new: mac(2) }]
+        );
+
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        claim(&mut sim, &peer_device, Op::Request, mac(3));
+        let kinds = kinds(&stack);
+        assert_eq!(kinds.len(), 4);
+        assert_eq!(
+            kinds[3],
+            IncidentKind::Flapping { macs: vec![mac(1), mac(2), mac(3)] }
+        );
+        // counted afresh after being reported
+        assert_eq!(remembered(&stack), (0, 0));
+    }
+
+    #[test]
+    fn changes_further_apart_than_the_window_are_not_flapping() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer_device) = guarded(&mut sim);
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        for n in 2..6 {
+            claim(&mut sim, &peer_device, Op::Request, mac(n));
+            sim.run_for(time::Duration::from_secs(31));
+        }
+        assert_eq!(learned(&stack), Some(mac(5)));
+        let kinds = kinds(&stack);
+        assert_eq!(kinds.len(), 4);
+        assert!(kinds
+            .iter()
+            .all(|kind| matches!(kind, IncidentKind::MacChanged { .. })));
+    }
+
+    #[test]
+    fn unsolicited_replies_are_limited_per_window() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer_device) = guarded(&mut sim);
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        // ten within a second, all but the first few refused, reported once
+        for _ in 0..10 {
+            send(&peer_device, Op::Reply, mac(1));
+        }
+        sim.run_for(time::Duration::from_millis(100));
+        assert_eq!(
+            kinds(&stack),
+            vec![IncidentKind::UnsolicitedRateLimited { mac: mac(1) }]
+        );
+        // a changed address past the limit is not taken
+        claim(&mut sim, &peer_device, Op::Reply, mac(2));
+        assert_eq!(learned(&stack), Some(mac(1)));
+
+        // but is once the window is over
+        sim.run_for(time::Duration::from_secs(1));
+        claim(&mut sim, &peer_device, Op::Reply, mac(2));
+        assert_eq!(learned(&stack), Some(mac(2)));
+    }
+
+    #[test]
+    fn a_pinned_entry_refuses_other_addresses() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer_device) = guarded(&mut sim);
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        arp::pin(&stack, &CLAIMED).unwrap();
+        claim(&mut sim, &peer_device, Op::Reply, mac(1));
+        claim(&mut sim, &peer_device, Op::Request, mac(2));
+        assert_eq!(learned(&stack), Some(mac(1)));
+        assert_eq!(
+            kinds(&stack),
+            vec![IncidentKind::PinnedOverride { pinned: mac(1), claimed: mac(2) }]
+        );
+    }
+
+    #[test]
+    fn history_is_forgotten_once_its_window_is_over() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer_device) = guarded(&mut sim);
+        claim(&mut sim, &peer_device, Op::Request, mac(1));
+        claim(&mut sim, &peer_device, Op::Reply, mac(2));
+        assert_eq!(remembered(&stack), (1, 1));
+
+        // the unsolicited reply by the next aging, the change once the flap window is over
+        sim.run_for(time::Duration::from_secs(arp::AGING_INTERVAL as u64));
+        assert_eq!(remembered(&stack), (1, 0));
+        sim.run_for(time::Duration::from_secs(60));
+        assert_eq!(remembered(&stack), (0, 0));
+    }
+}
@@ -1,18 +1,59 @@
-use std::error::Error;
-use std::sync::{Arc, Condvar, Mutex};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
 use chrono::{DateTime, Duration, Utc};
 
-use crate::{buffer::Buffer, ethernet, ip, util::RuntimeError};
+use crate::{
+    buffer::Buffer,
+    ethernet, ip,
+    stack::Stack,
+    timer::{self, TimerId},
+};
+
+// pending packets kept per unresolved entry
+pub const QUEUE_MAX: usize = 3;
+// how long a confirmed entry stays REACHABLE before it becomes STALE
+const REACHABLE_TIME: i64 = 30;
+// how long an unused STALE entry is kept
+const STALE_TIME: i64 = 300;
+// how often entries are aged, in seconds
+pub const AGING_INTERVAL: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Incomplete,
+    Reachable,
+    Stale,
+    Permanent,
+}
+
+use std::fmt;
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                State::Incomplete => "INCOMPLETE",
+                State::Reachable => "REACHABLE",
+                State::Stale => "STALE",
+                State::Permanent => "PERMANENT",
+            }
+        )
+    }
+}
 
 #[derive(Debug)]
 pub struct Entry {
     pub ip_addr: ip::Addr,
     pub mac_addr: ethernet::MacAddr,
+    pub state: State,
     pub timestamp: DateTime<Utc>,
-    pub cond: Condvar,
-    pub data: Buffer,
+    pub requested: Option<DateTime<Utc>>,
+    pub queue: VecDeque<Buffer>,
     pub interface: ip::interface::Interface,
+    // the next retransmission of the request, while INCOMPLETE
+    pub retransmit: Option<TimerId>,
 }
 
 impl Entry {
@@ -20,62 +61,78 @@ impl Entry {
         ip_addr: ip::Addr,
         mac_addr: ethernet::MacAddr,
         interface: ip::interface::Interface,
+        timestamp: DateTime<Utc>,
     ) -> Entry {
         Entry {
-            ip_addr: ip_addr,
-            mac_addr: mac_addr,
-            timestamp: Utc::now(),
-            cond: Condvar::new(),
-            data: Buffer::empty(),
-            interface: interface,
+            ip_addr,
+            mac_addr,
+            state: if mac_addr == ethernet::ADDR_ANY {
+                State::Incomplete
+            } else {
+                State::Reachable
+            },
+            timestamp,
+            requested: None,
+            queue: VecDeque::new(),
+            interface,
+            retransmit: None,
         }
     }
-}
 
-lazy_static! {
-    pub static ref TABLE: Arc<Mutex<Vec<Entry>>> = Arc::new(Mutex::new(vec![]));
-    static ref TIMESTAMP: Mutex<DateTime<Utc>> = Mutex::new(Utc::now());
-}
+    pub fn matches(&self, interface: &ip::interface::Interface, ip_addr: &ip::Addr) -> bool {
+        &self.ip_addr == ip_addr && Arc::ptr_eq(&self.interface.0, &interface.0)
+    }
 
-pub fn lookup(ip_addr: &ip::Addr) -> Option<usize> {
-    let table = TABLE.lock().unwrap();
-    for (idx, entry) in table.iter().enumerate() {
-        if &entry.ip_addr == ip_addr {
-            return Some(idx);
+    /// queues a packet waiting for resolution, dropping the oldest one when full.
+    pub fn enqueue(&mut self, data: Buffer) {
+        if self.queue.len() >= QUEUE_MAX {
+            self.queue.pop_front();
         }
+        self.queue.push_back(data);
     }
-    None
 }
 
-pub fn remove(idx: usize) -> Result<(), Box<dyn Error>> {
-    let mut table = TABLE.lock().unwrap();
-    if idx < table.len() {
-        table.remove(idx);
-        Ok(())
-    } else {
-        Err(RuntimeError::new(format!(
-            "out of range: index is {}, but table size is {}",
-            idx,
-            table.len()
-        )))
-    }
+// a prefix an interface answers ARP requests for on behalf of the hosts it routes to
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub network: ip::Addr,
+    pub netmask: ip::Addr,
+    pub interface: ip::interface::Interface,
+}
+
+#[derive(Default)]
+pub(crate) struct Tables {
+    pub entries: Mutex<Vec<Entry>>,
+    pub proxies: Mutex<Vec<Proxy>>,
 }
 
-pub fn patrol() {
-    let mut timestamp = TIMESTAMP.lock().unwrap();
-    if (Utc::now() - *timestamp).num_seconds() > 10 {
-        let mut table = TABLE.lock().unwrap();
-        *timestamp = Utc::now();
-        let _: Vec<_> = table
-            .drain_filter(|entry| {
-                let timeout = Duration::seconds(300);
-                if *timestamp - entry.timestamp > timeout {
-                    entry.cond.notify_all();
-                    true
-                } else {
-                    false
-                }
-            })
-            .collect();
+pub fn is_proxied(interface: &ip::interface::Interface, ip_addr: &ip::Addr) -> bool {
+    let stack = interface.stack();
+    let proxies = stack.0.arp.table.proxies.lock().unwrap();
+    proxies.iter().any(|proxy| {
+        Arc::ptr_eq(&proxy.interface.0, &interface.0)
+            && ip_addr.apply_mask(&proxy.netmask) == proxy.network
+    })
+}
+
+/// ages the entries, run by `arp::patrol`.
+pub fn patrol(stack: &Stack) {
+    let now = stack.now();
+    let mut table = stack.0.arp.table.entries.lock().unwrap();
+    for entry in table.iter_mut() {
+        if entry.state == State::Reachable
+            && now - entry.timestamp > Duration::seconds(REACHABLE_TIME)
+        {
+            entry.state = State::Stale;
+        }
     }
+    // INCOMPLETE entries are expired by their retransmission instead
+    table.retain(|entry| {
+        let keep =
+            entry.state != State::Stale || now - entry.timestamp <= Duration::seconds(STALE_TIME);
+        if let (false, Some(id)) = (keep, entry.retransmit) {
+            timer::cancel(stack, id);
+        }
+        keep
+    });
 }
@@ -1,7 +1,6 @@
-use crate::{ethernet, ip, util};
+use crate::{error::Error, ethernet, ip};
 use arrayvec::ArrayVec;
 use std::collections::VecDeque;
-use std::error::Error;
 
 #[derive(Debug, Clone)]
 pub struct Buffer(pub VecDeque<u8>);
@@ -9,9 +8,9 @@ pub struct Buffer(pub VecDeque<u8>);
 use std::fmt;
 impl fmt::Display for Buffer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
+        writeln!(
             f,
-            "-----+------------------------------------------------+------------------+\n"
+            "-----+------------------------------------------------+------------------+"
         )?;
         for i in 0..self.0.len() / 16 + 1 {
             let offset = i * 16;
@@ -36,11 +35,11 @@ impl fmt::Display for Buffer {
                     write!(f, " ")?;
                 }
             }
-            write!(f, "\n")?;
+            writeln!(f)?;
         }
-        write!(
+        writeln!(
             f,
-            "-----+------------------------------------------------+------------------+\n"
+            "-----+------------------------------------------------+------------------+"
         )
     }
 }
@@ -88,7 +87,6 @@ impl Buffer {
             self.0.append(&mut buf.0);
             self.0.append(&mut after);
         } else {
-            buf.0.split_off(after.len());
             self.0.append(&mut buf.0);
         }
     }
@@ -96,77 +94,65 @@ impl Buffer {
         self.0[pos] = v as u8;
         self.0[pos + 1] = (v >> 8) as u8;
     }
-    pub fn pop_mac_addr(&mut self, label: &str) -> Result<ethernet::MacAddr, Box<dyn Error>> {
+    fn truncated(&self, label: &str) -> Error {
+        Error::parse(label, format!("truncated, {} bytes left", self.0.len()))
+    }
+    pub fn pop_mac_addr(&mut self, label: &str) -> Result<ethernet::MacAddr, Error> {
         if ethernet::ADDR_LEN <= self.0.len() {
             let buf = self.0.split_off(ethernet::ADDR_LEN);
             let buf = ::std::mem::replace(&mut self.0, buf);
             let arr_vec: ArrayVec<[_; ethernet::ADDR_LEN]> = buf.into_iter().collect();
             Ok(ethernet::MacAddr(arr_vec.into_inner().unwrap()))
         } else {
-            Err(util::RuntimeError::new(format!(
-                "cannot pop {} from {:?}",
-                label, self.0
-            )))
+            Err(self.truncated(label))
         }
     }
-    pub fn pop_ip_addr(&mut self, label: &str) -> Result<ip::Addr, Box<dyn Error>> {
+    pub fn pop_ip_addr(&mut self, label: &str) -> Result<ip::Addr, Error> {
         if ip::ADDR_LEN <= self.0.len() {
             let buf = self.0.split_off(ip::ADDR_LEN);
             let buf = ::std::mem::replace(&mut self.0, buf);
             let arr_vec: ArrayVec<[_; ip::ADDR_LEN]> = buf.into_iter().collect();
             Ok(ip::Addr(arr_vec.into_inner().unwrap()))
         } else {
-            Err(util::RuntimeError::new(format!(
-                "cannot pop {} from {:?}",
-                label, self.0
-            )))
+            Err(self.truncated(label))
         }
     }
-    pub fn pop_u8(&mut self, label: &str) -> Result<u8, Box<dyn Error>> {
-        self.0.pop_front().ok_or(util::RuntimeError::new(format!(
-            "cannot pop {} from {:?}",
-            label, self.0
-        )))
+    pub fn pop_u8(&mut self, label: &str) -> Result<u8, Error> {
+        match self.0.pop_front() {
+            Some(n) => Ok(n),
+            None => Err(self.truncated(label)),
+        }
     }
 
-    pub fn pop_u16(&mut self, label: &str) -> Result<u16, Box<dyn Error>> {
+    pub fn pop_u16(&mut self, label: &str) -> Result<u16, Error> {
         if 2 <= self.0.len() {
             let buf = self.0.split_off(2);
             let buf = ::std::mem::replace(&mut self.0, buf);
             let arr_vec: ArrayVec<[_; 2]> = buf.into_iter().collect();
             Ok(u16::from_be_bytes(arr_vec.into_inner().unwrap()))
         } else {
-            Err(util::RuntimeError::new(format!(
-                "cannot pop {} from {:?}",
-                label, self.0
-            )))
+            Err(self.truncated(label))
         }
     }
 
-    pub fn pop_u32(&mut self, label: &str) -> Result<u32, Box<dyn Error>> {
+    pub fn pop_u32(&mut self, label: &str) -> Result<u32, Error> {
         if 4 <= self.0.len() {
             let buf = self.0.split_off(4);
             let buf = ::std::mem::replace(&mut self.0, buf);
             let arr_vec: ArrayVec<[_; 4]> = buf.into_iter().collect();
             Ok(u32::from_be_bytes(arr_vec.into_inner().unwrap()))
         } else {
-            Err(util::RuntimeError::new(format!(
-                "cannot pop {} from {:?}",
-                label, self.0
-            )))
+            Err(self.truncated(label))
         }
     }
 
-    pub fn pop_buffer(&mut self, len: usize, label: &str) -> Result<Buffer, Box<dyn Error>> {
+    pub fn pop_buffer(&mut self, len: usize, label: &str) -> Result<Buffer, Error> {
         if len <= self.0.len() {
             let buf = self.0.split_off(len);
             let buf = ::std::mem::replace(&mut self.0, buf);
             Ok(Buffer(buf))
         } else {
-            Err(util::RuntimeError::new(format!(
-                "cannot pop {} from {:?}",
-                label, self.0
-            )))
+            Err(self.truncated(label))
         }
     }
 }
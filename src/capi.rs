@@ -0,0 +1,486 @@
+// a BSD-style socket API for C programs, exported from the cdylib
+//
+// `mps_init` brings up one stack on one ethernet device. The other calls mirror their
+// socket(2) namesakes for UDP over IPv4: on failure they return -1 and set errno.
+// build.rs writes include/microps.h from the declarations here, doc comments included.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use libc::{nfds_t, pollfd, sa_family_t, sockaddr, sockaddr_in, socklen_t, ssize_t};
+
+use crate::{
+    arp,
+    buffer::Buffer,
+    error::Error,
+    ethernet, ip, raw,
+    stack::Stack,
+    udp::{self, Events, PollFd},
+};
+
+/// `struct mps_config`, what `mps_init` brings up.
+#[repr(C)]
+pub struct Config {
+    /// e.g. "eth0", or "tap0" for a tap device
+    pub device: *const c_char,
+    /// dotted quads
+    pub address: *const c_char,
+    pub netmask: *const c_char,
+    /// may be NULL
+    pub gateway: *const c_char,
+}
+
+struct Runtime {
+    stack: Stack,
+    interface: ip::interface::Interface,
+    sockets: HashMap<c_int, udp::Socket>,
+    next_fd: c_int,
+}
+
+lazy_static! {
+    static ref RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+}
+
+// how long `mps_init` waits at most for conflict detection to clear the address
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// the errno closest to `err`.
+pub fn errno(err: &Error) -> c_int {
+    match err {
+        Error::Parse { .. } | Error::Invalid(_) => libc::EINVAL,
+        Error::Dropped(_) => libc::EIO,
+        Error::Timeout | Error::WouldBlock | Error::PortsExhausted => libc::EAGAIN,
+        Error::NoRoute(_) => libc::ENETUNREACH,
+        Error::Unresolved(_) => libc::EHOSTUNREACH,
+        Error::PortInUse(_) => libc::EADDRINUSE,
+        Error::NotFound(_) => libc::ENOENT,
+        Error::Exists(_) => libc::EEXIST,
+        Error::Io(err) => err.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+// an errno to fail with, so that `?` works on both crate errors and plain codes
+pub(crate) struct Errno(pub(crate) c_int);
+
+impl From<Error> for Errno {
+    fn from(err: Error) -> Errno {
+        Errno(errno(&err))
+    }
+}
+
+// runs the body of a call, turning an error or a panic into -1 and errno
+pub(crate) fn call<T: From<i8>, F: FnOnce() -> Result<T, Errno>>(f: F) -> T {
+    let code = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(ret)) => return ret,
+        Ok(Err(Errno(code))) => code,
+        Err(_) => libc::EIO,
+    };
+    unsafe {
+        *libc::__errno_location() = code;
+    }
+    T::from(-1)
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, Errno> {
+    if s.is_null() {
+        return Err(Errno(libc::EINVAL));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| Errno(libc::EINVAL))
+}
+
+unsafe fn to_addr(s: *const c_char) -> Result<ip::Addr, Errno> {
+    Ok(ip::Addr::from_str(to_str(s)?)?)
+}
+
+unsafe fn from_sockaddr(addr: *const sockaddr, len: socklen_t) -> Result<(ip::Addr, u16), Errno> {
+    if addr.is_null() || (len as usize) < mem::size_of::<sockaddr_in>() {
+        return Err(Errno(libc::EINVAL));
+    }
+    let addr = &*(addr as *const sockaddr_in);
+    if addr.sin_family != libc::AF_INET as sa_family_t {
+        return Err(Errno(libc::EAFNOSUPPORT));
+    }
+    Ok((
+        ip::Addr(addr.sin_addr.s_addr.to_ne_bytes()),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+// writes as much of the address as fits, like recvfrom(2)
+unsafe fn to_sockaddr(ip_addr: ip::Addr, port: u16, addr: *mut sockaddr, len: *mut socklen_t) {
+    if addr.is_null() || len.is_null() {
+        return;
+    }
+    let mut sin: sockaddr_in = mem::zeroed();
+    sin.sin_family = libc::AF_INET as sa_family_t;
+    sin.sin_port = port.to_be();
+    sin.sin_addr.s_addr = u32::from_ne_bytes(ip_addr.0);
+    let size = mem::size_of::<sockaddr_in>();
+    ptr::copy_nonoverlapping(
+        &sin as *const sockaddr_in as *const u8,
+        addr as *mut u8,
+        size.min(*len as usize),
+    );
+    *len = size as socklen_t;
+}
+
+fn socket(fd: c_int) -> Result<udp::Socket, Errno> {
+    let runtime = RUNTIME.lock().unwrap();
+    let runtime = runtime.as_ref().ok_or(Errno(libc::ENETDOWN))?;
+    match runtime.sockets.get(&fd) {
+        Some(socket) => Ok(socket.share()),
+        None => Err(Errno(libc::EBADF)),
+    }
+}
+
+// a socket another thread closed after `socket` looked it up
+fn closed_is_bad_fd(err: Error) -> Errno {
+    match err {
+        Error::NotFound(_) => Errno(libc::EBADF),
+        err => err.into(),
+    }
+}
+
+/// brings up the stack on `config.device`, returning once conflict detection has cleared its
+/// address, a few seconds later; fails with EADDRINUSE if another host has it.
+///
+/// # Safety
+/// `config` and its strings must be valid, or the strings null where allowed.
+#[no_mangle]
+pub unsafe extern "C" fn mps_init(config: *const Config) -> c_int {
+    call(|| {
+        let config = config.as_ref().ok_or(Errno(libc::EINVAL))?;
+        let gateway = if config.gateway.is_null() {
+            None
+        } else {
+            Some(to_addr(config.gateway)?)
+        };
+        init(
+            to_str(config.device)?,
+            ethernet::ADDR_ANY,
+            to_addr(config.address)?,
+            to_addr(config.netmask)?,
+            gateway,
+        )?;
+        Ok(0)
+    })
+}
+
+// brings up the stack behind the calls; the table is not held while the address is
+// probed, so that other calls fail fast meanwhile
+pub(crate) fn init(
+    device: &str,
+    mac: ethernet::MacAddr,
+    unicast: ip::Addr,
+    netmask: ip::Addr,
+    gateway: Option<ip::Addr>,
+) -> Result<(), Errno> {
+    if RUNTIME.lock().unwrap().is_some() {
+        return Err(Errno(libc::EALREADY));
+    }
+    let stack = Stack::new();
+    let raw = raw::try_open(raw::Type::Auto, device)?;
+    let mut device = ethernet::Device::from_raw(&stack, raw, mac)?;
+    let interface = ip::interface::Interface::new(device.clone(), unicast, netmask, gateway);
+    device.add_interface(interface.clone());
+    device.run()?;
+    let started = stack.now();
+    while arp::is_tentative(&interface) {
+        if (stack.now() - started).to_std().unwrap() > PROBE_TIMEOUT {
+            device.close()?;
+            return Err(Errno(libc::ETIMEDOUT));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if arp::is_conflicted(&interface) {
+        device.close()?;
+        return Err(Errno(libc::EADDRINUSE));
+    }
+    let mut runtime = RUNTIME.lock().unwrap();
+    if runtime.is_some() {
+        device.close()?;
+        return Err(Errno(libc::EALREADY));
+    }
+    *runtime = Some(Runtime {
+        stack,
+        interface,
+        sockets: HashMap::new(),
+        next_fd: 3,
+    });
+    Ok(())
+}
+
+/// opens a UDP socket; `type_` may carry SOCK_NONBLOCK and SOCK_CLOEXEC.
+#[no_mangle]
+pub extern "C" fn mps_socket(domain: c_int, type_: c_int, protocol: c_int) -> c_int {
+    call(|| {
+        if domain != libc::AF_INET {
+            return Err(Errno(libc::EAFNOSUPPORT));
+        }
+        if type_ & !(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) != libc::SOCK_DGRAM
+            || (protocol != 0 && protocol != libc::IPPROTO_UDP)
+        {
+            return Err(Errno(libc::EPROTONOSUPPORT));
+        }
+        let mut runtime = RUNTIME.lock().unwrap();
+        let runtime = runtime.as_mut().ok_or(Errno(libc::ENETDOWN))?;
+        let mut socket = udp::open(&runtime.stack)?;
+        // bound to an ephemeral port on first send, as by the kernel
+        socket.bind_interface(runtime.interface.clone(), 0)?;
+        if type_ & libc::SOCK_NONBLOCK != 0 {
+            socket.set_nonblocking(true)?;
+        }
+        let fd = runtime.next_fd;
+        runtime.next_fd += 1;
+        runtime.sockets.insert(fd, socket);
+        Ok(fd)
+    })
+}
+
+/// binds to a port of the stack's address, or of INADDR_ANY.
+///
+/// # Safety
+/// `addr` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mps_bind(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    call(|| {
+        let (ip_addr, port) = from_sockaddr(addr, len)?;
+        let mut socket = socket(fd)?;
+        let interface = match RUNTIME.lock().unwrap().as_ref() {
+            Some(runtime) => runtime.interface.clone(),
+            None => return Err(Errno(libc::ENETDOWN)),
+        };
+        let unicast = interface.0.lock().unwrap().unicast;
+        if ip_addr != ip::Addr::empty() && ip_addr != unicast {
+            return Err(Errno(libc::EADDRNOTAVAIL));
+        }
+        socket
+            .bind_interface(interface, port)
+            .map_err(closed_is_bad_fd)?;
+        Ok(0)
+    })
+}
+
+/// sends `len` bytes of `buf` to `dst`.
+///
+/// # Safety
+/// `buf` and `dst` must point to `len` and `dst_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mps_sendto(
+    fd: c_int,
+    buf: *const c_void,
+    len: usize,
+    _flags: c_int,
+    dst: *const sockaddr,
+    dst_len: socklen_t,
+) -> ssize_t {
+    call(|| {
+        let (ip_addr, port) = from_sockaddr(dst, dst_len)?;
+        if buf.is_null() && len > 0 {
+            return Err(Errno(libc::EFAULT));
+        }
+        let data = if len == 0 {
+            vec![]
+        } else {
+            std::slice::from_raw_parts(buf as *const u8, len).to_vec()
+        };
+        socket(fd)?
+            .send_to(Buffer::from_vec(data), ip_addr, port)
+            .map_err(closed_is_bad_fd)?;
+        Ok(len as ssize_t)
+    })
+}
+
+/// receives a datagram, truncated to `len`; MSG_DONTWAIT makes this call non-blocking.
+///
+/// # Safety
+/// `buf` must point to `len` writable bytes, and `src` to `*src_len` unless null.
+#[no_mangle]
+pub unsafe extern "C" fn mps_recvfrom(
+    fd: c_int,
+    buf: *mut c_void,
+    len: usize,
+    flags: c_int,
+    src: *mut sockaddr,
+    src_len: *mut socklen_t,
+) -> ssize_t {
+    call(|| {
+        let mut socket = socket(fd)?;
+        let (ip_addr, port, data) = if flags & libc::MSG_DONTWAIT != 0 {
+            socket.recv_from_timeout(Duration::from_secs(0))
+        } else {
+            socket.recv_from(-1)
+        }
+        .map_err(closed_is_bad_fd)?;
+        let data = data.to_vec();
+        let n = data.len().min(len);
+        if n > 0 {
+            if buf.is_null() {
+                return Err(Errno(libc::EFAULT));
+            }
+            ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, n);
+        }
+        to_sockaddr(ip_addr, port, src, src_len);
+        Ok(n as ssize_t)
+    })
+}
+
+/// closes a socket, waking whoever waits on it.
+#[no_mangle]
+pub extern "C" fn mps_close(fd: c_int) -> c_int {
+    call(|| {
+        let socket = {
+            let mut runtime = RUNTIME.lock().unwrap();
+            let runtime = runtime.as_mut().ok_or(Errno(libc::ENETDOWN))?;
+            runtime.sockets.remove(&fd).ok_or(Errno(libc::EBADF))?
+        };
+        socket.close()?;
+        Ok(0)
+    })
+}
+
+/// poll(2) over sockets of the stack; other descriptors are reported as POLLNVAL.
+///
+/// # Safety
+/// `fds` must point to `nfds` pollfds.
+#[no_mangle]
+pub unsafe extern "C" fn mps_poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int {
+    call(|| {
+        if fds.is_null() && nfds > 0 {
+            return Err(Errno(libc::EFAULT));
+        }
+        let fds: &mut [pollfd] = if nfds == 0 {
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(fds, nfds as usize)
+        };
+        let sockets: Vec<Option<udp::Socket>> = fds.iter().map(|fd| socket(fd.fd).ok()).collect();
+        let mut invalid = 0;
+        for (fd, socket) in fds.iter_mut().zip(sockets.iter()) {
+            fd.revents = 0;
+            if socket.is_none() {
+                fd.revents = libc::POLLNVAL;
+                invalid += 1;
+            }
+        }
+        let mut poll_fds: Vec<PollFd> = fds
+            .iter()
+            .zip(sockets.iter())
+            .filter_map(|(fd, socket)| {
+                let socket = socket.as_ref()?;
+                let mut events = Events::empty();
+                if fd.events & libc::POLLIN != 0 {
+                    events |= Events::READABLE;
+                }
+                if fd.events & libc::POLLOUT != 0 {
+                    events |= Events::WRITABLE;
+                }
+                Some(PollFd::new(socket, events))
+            })
+            .collect();
+        // like poll(2), return at once when there is a bad descriptor to report
+        udp::poll(&mut poll_fds, if invalid > 0 { 0 } else { timeout })?;
+        let mut poll_fds = poll_fds.into_iter();
+        let mut ready = invalid;
+        for (fd, socket) in fds.iter_mut().zip(sockets.iter()) {
+            if socket.is_none() {
+                continue;
+            }
+            let revents = poll_fds.next().unwrap().revents;
+            if revents.contains(Events::READABLE) {
+                fd.revents |= libc::POLLIN;
+            }
+            if revents.contains(Events::WRITABLE) {
+                fd.revents |= libc::POLLOUT;
+            }
+            if revents.contains(Events::ERROR) {
+                fd.revents |= libc::POLLERR;
+            }
+            if fd.revents != 0 {
+                ready += 1;
+            }
+        }
+        Ok(ready)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn errors_map_to_errnos() {
+        let addr = ip::Addr([10, 0, 0, 1]);
+        let cases = vec![
+            (Error::parse("ihl", "too short"), libc::EINVAL),
+            (Error::Invalid("x".to_string()), libc::EINVAL),
+            (Error::Dropped("x"), libc::EIO),
+            (Error::Timeout, libc::EAGAIN),
+            (Error::WouldBlock, libc::EAGAIN),
+            (Error::PortsExhausted, libc::EAGAIN),
+            (Error::NoRoute(addr), libc::ENETUNREACH),
+            (Error::Unresolved(addr), libc::EHOSTUNREACH),
+            (Error::PortInUse(7), libc::EADDRINUSE),
+            (Error::NotFound("x".to_string()), libc::ENOENT),
+            (Error::Exists("x".to_string()), libc::EEXIST),
+            (Error::Io(io::Error::from_raw_os_error(libc::ENODEV)), libc::ENODEV),
+            (Error::Io(io::Error::other("x")), libc::EIO),
+        ];
+        for (err, code) in cases {
+            assert_eq!(errno(&err), code, "{}", err);
+        }
+    }
+
+    #[test]
+    fn failed_calls_set_errno() {
+        assert_eq!(call(|| -> Result<c_int, Errno> { Err(Error::PortInUse(7).into()) }), -1);
+        assert_eq!(unsafe { *libc::__errno_location() }, libc::EADDRINUSE);
+        assert_eq!(call(|| -> Result<ssize_t, Errno> { panic!("oops") }), -1);
+        assert_eq!(unsafe { *libc::__errno_location() }, libc::EIO);
+    }
+
+    #[test]
+    fn sockaddrs_round_trip() {
+        let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<sockaddr_in>() as socklen_t;
+        let addr = &mut sin as *mut sockaddr_in as *mut sockaddr;
+        unsafe { to_sockaddr(ip::Addr([192, 0, 2, 1]), 5353, addr, &mut len) };
+        assert_eq!(sin.sin_family, libc::AF_INET as sa_family_t);
+        // in network order, as C expects
+        assert_eq!(sin.sin_port, 5353u16.to_be());
+        assert_eq!(sin.sin_addr.s_addr.to_ne_bytes(), [192, 0, 2, 1]);
+        let (ip_addr, port) = unsafe { from_sockaddr(addr, len) }.ok().unwrap();
+        assert_eq!((ip_addr, port), (ip::Addr([192, 0, 2, 1]), 5353));
+    }
+
+    #[test]
+    fn short_sockaddrs_are_truncated_or_refused() {
+        // written only as far as it fits, with the length it would have taken
+        let mut buf = [0xffu8; 4];
+        let mut len = 4 as socklen_t;
+        let addr = buf.as_mut_ptr() as *mut sockaddr;
+        unsafe { to_sockaddr(ip::Addr([192, 0, 2, 1]), 7, addr, &mut len) };
+        assert_eq!(len as usize, mem::size_of::<sockaddr_in>());
+        assert_eq!(buf[2..], 7u16.to_be_bytes());
+        // nowhere to write is not an error
+        unsafe { to_sockaddr(ip::Addr([192, 0, 2, 1]), 7, ptr::null_mut(), ptr::null_mut()) };
+
+        let sin: sockaddr_in = unsafe { mem::zeroed() };
+        let addr = &sin as *const sockaddr_in as *const sockaddr;
+        let full = mem::size_of::<sockaddr_in>() as socklen_t;
+        let code = |ret: Result<(ip::Addr, u16), Errno>| ret.err().map(|Errno(code)| code);
+        assert_eq!(code(unsafe { from_sockaddr(addr, full - 1) }), Some(libc::EINVAL));
+        assert_eq!(code(unsafe { from_sockaddr(ptr::null(), full) }), Some(libc::EINVAL));
+        // zeroed, so AF_UNSPEC
+        assert_eq!(code(unsafe { from_sockaddr(addr, full) }), Some(libc::EAFNOSUPPORT));
+    }
+}
@@ -0,0 +1,265 @@
+// where a stack reads the time from, and how its background work waits on it
+//
+// The system clock is plain threads and sleeps. A virtual clock only moves when its
+// simulator advances it, and its threads take turns: one runs at a time, in the order
+// they became ready, and only while the simulator waits for them to settle. That makes
+// a simulation replay the same way every time.
+
+use std::cell::Cell;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// `duration` after `now`, or `None` if that is past what the time can count to.
+pub fn deadline(now: DateTime<Utc>, duration: Duration) -> Option<DateTime<Utc>> {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| now.checked_add_signed(duration))
+}
+
+#[derive(Clone)]
+pub enum Clock {
+    System,
+    Virtual(Arc<VirtualClock>),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::System => Utc::now(),
+            Clock::Virtual(clock) => clock.now(),
+        }
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        match self {
+            Clock::System => thread::sleep(duration),
+            Clock::Virtual(clock) => clock.sleep(duration),
+        }
+    }
+
+    /// runs `f` on its own thread, which takes turns with the others on a virtual clock.
+    pub fn spawn<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self {
+            Clock::System => thread::spawn(f),
+            Clock::Virtual(clock) => clock.spawn(f),
+        }
+    }
+
+    /// waits on `cond` while `condition` holds, at most `timeout`; true if it timed out.
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        cond: &Condvar,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        condition: F,
+    ) -> (MutexGuard<'a, T>, bool)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        match self {
+            Clock::System => {
+                let (guard, result) = cond.wait_timeout_while(guard, timeout, condition).unwrap();
+                (guard, result.timed_out())
+            }
+            Clock::Virtual(clock) => clock.wait_timeout_while(cond, guard, timeout, condition),
+        }
+    }
+}
+
+// how often a thread outside the clock looks at it again while waiting on virtual time
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+thread_local! {
+    // the virtual clock (by address) that spawned this thread, and the thread's ticket
+    static TICKET: Cell<Option<(usize, u64)>> = const { Cell::new(None) };
+}
+
+struct Schedule {
+    now: DateTime<Utc>,
+    // the simulator is running; the clock's threads wait until it settles
+    held: bool,
+    running: Option<u64>,
+    ready: VecDeque<u64>,
+    sleeping: BTreeSet<(DateTime<Utc>, u64)>,
+    next_ticket: u64,
+}
+
+pub struct VirtualClock {
+    schedule: Mutex<Schedule>,
+    changed: Condvar,
+}
+
+impl fmt::Debug for VirtualClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtualClock({})", self.now())
+    }
+}
+
+// hands the turn on when a clock thread ends, even by panicking
+struct Finish<'a> {
+    clock: &'a VirtualClock,
+}
+
+impl<'a> Drop for Finish<'a> {
+    fn drop(&mut self) {
+        let mut schedule = self.clock.schedule.lock().unwrap();
+        schedule.running = None;
+        self.clock.changed.notify_all();
+    }
+}
+
+impl VirtualClock {
+    /// a clock standing still at `start` until it is advanced.
+    pub fn new(start: DateTime<Utc>) -> VirtualClock {
+        VirtualClock {
+            schedule: Mutex::new(Schedule {
+                now: start,
+                held: true,
+                running: None,
+                ready: VecDeque::new(),
+                sleeping: BTreeSet::new(),
+                next_ticket: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.schedule.lock().unwrap().now
+    }
+
+    fn id(&self) -> usize {
+        self as *const VirtualClock as usize
+    }
+
+    fn ticket(&self) -> Option<u64> {
+        match TICKET.with(|ticket| ticket.get()) {
+            Some((id, ticket)) if id == self.id() => Some(ticket),
+            _ => None,
+        }
+    }
+
+    fn wait_turn<'a>(
+        &self,
+        mut schedule: MutexGuard<'a, Schedule>,
+        ticket: u64,
+    ) -> MutexGuard<'a, Schedule> {
+        while schedule.held || schedule.running.is_some() || schedule.ready.front() != Some(&ticket)
+        {
+            schedule = self.changed.wait(schedule).unwrap();
+        }
+        schedule.ready.pop_front();
+        schedule.running = Some(ticket);
+        schedule
+    }
+
+    fn spawn<F>(self: &Arc<Self>, f: F) -> JoinHandle<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let ticket = {
+            let mut schedule = self.schedule.lock().unwrap();
+            let ticket = schedule.next_ticket;
+            schedule.next_ticket += 1;
+            schedule.ready.push_back(ticket);
+            ticket
+        };
+        self.changed.notify_all();
+        let clock = self.clone();
+        thread::spawn(move || {
+            TICKET.with(|cell| cell.set(Some((clock.id(), ticket))));
+            drop(clock.wait_turn(clock.schedule.lock().unwrap(), ticket));
+            let _finish = Finish { clock: &clock };
+            f();
+        })
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut schedule = self.schedule.lock().unwrap();
+        // too long to count to is for ever, which the latest time there is stands for
+        let deadline =
+            deadline(schedule.now, duration).unwrap_or_else(|| chrono::MAX_DATE.and_hms(23, 59, 59));
+        match self.ticket() {
+            Some(ticket) => {
+                schedule.sleeping.insert((deadline, ticket));
+                schedule.running = None;
+                self.changed.notify_all();
+                drop(self.wait_turn(schedule, ticket));
+            }
+            None => {
+                while schedule.now < deadline {
+                    schedule = self.changed.wait(schedule).unwrap();
+                }
+            }
+        }
+    }
+
+    // only for threads outside the clock, as it keeps the turn of a clock thread
+    fn wait_timeout_while<'a, T, F>(
+        &self,
+        cond: &Condvar,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, bool)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        // none if too long to count to, when it never times out
+        let deadline = deadline(self.now(), timeout);
+        loop {
+            if !condition(&mut *guard) {
+                return (guard, false);
+            }
+            if deadline.is_some_and(|deadline| self.now() >= deadline) {
+                return (guard, true);
+            }
+            guard = cond.wait_timeout(guard, POLL_INTERVAL).unwrap().0;
+        }
+    }
+
+    /// lets the clock's threads run until each has finished or gone to sleep.
+    pub fn settle(&self) {
+        let mut schedule = self.schedule.lock().unwrap();
+        schedule.held = false;
+        self.changed.notify_all();
+        while schedule.running.is_some() || !schedule.ready.is_empty() {
+            schedule = self.changed.wait(schedule).unwrap();
+        }
+        schedule.held = true;
+    }
+
+    /// when the earliest sleeping thread wants to wake up.
+    pub fn next_wakeup(&self) -> Option<DateTime<Utc>> {
+        let schedule = self.schedule.lock().unwrap();
+        schedule
+            .sleeping
+            .iter()
+            .next()
+            .map(|(deadline, _)| *deadline)
+    }
+
+    /// moves the time to `to`, making every thread that slept until then ready to run.
+    pub fn advance(&self, to: DateTime<Utc>) {
+        let mut schedule = self.schedule.lock().unwrap();
+        if to > schedule.now {
+            schedule.now = to;
+        }
+        while let Some(&(deadline, ticket)) = schedule.sleeping.iter().next() {
+            if deadline > schedule.now {
+                break;
+            }
+            schedule.sleeping.remove(&(deadline, ticket));
+            schedule.ready.push_back(ticket);
+        }
+        self.changed.notify_all();
+    }
+}
@@ -0,0 +1,1154 @@
+// a stack described by a file, for topologies kept under version control
+//
+//   [ip]
+//   forwarding = true
+//
+//   [[device]]
+//   name = "tap0"
+//   type = "tap"                # tap, socket, tun, slip or loopback; by the name if left out
+//   mac = "00:00:5E:00:53:00"   # ethernet only; the device's own if left out
+//   mtu = 1500
+//   baud = 115200               # slip only
+//
+//   [[interface]]
+//   device = "tap0"
+//   address = "172.16.0.1"
+//   netmask = "255.255.255.0"
+//   gateway = "172.16.0.254"
+//
+//   [[interface]]               # refused until there is a dhcp client
+//   device = "tap1"
+//   dhcp = true                 # ethernet only; instead of netmask and gateway
+//   address = "172.16.1.7"      # the address to ask for, if any
+//   hostname = "host1"
+//   lease = 3600                # seconds to ask for; the server's choice if left out
+//
+//   [[route]]
+//   network = "10.0.0.0"
+//   netmask = "255.0.0.0"
+//   nexthop = "172.16.0.253"
+//   device = "tap0"
+//
+//   [[arp]]
+//   device = "tap0"
+//   address = "172.16.0.9"
+//   mac = "00:00:5E:00:53:09"
+//
+// `Config::load` reports every mistake it finds at once, each with its line.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    arp,
+    error::Error,
+    ethernet::{self, MacAddr},
+    ip::{self, interface::Interface, route},
+    link::{loopback, p2p},
+    raw, slip,
+    stack::Stack,
+};
+
+pub mod toml;
+
+use self::toml::{Table, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Tap,
+    Socket,
+    Tun,
+    Slip,
+    Loopback,
+}
+
+impl DeviceType {
+    fn from_str(s: &str) -> Option<DeviceType> {
+        Some(match s {
+            "tap" => DeviceType::Tap,
+            "socket" => DeviceType::Socket,
+            "tun" => DeviceType::Tun,
+            "slip" => DeviceType::Slip,
+            "loopback" => DeviceType::Loopback,
+            _ => return None,
+        })
+    }
+    pub fn is_ethernet(&self) -> bool {
+        *self == DeviceType::Tap || *self == DeviceType::Socket
+    }
+    fn raw_type(&self) -> raw::Type {
+        match self {
+            DeviceType::Tap => raw::Type::Tap,
+            DeviceType::Socket => raw::Type::Socket,
+            DeviceType::Tun => raw::Type::Tun,
+            DeviceType::Slip => raw::Type::Slip,
+            DeviceType::Loopback => unreachable!("loopback has no raw device"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub type_: DeviceType,
+    pub mac: Option<MacAddr>,
+    pub mtu: Option<usize>,
+    pub baud_rate: Option<u32>,
+}
+
+/// an interface whose address is left to a DHCP server
+#[derive(Debug, Clone, Default)]
+pub struct DhcpConfig {
+    /// the address to ask for, e.g. the one of an earlier lease
+    pub requested: Option<ip::Addr>,
+    pub hostname: Option<String>,
+    /// the lease to ask for in seconds
+    pub lease_time: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InterfaceConfig {
+    pub device: String,
+    /// 0.0.0.0 with `dhcp`, until a lease comes
+    pub address: ip::Addr,
+    pub netmask: ip::Addr,
+    pub gateway: Option<ip::Addr>,
+    pub dhcp: Option<DhcpConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    pub network: ip::Addr,
+    pub netmask: ip::Addr,
+    pub nexthop: Option<ip::Addr>,
+    pub device: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArpConfig {
+    pub device: String,
+    pub address: ip::Addr,
+    pub mac: MacAddr,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub forwarding: bool,
+    pub devices: Vec<DeviceConfig>,
+    pub interfaces: Vec<InterfaceConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub arp: Vec<ArpConfig>,
+}
+
+// the largest MTU of a point-to-point link
+const P2P_MTU_MAX: usize = 65535;
+// the smallest MTU IPv4 allows
+const MTU_MIN: usize = 68;
+// the longest a DHCP option, the host name included, can be
+const DHCP_OPTION_LEN_MAX: usize = 255;
+
+impl Config {
+    /// reads and checks the file at `path`; errors name it and the offending lines.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|err| Error::Invalid(format!("{}: {}", path.display(), err)))?;
+        Config::parse_named(&text, &path.display().to_string())
+    }
+
+    pub fn parse(text: &str) -> Result<Config, Error> {
+        Config::parse_named(text, "config")
+    }
+
+    fn parse_named(text: &str, origin: &str) -> Result<Config, Error> {
+        let tables = toml::parse(text)
+            .map_err(|err| Error::Invalid(format!("{}:{}: {}", origin, err.line, err.reason)))?;
+        let mut reader = Reader::default();
+        let config = reader.read(&tables);
+        if reader.errors.is_empty() {
+            Ok(config)
+        } else {
+            reader.errors.sort_by_key(|(line, _)| *line);
+            let errors: Vec<String> = reader
+                .errors
+                .iter()
+                .map(|(line, reason)| format!("{}:{}: {}", origin, line, reason))
+                .collect();
+            Err(Error::Invalid(errors.join("\n")))
+        }
+    }
+
+    pub fn device(&self, name: &str) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|device| device.name == name)
+    }
+
+    /// opens the devices, assigns the addresses, adds the routes and ARP entries, and runs
+    /// the devices. Whatever was opened is closed again on failure.
+    pub fn start(&self, stack: &Stack) -> Result<Network, Error> {
+        let mut network = Network {
+            links: vec![],
+            interfaces: vec![],
+        };
+        if let Err(err) = self.build(stack, &mut network) {
+            network.close()?;
+            return Err(err);
+        }
+        Ok(network)
+    }
+
+    fn build(&self, stack: &Stack, network: &mut Network) -> Result<(), Error> {
+        ip::set_is_forwarding(stack, self.forwarding);
+        for device in self.devices.iter() {
+            let context = |err: Error| Error::Invalid(format!("device `{}`: {}", device.name, err));
+            let link = match device.type_ {
+                DeviceType::Loopback => Link::Loopback(loopback::Device::open(stack)),
+                DeviceType::Tap | DeviceType::Socket => {
+                    let raw =
+                        raw::try_open(device.type_.raw_type(), &device.name).map_err(context)?;
+                    let device_ = ethernet::Device::from_raw(
+                        stack,
+                        raw,
+                        device.mac.unwrap_or(ethernet::ADDR_ANY),
+                    )
+                    .map_err(context)?;
+                    if let Some(mtu) = device.mtu {
+                        device_.0.lock().unwrap().mtu = mtu;
+                    }
+                    Link::Ethernet(device_)
+                }
+                DeviceType::Tun | DeviceType::Slip => {
+                    let raw = match device.baud_rate {
+                        Some(baud_rate) => match slip::baud_rate(baud_rate) {
+                            Some(baud_rate) => {
+                                let config = slip::Config {
+                                    baud_rate,
+                                    ..Default::default()
+                                };
+                                slip::Device::open(&device.name, &config)
+                            }
+                            None => Err(Error::Invalid(format!(
+                                "{} is not a supported baud rate",
+                                baud_rate
+                            ))),
+                        },
+                        None => raw::try_open(device.type_.raw_type(), &device.name),
+                    }
+                    .map_err(context)?;
+                    let mtu = device.mtu.unwrap_or(p2p::MTU);
+                    Link::P2p(p2p::Device::open(stack, raw, mtu).map_err(context)?)
+                }
+            };
+            network.links.push((device.name.clone(), link));
+        }
+        for config in self.interfaces.iter() {
+            if config.dhcp.is_some() {
+                return Err(Error::Invalid(format!(
+                    "device `{}`: there is no dhcp client yet",
+                    config.device
+                )));
+            }
+            let link = network.link_mut(&config.device).ok_or_else(|| {
+                Error::NotFound(format!("device `{}` of an interface", config.device))
+            })?;
+            let interface = link.add_interface(config.address, config.netmask, config.gateway);
+            network.interfaces.push((config.device.clone(), interface));
+        }
+        for config in self.routes.iter() {
+            route::add(
+                stack,
+                route::Route {
+                    network: config.network,
+                    netmask: config.netmask,
+                    nexthop: config.nexthop,
+                    interface: network.interface_of(&config.device)?.clone(),
+                },
+            );
+        }
+        for config in self.arp.iter() {
+            let interface = network.interface_of(&config.device)?;
+            arp::add_static(interface, config.address, config.mac)?;
+        }
+        for (name, link) in network.links.iter_mut() {
+            link.run()
+                .map_err(|err| Error::Invalid(format!("device `{}`: {}", name, err)))?;
+        }
+        Ok(())
+    }
+}
+
+/// a device opened by `Config::start`
+#[derive(Debug, Clone)]
+pub enum Link {
+    Ethernet(ethernet::Device),
+    P2p(p2p::Device),
+    Loopback(loopback::Device),
+}
+
+impl Link {
+    fn add_interface(
+        &mut self,
+        address: ip::Addr,
+        netmask: ip::Addr,
+        gateway: Option<ip::Addr>,
+    ) -> Interface {
+        match self {
+            Link::Ethernet(device) => {
+                let interface = Interface::new(device.clone(), address, netmask, gateway);
+                device.add_interface(interface.clone());
+                interface
+            }
+            Link::P2p(device) => {
+                let interface = Interface::new(device.clone(), address, netmask, gateway);
+                device.add_interface(interface.clone());
+                interface
+            }
+            Link::Loopback(device) => {
+                let interface = Interface::new(device.clone(), address, netmask, gateway);
+                device.add_interface(interface.clone());
+                interface
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        match self {
+            Link::Ethernet(device) => device.run(),
+            Link::P2p(device) => device.run(),
+            Link::Loopback(device) => device.run(),
+        }
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        match self {
+            Link::Ethernet(device) => device.close(),
+            Link::P2p(device) => device.close(),
+            Link::Loopback(device) => device.close(),
+        }
+    }
+}
+
+/// the devices and interfaces of a started `Config`, by device name
+#[derive(Debug)]
+pub struct Network {
+    pub links: Vec<(String, Link)>,
+    pub interfaces: Vec<(String, Interface)>,
+}
+
+impl Network {
+    pub fn link(&self, device: &str) -> Option<&Link> {
+        self.links
+            .iter()
+            .find(|(name, _)| name == device)
+            .map(|(_, link)| link)
+    }
+
+    fn link_mut(&mut self, device: &str) -> Option<&mut Link> {
+        self.links
+            .iter_mut()
+            .find(|(name, _)| name == device)
+            .map(|(_, link)| link)
+    }
+
+    pub fn interface(&self, device: &str) -> Option<&Interface> {
+        self.interfaces
+            .iter()
+            .find(|(name, _)| name == device)
+            .map(|(_, interface)| interface)
+    }
+
+    // like `interface`, for what the config says must be there
+    fn interface_of(&self, device: &str) -> Result<&Interface, Error> {
+        self.interface(device)
+            .ok_or_else(|| Error::NotFound(format!("interface on device `{}`", device)))
+    }
+
+    /// stops and closes every device, in the order they were opened.
+    pub fn close(self) -> Result<(), Error> {
+        for (_, link) in self.links {
+            link.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn is_contiguous(netmask: ip::Addr) -> bool {
+    let bits = u32::from_be_bytes(netmask.0);
+    bits.leading_ones() + bits.trailing_zeros() == 32
+}
+
+// turns tables into a `Config`, collecting every error on the way
+#[derive(Default)]
+struct Reader {
+    errors: Vec<(usize, String)>,
+}
+
+impl Reader {
+    fn error<S: Into<String>>(&mut self, line: usize, reason: S) {
+        self.errors.push((line, reason.into()));
+    }
+
+    fn check_keys(&mut self, table: &Table, known: &[&str]) {
+        for entry in table.entries.iter() {
+            if !known.contains(&entry.key.as_str()) {
+                let reason = if table.name.is_empty() {
+                    format!("unknown key `{}` outside of any table", entry.key)
+                } else {
+                    format!(
+                        "unknown key `{}` in {}; expected one of {}",
+                        entry.key,
+                        header(table),
+                        known.join(", ")
+                    )
+                };
+                self.error(entry.line, reason);
+            }
+        }
+    }
+
+    fn value(&mut self, table: &Table, key: &str, required: bool) -> Option<(Value, usize)> {
+        match table.get(key) {
+            Some(entry) => Some((entry.value.clone(), entry.line)),
+            None => {
+                if required {
+                    self.error(table.line, format!("{} needs `{}`", header(table), key));
+                }
+                None
+            }
+        }
+    }
+
+    fn string(&mut self, table: &Table, key: &str, required: bool) -> Option<(String, usize)> {
+        match self.value(table, key, required)? {
+            (Value::String(s), line) => Some((s, line)),
+            (value, line) => {
+                self.error(
+                    line,
+                    format!("`{}` must be a string, not {}", key, value.type_name()),
+                );
+                None
+            }
+        }
+    }
+
+    fn integer(&mut self, table: &Table, key: &str) -> Option<(i64, usize)> {
+        match self.value(table, key, false)? {
+            (Value::Integer(n), line) => Some((n, line)),
+            (value, line) => {
+                self.error(
+                    line,
+                    format!("`{}` must be an integer, not {}", key, value.type_name()),
+                );
+                None
+            }
+        }
+    }
+
+    fn boolean(&mut self, table: &Table, key: &str) -> Option<bool> {
+        match self.value(table, key, false)? {
+            (Value::Boolean(b), _) => Some(b),
+            (value, line) => {
+                self.error(
+                    line,
+                    format!("`{}` must be true or false, not {}", key, value.type_name()),
+                );
+                None
+            }
+        }
+    }
+
+    fn addr(&mut self, table: &Table, key: &str, required: bool) -> Option<(ip::Addr, usize)> {
+        let (s, line) = self.string(table, key, required)?;
+        match ip::Addr::from_str(&s) {
+            Ok(addr) => Some((addr, line)),
+            Err(_) => {
+                self.error(line, format!("`{}` is not an IPv4 address: {:?}", key, s));
+                None
+            }
+        }
+    }
+
+    fn netmask(&mut self, table: &Table, required: bool) -> Option<ip::Addr> {
+        let (netmask, line) = self.addr(table, "netmask", required)?;
+        if !is_contiguous(netmask) {
+            self.error(line, format!("netmask {} is not contiguous", netmask));
+            return None;
+        }
+        Some(netmask)
+    }
+
+    fn mac(&mut self, table: &Table, key: &str, required: bool) -> Option<(MacAddr, usize)> {
+        let (s, line) = self.string(table, key, required)?;
+        match MacAddr::from_str(&s) {
+            Ok(mac) if mac.is_multicast() => {
+                self.error(line, format!("`{}` is a multicast address: {:?}", key, s));
+                None
+            }
+            Ok(mac) => Some((mac, line)),
+            Err(_) => {
+                self.error(line, format!("`{}` is not a MAC address: {:?}", key, s));
+                None
+            }
+        }
+    }
+
+    // the device named by `device`, which must have been declared
+    fn device<'a>(
+        &mut self,
+        config: &'a Config,
+        table: &Table,
+    ) -> Option<(&'a DeviceConfig, usize)> {
+        let (name, line) = self.string(table, "device", true)?;
+        match config.device(&name) {
+            Some(device) => Some((device, line)),
+            None => {
+                self.error(line, format!("no [[device]] is named `{}`", name));
+                None
+            }
+        }
+    }
+
+    // the interface on the device named by `device`, which must have one
+    fn interface<'a>(
+        &mut self,
+        config: &'a Config,
+        table: &Table,
+    ) -> Option<(&'a InterfaceConfig, usize)> {
+        let (device, line) = self.device(config, table)?;
+        match config
+            .interfaces
+            .iter()
+            .find(|interface| interface.device == device.name)
+        {
+            Some(interface) => Some((interface, line)),
+            None => {
+                self.error(
+                    line,
+                    format!("device `{}` has no [[interface]]", device.name),
+                );
+                None
+            }
+        }
+    }
+
+    fn read(&mut self, tables: &[Table]) -> Config {
+        let mut config = Config::default();
+        for table in tables.iter() {
+            match (table.name.as_str(), table.is_array) {
+                ("", _) => self.check_keys(table, &[]),
+                ("ip", false) => {
+                    self.check_keys(table, &["forwarding"]);
+                    config.forwarding = self.boolean(table, "forwarding").unwrap_or(false);
+                }
+                ("device", true) | ("interface", true) | ("route", true) | ("arp", true) => {}
+                ("ip", true) => self.error(table.line, "write [ip], not [[ip]]"),
+                (name @ "device", false)
+                | (name @ "interface", false)
+                | (name @ "route", false)
+                | (name @ "arp", false) => {
+                    self.error(table.line, format!("write [[{}]], not [{}]", name, name))
+                }
+                (name, _) => self.error(
+                    table.line,
+                    format!(
+                        "unknown table `{}`; expected ip, device, interface, route or arp",
+                        name
+                    ),
+                ),
+            }
+        }
+        // in dependency order, so that each can refer to what the previous ones declared
+        let of = |name: &'static str| {
+            tables
+                .iter()
+                .filter(move |table| table.is_array && table.name == name)
+        };
+        for table in of("device") {
+            if let Some(device) = self.read_device(&config, table) {
+                config.devices.push(device);
+            }
+        }
+        for table in of("interface") {
+            if let Some(interface) = self.read_interface(&config, table) {
+                config.interfaces.push(interface);
+            }
+        }
+        for table in of("route") {
+            if let Some(route) = self.read_route(&config, table) {
+                config.routes.push(route);
+            }
+        }
+        for table in of("arp") {
+            if let Some(arp) = self.read_arp(&config, table) {
+                config.arp.push(arp);
+            }
+        }
+        config
+    }
+
+    fn read_device(&mut self, config: &Config, table: &Table) -> Option<DeviceConfig> {
+        self.check_keys(table, &["name", "type", "mac", "mtu", "baud"]);
+        let (name, name_line) = self.string(table, "name", true)?;
+        if config.device(&name).is_some() {
+            self.error(name_line, format!("device `{}` is declared twice", name));
+            return None;
+        }
+        let type_ = match self.string(table, "type", false) {
+            Some((type_, line)) => match DeviceType::from_str(&type_) {
+                Some(type_) => type_,
+                None => {
+                    self.error(
+                        line,
+                        format!(
+                            "unknown device type {:?}; expected tap, socket, tun, slip or loopback",
+                            type_
+                        ),
+                    );
+                    return None;
+                }
+            },
+            None => match raw::detect_type(&name) {
+                raw::Type::Tap => DeviceType::Tap,
+                raw::Type::Tun => DeviceType::Tun,
+                raw::Type::Slip => DeviceType::Slip,
+                _ => DeviceType::Socket,
+            },
+        };
+        if type_ == DeviceType::Loopback
+            && config
+                .devices
+                .iter()
+                .any(|device| device.type_ == DeviceType::Loopback)
+        {
+            self.error(table.line, "only one loopback device is supported");
+        }
+        let mac = self.mac(table, "mac", false).and_then(|(mac, line)| {
+            if type_.is_ethernet() {
+                Some(mac)
+            } else {
+                self.error(line, format!("device `{}` has no MAC address", name));
+                None
+            }
+        });
+        let mtu = self.integer(table, "mtu").and_then(|(mtu, line)| {
+            let max = match type_ {
+                DeviceType::Tap | DeviceType::Socket => ethernet::PAYLOAD_SIZE_MAX,
+                DeviceType::Tun | DeviceType::Slip => P2P_MTU_MAX,
+                DeviceType::Loopback => {
+                    self.error(line, "the MTU of loopback is fixed");
+                    return None;
+                }
+            };
+            if mtu < MTU_MIN as i64 || mtu > max as i64 {
+                self.error(
+                    line,
+                    format!("mtu {} is out of range {}..={}", mtu, MTU_MIN, max),
+                );
+                return None;
+            }
+            Some(mtu as usize)
+        });
+        let baud_rate = self.integer(table, "baud").and_then(|(baud, line)| {
+            if type_ != DeviceType::Slip {
+                self.error(line, "`baud` is for slip devices only");
+                return None;
+            }
+            if baud < 0 || baud > u32::MAX as i64 || slip::baud_rate(baud as u32).is_none() {
+                self.error(line, format!("{} is not a supported baud rate", baud));
+                return None;
+            }
+            Some(baud as u32)
+        });
+        Some(DeviceConfig {
+            name,
+            type_,
+            mac,
+            mtu,
+            baud_rate,
+        })
+    }
+
+    fn read_interface(&mut self, config: &Config, table: &Table) -> Option<InterfaceConfig> {
+        self.check_keys(
+            table,
+            &[
+                "device", "address", "netmask", "gateway", "dhcp", "hostname", "lease",
+            ],
+        );
+        if let Some(true) = self.boolean(table, "dhcp") {
+            // checked all the same, to be right as written once there is a client
+            self.read_dhcp_interface(config, table)?;
+            let line = table.get("dhcp").map_or(table.line, |entry| entry.line);
+            self.error(
+                line,
+                "there is no dhcp client yet; give `address` and `netmask` instead",
+            );
+            return None;
+        }
+        for key in ["hostname", "lease"].iter() {
+            if let Some(entry) = table.get(key) {
+                self.error(entry.line, format!("`{}` is for dhcp only", key));
+            }
+        }
+        let device = self.device(config, table);
+        let address = self.addr(table, "address", true);
+        let netmask = self.netmask(table, true);
+        let gateway = self.addr(table, "gateway", false);
+        let ((device, device_line), (address, address_line), netmask) =
+            (device?, address?, netmask?);
+        if !self.check_unconfigured(config, device, device_line) {
+            return None;
+        }
+        if let Some(other) = config
+            .interfaces
+            .iter()
+            .find(|interface| interface.address == address)
+        {
+            self.error(
+                address_line,
+                format!("{} is already the address of `{}`", address, other.device),
+            );
+            return None;
+        }
+        if address == ip::Addr::empty() {
+            self.error(address_line, "the address must not be 0.0.0.0");
+            return None;
+        }
+        let gateway = match gateway {
+            Some((gateway, line)) => {
+                if device.type_ == DeviceType::Loopback {
+                    self.error(line, "loopback takes no gateway");
+                    return None;
+                }
+                if gateway.apply_mask(&netmask) != address.apply_mask(&netmask) {
+                    self.error(
+                        line,
+                        format!(
+                            "gateway {} is outside {}/{}",
+                            gateway,
+                            address.apply_mask(&netmask),
+                            netmask
+                        ),
+                    );
+                    return None;
+                }
+                Some(gateway)
+            }
+            None => None,
+        };
+        Some(InterfaceConfig {
+            device: device.name.clone(),
+            address,
+            netmask,
+            gateway,
+            dhcp: None,
+        })
+    }
+
+    // whether `device` has no [[interface]] yet
+    fn check_unconfigured(&mut self, config: &Config, device: &DeviceConfig, line: usize) -> bool {
+        if config
+            .interfaces
+            .iter()
+            .any(|interface| interface.device == device.name)
+        {
+            self.error(
+                line,
+                format!("device `{}` already has an [[interface]]", device.name),
+            );
+            return false;
+        }
+        true
+    }
+
+    fn read_dhcp_interface(&mut self, config: &Config, table: &Table) -> Option<InterfaceConfig> {
+        for key in ["netmask", "gateway"].iter() {
+            if let Some(entry) = table.get(key) {
+                self.error(
+                    entry.line,
+                    format!("`{}` comes with the lease when `dhcp` is on", key),
+                );
+            }
+        }
+        let device = self.device(config, table);
+        let requested = self.addr(table, "address", false);
+        let hostname = self
+            .string(table, "hostname", false)
+            .and_then(|(hostname, line)| {
+                if hostname.is_empty() || hostname.len() > DHCP_OPTION_LEN_MAX {
+                    self.error(
+                        line,
+                        format!(
+                            "the hostname must be 1 to {} bytes long",
+                            DHCP_OPTION_LEN_MAX
+                        ),
+                    );
+                    return None;
+                }
+                Some(hostname)
+            });
+        let lease_time = self.integer(table, "lease").and_then(|(lease, line)| {
+            if lease < 1 || lease > u32::MAX as i64 {
+                self.error(
+                    line,
+                    format!("lease {} is out of range 1..={}", lease, u32::MAX),
+                );
+                return None;
+            }
+            Some(lease as u32)
+        });
+        let (device, device_line) = device?;
+        if !device.type_.is_ethernet() {
+            self.error(
+                device_line,
+                format!("dhcp needs an ethernet device, not `{}`", device.name),
+            );
+            return None;
+        }
+        if !self.check_unconfigured(config, device, device_line) {
+            return None;
+        }
+        let requested = match requested {
+            Some((address, line)) if address == ip::Addr::empty() => {
+                self.error(line, "the address must not be 0.0.0.0");
+                return None;
+            }
+            Some((address, _)) => Some(address),
+            None => None,
+        };
+        Some(InterfaceConfig {
+            device: device.name.clone(),
+            address: ip::Addr::empty(),
+            netmask: ip::Addr::empty(),
+            gateway: None,
+            dhcp: Some(DhcpConfig {
+                requested,
+                hostname,
+                lease_time,
+            }),
+        })
+    }
+
+    fn read_route(&mut self, config: &Config, table: &Table) -> Option<RouteConfig> {
+        self.check_keys(table, &["network", "netmask", "nexthop", "device"]);
+        let interface = self.interface(config, table);
+        let network = self.addr(table, "network", true);
+        let netmask = self.netmask(table, true);
+        let nexthop = self.addr(table, "nexthop", false);
+        let ((interface, _), (network, network_line), netmask) = (interface?, network?, netmask?);
+        if network.apply_mask(&netmask) != network {
+            self.error(
+                network_line,
+                format!("network {} has bits outside netmask {}", network, netmask),
+            );
+            return None;
+        }
+        if config
+            .routes
+            .iter()
+            .any(|route| route.network == network && route.netmask == netmask)
+        {
+            self.error(
+                network_line,
+                format!("{}/{} is routed twice", network, netmask),
+            );
+            return None;
+        }
+        let nexthop = match nexthop {
+            Some((nexthop, line)) => {
+                let subnet = interface.address.apply_mask(&interface.netmask);
+                if nexthop.apply_mask(&interface.netmask) != subnet {
+                    self.error(
+                        line,
+                        format!(
+                            "nexthop {} is outside {}/{} of `{}`",
+                            nexthop, subnet, interface.netmask, interface.device
+                        ),
+                    );
+                    return None;
+                }
+                Some(nexthop)
+            }
+            None => None,
+        };
+        Some(RouteConfig {
+            network,
+            netmask,
+            nexthop,
+            device: interface.device.clone(),
+        })
+    }
+
+    fn read_arp(&mut self, config: &Config, table: &Table) -> Option<ArpConfig> {
+        self.check_keys(table, &["device", "address", "mac"]);
+        let interface = self.interface(config, table);
+        let address = self.addr(table, "address", true);
+        let mac = self.mac(table, "mac", true);
+        let ((interface, device_line), (address, address_line), (mac, _)) =
+            (interface?, address?, mac?);
+        if !config
+            .device(&interface.device)
+            .unwrap()
+            .type_
+            .is_ethernet()
+        {
+            self.error(
+                device_line,
+                format!("device `{}` does not use ARP", interface.device),
+            );
+            return None;
+        }
+        if config
+            .arp
+            .iter()
+            .any(|arp| arp.device == interface.device && arp.address == address)
+        {
+            self.error(address_line, format!("{} has two [[arp]] entries", address));
+            return None;
+        }
+        Some(ArpConfig {
+            device: interface.device.clone(),
+            address,
+            mac,
+        })
+    }
+}
+
+fn header(table: &Table) -> String {
+    if table.is_array {
+        format!("[[{}]]", table.name)
+    } else {
+        format!("[{}]", table.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, VirtualClock};
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+
+    // the errors of `text`, one per line
+    fn errors(text: &str) -> Vec<String> {
+        match Config::parse(text) {
+            Err(Error::Invalid(errors)) => errors.lines().map(str::to_string).collect(),
+            other => panic!("expected errors, got {:?}", other),
+        }
+    }
+
+    const TAP: &str = "[[device]]\nname = \"tap0\"\ntype = \"tap\"\n";
+
+    fn loopback() -> Config {
+        Config {
+            devices: vec![DeviceConfig {
+                name: "lo".to_string(),
+                type_: DeviceType::Loopback,
+                mac: None,
+                mtu: None,
+                baud_rate: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn stack() -> Stack {
+        let clock = VirtualClock::new(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0));
+        Stack::with_clock(Clock::Virtual(Arc::new(clock)), 1)
+    }
+
+    #[test]
+    fn built_configs_are_checked_when_started() {
+        let lo = ip::Addr([127, 0, 0, 1]);
+        let mask = ip::Addr([255, 0, 0, 0]);
+        let interface = |device: &str| InterfaceConfig {
+            device: device.to_string(),
+            address: lo,
+            netmask: mask,
+            gateway: None,
+            dhcp: None,
+        };
+
+        let mut config = loopback();
+        config.interfaces.push(interface("eth9"));
+        assert!(matches!(config.start(&stack()), Err(Error::NotFound(_))));
+
+        let mut config = loopback();
+        config.routes.push(RouteConfig {
+            network: ip::Addr([10, 0, 0, 0]),
+            netmask: mask,
+            nexthop: None,
+            device: "lo".to_string(),
+        });
+        assert!(matches!(config.start(&stack()), Err(Error::NotFound(_))));
+
+        let mut config = loopback();
+        config.arp.push(ArpConfig {
+            device: "lo".to_string(),
+            address: lo,
+            mac: MacAddr([2, 0, 0, 0, 0, 1]),
+        });
+        assert!(matches!(config.start(&stack()), Err(Error::NotFound(_))));
+
+        let mut config = loopback();
+        config.devices.push(DeviceConfig {
+            name: "/dev/ttyS0".to_string(),
+            type_: DeviceType::Slip,
+            mac: None,
+            mtu: None,
+            baud_rate: Some(12345),
+        });
+        match config.start(&stack()) {
+            Err(Error::Invalid(reason)) => {
+                assert_eq!(reason, "device `/dev/ttyS0`: 12345 is not a supported baud rate")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn full_config() {
+        let text = format!(
+            "[ip]\nforwarding = true\n{}mac = \"02:00:00:00:00:01\"\nmtu = 1400\n\
+             [[interface]]\ndevice = \"tap0\"\naddress = \"172.16.0.1\"\nnetmask = \"255.255.255.0\"\n\
+             gateway = \"172.16.0.254\"\n\
+             [[route]]\nnetwork = \"10.0.0.0\"\nnetmask = \"255.0.0.0\"\nnexthop = \"172.16.0.253\"\n\
+             device = \"tap0\"\n\
+             [[arp]]\ndevice = \"tap0\"\naddress = \"172.16.0.9\"\nmac = \"02:00:00:00:00:09\"\n",
+            TAP
+        );
+        let config = Config::parse(&text).unwrap();
+        assert!(config.forwarding);
+        let device = config.device("tap0").unwrap();
+        assert_eq!(device.type_, DeviceType::Tap);
+        assert_eq!(device.mtu, Some(1400));
+        assert_eq!(
+            config.interfaces[0].gateway,
+            Some(ip::Addr([172, 16, 0, 254]))
+        );
+        assert_eq!(config.routes[0].nexthop, Some(ip::Addr([172, 16, 0, 253])));
+        assert_eq!(config.arp[0].mac, MacAddr([2, 0, 0, 0, 0, 9]));
+    }
+
+    #[test]
+    fn syntax_errors_name_their_line() {
+        assert_eq!(
+            errors("[ip]\nforwarding"),
+            vec!["config:2: expected `.`, `=`"]
+        );
+    }
+
+    #[test]
+    fn every_mistake_is_reported_in_line_order() {
+        let text = format!(
+            "{}mtu = 9000\nbaud = 9600\n[[interface]]\ndevice = \"tap1\"\naddress = \"10.0.0.1\"\n\
+             netmask = \"255.0.255.0\"\n",
+            TAP
+        );
+        assert_eq!(
+            errors(&text),
+            vec![
+                "config:4: mtu 9000 is out of range 68..=1500",
+                "config:5: `baud` is for slip devices only",
+                "config:7: no [[device]] is named `tap1`",
+                "config:9: netmask 255.0.255.0 is not contiguous",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_tables_and_keys() {
+        let text = "speed = 1\n[ip]\nforwarding = 1\n[device]\n[[bridge]]\n[[arp]]\nport = 1\n";
+        let found = errors(text);
+        assert_eq!(
+            found[0],
+            "config:1: unknown key `speed` outside of any table"
+        );
+        assert_eq!(
+            found[1],
+            "config:3: `forwarding` must be true or false, not an integer"
+        );
+        assert_eq!(found[2], "config:4: write [[device]], not [device]");
+        assert!(found[3].starts_with("config:5: unknown table `bridge`"));
+        assert!(found.contains(&"config:6: [[arp]] needs `device`".to_string()));
+        assert!(found
+            .iter()
+            .any(|error| error.starts_with("config:7: unknown key `port` in [[arp]]")));
+        assert!(errors("[[ip]]")[0].ends_with("write [ip], not [[ip]]"));
+    }
+
+    #[test]
+    fn routes_and_arp_are_checked_against_the_interface() {
+        let text = format!(
+            "{}[[interface]]\ndevice = \"tap0\"\naddress = \"172.16.0.1\"\nnetmask = \"255.255.255.0\"\n\
+             [[route]]\nnetwork = \"10.0.0.1\"\nnetmask = \"255.0.0.0\"\ndevice = \"tap0\"\n\
+             [[route]]\nnetwork = \"10.0.0.0\"\nnetmask = \"255.0.0.0\"\nnexthop = \"192.0.2.1\"\n\
+             device = \"tap0\"\n\
+             [[arp]]\ndevice = \"tap0\"\naddress = \"172.16.0.9\"\nmac = \"01:00:5e:00:00:01\"\n",
+            TAP
+        );
+        assert_eq!(
+            errors(&text),
+            vec![
+                "config:9: network 10.0.0.1 has bits outside netmask 255.0.0.0",
+                "config:15: nexthop 192.0.2.1 is outside 172.16.0.0/255.255.255.0 of `tap0`",
+                "config:20: `mac` is a multicast address: \"01:00:5e:00:00:01\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn dhcp_is_refused_until_there_is_a_client() {
+        let text = format!(
+            "{}[[interface]]\ndevice = \"tap0\"\ndhcp = true\naddress = \"172.16.1.7\"\n\
+             hostname = \"host1\"\nlease = 3600\n",
+            TAP
+        );
+        assert_eq!(
+            errors(&text),
+            vec!["config:6: there is no dhcp client yet; give `address` and `netmask` instead"]
+        );
+
+        // nor is a built config left without an address
+        let mut config = loopback();
+        config.interfaces.push(InterfaceConfig {
+            device: "lo".to_string(),
+            address: ip::Addr::empty(),
+            netmask: ip::Addr::empty(),
+            gateway: None,
+            dhcp: Some(DhcpConfig::default()),
+        });
+        match config.start(&stack()) {
+            Err(Error::Invalid(reason)) => {
+                assert_eq!(reason, "device `lo`: there is no dhcp client yet")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn dhcp_mistakes() {
+        let text = format!(
+            "{}[[device]]\nname = \"tun0\"\ntype = \"tun\"\n\
+             [[interface]]\ndevice = \"tap0\"\ndhcp = true\nnetmask = \"255.255.255.0\"\n\
+             hostname = \"\"\nlease = 0\n\
+             [[interface]]\ndevice = \"tun0\"\ndhcp = true\n\
+             [[interface]]\ndevice = \"tun0\"\naddress = \"10.0.0.1\"\nnetmask = \"255.0.0.0\"\n\
+             lease = 60\n\
+             [[route]]\nnetwork = \"10.0.0.0\"\nnetmask = \"255.0.0.0\"\ndevice = \"tap0\"\n",
+            TAP
+        );
+        assert_eq!(
+            errors(&text),
+            vec![
+                "config:9: there is no dhcp client yet; give `address` and `netmask` instead",
+                "config:10: `netmask` comes with the lease when `dhcp` is on",
+                "config:11: the hostname must be 1 to 255 bytes long",
+                "config:12: lease 0 is out of range 1..=4294967295",
+                "config:14: dhcp needs an ethernet device, not `tun0`",
+                "config:20: `lease` is for dhcp only",
+                "config:24: device `tap0` has no [[interface]]",
+            ]
+        );
+    }
+}
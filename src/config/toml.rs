@@ -0,0 +1,321 @@
+// configuration files as tables whose entries know their line
+//
+// The `toml` crate reads the file; this keeps where each table and value was, so that
+// mistakes can be reported on their line. The root table's keys, each `[name]` table and
+// each element of a `[[name]]` array of tables make a `Table`; whatever is nested deeper
+// stays a value, for the reader to refuse.
+
+use std::fmt;
+use std::ops::Range;
+
+use ::toml::Spanned;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Float(f64),
+    Datetime(String),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Float(_) => "a float",
+            Value::Datetime(_) => "a date",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Datetime(s) => write!(f, "{}", s),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Value::Table(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{} = {}", key, value))
+                    .collect();
+                write!(f, "{{ {} }}", entries.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    // empty for the keys before the first header
+    pub name: String,
+    // whether the header was `[[name]]`
+    pub is_array: bool,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+}
+
+/// a syntax error on line `line`, counted from 1.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub reason: String,
+}
+
+// the key the toml crate hands a date over in, as a table of its own
+const DATETIME_KEY: &str = "$__toml_private_datetime";
+
+// a value as read, tables and arrays keeping where their pieces are
+enum Node {
+    Value(Value),
+    Table(Vec<(String, Spanned<Node>)>),
+    Array(Vec<Spanned<Node>>),
+}
+
+impl Node {
+    fn into_value(self) -> Value {
+        match self {
+            Node::Value(value) => value,
+            Node::Table(entries) => Value::Table(
+                entries
+                    .into_iter()
+                    .map(|(key, node)| (key, node.into_inner().into_value()))
+                    .collect(),
+            ),
+            Node::Array(nodes) => Value::Array(
+                nodes
+                    .into_iter()
+                    .map(|node| node.into_inner().into_value())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+struct NodeVisitor;
+
+impl<'de> Visitor<'de> for NodeVisitor {
+    type Value = Node;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a TOML value")
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<Node, E> {
+        Ok(Node::Value(Value::Boolean(b)))
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<Node, E> {
+        Ok(Node::Value(Value::Integer(n)))
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<Node, E> {
+        if n > i64::MAX as u64 {
+            return Err(E::custom(format!("{} is out of range", n)));
+        }
+        Ok(Node::Value(Value::Integer(n as i64)))
+    }
+
+    fn visit_f64<E: de::Error>(self, x: f64) -> Result<Node, E> {
+        Ok(Node::Value(Value::Float(x)))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Node, E> {
+        Ok(Node::Value(Value::String(s.to_string())))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Node, A::Error> {
+        let mut nodes = vec![];
+        while let Some(node) = seq.next_element()? {
+            nodes.push(node);
+        }
+        Ok(Node::Array(nodes))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node, A::Error> {
+        let mut entries = vec![];
+        while let Some(key) = map.next_key::<String>()? {
+            if key == DATETIME_KEY {
+                return Ok(Node::Value(Value::Datetime(map.next_value()?)));
+            }
+            entries.push((key, map.next_value()?));
+        }
+        Ok(Node::Table(entries))
+    }
+}
+
+/// the tables of `text` in the order they appear, the root table first.
+pub fn parse(text: &str) -> Result<Vec<Table>, ParseError> {
+    let line = |span: Range<usize>| text[..span.start].matches('\n').count() + 1;
+    let entries = match ::toml::from_str::<Node>(text) {
+        Ok(Node::Table(entries)) => entries,
+        Ok(_) => unreachable!("a document is a table"),
+        Err(err) => {
+            return Err(ParseError {
+                line: err.span().map(line).unwrap_or(1),
+                reason: err.message().to_string(),
+            })
+        }
+    };
+    let table = |name: &str, is_array, node: Spanned<Node>| {
+        let at = line(node.span());
+        let entries = match node.into_inner() {
+            Node::Table(entries) => entries,
+            _ => unreachable!("only tables are made tables"),
+        };
+        Table {
+            name: name.to_string(),
+            is_array,
+            line: at,
+            entries: entries
+                .into_iter()
+                .map(|(key, node)| Entry {
+                    key,
+                    line: line(node.span()),
+                    value: node.into_inner().into_value(),
+                })
+                .collect(),
+        }
+    };
+    let is_table = |node: &Spanned<Node>| matches!(node.get_ref(), Node::Table(_));
+    let mut root = table("", false, Spanned::new(0..0, Node::Table(vec![])));
+    root.line = 0;
+    let mut tables = vec![];
+    for (key, node) in entries {
+        match node.get_ref() {
+            Node::Table(_) => tables.push(table(&key, false, node)),
+            Node::Array(nodes) if !nodes.is_empty() && nodes.iter().all(is_table) => {
+                match node.into_inner() {
+                    Node::Array(nodes) => {
+                        tables.extend(nodes.into_iter().map(|node| table(&key, true, node)))
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            _ => root.entries.push(Entry {
+                line: line(node.span()),
+                key,
+                value: node.into_inner().into_value(),
+            }),
+        }
+    }
+    tables.sort_by_key(|table| table.line);
+    tables.insert(0, root);
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> (usize, String) {
+        let err = parse(text).unwrap_err();
+        (err.line, err.reason)
+    }
+
+    #[test]
+    fn tables_in_order() {
+        let text = "# a stack\nname = \"x\"\n\n[[device]]\nmtu = 1_500\n\n[ip]\nforwarding = true # on\n\n[[device]]\nmtu = 0x10\n";
+        let tables = parse(text).unwrap();
+        let names: Vec<(&str, bool, usize)> = tables
+            .iter()
+            .map(|table| (table.name.as_str(), table.is_array, table.line))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("", false, 0),
+                ("device", true, 4),
+                ("ip", false, 7),
+                ("device", true, 10)
+            ]
+        );
+        assert_eq!(
+            tables[0].get("name").unwrap().value,
+            Value::String("x".to_string())
+        );
+        let forwarding = tables[2].get("forwarding").unwrap();
+        assert_eq!(
+            (forwarding.value.clone(), forwarding.line),
+            (Value::Boolean(true), 8)
+        );
+        assert_eq!(tables[1].get("mtu").unwrap().value, Value::Integer(1500));
+        assert_eq!(tables[3].get("mtu").unwrap().value, Value::Integer(16));
+    }
+
+    #[test]
+    fn string_escapes() {
+        let tables = parse(r#"s = "a\"b\\c\t# not a comment" # a comment"#).unwrap();
+        assert_eq!(
+            tables[0].get("s").unwrap().value,
+            Value::String("a\"b\\c\t# not a comment".to_string())
+        );
+    }
+
+    #[test]
+    fn nested_values_are_left_to_the_reader() {
+        let text = "[ip]\nmask = [255, 0]\nat = 1979-05-27\nratio = 0.5\nopts = { a = 1 }\n";
+        let tables = parse(text).unwrap();
+        let ip = &tables[1];
+        let types: Vec<(&str, &str, usize)> = ip
+            .entries
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.type_name(), entry.line))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("mask", "an array", 2),
+                ("at", "a date", 3),
+                ("ratio", "a float", 4),
+                ("opts", "a table", 5)
+            ]
+        );
+        assert_eq!(ip.get("at").unwrap().value.to_string(), "1979-05-27");
+        assert_eq!(ip.get("opts").unwrap().value.to_string(), "{ a = 1 }");
+    }
+
+    #[test]
+    fn syntax_errors_carry_their_line() {
+        assert_eq!(error("\n[ip").0, 2);
+        assert_eq!(error("[ip]\n[ip]").0, 2);
+        assert_eq!(error("[a]\n\n[[a]]").0, 3);
+        assert_eq!(error("a = 1\na = 2").0, 2);
+        assert_eq!(error("\n\na = \"x").0, 3);
+        assert_eq!(error("a = yes").0, 1);
+        assert!(!error("just words").1.is_empty());
+    }
+}
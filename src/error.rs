@@ -0,0 +1,143 @@
+use std::fmt;
+use std::io;
+
+use crate::ip;
+
+/// the protocol layer a packet failed to parse at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Ethernet,
+    Llc,
+    Stp,
+    Lldp,
+    Arp,
+    Ip,
+    Icmp,
+    Udp,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Layer::Ethernet => "ethernet",
+                Layer::Llc => "llc",
+                Layer::Stp => "stp",
+                Layer::Lldp => "lldp",
+                Layer::Arp => "arp",
+                Layer::Ip => "ip",
+                Layer::Icmp => "icmp",
+                Layer::Udp => "udp",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// a received packet is malformed or of a kind the layer does not know
+    Parse {
+        // filled in by `Packet::parse` when the failure came from deep inside a `Buffer`
+        layer: Option<Layer>,
+        field: String,
+        reason: String,
+    },
+    /// a well-formed packet nobody here takes, with the same reason `stats` counts it under
+    Dropped(&'static str),
+    Timeout,
+    NoRoute(ip::Addr),
+    /// no link-layer address could be resolved for the address
+    Unresolved(ip::Addr),
+    PortInUse(u16),
+    PortsExhausted,
+    WouldBlock,
+    /// a bad argument or configuration
+    Invalid(String),
+    NotFound(String),
+    Exists(String),
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn parse<F: Into<String>, R: Into<String>>(field: F, reason: R) -> Error {
+        Error::Parse {
+            layer: None,
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// attributes a parse error to `layer` unless it already names one.
+    pub fn in_layer(self, layer: Layer) -> Error {
+        match self {
+            Error::Parse {
+                layer: None,
+                field,
+                reason,
+            } => Error::Parse {
+                layer: Some(layer),
+                field,
+                reason,
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse {
+                layer,
+                field,
+                reason,
+            } => {
+                if let Some(layer) = layer {
+                    write!(f, "{}: ", layer)?;
+                }
+                write!(f, "invalid {}: {}", field, reason)
+            }
+            Error::Dropped(reason) => write!(f, "dropped: {}", reason),
+            Error::Timeout => write!(f, "timed out"),
+            Error::NoRoute(addr) => write!(f, "no route to {}", addr),
+            Error::Unresolved(addr) => write!(f, "host unreachable: {}", addr),
+            Error::PortInUse(port) => write!(f, "port {} is in use", port),
+            Error::PortsExhausted => write!(f, "no port available"),
+            Error::WouldBlock => write!(f, "operation would block"),
+            Error::Invalid(message) => write!(f, "{}", message),
+            Error::NotFound(message) => write!(f, "not found: {}", message),
+            Error::Exists(message) => write!(f, "already exists: {}", message),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::WouldBlock => Error::WouldBlock,
+            io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(err: nix::Error) -> Error {
+        match err {
+            nix::Error::Sys(errno) => Error::from(io::Error::from_raw_os_error(errno as i32)),
+            err => Error::Io(io::Error::other(err.to_string())),
+        }
+    }
+}
@@ -1,14 +1,25 @@
-use std::collections::HashMap;
-use std::error::Error;
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use arrayvec::ArrayVec;
-use bitflags::bitflags;
-
-use crate::{arp, buffer::Buffer, ip, packet, raw, util::RuntimeError};
+use crate::{
+    arp,
+    buffer::Buffer,
+    error::Error,
+    ip,
+    link::{self, DeviceFlags, LinkDevice, Resolution},
+    packet, raw,
+    stack::{Stack, WeakStack},
+    stats,
+};
 
+pub mod bridge;
 mod frame;
+pub mod llc;
+pub mod lldp;
+pub mod stp;
+pub mod vlan;
 
 pub const ADDR_LEN: usize = 6;
 pub const ADDR_STR_LEN: usize = 18;
@@ -30,12 +41,25 @@ impl MacAddr {
     pub fn empty() -> MacAddr {
         MacAddr([0; ADDR_LEN])
     }
-    pub fn from_str(str: &String) -> Result<Self, Box<dyn Error>> {
-        str.split(':')
-            .map(|n| u8::from_str_radix(n, 16))
-            .collect::<Result<ArrayVec<[_; 6]>, _>>()
-            .map(|arr| Self(arr.into_inner().unwrap()))
-            .or_else(|err| Err(RuntimeError::new(format!("{}", err))))
+    /// true for group addresses, including broadcast
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::Invalid(format!("invalid mac address: {}", s));
+        if s.split(':').count() != ADDR_LEN {
+            return Err(invalid());
+        }
+        let mut addr = [0; ADDR_LEN];
+        for (octet, n) in addr.iter_mut().zip(s.split(':')) {
+            *octet = u8::from_str_radix(n, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(addr))
     }
 }
 
@@ -49,24 +73,13 @@ impl fmt::Display for MacAddr {
     }
 }
 
-bitflags! {
-    pub struct DeviceFlags: u32 {
-        const EMPTY = 0x00;
-        const BROADCAST = 0x01;
-        const MULTICAST = 0x02;
-        const P2P       = 0x04;
-        const LOOPBACK  = 0x08;
-        const NOARP     = 0x10;
-        const PROMISC   = 0x20;
-        const RUNNING   = 0x40;
-        const UP        = 0x80;
-    }
-}
-
 #[repr(u16)]
 pub enum Type {
     Arp = 0x0806,
     Ip = 0x0800,
+    Lldp = 0x88cc,
+    // not an ethertype: an 802.3 frame carrying LLC, like Linux's ETH_P_802_2
+    Llc = 0x0004,
 }
 
 impl Type {
@@ -75,6 +88,8 @@ impl Type {
             Some(Type::Arp)
         } else if n == Type::Ip as u16 {
             Some(Type::Ip)
+        } else if n == Type::Lldp as u16 {
+            Some(Type::Lldp)
         } else {
             None
         }
@@ -90,25 +105,28 @@ impl fmt::Display for Type {
             match self {
                 Type::Arp => "ARP",
                 Type::Ip => "IP",
+                Type::Lldp => "LLDP",
+                Type::Llc => "LLC",
             }
         )
     }
 }
 
-lazy_static! {
-    static ref JOIN_HANDLES: Mutex<HashMap<String, thread::JoinHandle<()>>> =
-        Mutex::new(HashMap::new());
-    pub static ref DEVICES: Arc<Mutex<Vec<Device>>> = Arc::new(Mutex::new(vec![]));
-}
-
 #[derive(Debug, Clone)]
 pub struct DeviceImpl {
-    pub interface: Option<ip::interface::Interface>,
+    pub interface: Option<ip::interface::WeakInterface>,
     pub name: String,
     pub raw: Arc<dyn raw::RawDevice + Sync + Send>,
     pub addr: MacAddr,
     pub broadcast_addr: MacAddr,
+    // the largest payload sent in one frame, PAYLOAD_SIZE_MAX unless lowered
+    pub mtu: usize,
+    pub flags: DeviceFlags,
     pub terminate: bool,
+    // set while the device is a port of a bridge, which then receives all its frames
+    pub bridge: Option<bridge::WeakBridge>,
+    pub stats: stats::Device,
+    pub stack: WeakStack,
 }
 
 #[derive(Debug, Clone)]
@@ -116,30 +134,59 @@ pub struct Device(pub Arc<Mutex<DeviceImpl>>);
 
 impl Device {
     pub fn open(
+        stack: &Stack,
         name: &str,
-        mut addr: MacAddr,
+        addr: MacAddr,
         raw_type: raw::Type,
-    ) -> Result<Device, Box<dyn Error>> {
-        let raw = raw::open(raw_type, name);
+    ) -> Result<Device, Error> {
+        let raw = raw::try_open(raw_type, name)?;
+        match Device::from_raw(stack, raw.clone(), addr) {
+            Ok(device) => Ok(device),
+            Err(err) => {
+                raw.close()?;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn from_raw(
+        stack: &Stack,
+        raw: Arc<dyn raw::RawDevice + Sync + Send>,
+        mut addr: MacAddr,
+    ) -> Result<Device, Error> {
+        if !raw.type_().is_ethernet() {
+            return Err(Error::Invalid(format!(
+                "`{}` does not carry ethernet frames",
+                raw.name()
+            )));
+        }
         if addr == ADDR_ANY {
-            addr = { raw.addr()? };
+            addr = raw.addr()?;
         }
         let device = Device(Arc::new(Mutex::new(DeviceImpl {
             interface: None,
-            name: name.to_string(),
-            raw: raw,
-            addr: addr,
-            broadcast_addr: ADDR_BROADCAST.clone(),
+            name: raw.name().clone(),
+            raw,
+            addr,
+            broadcast_addr: ADDR_BROADCAST,
+            mtu: PAYLOAD_SIZE_MAX,
+            flags: DeviceFlags::BROADCAST,
             terminate: false,
+            bridge: None,
+            stats: stats::Device::default(),
+            stack: stack.downgrade(),
         })));
-        let mut devices = DEVICES.lock().unwrap();
-        devices.push(device.clone());
+        link::register(stack, Arc::new(device.clone()));
         Ok(device)
     }
 
-    pub fn close(self) -> Result<(), Box<dyn Error>> {
-        let name = { self.0.lock().unwrap().name.clone() };
-        if let Some(handle) = JOIN_HANDLES.lock().unwrap().remove(&name) {
+    pub fn close(self) -> Result<(), Error> {
+        let (name, stack) = {
+            let inner = self.0.lock().unwrap();
+            (inner.name.clone(), inner.stack.get())
+        };
+        let handle = stack.0.join_handles.lock().unwrap().remove(&name);
+        if let Some(handle) = handle {
             {
                 self.0.lock().unwrap().terminate = true;
             }
@@ -151,68 +198,117 @@ impl Device {
 
     pub fn add_interface(&mut self, interface: ip::interface::Interface) {
         let mut inner = self.0.lock().unwrap();
-        inner.interface = Some(interface);
+        inner.interface = Some(interface.downgrade());
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    /// receives on a thread of the device's own; an `event_loop::EventLoop` can drive
+    /// several devices from one thread instead.
+    pub fn run(&mut self) -> Result<(), Error> {
         let device = self.clone();
         let name = device.0.lock().unwrap().name.clone();
         let join_handle = thread::spawn(move || loop {
             let device_ = device.clone();
-            let (terminate, raw) = {
+            let (terminate, raw, stack) = {
                 let device_inner = device.0.lock().unwrap();
-                (device_inner.terminate, device_inner.raw.clone())
+                (
+                    device_inner.terminate,
+                    device_inner.raw.clone(),
+                    device_inner.stack.clone(),
+                )
             };
-            if terminate {
+            if terminate || stack.upgrade().is_none_or(|stack| stack.is_shut_down()) {
                 break;
             }
-            let tx_handle = raw.rx(Box::new(move |buf: Buffer| device_.rx(buf)), 1000);
-            match tx_handle {
-                Ok(Some(tx_join_handle)) => {
-                    tx_join_handle.join().unwrap();
-                }
-                Ok(None) => {}
-                Err(err) => {
-                    eprintln!("err : {}", err);
-                }
+            // each rejected frame is counted in `stats` too
+            if let Err(err) = raw.rx(Box::new(move |buf: Buffer| device_.rx(buf)), 1000) {
+                log_rx_error!(device.name(), err);
             }
         });
-        JOIN_HANDLES.lock().unwrap().insert(name, join_handle);
+        let stack = self.stack();
+        stack.0.join_handles.lock().unwrap().insert(name, join_handle);
         Ok(())
     }
 
-    pub fn tx(
+    /// sends a frame carrying `tags`, outermost first, between the addresses and the type.
+    pub fn tx_tagged(
         &self,
         type_: Type,
         payload: Buffer,
         dst_addr: MacAddr,
-    ) -> Result<(), Box<dyn Error>> {
-        let device_inner = self.0.lock().unwrap();
-        let src_addr = device_inner.addr.clone();
+        tags: Vec<vlan::Tag>,
+    ) -> Result<(), Error> {
+        let mut device_inner = self.0.lock().unwrap();
+        let src_addr = device_inner.addr;
         let frame = frame::Frame {
-            dst_addr: dst_addr,
-            src_addr: src_addr,
-            type_: type_,
-            payload: payload,
+            dst_addr,
+            src_addr,
+            tags,
+            type_,
+            payload,
         };
-        if cfg!(debug_assertions) {
-            eprintln!(">>> ethernet tx <<<");
-            frame.dump();
-        }
+        log_packet!("tx", frame);
         use packet::Packet;
-        device_inner.raw.tx(frame.to_buffer())
+        let buf = frame.to_buffer();
+        let len = buf.0.len() as u64;
+        let ret = device_inner.raw.tx(buf);
+        if ret.is_ok() {
+            device_inner.stats.tx_frames += 1;
+            device_inner.stats.tx_bytes += len;
+        } else {
+            device_inner.stats.tx_errors += 1;
+        }
+        ret
     }
 
-    pub fn rx(&self, buffer: Buffer) -> Result<Option<thread::JoinHandle<()>>, Box<dyn Error>> {
+    pub fn rx(&self, buffer: Buffer) -> Result<(), Error> {
         use packet::Packet;
-        let frame = frame::Frame::from_buffer(buffer)?;
-
-        if cfg!(debug_assertions) {
-            eprintln!(">>> ethernet rx <<<");
-            frame.dump();
+        let (bridge, stack) = {
+            let mut inner = self.0.lock().unwrap();
+            inner.stats.rx_frames += 1;
+            inner.stats.rx_bytes += buffer.0.len() as u64;
+            (
+                inner.bridge.as_ref().and_then(bridge::WeakBridge::upgrade),
+                inner.stack.upgrade(),
+            )
+        };
+        // frames still read after the stack was dropped
+        let stack = stack.ok_or(Error::Dropped("ethernet: stack is gone"))?;
+        if let Some(bridge) = bridge {
+            return bridge.input(self, buffer);
         }
+        let type_ = frame::peek_type(&buffer);
+        let frame = match frame::Frame::parse(buffer) {
+            Ok(frame) => frame,
+            Err(err) => {
+                let mut inner = self.0.lock().unwrap();
+                match type_ {
+                    Some(n) if n > llc::LENGTH_MAX && Type::from_u16(n).is_none() => {
+                        inner.stats.rx_drops += 1;
+                        stats::dropped(&stack, "ethernet: unknown type");
+                        return Err(Error::Dropped("ethernet: unknown type"));
+                    }
+                    _ => {
+                        inner.stats.rx_errors += 1;
+                        stats::dropped(&stack, "ethernet: malformed frame");
+                    }
+                }
+                return Err(err);
+            }
+        };
+
+        log_packet!("rx", frame);
         let type_ = frame.type_;
         let payload = frame.payload;
+        if !frame.tags.is_empty() {
+            return match vlan::lookup(self, &frame.tags) {
+                Some(device) => device.rx(type_, payload),
+                None => {
+                    self.0.lock().unwrap().stats.rx_drops += 1;
+                    stats::dropped(&stack, "ethernet: no such vlan");
+                    Err(Error::Dropped("ethernet: no such vlan"))
+                }
+            };
+        }
         self.rx_handler(type_, payload)
     }
 
@@ -220,16 +316,83 @@ impl Device {
         &self,
         type_: Type,
         payload: Buffer,
-    ) -> Result<Option<thread::JoinHandle<()>>, Box<dyn Error>> {
+    ) -> Result<(), Error> {
         match type_ {
             Type::Arp => arp::rx(payload, self),
             Type::Ip => ip::rx(payload, self),
+            Type::Lldp => lldp::rx(payload, self),
+            // nothing but a bridge consumes LLC
+            Type::Llc => {
+                self.0.lock().unwrap().stats.rx_drops += 1;
+                stats::dropped(&self.stack(), "ethernet: unhandled llc");
+                Ok(())
+            }
         }
     }
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        // TODO: remove the same device from `DEVICES`
+impl LinkDevice for Device {
+    fn name(&self) -> String {
+        self.0.lock().unwrap().name.clone()
+    }
+    fn flags(&self) -> DeviceFlags {
+        self.0.lock().unwrap().flags
+    }
+    fn mtu(&self) -> usize {
+        self.0.lock().unwrap().mtu
+    }
+    fn header_len(&self) -> usize {
+        HDR_SIZE
+    }
+    fn resolution(&self) -> Resolution {
+        if self.flags().contains(DeviceFlags::NOARP) {
+            Resolution::None
+        } else {
+            Resolution::Arp
+        }
+    }
+    fn addr(&self) -> MacAddr {
+        self.0.lock().unwrap().addr
+    }
+    fn broadcast_addr(&self) -> MacAddr {
+        self.0.lock().unwrap().broadcast_addr
+    }
+    fn interface(&self) -> Option<ip::interface::Interface> {
+        let interface = self.0.lock().unwrap().interface.clone();
+        interface.and_then(|interface| interface.upgrade())
+    }
+    fn stats(&self) -> Option<stats::Device> {
+        Some(self.0.lock().unwrap().stats)
+    }
+    fn stack(&self) -> Stack {
+        self.0.lock().unwrap().stack.get()
+    }
+    fn stop(&self) {
+        self.0.lock().unwrap().terminate = true;
+    }
+    fn fd(&self) -> Option<RawFd> {
+        self.0.lock().unwrap().raw.fd()
+    }
+    fn receive(&self) -> Result<(), Error> {
+        let raw = self.0.lock().unwrap().raw.clone();
+        let device = self.clone();
+        // the descriptor is readable, so this does not block
+        raw.rx(Box::new(move |buf| device.rx(buf)), 0)
+    }
+    fn is_running(&self) -> bool {
+        let name = self.name();
+        let stack = self.stack();
+        let join_handles = stack.0.join_handles.lock().unwrap();
+        join_handles.contains_key(&name)
+    }
+
+    fn tx(
+        &self,
+        type_: Type,
+        payload: Buffer,
+        dst_addr: MacAddr,
+    ) -> Result<(), Error> {
+        self.tx_tagged(type_, payload, dst_addr, vec![])
     }
 }
+
@@ -0,0 +1,560 @@
+// a learning bridge joining several ethernet devices into one segment
+
+use std::sync::{Arc, Mutex, Weak};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    arp,
+    buffer::Buffer,
+    error::{Error, Layer},
+    ethernet::{self, frame, llc, lldp, stp, MacAddr},
+    ip,
+    link::{self, DeviceFlags, LinkDevice, Resolution},
+    packet::Packet,
+    stack::{Stack, WeakStack},
+    stats,
+    timer::{self, TimerId},
+};
+
+// how long a learned address is kept without seeing it again (in seconds)
+pub const AGEING_TIME: i64 = 300;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PortStats {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    // frames sent out of this port because their destination was not known
+    pub flooded: u64,
+    // frames received whose destination lives on the same port
+    pub filtered: u64,
+    pub tx_errors: u64,
+}
+
+#[derive(Debug)]
+struct Port {
+    device: ethernet::Device,
+    stats: PortStats,
+}
+
+#[derive(Debug)]
+struct FdbEntry {
+    mac_addr: MacAddr,
+    port: usize,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FdbInfo {
+    pub mac_addr: MacAddr,
+    pub port: String,
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+pub struct BridgeImpl {
+    pub interface: Option<ip::interface::WeakInterface>,
+    pub name: String,
+    pub addr: MacAddr,
+    pub ageing_time: Duration,
+    pub stp: stp::Stp,
+    // ticks STP every second while it is enabled
+    stp_timer: Option<TimerId>,
+    ports: Vec<Port>,
+    fdb: Vec<FdbEntry>,
+    pub stack: WeakStack,
+}
+
+#[derive(Debug, Clone)]
+pub struct Bridge(pub Arc<Mutex<BridgeImpl>>);
+
+/// a bridge as its ports refer to it, which does not keep it alive.
+#[derive(Debug, Clone)]
+pub struct WeakBridge(Weak<Mutex<BridgeImpl>>);
+
+impl WeakBridge {
+    pub fn upgrade(&self) -> Option<Bridge> {
+        self.0.upgrade().map(Bridge)
+    }
+}
+
+impl BridgeImpl {
+    fn port_of(&self, device: &ethernet::Device) -> Option<usize> {
+        self.ports
+            .iter()
+            .position(|port| Arc::ptr_eq(&port.device.0, &device.0))
+    }
+
+    // entries age out quickly while the spanning tree is changing
+    fn effective_ageing_time(&self) -> Duration {
+        if self.stp.enabled && self.stp.topology_change {
+            Duration::seconds(self.stp.forward_delay as i64)
+        } else {
+            self.ageing_time
+        }
+    }
+
+    fn learn(&mut self, mac_addr: MacAddr, port: usize) {
+        let now = self.stack.get().now();
+        let ageing_time = self.effective_ageing_time();
+        self.fdb.retain(|entry| now - entry.timestamp <= ageing_time);
+        match self.fdb.iter_mut().find(|entry| entry.mac_addr == mac_addr) {
+            Some(entry) => {
+                entry.port = port;
+                entry.timestamp = now;
+            }
+            None => self.fdb.push(FdbEntry {
+                mac_addr,
+                port,
+                timestamp: now,
+            }),
+        }
+    }
+
+    fn lookup(&self, mac_addr: &MacAddr) -> Option<usize> {
+        if mac_addr.is_multicast() {
+            return None;
+        }
+        let now = self.stack.get().now();
+        let ageing_time = self.effective_ageing_time();
+        self.fdb
+            .iter()
+            .find(|entry| &entry.mac_addr == mac_addr && now - entry.timestamp <= ageing_time)
+            .map(|entry| entry.port)
+    }
+
+    fn send(&mut self, port: usize, buf: &Buffer, flooded: bool) -> Result<(), Error> {
+        let raw = self.ports[port].device.0.lock().unwrap().raw.clone();
+        let stats = &mut self.ports[port].stats;
+        stats.tx_frames += 1;
+        stats.tx_bytes += buf.0.len() as u64;
+        if flooded {
+            stats.flooded += 1;
+        }
+        let result = raw.tx(buf.clone());
+        if result.is_err() {
+            self.ports[port].stats.tx_errors += 1;
+        }
+        result
+    }
+
+    /// sends `buf` to the port owning `dst`, or to every port but `except` when it is unknown.
+    fn forward(
+        &mut self,
+        buf: &Buffer,
+        dst: &MacAddr,
+        except: Option<usize>,
+    ) -> Result<(), Error> {
+        match self.lookup(dst) {
+            Some(port) if Some(port) == except => {
+                self.ports[port].stats.filtered += 1;
+                Ok(())
+            }
+            Some(port) if !self.stp.is_forwarding(port) => Ok(()),
+            Some(port) => self.send(port, buf, false),
+            None => {
+                for port in 0..self.ports.len() {
+                    // a failing port must not keep the frame from the others
+                    if Some(port) != except && self.stp.is_forwarding(port) {
+                        if let Err(err) = self.send(port, buf, true) {
+                            let name = self.ports[port].device.name();
+                            debug!("{}: flooding failed: {}", name, err);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn transmit_bpdus(&mut self, out: stp::Output) {
+        for (port, bpdu) in out {
+            log_packet!(format!("bpdu tx on {}", self.ports[port].device.name()), bpdu);
+            let pdu = llc::Pdu {
+                dsap: llc::SAP_STP,
+                ssap: llc::SAP_STP,
+                control: llc::CONTROL_UI,
+                payload: bpdu.to_buffer(),
+            };
+            let frame = frame::Frame {
+                dst_addr: stp::GROUP_ADDR,
+                src_addr: self.ports[port].device.addr(),
+                tags: vec![],
+                type_: ethernet::Type::Llc,
+                payload: pdu.to_buffer(),
+            };
+            if let Err(err) = self.send(port, &frame.to_buffer(), false) {
+                warn!("{}: sending bpdu failed: {}", self.ports[port].device.name(), err);
+            }
+        }
+        // forget what was learned through ports that no longer pass traffic, or behind
+        // which the topology changed
+        let flushes = self.stp.take_flushes();
+        let stp = &self.stp;
+        self.fdb
+            .retain(|entry| stp.is_learning(entry.port) && !flushes.contains(&entry.port));
+    }
+
+    fn port_idx(&self, device: &ethernet::Device) -> Result<usize, Error> {
+        self.port_of(device).ok_or(Error::NotFound(format!(
+            "`{}` as a port of `{}`",
+            device.name(),
+            self.name
+        )))
+    }
+}
+
+// 01:80:C2:00:00:00 to 0F, which 802.1D bridges never forward; STP's own address is only
+// passed on while STP is disabled
+fn is_reserved(addr: &MacAddr) -> bool {
+    addr.0[..5] == stp::GROUP_ADDR.0[..5] && addr.0[5] & 0xf0 == 0 && addr != &stp::GROUP_ADDR
+}
+
+impl Bridge {
+    /// creates a bridge; with `ADDR_ANY` it takes the address of its first port.
+    pub fn new(stack: &Stack, name: &str, addr: MacAddr) -> Bridge {
+        let bridge = Bridge(Arc::new(Mutex::new(BridgeImpl {
+            interface: None,
+            name: name.to_string(),
+            addr,
+            ageing_time: Duration::seconds(AGEING_TIME),
+            stp: stp::Stp::new(addr),
+            stp_timer: None,
+            ports: vec![],
+            fdb: vec![],
+            stack: stack.downgrade(),
+        })));
+        link::register(stack, Arc::new(bridge.clone()));
+        bridge
+    }
+
+    pub fn add_port(&self, device: &ethernet::Device) -> Result<(), Error> {
+        if device.stack() != self.stack() {
+            return Err(Error::Invalid(format!(
+                "`{}` belongs to another stack",
+                device.name()
+            )));
+        }
+        let mut inner = self.0.lock().unwrap();
+        if inner.ports.len() >= stp::PORT_MAX {
+            return Err(Error::Invalid(format!(
+                "`{}` has {} ports already",
+                inner.name,
+                stp::PORT_MAX
+            )));
+        }
+        {
+            let mut device_inner = device.0.lock().unwrap();
+            if device_inner.bridge.is_some() {
+                return Err(Error::Exists(format!(
+                    "bridge of `{}`",
+                    device_inner.name
+                )));
+            }
+            device_inner.bridge = Some(WeakBridge(Arc::downgrade(&self.0)));
+        }
+        if inner.addr == ethernet::ADDR_ANY {
+            inner.addr = device.addr();
+            let priority = inner.stp.bridge_id.priority();
+            inner.stp.bridge_id = stp::BridgeId::new(priority, inner.addr);
+            inner.stp.root_id = inner.stp.bridge_id;
+        }
+        inner.ports.push(Port {
+            device: device.clone(),
+            stats: PortStats::default(),
+        });
+        let out = inner.stp.add_port();
+        inner.transmit_bpdus(out);
+        Ok(())
+    }
+
+    pub fn remove_port(&self, device: &ethernet::Device) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        let idx = inner.port_idx(device)?;
+        inner.ports.remove(idx);
+        // entries behind later ports shift down with them
+        inner.fdb.retain(|entry| entry.port != idx);
+        for entry in inner.fdb.iter_mut() {
+            if entry.port > idx {
+                entry.port -= 1;
+            }
+        }
+        let out = inner.stp.remove_port(idx);
+        inner.transmit_bpdus(out);
+        device.0.lock().unwrap().bridge = None;
+        Ok(())
+    }
+
+    /// runs the spanning tree protocol on all ports, which start out blocking; RSTP unless
+    /// `set_stp_version` says otherwise.
+    pub fn enable_stp(&self) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.stp.enabled {
+            return;
+        }
+        let out = inner.stp.enable();
+        inner.transmit_bpdus(out);
+        if inner.stp_timer.is_none() {
+            let bridge = self.clone();
+            let stack = inner.stack.get();
+            let tick = move |_: &Stack| {
+                let mut inner = bridge.0.lock().unwrap();
+                let out = inner.stp.tick();
+                inner.transmit_bpdus(out);
+            };
+            let interval = Duration::seconds(1);
+            let timer = timer::add_periodic(&stack, "bridge: stp", interval, tick)
+                .expect("a second is positive");
+            inner.stp_timer = Some(timer);
+        }
+    }
+
+    /// stops the spanning tree protocol; every port forwards again.
+    pub fn disable_stp(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.stp.disable();
+        if let Some(id) = inner.stp_timer.take() {
+            timer::cancel(&inner.stack.get(), id);
+        }
+    }
+
+    pub fn set_priority(&self, priority: u16) {
+        let mut inner = self.0.lock().unwrap();
+        let out = inner.stp.set_bridge_priority(priority);
+        inner.transmit_bpdus(out);
+    }
+
+    pub fn set_port_cost(&self, device: &ethernet::Device, cost: u32) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        let idx = inner.port_idx(device)?;
+        let out = inner.stp.set_path_cost(idx, cost);
+        inner.transmit_bpdus(out);
+        Ok(())
+    }
+
+    pub fn set_port_priority(
+        &self,
+        device: &ethernet::Device,
+        priority: u8,
+    ) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        let idx = inner.port_idx(device)?;
+        let out = inner.stp.set_port_priority(idx, priority);
+        inner.transmit_bpdus(out);
+        Ok(())
+    }
+
+    pub fn set_stp_version(&self, version: stp::Version) {
+        let mut inner = self.0.lock().unwrap();
+        let out = inner.stp.set_version(version);
+        inner.transmit_bpdus(out);
+    }
+
+    /// marks a port as leading to hosts only, so that RSTP forwards on it at once.
+    pub fn set_port_edge(&self, device: &ethernet::Device, edge: bool) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        let idx = inner.port_idx(device)?;
+        let out = inner.stp.set_edge(idx, edge);
+        inner.transmit_bpdus(out);
+        Ok(())
+    }
+
+    /// ports are taken to be point-to-point links; a shared segment must be marked so.
+    pub fn set_port_point_to_point(
+        &self,
+        device: &ethernet::Device,
+        point_to_point: bool,
+    ) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        let idx = inner.port_idx(device)?;
+        let out = inner.stp.set_point_to_point(idx, point_to_point);
+        inner.transmit_bpdus(out);
+        Ok(())
+    }
+
+    pub fn root_id(&self) -> stp::BridgeId {
+        self.0.lock().unwrap().stp.root_id
+    }
+
+    /// returns the spanning tree role and state of each port.
+    pub fn stp_ports(&self) -> Vec<(String, stp::PortInfo)> {
+        let inner = self.0.lock().unwrap();
+        (0..inner.ports.len())
+            .map(|idx| (inner.ports[idx].device.name(), inner.stp.port_info(idx)))
+            .collect()
+    }
+
+    pub fn add_interface(&mut self, interface: ip::interface::Interface) {
+        self.0.lock().unwrap().interface = Some(interface.downgrade());
+    }
+
+    pub fn set_ageing_time(&self, ageing_time: Duration) {
+        self.0.lock().unwrap().ageing_time = ageing_time;
+    }
+
+    /// returns a snapshot of the learned addresses.
+    pub fn fdb(&self) -> Vec<FdbInfo> {
+        let inner = self.0.lock().unwrap();
+        let now = inner.stack.get().now();
+        inner
+            .fdb
+            .iter()
+            .filter(|entry| now - entry.timestamp <= inner.ageing_time)
+            .map(|entry| FdbInfo {
+                mac_addr: entry.mac_addr,
+                port: inner.ports[entry.port].device.name(),
+                age: now - entry.timestamp,
+            })
+            .collect()
+    }
+
+    pub fn port_stats(&self) -> Vec<(String, PortStats)> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .ports
+            .iter()
+            .map(|port| (port.device.name(), port.stats))
+            .collect()
+    }
+
+    /// handles a frame received on one of the ports.
+    pub fn input(
+        &self,
+        device: &ethernet::Device,
+        buf: Buffer,
+    ) -> Result<(), Error> {
+        if buf.0.len() < ethernet::HDR_SIZE {
+            return Err(Error::parse(
+                "frame",
+                format!("too short: {} bytes", buf.0.len()),
+            )
+            .in_layer(Layer::Ethernet));
+        }
+        let mut dst = ethernet::ADDR_ANY;
+        let mut src = ethernet::ADDR_ANY;
+        for i in 0..ethernet::ADDR_LEN {
+            dst.0[i] = buf.0[i];
+            src.0[i] = buf.0[ethernet::ADDR_LEN + i];
+        }
+        let is_local = {
+            let mut inner = self.0.lock().unwrap();
+            let port = match inner.port_of(device) {
+                Some(port) => port,
+                None => return Ok(()),
+            };
+            inner.ports[port].stats.rx_frames += 1;
+            inner.ports[port].stats.rx_bytes += buf.0.len() as u64;
+            if dst == stp::GROUP_ADDR && inner.stp.enabled {
+                // BPDUs are consumed, never forwarded
+                let frame = frame::Frame::parse(buf)?;
+                let pdu = llc::Pdu::parse(frame.payload)?;
+                if pdu.dsap != llc::SAP_STP {
+                    return Ok(());
+                }
+                let bpdu = stp::Bpdu::parse(pdu.payload)?;
+                log_packet!(format!("bpdu rx on {}", device.name()), bpdu);
+                let out = inner.stp.received(port, bpdu);
+                inner.transmit_bpdus(out);
+                return Ok(());
+            }
+            if dst == lldp::ADDR_NEAREST_BRIDGE {
+                // link-local, so the port itself is the LLDP agent
+                drop(inner);
+                let frame = frame::Frame::parse(buf)?;
+                return match frame.type_ {
+                    ethernet::Type::Lldp => lldp::rx(frame.payload, device),
+                    _ => Ok(()),
+                };
+            }
+            if is_reserved(&dst) {
+                // link-local control protocols this bridge does not speak
+                drop(inner);
+                stats::dropped(&self.stack(), "bridge: reserved group address");
+                return Ok(());
+            }
+            if !inner.stp.is_learning(port) {
+                return Ok(());
+            }
+            if !src.is_multicast() {
+                inner.learn(src, port);
+            }
+            if !inner.stp.is_forwarding(port) {
+                return Ok(());
+            }
+            if dst == inner.addr {
+                true
+            } else {
+                inner.forward(&buf, &dst, Some(port))?;
+                dst.is_multicast()
+            }
+        };
+        if !is_local || self.interface().is_none() {
+            return Ok(());
+        }
+        // the bridge's own interface sees untagged frames only
+        let frame = match frame::Frame::parse(buf) {
+            Ok(frame) => frame,
+            // flooded traffic of protocols the stack does not speak
+            Err(_) if dst.is_multicast() => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if !frame.tags.is_empty() {
+            return Ok(());
+        }
+        match frame.type_ {
+            ethernet::Type::Arp => arp::rx(frame.payload, self),
+            ethernet::Type::Ip => ip::rx(frame.payload, self),
+            ethernet::Type::Lldp | ethernet::Type::Llc => Ok(()),
+        }
+    }
+}
+
+impl LinkDevice for Bridge {
+    fn name(&self) -> String {
+        self.0.lock().unwrap().name.clone()
+    }
+    fn flags(&self) -> DeviceFlags {
+        DeviceFlags::BROADCAST
+    }
+    fn mtu(&self) -> usize {
+        ethernet::PAYLOAD_SIZE_MAX
+    }
+    fn header_len(&self) -> usize {
+        ethernet::HDR_SIZE
+    }
+    fn resolution(&self) -> Resolution {
+        Resolution::Arp
+    }
+    fn addr(&self) -> MacAddr {
+        self.0.lock().unwrap().addr
+    }
+    fn broadcast_addr(&self) -> MacAddr {
+        ethernet::ADDR_BROADCAST
+    }
+    fn interface(&self) -> Option<ip::interface::Interface> {
+        let interface = self.0.lock().unwrap().interface.clone();
+        interface.and_then(|interface| interface.upgrade())
+    }
+    fn stack(&self) -> Stack {
+        self.0.lock().unwrap().stack.get()
+    }
+    fn tx(
+        &self,
+        type_: ethernet::Type,
+        payload: Buffer,
+        dst: MacAddr,
+    ) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        let frame = frame::Frame {
+            dst_addr: dst,
+            src_addr: inner.addr,
+            tags: vec![],
+            type_,
+            payload,
+        };
+        log_packet!("tx", frame);
+        inner.forward(&frame.to_buffer(), &dst, None)
+    }
+}
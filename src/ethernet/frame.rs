@@ -1,33 +1,91 @@
-use crate::{buffer::Buffer, ethernet, packet::Packet, util::RuntimeError};
-use std::error::Error;
+use crate::{
+    buffer::Buffer,
+    error::{Error, Layer},
+    ethernet::{self, llc, vlan},
+    packet::Packet,
+};
 
 pub struct Frame {
     pub dst_addr: ethernet::MacAddr,
     pub src_addr: ethernet::MacAddr,
+    // outermost first
+    pub tags: Vec<vlan::Tag>,
     pub type_: ethernet::Type,
     pub payload: Buffer,
 }
 
 impl Frame {
-    pub fn dump(&self) {
-        eprintln!("dst : {}", self.dst_addr);
-        eprintln!("src : {}", self.src_addr);
-        eprintln!("type: {}", self.type_);
-        eprintln!("{}", self.payload);
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "dst : {}", self.dst_addr);
+        let _ = writeln!(out, "src : {}", self.src_addr);
+        for tag in self.tags.iter() {
+            let _ = writeln!(out, "tag : {}", tag);
+        }
+        let _ = writeln!(out, "type: {}", self.type_);
+        let _ = writeln!(out, "{}", self.payload);
+        out
+    }
+}
+
+/// reads the type/length field past any tags, or `None` if `buf` is cut short.
+pub fn peek_type(buf: &Buffer) -> Option<u16> {
+    let mut offset = ethernet::ADDR_LEN * 2;
+    loop {
+        if buf.0.len() < offset + 2 {
+            return None;
+        }
+        let n = ((buf.0[offset] as u16) << 8) | buf.0[offset + 1] as u16;
+        if n != vlan::TPID_CTAG && n != vlan::TPID_STAG {
+            return Some(n);
+        }
+        offset += vlan::TAG_SIZE;
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} > {}", self.src_addr, self.dst_addr)?;
+        for tag in self.tags.iter() {
+            write!(f, " [{}]", tag)?;
+        }
+        write!(f, " {} {} bytes", self.type_, self.payload.0.len())
     }
 }
 
 impl Packet<Frame> for Frame {
-    fn from_buffer(mut buf: Buffer) -> Result<Self, Box<dyn Error>> {
+    const LAYER: Layer = Layer::Ethernet;
+
+    fn from_buffer(mut buf: Buffer) -> Result<Self, Error> {
         let dst_addr = buf.pop_mac_addr("dst addr")?;
         let src_addr = buf.pop_mac_addr("src addr")?;
-        let n = buf.pop_u16("type")?;
+        let mut tags = vec![];
+        let mut n = buf.pop_u16("type")?;
+        while n == vlan::TPID_CTAG || n == vlan::TPID_STAG {
+            let tci = buf.pop_u16("tci")?;
+            tags.push(vlan::Tag::from_tci(n, tci));
+            n = buf.pop_u16("type")?;
+        }
+        if n <= llc::LENGTH_MAX {
+            // an 802.3 frame; drop the padding after the LLC PDU
+            let payload = buf.pop_buffer(n as usize, "llc pdu")?;
+            return Ok(Frame {
+                dst_addr,
+                src_addr,
+                tags,
+                type_: ethernet::Type::Llc,
+                payload,
+            });
+        }
         let type_ = ethernet::Type::from_u16(n)
-            .ok_or(RuntimeError::new(format!("{} can not be EthernetType", n)))?;
+            .ok_or_else(|| Error::parse("type", format!("unknown: {:04x}", n)))?;
         Ok(Frame {
-            dst_addr: dst_addr,
-            src_addr: src_addr,
-            type_: type_,
+            dst_addr,
+            src_addr,
+            tags,
+            type_,
             payload: buf,
         })
     }
@@ -35,8 +93,113 @@ impl Packet<Frame> for Frame {
         let mut buf = Buffer::new(ethernet::FRAME_SIZE_MAX);
         buf.push_mac_addr(self.dst_addr);
         buf.push_mac_addr(self.src_addr);
-        buf.push_u16(self.type_ as u16);
+        for tag in self.tags.iter() {
+            buf.push_u16(tag.tpid);
+            buf.push_u16(tag.tci());
+        }
+        match self.type_ {
+            ethernet::Type::Llc => buf.push_u16(self.payload.0.len() as u16),
+            type_ => buf.push_u16(type_ as u16),
+        }
         buf.append(self.payload);
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DST: [u8; 6] = [0xff; 6];
+    const SRC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+
+    fn frame(fields: &[u16], payload: &[u8]) -> Buffer {
+        let mut bytes = DST.to_vec();
+        bytes.extend(SRC.iter());
+        for field in fields {
+            bytes.extend(field.to_be_bytes().iter());
+        }
+        bytes.extend(payload);
+        Buffer::from_vec(bytes)
+    }
+
+    #[test]
+    fn untagged() {
+        let parsed = Frame::parse(frame(&[ethernet::Type::Ip as u16], &[0x45, 0])).unwrap();
+        assert_eq!(parsed.dst_addr, ethernet::MacAddr(DST));
+        assert_eq!(parsed.src_addr, ethernet::MacAddr(SRC));
+        assert!(parsed.tags.is_empty());
+        assert_eq!(parsed.type_ as u16, ethernet::Type::Ip as u16);
+        assert_eq!(parsed.payload.to_vec(), vec![0x45, 0]);
+    }
+
+    #[test]
+    fn single_tag() {
+        // priority 5, vid 100
+        let buf = frame(&[vlan::TPID_CTAG, 0xa064, ethernet::Type::Arp as u16], &[]);
+        let parsed = Frame::parse(buf).unwrap();
+        assert_eq!(
+            parsed.tags,
+            vec![vlan::Tag {
+                tpid: vlan::TPID_CTAG,
+                pcp: 5,
+                dei: false,
+                vid: 100
+            }]
+        );
+        assert_eq!(parsed.type_ as u16, ethernet::Type::Arp as u16);
+    }
+
+    #[test]
+    fn stacked_tags_outermost_first() {
+        let fields = [
+            vlan::TPID_STAG,
+            10,
+            vlan::TPID_CTAG,
+            0x1000 | 20,
+            ethernet::Type::Ip as u16,
+        ];
+        let buf = frame(&fields, &[]);
+        assert_eq!(peek_type(&buf), Some(ethernet::Type::Ip as u16));
+        let parsed = Frame::parse(buf).unwrap();
+        let vids: Vec<u16> = parsed.tags.iter().map(|tag| tag.vid).collect();
+        assert_eq!(vids, vec![10, 20]);
+        assert!(parsed.tags[1].dei);
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let buf = frame(
+            &[vlan::TPID_CTAG, 0x2007, ethernet::Type::Ip as u16],
+            &[1, 2],
+        );
+        let bytes = buf.clone().to_vec();
+        assert_eq!(Frame::parse(buf).unwrap().to_buffer().to_vec(), bytes);
+    }
+
+    #[test]
+    fn llc_drops_padding() {
+        let parsed = Frame::parse(frame(&[3], &[0x42, 0x42, 0x03, 0, 0, 0])).unwrap();
+        assert_eq!(parsed.type_ as u16, ethernet::Type::Llc as u16);
+        assert_eq!(parsed.payload.to_vec(), vec![0x42, 0x42, 0x03]);
+    }
+
+    #[test]
+    fn llc_longer_than_the_frame_is_an_error() {
+        assert!(Frame::parse(frame(&[10], &[0x42, 0x42, 0x03])).is_err());
+    }
+
+    #[test]
+    fn truncated_is_an_error() {
+        assert!(Frame::parse(Buffer::from_vec(DST.to_vec())).is_err());
+        // a tag without the type after it
+        let buf = frame(&[vlan::TPID_CTAG, 100], &[]);
+        assert_eq!(peek_type(&buf), None);
+        assert!(Frame::parse(buf).is_err());
+    }
+
+    #[test]
+    fn unknown_type_is_an_error() {
+        assert!(Frame::parse(frame(&[0x86dd], &[])).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+// IEEE 802.2 LLC, carried by 802.3 frames whose type field holds a length
+
+use crate::{
+    buffer::Buffer,
+    error::{Error, Layer},
+    packet::Packet,
+};
+
+// type/length values up to this are lengths, larger ones are ethertypes
+pub const LENGTH_MAX: u16 = 1500;
+pub const HDR_SIZE: usize = 3;
+
+pub const SAP_STP: u8 = 0x42;
+pub const CONTROL_UI: u8 = 0x03;
+
+#[derive(Debug)]
+pub struct Pdu {
+    pub dsap: u8,
+    pub ssap: u8,
+    pub control: u8,
+    pub payload: Buffer,
+}
+
+impl Pdu {
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "dsap   : {:02x}", self.dsap);
+        let _ = writeln!(out, "ssap   : {:02x}", self.ssap);
+        let _ = writeln!(out, "control: {:02x}", self.control);
+        let _ = writeln!(out, "{}", self.payload);
+        out
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Pdu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x} > {:02x} control {:02x} {} bytes",
+            self.ssap,
+            self.dsap,
+            self.control,
+            self.payload.0.len()
+        )
+    }
+}
+
+impl Packet<Pdu> for Pdu {
+    const LAYER: Layer = Layer::Llc;
+
+    fn from_buffer(mut buf: Buffer) -> Result<Self, Error> {
+        let dsap = buf.pop_u8("dsap")?;
+        let ssap = buf.pop_u8("ssap")?;
+        let control = buf.pop_u8("control")?;
+        // only the unnumbered format (type 1 operation) has a one-byte control field
+        if control & 0x03 != 0x03 {
+            return Err(Error::parse(
+                "control",
+                format!("unsupported: {:02x}", control),
+            ));
+        }
+        Ok(Pdu {
+            dsap,
+            ssap,
+            control,
+            payload: buf,
+        })
+    }
+    fn to_buffer(self) -> Buffer {
+        let mut buf = Buffer::new(HDR_SIZE + self.payload.0.len());
+        buf.push_u8(self.dsap);
+        buf.push_u8(self.ssap);
+        buf.push_u8(self.control);
+        buf.append(self.payload);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let pdu = Pdu {
+            dsap: SAP_STP,
+            ssap: SAP_STP,
+            control: CONTROL_UI,
+            payload: Buffer::from_vec(vec![1, 2, 3]),
+        };
+        let parsed = Pdu::parse(pdu.to_buffer()).unwrap();
+        assert_eq!(
+            (parsed.dsap, parsed.ssap, parsed.control),
+            (SAP_STP, SAP_STP, CONTROL_UI)
+        );
+        assert_eq!(parsed.payload.to_vec(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn numbered_formats_are_rejected() {
+        // an I-format and an S-format control field
+        for control in [0x00, 0x01].iter() {
+            let buf = Buffer::from_vec(vec![SAP_STP, SAP_STP, *control, 0]);
+            assert!(Pdu::parse(buf).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_is_an_error() {
+        assert!(Pdu::parse(Buffer::from_vec(vec![SAP_STP, SAP_STP])).is_err());
+    }
+}
@@ -0,0 +1,466 @@
+// IEEE 802.1AB link layer discovery protocol
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    buffer::Buffer,
+    error::{Error, Layer},
+    ethernet::{self, MacAddr},
+    ip,
+    link::LinkDevice,
+    packet::Packet,
+    stack::Stack,
+    timer::{self, TimerId},
+};
+
+// frames sent here are never forwarded by bridges
+pub const ADDR_NEAREST_BRIDGE: MacAddr = MacAddr([0x01, 0x80, 0xc2, 0x00, 0x00, 0x0e]);
+
+// all in seconds
+pub const TX_INTERVAL: u64 = 30;
+pub const TX_HOLD: u16 = 4;
+
+// the 9-bit length of a TLV, and the longest system name it may carry
+const TLV_VALUE_MAX: usize = 0x01ff;
+pub const SYSTEM_NAME_MAX: usize = 255;
+
+const TLV_END: u8 = 0;
+const TLV_CHASSIS_ID: u8 = 1;
+const TLV_PORT_ID: u8 = 2;
+const TLV_TTL: u8 = 3;
+const TLV_SYSTEM_NAME: u8 = 5;
+const TLV_MANAGEMENT_ADDR: u8 = 8;
+
+pub const CHASSIS_ID_MAC_ADDR: u8 = 4;
+pub const PORT_ID_INTERFACE_NAME: u8 = 5;
+const ADDR_FAMILY_IPV4: u8 = 1;
+const IF_NUMBERING_UNKNOWN: u8 = 1;
+
+/// a chassis or port ID, interpreted by its subtype
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Id {
+    pub subtype: u8,
+    pub value: Vec<u8>,
+}
+
+use std::fmt;
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.subtype == CHASSIS_ID_MAC_ADDR && self.value.len() == ethernet::ADDR_LEN {
+            let mut addr = ethernet::ADDR_ANY;
+            addr.0.copy_from_slice(&self.value);
+            write!(f, "{}", addr)
+        } else {
+            write!(f, "{}", String::from_utf8_lossy(&self.value))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lldpdu {
+    pub chassis_id: Id,
+    pub port_id: Id,
+    pub ttl: u16,
+    pub system_name: Option<String>,
+    pub management_addr: Option<ip::Addr>,
+}
+
+impl Lldpdu {
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "chassis: {}", self.chassis_id);
+        let _ = writeln!(out, "port   : {}", self.port_id);
+        let _ = writeln!(out, "ttl    : {}", self.ttl);
+        if let Some(system_name) = &self.system_name {
+            let _ = writeln!(out, "name   : {}", system_name);
+        }
+        if let Some(addr) = &self.management_addr {
+            let _ = writeln!(out, "addr   : {}", addr);
+        }
+        out
+    }
+}
+
+impl fmt::Display for Lldpdu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "chassis {} port {} ttl {}",
+            self.chassis_id, self.port_id, self.ttl
+        )
+    }
+}
+
+// a value too long for the length field is cut to fit it, so that the TLVs after it are
+// still found where their headers say
+fn push_tlv(buf: &mut Buffer, type_: u8, mut value: Vec<u8>) {
+    value.truncate(TLV_VALUE_MAX);
+    buf.push_u16(((type_ as u16) << 9) | value.len() as u16);
+    buf.append(Buffer::from_vec(value));
+}
+
+impl Packet<Lldpdu> for Lldpdu {
+    const LAYER: Layer = Layer::Lldp;
+
+    fn from_buffer(mut buf: Buffer) -> Result<Self, Error> {
+        let mut chassis_id = None;
+        let mut port_id = None;
+        let mut ttl = None;
+        let mut system_name = None;
+        let mut management_addr = None;
+        loop {
+            let header = buf.pop_u16("tlv header")?;
+            let type_ = (header >> 9) as u8;
+            let len = (header & 0x01ff) as usize;
+            let value = buf.pop_buffer(len, "tlv value")?.to_vec();
+            match type_ {
+                TLV_END => break,
+                TLV_CHASSIS_ID | TLV_PORT_ID if len >= 2 => {
+                    let id = Id {
+                        subtype: value[0],
+                        value: value[1..].to_vec(),
+                    };
+                    if type_ == TLV_CHASSIS_ID {
+                        chassis_id = Some(id);
+                    } else {
+                        port_id = Some(id);
+                    }
+                }
+                TLV_TTL if len >= 2 => ttl = Some(((value[0] as u16) << 8) | value[1] as u16),
+                TLV_SYSTEM_NAME => {
+                    system_name = Some(String::from_utf8_lossy(&value).into_owned())
+                }
+                // the first IPv4 management address, if any
+                TLV_MANAGEMENT_ADDR
+                    if management_addr.is_none()
+                        && len >= 6
+                        && value[0] == 5
+                        && value[1] == ADDR_FAMILY_IPV4 =>
+                {
+                    management_addr = Some(ip::Addr([value[2], value[3], value[4], value[5]]))
+                }
+                _ => {}
+            }
+        }
+        // the first three TLVs are mandatory
+        match (chassis_id, port_id, ttl) {
+            (Some(chassis_id), Some(port_id), Some(ttl)) => Ok(Lldpdu {
+                chassis_id,
+                port_id,
+                ttl,
+                system_name,
+                management_addr,
+            }),
+            _ => Err(Error::parse("tlvs", "mandatory tlvs missing")),
+        }
+    }
+    fn to_buffer(self) -> Buffer {
+        let mut buf = Buffer::new(ethernet::PAYLOAD_SIZE_MIN);
+        let mut value = vec![self.chassis_id.subtype];
+        value.extend(self.chassis_id.value);
+        push_tlv(&mut buf, TLV_CHASSIS_ID, value);
+        let mut value = vec![self.port_id.subtype];
+        value.extend(self.port_id.value);
+        push_tlv(&mut buf, TLV_PORT_ID, value);
+        push_tlv(&mut buf, TLV_TTL, self.ttl.to_be_bytes().to_vec());
+        if let Some(system_name) = self.system_name {
+            push_tlv(&mut buf, TLV_SYSTEM_NAME, system_name.into_bytes());
+        }
+        if let Some(addr) = self.management_addr {
+            let mut value = vec![1 + ip::ADDR_LEN as u8, ADDR_FAMILY_IPV4];
+            value.extend(addr.0.iter());
+            value.push(IF_NUMBERING_UNKNOWN);
+            value.extend([0u8; 4].iter());
+            // no OID
+            value.push(0);
+            push_tlv(&mut buf, TLV_MANAGEMENT_ADDR, value);
+        }
+        push_tlv(&mut buf, TLV_END, vec![]);
+        buf
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // in seconds
+    pub interval: u64,
+    pub system_name: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            interval: TX_INTERVAL,
+            system_name: Some("microps-rs".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    // the local device it was heard on
+    pub port: String,
+    pub chassis_id: Id,
+    pub port_id: Id,
+    pub system_name: Option<String>,
+    pub management_addr: Option<ip::Addr>,
+    pub ttl: u16,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Neighbor {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now - self.timestamp > Duration::seconds(self.ttl as i64)
+    }
+}
+
+struct Agent {
+    device: ethernet::Device,
+    config: Config,
+    timer: TimerId,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    agents: Mutex<Vec<Agent>>,
+    neighbors: Mutex<Vec<Neighbor>>,
+}
+
+fn send(device: &ethernet::Device, config: &Config, ttl: u16) -> Result<(), Error> {
+    let management_addr = device
+        .interface()
+        .map(|interface| interface.0.lock().unwrap().unicast);
+    let lldpdu = Lldpdu {
+        chassis_id: Id {
+            subtype: CHASSIS_ID_MAC_ADDR,
+            value: device.addr().0.to_vec(),
+        },
+        port_id: Id {
+            subtype: PORT_ID_INTERFACE_NAME,
+            value: device.name().into_bytes(),
+        },
+        ttl,
+        system_name: config.system_name.clone(),
+        management_addr,
+    };
+    log_packet!("tx", lldpdu);
+    device.tx(ethernet::Type::Lldp, lldpdu.to_buffer(), ADDR_NEAREST_BRIDGE)
+}
+
+/// starts advertising `device` every `config.interval` seconds.
+pub fn enable(device: &ethernet::Device, config: Config) -> Result<(), Error> {
+    // advertised as held for up to `u16::MAX` seconds, which must outlast the interval
+    if config.interval < 1 || config.interval > u16::MAX as u64 {
+        return Err(Error::Invalid(format!(
+            "an lldp interval of {} seconds is not within 1 to {}",
+            config.interval,
+            u16::MAX
+        )));
+    }
+    if let Some(system_name) = &config.system_name {
+        if system_name.len() > SYSTEM_NAME_MAX {
+            return Err(Error::Invalid(format!(
+                "an lldp system name of {} bytes is longer than {}",
+                system_name.len(),
+                SYSTEM_NAME_MAX
+            )));
+        }
+    }
+    let stack = device.stack();
+    let mut agents = stack.0.lldp.agents.lock().unwrap();
+    if agents
+        .iter()
+        .any(|agent| Arc::ptr_eq(&agent.device.0, &device.0))
+    {
+        return Err(Error::Exists(format!(
+            "lldp agent on `{}`",
+            device.name()
+        )));
+    }
+    let ttl = config
+        .interval
+        .saturating_mul(TX_HOLD as u64)
+        .min(u16::MAX as u64) as u16;
+    let interval = Duration::seconds(config.interval as i64);
+    let (device_, config_) = (device.clone(), config.clone());
+    let timer = timer::add_periodic(&stack, "lldp: tx", interval, move |_| {
+        if let Err(err) = send(&device_, &config_, ttl) {
+            warn!("{}: sending lldpdu failed: {}", device_.name(), err);
+        }
+    })?;
+    agents.push(Agent {
+        device: device.clone(),
+        config: config.clone(),
+        timer,
+    });
+    drop(agents);
+    if let Err(err) = send(device, &config, ttl) {
+        warn!("{}: sending lldpdu failed: {}", device.name(), err);
+    }
+    Ok(())
+}
+
+pub fn disable(device: &ethernet::Device) {
+    let stack = device.stack();
+    let agent = {
+        let mut agents = stack.0.lldp.agents.lock().unwrap();
+        match agents
+            .iter()
+            .position(|agent| Arc::ptr_eq(&agent.device.0, &device.0))
+        {
+            Some(idx) => agents.remove(idx),
+            None => return,
+        }
+    };
+    timer::cancel(&stack, agent.timer);
+    // tell the neighbors to forget us right away
+    if let Err(err) = send(device, &agent.config, 0) {
+        warn!("{}: sending lldpdu failed: {}", device.name(), err);
+    }
+}
+
+/// returns the neighbors whose advertisement has not expired yet.
+pub fn neighbors(stack: &Stack) -> Vec<Neighbor> {
+    let now = stack.now();
+    let mut neighbors = stack.0.lldp.neighbors.lock().unwrap();
+    neighbors.retain(|neighbor| !neighbor.is_expired(now));
+    neighbors.clone()
+}
+
+pub fn rx(
+    payload: Buffer,
+    device: &ethernet::Device,
+) -> Result<(), Error> {
+    let lldpdu = Lldpdu::parse(payload)?;
+    log_packet!("rx", lldpdu);
+    let port = device.name();
+    let stack = device.stack();
+    let now = stack.now();
+    let mut neighbors = stack.0.lldp.neighbors.lock().unwrap();
+    neighbors.retain(|neighbor| {
+        let replaced = neighbor.port == port
+            && neighbor.chassis_id == lldpdu.chassis_id
+            && neighbor.port_id == lldpdu.port_id;
+        !(neighbor.is_expired(now) || replaced)
+    });
+    // a TTL of zero is a shutdown notice
+    if lldpdu.ttl != 0 {
+        neighbors.push(Neighbor {
+            port,
+            chassis_id: lldpdu.chassis_id,
+            port_id: lldpdu.port_id,
+            system_name: lldpdu.system_name,
+            management_addr: lldpdu.management_addr,
+            ttl: lldpdu.ttl,
+            timestamp: now,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lldpdu() -> Lldpdu {
+        Lldpdu {
+            chassis_id: Id {
+                subtype: CHASSIS_ID_MAC_ADDR,
+                value: vec![0x02, 0, 0, 0, 0, 1],
+            },
+            port_id: Id {
+                subtype: PORT_ID_INTERFACE_NAME,
+                value: b"eth0".to_vec(),
+            },
+            ttl: 120,
+            system_name: Some("host".to_string()),
+            management_addr: Some(ip::Addr([192, 0, 2, 1])),
+        }
+    }
+
+    fn tlv(type_: u8, value: &[u8]) -> Vec<u8> {
+        let mut buf = Buffer::empty();
+        push_tlv(&mut buf, type_, value.to_vec());
+        buf.to_vec()
+    }
+
+    #[test]
+    fn round_trip() {
+        let parsed = Lldpdu::parse(lldpdu().to_buffer()).unwrap();
+        assert_eq!(parsed.chassis_id, lldpdu().chassis_id);
+        assert_eq!(parsed.port_id, lldpdu().port_id);
+        assert_eq!(parsed.ttl, 120);
+        assert_eq!(parsed.system_name.as_deref(), Some("host"));
+        assert_eq!(parsed.management_addr, Some(ip::Addr([192, 0, 2, 1])));
+        assert_eq!(parsed.chassis_id.to_string(), "2:0:0:0:0:1");
+    }
+
+    #[test]
+    fn skips_unknown_tlvs() {
+        let mut bytes = tlv(TLV_CHASSIS_ID, &[CHASSIS_ID_MAC_ADDR, 2, 0, 0, 0, 0, 1]);
+        bytes.extend(tlv(TLV_PORT_ID, &[PORT_ID_INTERFACE_NAME, b'p']));
+        bytes.extend(tlv(TLV_TTL, &[0, 30]));
+        // a port description and an organizationally specific TLV
+        bytes.extend(tlv(4, b"uplink"));
+        bytes.extend(tlv(127, &[0x00, 0x80, 0xc2, 1, 0, 1]));
+        bytes.extend(tlv(TLV_END, &[]));
+        let parsed = Lldpdu::parse(Buffer::from_vec(bytes)).unwrap();
+        assert_eq!(parsed.ttl, 30);
+        assert_eq!(parsed.system_name, None);
+    }
+
+    #[test]
+    fn mandatory_tlvs_are_required() {
+        let mut bytes = tlv(TLV_CHASSIS_ID, &[CHASSIS_ID_MAC_ADDR, 2, 0, 0, 0, 0, 1]);
+        bytes.extend(tlv(TLV_PORT_ID, &[PORT_ID_INTERFACE_NAME, b'p']));
+        bytes.extend(tlv(TLV_END, &[]));
+        assert!(Lldpdu::parse(Buffer::from_vec(bytes)).is_err());
+    }
+
+    #[test]
+    fn truncated_is_an_error() {
+        let bytes = lldpdu().to_buffer().to_vec();
+        // cut inside the system name, and before the end TLV
+        for len in [1, 5, bytes.len() - 2].iter() {
+            let buf = Buffer::from_vec(bytes[..*len].to_vec());
+            assert!(Lldpdu::parse(buf).is_err(), "{} bytes parsed", len);
+        }
+    }
+
+    #[test]
+    fn overlong_values_keep_the_tlvs_after_them() {
+        let mut lldpdu = lldpdu();
+        lldpdu.system_name = Some("x".repeat(600));
+        let parsed = Lldpdu::parse(lldpdu.to_buffer()).unwrap();
+        assert_eq!(parsed.system_name, Some("x".repeat(TLV_VALUE_MAX)));
+        assert_eq!(parsed.management_addr, Some(ip::Addr([192, 0, 2, 1])));
+    }
+
+    #[test]
+    fn enabling_checks_the_config() {
+        use crate::sim::{LinkConfig, Simulator};
+        let mut sim = Simulator::new(1);
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (device, _) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let config = |interval, system_name: &str| Config {
+            interval,
+            system_name: Some(system_name.to_string()),
+        };
+        let long_name = "x".repeat(SYSTEM_NAME_MAX + 1);
+        for config in [config(0, "host"), config(1 << 16, "host"), config(30, &long_name)].iter() {
+            assert!(matches!(enable(&device, config.clone()), Err(Error::Invalid(_))));
+        }
+        assert!(timer::timers(&stack).iter().all(|timer| timer.name != "lldp: tx"));
+
+        // the longest interval is held for as long as a TTL can say
+        enable(&device, config(u16::MAX as u64, &"x".repeat(SYSTEM_NAME_MAX))).unwrap();
+        sim.run_for(::std::time::Duration::from_secs(1));
+        let neighbors = neighbors(&peer);
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].ttl, u16::MAX);
+        assert_eq!(neighbors[0].system_name.as_ref().map(String::len), Some(SYSTEM_NAME_MAX));
+    }
+}
@@ -0,0 +1,1904 @@
+// IEEE 802.1D spanning tree protocols for bridge ports
+//
+// The bridge feeds received BPDUs and a one second tick into `Stp`, and sends out the
+// BPDUs it returns. By default ports run the rapid spanning tree (802.1D-2004, once
+// 802.1w): edge ports and root ports forward at once, and a designated port on a
+// point-to-point link proposes to its neighbor and forwards as soon as it agrees. Other
+// designated ports wait out the forward delay twice. A port that hears a plain STP BPDU
+// speaks STP on that link, and the whole bridge can be switched to the 1998 protocol.
+
+use crate::{
+    buffer::Buffer,
+    error::{Error, Layer},
+    ethernet::{self, MacAddr},
+    packet::Packet,
+};
+
+pub const GROUP_ADDR: MacAddr = MacAddr([0x01, 0x80, 0xc2, 0x00, 0x00, 0x00]);
+
+pub const DEFAULT_BRIDGE_PRIORITY: u16 = 0x8000;
+pub const DEFAULT_PORT_PRIORITY: u8 = 0x80;
+// port numbers take the low byte of a port id, and 0 is not one
+pub const PORT_MAX: usize = 255;
+// the recommended cost of a 100 Mb/s link
+pub const DEFAULT_PATH_COST: u32 = 19;
+
+// all in seconds
+pub const MAX_AGE: u16 = 20;
+pub const HELLO_TIME: u16 = 2;
+pub const FORWARD_DELAY: u16 = 15;
+const MESSAGE_AGE_INCREMENT: u16 = 1;
+
+const PROTOCOL_ID: u16 = 0x0000;
+const VERSION_STP: u8 = 0;
+const VERSION_RSTP: u8 = 2;
+const TYPE_CONFIG: u8 = 0x00;
+const TYPE_TCN: u8 = 0x80;
+const TYPE_RST: u8 = 0x02;
+const FLAG_TC: u8 = 0x01;
+const FLAG_PROPOSAL: u8 = 0x02;
+const FLAG_ROLE_MASK: u8 = 0x0c;
+const FLAG_ROLE_SHIFT: u8 = 2;
+const FLAG_LEARNING: u8 = 0x10;
+const FLAG_FORWARDING: u8 = 0x20;
+const FLAG_AGREEMENT: u8 = 0x40;
+const FLAG_TCA: u8 = 0x80;
+// the role field of RST BPDU flags
+const ROLE_ALTERNATE: u8 = 1;
+const ROLE_ROOT: u8 = 2;
+const ROLE_DESIGNATED: u8 = 3;
+// how long a port keeps to the protocol it switched to before it may switch back
+const MIGRATE_TIME: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    // 802.1D-1998
+    Stp,
+    // 802.1D-2004
+    Rstp,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Version::Stp => "stp",
+                Version::Rstp => "rstp",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BridgeId(pub u64);
+
+impl BridgeId {
+    pub fn new(priority: u16, addr: MacAddr) -> BridgeId {
+        let mut id = priority as u64;
+        for byte in addr.0.iter() {
+            id = (id << 8) | *byte as u64;
+        }
+        BridgeId(id)
+    }
+    pub fn priority(&self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+}
+
+use std::fmt;
+impl fmt::Display for BridgeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}.{:012x}", self.priority(), self.0 & 0xffff_ffff_ffff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Disabled,
+    // RSTP merges blocking and listening
+    Discarding,
+    Blocking,
+    Listening,
+    Learning,
+    Forwarding,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                State::Disabled => "disabled",
+                State::Discarding => "discarding",
+                State::Blocking => "blocking",
+                State::Listening => "listening",
+                State::Learning => "learning",
+                State::Forwarding => "forwarding",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Root,
+    Designated,
+    Alternate,
+    // a second port of this bridge on a segment another of its ports is designated for
+    Backup,
+    Disabled,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Role::Root => "root",
+                Role::Designated => "designated",
+                Role::Alternate => "alternate",
+                Role::Backup => "backup",
+                Role::Disabled => "disabled",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // received as an RST BPDU
+    pub rstp: bool,
+    pub flags: u8,
+    pub root_id: BridgeId,
+    pub root_path_cost: u32,
+    pub bridge_id: BridgeId,
+    pub port_id: u16,
+    // all in seconds
+    pub message_age: u16,
+    pub max_age: u16,
+    pub hello_time: u16,
+    pub forward_delay: u16,
+}
+
+impl Config {
+    /// the role of the sending port; plain configuration BPDUs come from designated ports.
+    pub fn role(&self) -> Option<Role> {
+        if !self.rstp {
+            return Some(Role::Designated);
+        }
+        match (self.flags & FLAG_ROLE_MASK) >> FLAG_ROLE_SHIFT {
+            ROLE_ALTERNATE => Some(Role::Alternate),
+            ROLE_ROOT => Some(Role::Root),
+            ROLE_DESIGNATED => Some(Role::Designated),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Bpdu {
+    Config(Config),
+    Tcn,
+}
+
+impl Bpdu {
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        match self {
+            Bpdu::Config(config) => {
+                let _ = writeln!(out, "type : {}", if config.rstp { "RST" } else { "config" });
+                let _ = writeln!(out, "flags: {:02x}", config.flags);
+                let _ = writeln!(out, "root : {} cost {}", config.root_id, config.root_path_cost);
+                let _ = writeln!(out, "from : {} port {:04x}", config.bridge_id, config.port_id);
+                let _ = writeln!(
+                    out,
+                    "times: age {} max {} hello {} delay {}",
+                    config.message_age, config.max_age, config.hello_time, config.forward_delay
+                );
+            }
+            Bpdu::Tcn => {
+                let _ = writeln!(out, "type : TCN");
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Bpdu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bpdu::Config(config) => write!(
+                f,
+                "config root {} cost {} from {} port {:04x}",
+                config.root_id, config.root_path_cost, config.bridge_id, config.port_id
+            ),
+            Bpdu::Tcn => write!(f, "TCN"),
+        }
+    }
+}
+
+fn pop_bridge_id(buf: &mut Buffer, label: &str) -> Result<BridgeId, Error> {
+    let high = buf.pop_u32(label)? as u64;
+    let low = buf.pop_u32(label)? as u64;
+    Ok(BridgeId((high << 32) | low))
+}
+
+// BPDU times are in 1/256 seconds
+fn pop_time(buf: &mut Buffer, label: &str) -> Result<u16, Error> {
+    Ok(buf.pop_u16(label)? >> 8)
+}
+
+impl Packet<Bpdu> for Bpdu {
+    const LAYER: Layer = Layer::Stp;
+
+    fn from_buffer(mut buf: Buffer) -> Result<Self, Error> {
+        let protocol_id = buf.pop_u16("protocol id")?;
+        if protocol_id != PROTOCOL_ID {
+            return Err(Error::parse(
+                "protocol id",
+                format!("unknown: {:04x}", protocol_id),
+            ));
+        }
+        let version = buf.pop_u8("version")?;
+        let type_ = buf.pop_u8("type")?;
+        if type_ == TYPE_TCN {
+            return Ok(Bpdu::Tcn);
+        }
+        if type_ != TYPE_CONFIG && !(type_ == TYPE_RST && version >= VERSION_RSTP) {
+            return Err(Error::parse("type", format!("unknown: {:02x}", type_)));
+        }
+        Ok(Bpdu::Config(Config {
+            rstp: type_ == TYPE_RST,
+            flags: buf.pop_u8("flags")?,
+            root_id: pop_bridge_id(&mut buf, "root id")?,
+            root_path_cost: buf.pop_u32("root path cost")?,
+            bridge_id: pop_bridge_id(&mut buf, "bridge id")?,
+            port_id: buf.pop_u16("port id")?,
+            message_age: pop_time(&mut buf, "message age")?,
+            max_age: pop_time(&mut buf, "max age")?,
+            hello_time: pop_time(&mut buf, "hello time")?,
+            forward_delay: pop_time(&mut buf, "forward delay")?,
+        }))
+    }
+    fn to_buffer(self) -> Buffer {
+        let mut buf = Buffer::new(ethernet::PAYLOAD_SIZE_MIN);
+        buf.push_u16(PROTOCOL_ID);
+        match self {
+            Bpdu::Config(config) => {
+                if config.rstp {
+                    buf.push_u8(VERSION_RSTP);
+                    buf.push_u8(TYPE_RST);
+                } else {
+                    buf.push_u8(VERSION_STP);
+                    buf.push_u8(TYPE_CONFIG);
+                }
+                buf.push_u8(config.flags);
+                buf.push_u32((config.root_id.0 >> 32) as u32);
+                buf.push_u32(config.root_id.0 as u32);
+                buf.push_u32(config.root_path_cost);
+                buf.push_u32((config.bridge_id.0 >> 32) as u32);
+                buf.push_u32(config.bridge_id.0 as u32);
+                buf.push_u16(config.port_id);
+                buf.push_u16(config.message_age << 8);
+                buf.push_u16(config.max_age << 8);
+                buf.push_u16(config.hello_time << 8);
+                buf.push_u16(config.forward_delay << 8);
+                if config.rstp {
+                    // no version 1 (802.1w) information follows
+                    buf.push_u8(0);
+                }
+            }
+            Bpdu::Tcn => {
+                buf.push_u8(VERSION_STP);
+                buf.push_u8(TYPE_TCN);
+            }
+        }
+        buf
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Port {
+    pub port_id: u16,
+    pub path_cost: u32,
+    pub state: State,
+    designated_root: BridgeId,
+    designated_cost: u32,
+    designated_bridge: BridgeId,
+    designated_port: u16,
+    topology_change_ack: bool,
+    // configured as an edge port, one with no bridge behind it
+    pub edge: bool,
+    pub point_to_point: bool,
+    // the rest is RSTP only
+    // still an edge port, as no BPDU came in
+    oper_edge: bool,
+    // speaking RSTP to the neighbor rather than STP
+    send_rstp: bool,
+    // the role as of the last update, to notice changes
+    last_role: Role,
+    proposing: bool,
+    proposed: bool,
+    // the neighbor agreed to our proposal
+    agreed: bool,
+    // an agreement to send
+    agree: bool,
+    // something to announce with the next update
+    new_info: bool,
+    // timers count up in seconds while running
+    message_age_timer: Option<u16>,
+    forward_delay_timer: Option<u16>,
+    info_timer: Option<u16>,
+    migrate_timer: Option<u16>,
+    tc_timer: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PortInfo {
+    pub port_id: u16,
+    pub path_cost: u32,
+    pub state: State,
+    pub role: Role,
+    pub designated_root: BridgeId,
+    pub designated_bridge: BridgeId,
+    pub designated_port: u16,
+    pub edge: bool,
+    // whether the port speaks RSTP to its neighbor
+    pub rstp: bool,
+}
+
+/// the spanning tree state of one bridge; ports are indexed like the bridge's ports.
+#[derive(Debug)]
+pub struct Stp {
+    pub enabled: bool,
+    pub version: Version,
+    pub bridge_id: BridgeId,
+    pub root_id: BridgeId,
+    pub root_path_cost: u32,
+    root_port: Option<u16>,
+    // the times in use, learned from the root
+    pub max_age: u16,
+    pub hello_time: u16,
+    pub forward_delay: u16,
+    // the times used while this bridge is the root
+    pub bridge_max_age: u16,
+    pub bridge_hello_time: u16,
+    pub bridge_forward_delay: u16,
+    pub topology_change: bool,
+    topology_change_detected: bool,
+    hello_timer: Option<u16>,
+    tcn_timer: Option<u16>,
+    topology_change_timer: Option<u16>,
+    pub ports: Vec<Port>,
+    // ports whose learned addresses must go, see `take_flushes`
+    flushes: Vec<usize>,
+}
+
+// BPDUs to send, by port index
+pub type Output = Vec<(usize, Bpdu)>;
+
+fn expired(timer: &mut Option<u16>, limit: u16) -> bool {
+    match timer {
+        Some(value) => {
+            *value += 1;
+            *value >= limit
+        }
+        None => false,
+    }
+}
+
+impl Stp {
+    pub fn new(addr: MacAddr) -> Stp {
+        let bridge_id = BridgeId::new(DEFAULT_BRIDGE_PRIORITY, addr);
+        Stp {
+            enabled: false,
+            version: Version::Rstp,
+            bridge_id,
+            root_id: bridge_id,
+            root_path_cost: 0,
+            root_port: None,
+            max_age: MAX_AGE,
+            hello_time: HELLO_TIME,
+            forward_delay: FORWARD_DELAY,
+            bridge_max_age: MAX_AGE,
+            bridge_hello_time: HELLO_TIME,
+            bridge_forward_delay: FORWARD_DELAY,
+            topology_change: false,
+            topology_change_detected: false,
+            hello_timer: None,
+            tcn_timer: None,
+            topology_change_timer: None,
+            ports: vec![],
+            flushes: vec![],
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.root_id == self.bridge_id
+    }
+
+    /// true if frames received on the port may be learned from.
+    pub fn is_learning(&self, idx: usize) -> bool {
+        !self.enabled
+            || self.ports[idx].state == State::Learning
+            || self.ports[idx].state == State::Forwarding
+    }
+
+    pub fn is_forwarding(&self, idx: usize) -> bool {
+        !self.enabled || self.ports[idx].state == State::Forwarding
+    }
+
+    fn root_port_idx(&self) -> Option<usize> {
+        let root_port = self.root_port?;
+        self.ports.iter().position(|port| port.port_id == root_port)
+    }
+
+    fn is_designated(&self, idx: usize) -> bool {
+        let port = &self.ports[idx];
+        port.designated_bridge == self.bridge_id && port.designated_port == port.port_id
+    }
+
+    pub fn role(&self, idx: usize) -> Role {
+        if self.ports[idx].state == State::Disabled {
+            Role::Disabled
+        } else if Some(self.ports[idx].port_id) == self.root_port {
+            Role::Root
+        } else if self.is_designated(idx) {
+            Role::Designated
+        } else if self.ports[idx].designated_bridge == self.bridge_id {
+            Role::Backup
+        } else {
+            Role::Alternate
+        }
+    }
+
+    fn is_rstp(&self) -> bool {
+        self.version == Version::Rstp
+    }
+
+    pub fn port_info(&self, idx: usize) -> PortInfo {
+        let port = &self.ports[idx];
+        PortInfo {
+            port_id: port.port_id,
+            path_cost: port.path_cost,
+            state: port.state,
+            role: self.role(idx),
+            designated_root: port.designated_root,
+            designated_bridge: port.designated_bridge,
+            designated_port: port.designated_port,
+            edge: if self.is_rstp() { port.oper_edge } else { port.edge },
+            rstp: self.is_rstp() && port.send_rstp,
+        }
+    }
+
+    /// indexes of ports whose learned addresses went stale with a topology change;
+    /// the bridge flushes them after every call.
+    pub fn take_flushes(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.flushes)
+    }
+
+    /// starts the protocol with every port blocking (or discarding) and this bridge assuming
+    /// it is the root.
+    pub fn enable(&mut self) -> Output {
+        let mut out = vec![];
+        self.enabled = true;
+        self.root_id = self.bridge_id;
+        self.root_path_cost = 0;
+        self.root_port = None;
+        self.max_age = self.bridge_max_age;
+        self.hello_time = self.bridge_hello_time;
+        self.forward_delay = self.bridge_forward_delay;
+        self.topology_change = false;
+        self.topology_change_detected = false;
+        self.tcn_timer = None;
+        self.topology_change_timer = None;
+        for idx in 0..self.ports.len() {
+            self.initialize_port(idx);
+        }
+        if self.is_rstp() {
+            self.rstp_update(&mut out);
+        } else {
+            self.port_state_selection(&mut out);
+            self.config_bpdu_generation(&mut out);
+        }
+        self.hello_timer = Some(0);
+        out
+    }
+
+    /// switches protocols, restarting the protocol if it runs.
+    pub fn set_version(&mut self, version: Version) -> Output {
+        self.version = version;
+        if self.enabled {
+            self.enable()
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.hello_timer = None;
+        self.tcn_timer = None;
+        self.topology_change_timer = None;
+        self.topology_change = false;
+    }
+
+    pub fn add_port(&mut self) -> Output {
+        // the lowest number no port has, so that removed ports free theirs
+        let number = (1..=PORT_MAX as u16)
+            .find(|number| self.ports.iter().all(|port| port.port_id & 0xff != *number))
+            .expect("no port number left");
+        let port_id = ((DEFAULT_PORT_PRIORITY as u16) << 8) | number;
+        self.ports.push(Port {
+            port_id,
+            path_cost: DEFAULT_PATH_COST,
+            state: State::Blocking,
+            designated_root: self.root_id,
+            designated_cost: self.root_path_cost,
+            designated_bridge: self.bridge_id,
+            designated_port: port_id,
+            topology_change_ack: false,
+            edge: false,
+            point_to_point: true,
+            oper_edge: false,
+            send_rstp: false,
+            last_role: Role::Disabled,
+            proposing: false,
+            proposed: false,
+            agreed: false,
+            agree: false,
+            new_info: false,
+            message_age_timer: None,
+            forward_delay_timer: None,
+            info_timer: None,
+            migrate_timer: None,
+            tc_timer: None,
+        });
+        let mut out = vec![];
+        if self.enabled {
+            let idx = self.ports.len() - 1;
+            self.initialize_port(idx);
+            if self.is_rstp() {
+                self.rstp_update(&mut out);
+            } else {
+                self.port_state_selection(&mut out);
+                if self.is_root() {
+                    self.transmit_config(idx, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn remove_port(&mut self, idx: usize) -> Output {
+        let was_root = self.is_root();
+        self.ports.remove(idx);
+        let mut out = vec![];
+        if self.enabled {
+            self.reselect(was_root, &mut out);
+        }
+        out
+    }
+
+    pub fn set_bridge_priority(&mut self, priority: u16) -> Output {
+        let was_root = self.is_root();
+        let bridge_id = BridgeId((self.bridge_id.0 & 0xffff_ffff_ffff) | (priority as u64) << 48);
+        for idx in 0..self.ports.len() {
+            if self.is_designated(idx) {
+                self.ports[idx].designated_bridge = bridge_id;
+            }
+        }
+        if self.root_id == self.bridge_id {
+            self.root_id = bridge_id;
+        }
+        self.bridge_id = bridge_id;
+        let mut out = vec![];
+        if self.enabled {
+            self.reselect(was_root, &mut out);
+        }
+        out
+    }
+
+    pub fn set_path_cost(&mut self, idx: usize, path_cost: u32) -> Output {
+        let was_root = self.is_root();
+        self.ports[idx].path_cost = path_cost;
+        let mut out = vec![];
+        if self.enabled {
+            self.reselect(was_root, &mut out);
+        }
+        out
+    }
+
+    /// marks a port as having no bridge behind it, so that under RSTP it forwards at once.
+    /// a BPDU received on it proves otherwise until the protocol restarts.
+    pub fn set_edge(&mut self, idx: usize, edge: bool) -> Output {
+        let port = &mut self.ports[idx];
+        port.edge = edge;
+        port.oper_edge = edge;
+        let mut out = vec![];
+        if self.enabled && self.is_rstp() {
+            self.rstp_update(&mut out);
+        }
+        out
+    }
+
+    /// proposals are only made over point-to-point links, where a single bridge can agree.
+    pub fn set_point_to_point(&mut self, idx: usize, point_to_point: bool) -> Output {
+        self.ports[idx].point_to_point = point_to_point;
+        if !point_to_point {
+            self.ports[idx].proposing = false;
+        }
+        let mut out = vec![];
+        if self.enabled && self.is_rstp() {
+            self.rstp_update(&mut out);
+        }
+        out
+    }
+
+    pub fn set_port_priority(&mut self, idx: usize, priority: u8) -> Output {
+        let was_root = self.is_root();
+        let is_designated = self.is_designated(idx);
+        let port = &mut self.ports[idx];
+        let port_id = ((priority as u16) << 8) | (port.port_id & 0xff);
+        if self.root_port == Some(port.port_id) {
+            self.root_port = Some(port_id);
+        }
+        if is_designated {
+            port.designated_port = port_id;
+        }
+        port.port_id = port_id;
+        let mut out = vec![];
+        if self.enabled {
+            self.reselect(was_root, &mut out);
+        }
+        out
+    }
+
+    pub fn received(&mut self, idx: usize, bpdu: Bpdu) -> Output {
+        let mut out = vec![];
+        if !self.enabled || self.ports[idx].state == State::Disabled {
+            return out;
+        }
+        if self.is_rstp() {
+            self.rstp_received(idx, bpdu, &mut out);
+            return out;
+        }
+        match bpdu {
+            // agreements and the like mean nothing to STP
+            Bpdu::Config(config) if config.role() == Some(Role::Designated) => {
+                self.received_config(idx, config, &mut out)
+            }
+            Bpdu::Config(_) => {}
+            Bpdu::Tcn => {
+                if self.is_designated(idx) {
+                    self.topology_change_detection(&mut out);
+                    self.ports[idx].topology_change_ack = true;
+                    self.transmit_config(idx, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /// advances every timer by one second.
+    pub fn tick(&mut self) -> Output {
+        let mut out = vec![];
+        if !self.enabled {
+            return out;
+        }
+        if self.is_rstp() {
+            self.rstp_tick(&mut out);
+            return out;
+        }
+        if expired(&mut self.hello_timer, self.bridge_hello_time) {
+            self.config_bpdu_generation(&mut out);
+            self.hello_timer = Some(0);
+        }
+        if expired(&mut self.tcn_timer, self.bridge_hello_time) {
+            self.transmit_tcn(&mut out);
+            self.tcn_timer = Some(0);
+        }
+        let topology_change_time = self.bridge_max_age + self.bridge_forward_delay;
+        if expired(&mut self.topology_change_timer, topology_change_time) {
+            self.topology_change_detected = false;
+            self.topology_change = false;
+            self.topology_change_timer = None;
+        }
+        for idx in 0..self.ports.len() {
+            let max_age = self.max_age;
+            if expired(&mut self.ports[idx].message_age_timer, max_age) {
+                self.ports[idx].message_age_timer = None;
+                let was_root = self.is_root();
+                self.become_designated_port(idx);
+                self.reselect(was_root, &mut out);
+            }
+            let forward_delay = self.forward_delay;
+            if expired(&mut self.ports[idx].forward_delay_timer, forward_delay) {
+                self.forward_delay_expired(idx, &mut out);
+            }
+        }
+        out
+    }
+
+    fn initialize_port(&mut self, idx: usize) {
+        self.become_designated_port(idx);
+        let rstp = self.is_rstp();
+        let port = &mut self.ports[idx];
+        port.state = if rstp {
+            State::Discarding
+        } else {
+            State::Blocking
+        };
+        port.topology_change_ack = false;
+        port.oper_edge = port.edge;
+        port.send_rstp = rstp;
+        port.last_role = Role::Disabled;
+        port.proposing = false;
+        port.proposed = false;
+        port.agreed = false;
+        port.agree = false;
+        port.new_info = false;
+        port.message_age_timer = None;
+        port.forward_delay_timer = None;
+        port.info_timer = None;
+        port.migrate_timer = if rstp { Some(0) } else { None };
+        port.tc_timer = None;
+    }
+
+    fn received_config(&mut self, idx: usize, config: Config, out: &mut Output) {
+        if config.message_age >= config.max_age {
+            return;
+        }
+        if self.supersedes_port_info(idx, &config) {
+            let was_root = self.is_root();
+            {
+                let port = &mut self.ports[idx];
+                port.designated_root = config.root_id;
+                port.designated_cost = config.root_path_cost;
+                port.designated_bridge = config.bridge_id;
+                port.designated_port = config.port_id;
+                port.message_age_timer = Some(config.message_age);
+            }
+            self.reselect(was_root, out);
+            if self.root_port == Some(self.ports[idx].port_id) {
+                self.max_age = config.max_age;
+                self.hello_time = config.hello_time;
+                self.forward_delay = config.forward_delay;
+                self.topology_change = config.flags & FLAG_TC != 0;
+                self.config_bpdu_generation(out);
+                if config.flags & FLAG_TCA != 0 {
+                    self.topology_change_detected = false;
+                    self.tcn_timer = None;
+                }
+            }
+        } else if self.is_designated(idx) {
+            // an inferior bridge does not know better yet
+            self.transmit_config(idx, out);
+        }
+    }
+
+    fn supersedes_port_info(&self, idx: usize, config: &Config) -> bool {
+        let port = &self.ports[idx];
+        if config.root_id != port.designated_root {
+            return config.root_id < port.designated_root;
+        }
+        if config.root_path_cost != port.designated_cost {
+            return config.root_path_cost < port.designated_cost;
+        }
+        if config.bridge_id != port.designated_bridge {
+            return config.bridge_id < port.designated_bridge;
+        }
+        config.bridge_id != self.bridge_id || config.port_id <= port.designated_port
+    }
+
+    // recomputes roles and states, handling this bridge becoming or ceasing to be the root
+    fn reselect(&mut self, was_root: bool, out: &mut Output) {
+        self.root_selection();
+        self.designated_port_selection();
+        if self.is_rstp() {
+            if !was_root && self.is_root() {
+                self.max_age = self.bridge_max_age;
+                self.hello_time = self.bridge_hello_time;
+                self.forward_delay = self.bridge_forward_delay;
+            }
+            self.rstp_update(out);
+            return;
+        }
+        self.port_state_selection(out);
+        let is_root = self.is_root();
+        if was_root && !is_root {
+            self.hello_timer = None;
+            if self.topology_change_detected {
+                self.transmit_tcn(out);
+                self.tcn_timer = Some(0);
+            }
+        } else if !was_root && is_root {
+            self.max_age = self.bridge_max_age;
+            self.hello_time = self.bridge_hello_time;
+            self.forward_delay = self.bridge_forward_delay;
+            self.topology_change_detection(out);
+            self.tcn_timer = None;
+            self.config_bpdu_generation(out);
+            self.hello_timer = Some(0);
+        }
+    }
+
+    fn root_selection(&mut self) {
+        let mut best: Option<usize> = None;
+        for idx in 0..self.ports.len() {
+            let port = &self.ports[idx];
+            if self.is_designated(idx)
+                || port.state == State::Disabled
+                || port.designated_root >= self.bridge_id
+            {
+                continue;
+            }
+            let key = |port: &Port| {
+                (
+                    port.designated_root,
+                    port.designated_cost.saturating_add(port.path_cost),
+                    port.designated_bridge,
+                    port.designated_port,
+                    port.port_id,
+                )
+            };
+            if best.map(|best| key(port) < key(&self.ports[best])).unwrap_or(true) {
+                best = Some(idx);
+            }
+        }
+        match best {
+            Some(idx) => {
+                let port = &self.ports[idx];
+                self.root_port = Some(port.port_id);
+                self.root_id = port.designated_root;
+                self.root_path_cost = port.designated_cost.saturating_add(port.path_cost);
+            }
+            None => {
+                self.root_port = None;
+                self.root_id = self.bridge_id;
+                self.root_path_cost = 0;
+            }
+        }
+    }
+
+    fn designated_port_selection(&mut self) {
+        for idx in 0..self.ports.len() {
+            let port = &self.ports[idx];
+            let becomes_designated = self.is_designated(idx)
+                || port.designated_root != self.root_id
+                || self.root_path_cost < port.designated_cost
+                || (self.root_path_cost == port.designated_cost
+                    && (self.bridge_id < port.designated_bridge
+                        || (self.bridge_id == port.designated_bridge
+                            && port.port_id <= port.designated_port)));
+            if becomes_designated {
+                self.become_designated_port(idx);
+            }
+        }
+    }
+
+    fn become_designated_port(&mut self, idx: usize) {
+        let port = &mut self.ports[idx];
+        if (
+            port.designated_root,
+            port.designated_cost,
+            port.designated_bridge,
+            port.designated_port,
+        ) != (self.root_id, self.root_path_cost, self.bridge_id, port.port_id)
+        {
+            // the neighbor agreed to something else
+            port.agreed = false;
+        }
+        port.info_timer = None;
+        port.designated_root = self.root_id;
+        port.designated_cost = self.root_path_cost;
+        port.designated_bridge = self.bridge_id;
+        port.designated_port = port.port_id;
+    }
+
+    fn port_state_selection(&mut self, out: &mut Output) {
+        let mut detected = false;
+        for idx in 0..self.ports.len() {
+            if self.ports[idx].state == State::Disabled {
+                continue;
+            }
+            if Some(self.ports[idx].port_id) == self.root_port {
+                self.ports[idx].topology_change_ack = false;
+                self.make_forwarding(idx);
+            } else if self.is_designated(idx) {
+                self.ports[idx].message_age_timer = None;
+                self.make_forwarding(idx);
+            } else {
+                self.ports[idx].topology_change_ack = false;
+                detected |= self.make_blocking(idx);
+            }
+        }
+        if detected {
+            // a port stopped forwarding, so the paths behind it moved
+            self.topology_change_detection(out);
+        }
+    }
+
+    fn make_forwarding(&mut self, idx: usize) {
+        let port = &mut self.ports[idx];
+        if port.state == State::Blocking {
+            port.state = State::Listening;
+            port.forward_delay_timer = Some(0);
+        }
+    }
+
+    // returns true if the port was passing traffic
+    fn make_blocking(&mut self, idx: usize) -> bool {
+        let port = &mut self.ports[idx];
+        let was_active = port.state == State::Learning || port.state == State::Forwarding;
+        if port.state != State::Blocking {
+            port.state = State::Blocking;
+            port.forward_delay_timer = None;
+        }
+        was_active
+    }
+
+    fn forward_delay_expired(&mut self, idx: usize, out: &mut Output) {
+        let port = &mut self.ports[idx];
+        match port.state {
+            State::Listening => {
+                port.state = State::Learning;
+                port.forward_delay_timer = Some(0);
+            }
+            State::Learning => {
+                port.state = State::Forwarding;
+                port.forward_delay_timer = None;
+                if (0..self.ports.len()).any(|idx| self.is_designated(idx)) {
+                    self.topology_change_detection(out);
+                }
+            }
+            _ => port.forward_delay_timer = None,
+        }
+    }
+
+    fn topology_change_detection(&mut self, out: &mut Output) {
+        if self.is_root() {
+            self.topology_change = true;
+            self.topology_change_timer = Some(0);
+        } else if !self.topology_change_detected {
+            self.transmit_tcn(out);
+            self.tcn_timer = Some(0);
+        }
+        self.topology_change_detected = true;
+    }
+
+    fn config_bpdu_generation(&mut self, out: &mut Output) {
+        for idx in 0..self.ports.len() {
+            if self.is_designated(idx) && self.ports[idx].state != State::Disabled {
+                self.transmit_config(idx, out);
+            }
+        }
+    }
+
+    // the age of the root's information as sent on
+    fn message_age(&self) -> u16 {
+        if self.is_root() {
+            return 0;
+        }
+        match self.root_port_idx() {
+            Some(root) => self.ports[root].message_age_timer.unwrap_or(0) + MESSAGE_AGE_INCREMENT,
+            None => 0,
+        }
+    }
+
+    fn transmit_config(&mut self, idx: usize, out: &mut Output) {
+        let message_age = self.message_age();
+        if message_age >= self.max_age {
+            return;
+        }
+        let port = &mut self.ports[idx];
+        let mut flags = 0;
+        if port.topology_change_ack {
+            flags |= FLAG_TCA;
+        }
+        // the latter for an RSTP bridge telling an STP neighbor
+        if self.topology_change || port.tc_timer.is_some() {
+            flags |= FLAG_TC;
+        }
+        port.topology_change_ack = false;
+        out.push((
+            idx,
+            Bpdu::Config(Config {
+                rstp: false,
+                flags,
+                root_id: self.root_id,
+                root_path_cost: self.root_path_cost,
+                bridge_id: self.bridge_id,
+                port_id: port.port_id,
+                message_age,
+                max_age: self.max_age,
+                hello_time: self.hello_time,
+                forward_delay: self.forward_delay,
+            }),
+        ));
+    }
+
+    fn transmit_tcn(&mut self, out: &mut Output) {
+        if let Some(root) = self.root_port_idx() {
+            out.push((root, Bpdu::Tcn));
+        }
+    }
+
+    // RSTP from here on
+
+    fn rstp_received(&mut self, idx: usize, bpdu: Bpdu, out: &mut Output) {
+        let rst = matches!(&bpdu, Bpdu::Config(config) if config.rstp);
+        {
+            let port = &mut self.ports[idx];
+            // a bridge is there after all
+            port.oper_edge = false;
+            if port.migrate_timer.is_none() && port.send_rstp != rst {
+                port.send_rstp = rst;
+                port.migrate_timer = Some(0);
+                port.proposing = false;
+                port.new_info = true;
+            }
+        }
+        match bpdu {
+            Bpdu::Config(config) => self.rstp_received_config(idx, config, out),
+            Bpdu::Tcn => {
+                if self.is_designated(idx) {
+                    self.ports[idx].topology_change_ack = true;
+                    self.ports[idx].new_info = true;
+                    self.propagate_topology_change(idx);
+                }
+            }
+        }
+        self.rstp_update(out);
+    }
+
+    fn rstp_received_config(&mut self, idx: usize, config: Config, out: &mut Output) {
+        if config.message_age >= config.max_age {
+            return;
+        }
+        match config.role() {
+            Some(Role::Designated) => {
+                let port = &self.ports[idx];
+                // the port we hold information from may send worse news, too
+                let same_sender = !self.is_designated(idx)
+                    && config.bridge_id == port.designated_bridge
+                    && config.port_id == port.designated_port;
+                if same_sender || self.supersedes_port_info(idx, &config) {
+                    let was_root = self.is_root();
+                    let changed = {
+                        let port = &mut self.ports[idx];
+                        let changed = (
+                            port.designated_root,
+                            port.designated_cost,
+                            port.designated_bridge,
+                            port.designated_port,
+                        ) != (
+                            config.root_id,
+                            config.root_path_cost,
+                            config.bridge_id,
+                            config.port_id,
+                        );
+                        port.designated_root = config.root_id;
+                        port.designated_cost = config.root_path_cost;
+                        port.designated_bridge = config.bridge_id;
+                        port.designated_port = config.port_id;
+                        port.message_age_timer = Some(config.message_age);
+                        port.info_timer = Some(0);
+                        port.proposed |= config.flags & FLAG_PROPOSAL != 0;
+                        changed
+                    };
+                    if changed {
+                        self.reselect(was_root, out);
+                    }
+                    if self.root_port == Some(self.ports[idx].port_id) {
+                        self.max_age = config.max_age;
+                        self.hello_time = config.hello_time;
+                        self.forward_delay = config.forward_delay;
+                        if config.flags & FLAG_TCA != 0 {
+                            // an STP root heard our notification
+                            self.ports[idx].tc_timer = None;
+                        }
+                    }
+                } else if self.is_designated(idx) {
+                    // an inferior bridge does not know better yet
+                    self.ports[idx].new_info = true;
+                }
+            }
+            Some(_) => {
+                let agreed = config.flags & FLAG_AGREEMENT != 0
+                    && self.is_designated(idx)
+                    && self.ports[idx].point_to_point
+                    && config.root_id == self.root_id;
+                if agreed {
+                    let port = &mut self.ports[idx];
+                    port.agreed = true;
+                    port.proposing = false;
+                }
+            }
+            None => return,
+        }
+        if config.flags & FLAG_TC != 0 {
+            match self.role(idx) {
+                Role::Root | Role::Designated => self.propagate_topology_change(idx),
+                _ => {}
+            }
+        }
+    }
+
+    fn rstp_tick(&mut self, out: &mut Output) {
+        if expired(&mut self.hello_timer, self.bridge_hello_time) {
+            self.hello_timer = Some(0);
+            for idx in 0..self.ports.len() {
+                let announce = match self.role(idx) {
+                    Role::Designated => true,
+                    Role::Root => self.ports[idx].tc_timer.is_some(),
+                    _ => false,
+                };
+                self.ports[idx].new_info |= announce;
+            }
+        }
+        let was_root = self.is_root();
+        let mut aged = false;
+        for idx in 0..self.ports.len() {
+            let tc_time = if self.ports[idx].send_rstp {
+                self.hello_time + 1
+            } else {
+                self.max_age + self.forward_delay
+            };
+            let info_time = 3 * self.hello_time;
+            let forward_delay = self.forward_delay;
+            let port = &mut self.ports[idx];
+            if expired(&mut port.migrate_timer, MIGRATE_TIME) {
+                port.migrate_timer = None;
+            }
+            if expired(&mut port.tc_timer, tc_time) {
+                port.tc_timer = None;
+            }
+            if expired(&mut port.forward_delay_timer, forward_delay) {
+                match port.state {
+                    State::Discarding => {
+                        port.state = State::Learning;
+                        port.forward_delay_timer = Some(0);
+                        port.new_info = true;
+                    }
+                    State::Learning => {
+                        port.state = State::Forwarding;
+                        port.forward_delay_timer = None;
+                        port.new_info = true;
+                        if !port.oper_edge {
+                            self.detect_topology_change(idx);
+                        }
+                    }
+                    _ => port.forward_delay_timer = None,
+                }
+            }
+            if expired(&mut self.ports[idx].info_timer, info_time) {
+                // the neighbor went quiet
+                self.become_designated_port(idx);
+                aged = true;
+            }
+        }
+        if aged {
+            self.reselect(was_root, out);
+        } else {
+            self.rstp_update(out);
+        }
+    }
+
+    // the role transitions: ports leave the active topology first, then the root port and
+    // the designated ports join it as fast as they safely can
+    fn rstp_update(&mut self, out: &mut Output) {
+        for idx in 0..self.ports.len() {
+            let role = self.role(idx);
+            let port = &mut self.ports[idx];
+            if role != port.last_role {
+                port.new_info = true;
+            }
+            let discards = match role {
+                Role::Alternate | Role::Backup => true,
+                // the old root port may still lead to the old root
+                Role::Designated => port.last_role == Role::Root,
+                Role::Root | Role::Disabled => false,
+            };
+            if discards && port.state != State::Discarding && port.state != State::Disabled {
+                port.state = State::Discarding;
+                port.forward_delay_timer = None;
+                port.agreed = false;
+            }
+            if role != Role::Designated {
+                port.proposing = false;
+            }
+        }
+
+        if let Some(root) = self.root_port_idx() {
+            if self.ports[root].state != State::Forwarding {
+                let port = &mut self.ports[root];
+                port.state = State::Forwarding;
+                port.forward_delay_timer = None;
+                if !port.oper_edge {
+                    self.detect_topology_change(root);
+                }
+            }
+            if self.ports[root].proposed {
+                self.sync(root);
+                let port = &mut self.ports[root];
+                port.proposed = false;
+                port.agree = true;
+                port.new_info = true;
+            }
+        }
+
+        for idx in 0..self.ports.len() {
+            match self.role(idx) {
+                // nothing passes an alternate port, so it can agree at once
+                Role::Alternate | Role::Backup => {
+                    let port = &mut self.ports[idx];
+                    if port.proposed {
+                        port.proposed = false;
+                        port.agree = true;
+                        port.new_info = true;
+                    }
+                }
+                Role::Designated => {
+                    let port = &mut self.ports[idx];
+                    port.proposed = false;
+                    if port.state == State::Forwarding {
+                        port.proposing = false;
+                    } else if port.oper_edge || port.agreed {
+                        port.state = State::Forwarding;
+                        port.forward_delay_timer = None;
+                        port.proposing = false;
+                        port.new_info = true;
+                        if !port.oper_edge {
+                            self.detect_topology_change(idx);
+                        }
+                    } else {
+                        if port.state == State::Discarding && port.forward_delay_timer.is_none() {
+                            port.forward_delay_timer = Some(0);
+                        }
+                        if port.send_rstp && port.point_to_point && !port.proposing {
+                            port.proposing = true;
+                            port.new_info = true;
+                        }
+                    }
+                }
+                Role::Root | Role::Disabled => {}
+            }
+        }
+
+        for idx in 0..self.ports.len() {
+            self.ports[idx].last_role = self.role(idx);
+            if self.ports[idx].new_info {
+                self.ports[idx].new_info = false;
+                self.rstp_transmit(idx, out);
+            }
+        }
+    }
+
+    // on a proposal through the root port: designated ports that could close a loop through
+    // the new root discard until their neighbors agree in turn
+    fn sync(&mut self, root: usize) {
+        for idx in 0..self.ports.len() {
+            if idx == root || self.role(idx) != Role::Designated {
+                continue;
+            }
+            let port = &mut self.ports[idx];
+            if !port.oper_edge && !port.agreed && port.state != State::Discarding {
+                port.state = State::Discarding;
+                port.forward_delay_timer = None;
+            }
+        }
+    }
+
+    // a non-edge port started forwarding
+    fn detect_topology_change(&mut self, idx: usize) {
+        self.start_topology_change(idx);
+        self.propagate_topology_change(idx);
+    }
+
+    // every port but `origin` forgets the addresses it learned and passes the news on
+    fn propagate_topology_change(&mut self, origin: usize) {
+        for idx in 0..self.ports.len() {
+            if idx == origin || self.ports[idx].oper_edge {
+                continue;
+            }
+            if let Role::Root | Role::Designated = self.role(idx) {
+                self.flushes.push(idx);
+                self.start_topology_change(idx);
+            }
+        }
+    }
+
+    fn start_topology_change(&mut self, idx: usize) {
+        let port = &mut self.ports[idx];
+        if port.tc_timer.is_none() {
+            port.tc_timer = Some(0);
+            port.new_info = true;
+        }
+    }
+
+    fn rstp_transmit(&mut self, idx: usize, out: &mut Output) {
+        let role = self.role(idx);
+        if !self.ports[idx].send_rstp {
+            match role {
+                Role::Designated => self.transmit_config(idx, out),
+                Role::Root if self.ports[idx].tc_timer.is_some() => out.push((idx, Bpdu::Tcn)),
+                _ => {}
+            }
+            return;
+        }
+        let message_age = self.message_age();
+        if message_age >= self.max_age {
+            return;
+        }
+        let port = &mut self.ports[idx];
+        let mut flags = match role {
+            Role::Designated if port.proposing => (ROLE_DESIGNATED << FLAG_ROLE_SHIFT) | FLAG_PROPOSAL,
+            Role::Designated => ROLE_DESIGNATED << FLAG_ROLE_SHIFT,
+            // the other roles only speak up to agree or to announce a change
+            _ if !port.agree && port.tc_timer.is_none() => return,
+            Role::Root => ROLE_ROOT << FLAG_ROLE_SHIFT,
+            Role::Alternate | Role::Backup => ROLE_ALTERNATE << FLAG_ROLE_SHIFT,
+            Role::Disabled => return,
+        };
+        if port.agree {
+            flags |= FLAG_AGREEMENT;
+        }
+        if port.tc_timer.is_some() {
+            flags |= FLAG_TC;
+        }
+        match port.state {
+            State::Learning => flags |= FLAG_LEARNING,
+            State::Forwarding => flags |= FLAG_LEARNING | FLAG_FORWARDING,
+            _ => {}
+        }
+        port.agree = false;
+        out.push((
+            idx,
+            Bpdu::Config(Config {
+                rstp: true,
+                flags,
+                root_id: self.root_id,
+                root_path_cost: self.root_path_cost,
+                bridge_id: self.bridge_id,
+                port_id: port.port_id,
+                message_age,
+                max_age: self.max_age,
+                hello_time: self.hello_time,
+                forward_delay: self.forward_delay,
+            }),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> MacAddr {
+        MacAddr([0x02, 0, 0, 0, 0, n])
+    }
+
+    // a bridge with `ports` ports and 802.1D-1998 STP running
+    fn bridge(n: u8, ports: usize) -> Stp {
+        let mut stp = Stp::new(addr(n));
+        stp.version = Version::Stp;
+        for _ in 0..ports {
+            stp.add_port();
+        }
+        stp.enable();
+        stp
+    }
+
+    fn ticks(stp: &mut Stp, seconds: u16) -> Output {
+        let mut out = vec![];
+        for _ in 0..seconds {
+            out.extend(stp.tick());
+        }
+        out
+    }
+
+    // what the root sends on its port `port_id`
+    fn root_config(root: &Stp, port_id: u16) -> Bpdu {
+        Bpdu::Config(Config {
+            rstp: false,
+            flags: 0,
+            root_id: root.bridge_id,
+            root_path_cost: 0,
+            bridge_id: root.bridge_id,
+            port_id,
+            message_age: 0,
+            max_age: MAX_AGE,
+            hello_time: HELLO_TIME,
+            forward_delay: FORWARD_DELAY,
+        })
+    }
+
+    #[test]
+    fn alone_every_port_is_designated_and_forwards() {
+        let mut stp = bridge(1, 2);
+        assert!(stp.is_root());
+        for idx in 0..2 {
+            assert_eq!(stp.role(idx), Role::Designated);
+            assert_eq!(stp.ports[idx].state, State::Listening);
+        }
+        ticks(&mut stp, FORWARD_DELAY);
+        assert_eq!(stp.ports[0].state, State::Learning);
+        assert!(stp.is_learning(0) && !stp.is_forwarding(0));
+        ticks(&mut stp, FORWARD_DELAY);
+        assert_eq!(stp.ports[0].state, State::Forwarding);
+        assert!(stp.is_forwarding(0));
+    }
+
+    #[test]
+    fn hellos_go_out_of_designated_ports() {
+        let mut stp = bridge(1, 2);
+        let out = ticks(&mut stp, HELLO_TIME);
+        let ports: Vec<usize> = out.iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(ports, vec![0, 1]);
+    }
+
+    #[test]
+    fn superior_bpdu_makes_a_root_port() {
+        let root = Stp::new(addr(0));
+        let mut stp = bridge(1, 2);
+        stp.received(0, root_config(&root, 0x8001));
+        assert!(!stp.is_root());
+        assert_eq!(stp.root_id, root.bridge_id);
+        assert_eq!(stp.root_path_cost, DEFAULT_PATH_COST);
+        assert_eq!(stp.role(0), Role::Root);
+        assert_eq!(stp.role(1), Role::Designated);
+    }
+
+    #[test]
+    fn advertised_cost_saturates() {
+        let root = Stp::new(addr(0));
+        let mut stp = bridge(1, 2);
+        let mut far = root_config(&root, 0x8001);
+        if let Bpdu::Config(ref mut config) = far {
+            config.root_path_cost = u32::MAX;
+        }
+        stp.received(0, far);
+        assert_eq!(stp.root_path_cost, u32::MAX);
+        // any real path is still better than the saturated one
+        stp.received(1, root_config(&root, 0x8002));
+        assert_eq!(stp.role(1), Role::Root);
+        assert_eq!(stp.root_path_cost, DEFAULT_PATH_COST);
+    }
+
+    #[test]
+    fn second_link_to_the_root_blocks() {
+        let root = Stp::new(addr(0));
+        let mut stp = bridge(1, 2);
+        ticks(&mut stp, 2 * FORWARD_DELAY);
+        stp.received(0, root_config(&root, 0x8001));
+        stp.received(1, root_config(&root, 0x8002));
+        assert_eq!(stp.role(0), Role::Root);
+        assert_eq!(stp.role(1), Role::Alternate);
+        assert_eq!(stp.ports[1].state, State::Blocking);
+        assert!(!stp.is_learning(1));
+        // the root port keeps forwarding
+        assert_eq!(stp.ports[0].state, State::Forwarding);
+    }
+
+    #[test]
+    fn inferior_bpdu_is_answered() {
+        let mut stp = bridge(1, 1);
+        let other = Stp::new(addr(9));
+        let out = stp.received(0, root_config(&other, 0x8001));
+        assert!(stp.is_root());
+        assert_eq!(stp.role(0), Role::Designated);
+        match out.as_slice() {
+            [(0, Bpdu::Config(config))] => assert_eq!(config.root_id, stp.bridge_id),
+            out => panic!("expected our config on port 0: {:?}", out),
+        }
+    }
+
+    #[test]
+    fn root_is_forgotten_after_max_age() {
+        let root = Stp::new(addr(0));
+        let mut stp = bridge(1, 2);
+        stp.received(0, root_config(&root, 0x8001));
+        stp.received(1, root_config(&root, 0x8002));
+        assert_eq!(stp.role(1), Role::Alternate);
+        ticks(&mut stp, MAX_AGE);
+        assert!(stp.is_root());
+        assert_eq!(stp.role(0), Role::Designated);
+        assert_eq!(stp.role(1), Role::Designated);
+        assert_eq!(stp.ports[1].state, State::Listening);
+    }
+
+    #[test]
+    fn tcn_is_acknowledged() {
+        let mut stp = bridge(1, 1);
+        let out = stp.received(0, Bpdu::Tcn);
+        assert!(stp.topology_change);
+        match out.as_slice() {
+            [(0, Bpdu::Config(config))] => {
+                assert_eq!(config.flags, FLAG_TC | FLAG_TCA);
+            }
+            out => panic!("expected an acknowledgement: {:?}", out),
+        }
+    }
+
+    #[test]
+    fn disabled_ports_ignore_bpdus() {
+        let root = Stp::new(addr(0));
+        let mut stp = bridge(1, 1);
+        stp.ports[0].state = State::Disabled;
+        assert!(stp.received(0, root_config(&root, 0x8001)).is_empty());
+        assert!(stp.is_root());
+        assert_eq!(stp.role(0), Role::Disabled);
+    }
+
+    #[test]
+    fn bpdu_round_trip() {
+        let root = Stp::new(addr(0));
+        let buf = root_config(&root, 0x8003).to_buffer();
+        match Bpdu::parse(buf).unwrap() {
+            Bpdu::Config(config) => {
+                assert_eq!(config.root_id, root.bridge_id);
+                assert_eq!(config.port_id, 0x8003);
+                assert_eq!(config.max_age, MAX_AGE);
+                assert_eq!(config.forward_delay, FORWARD_DELAY);
+            }
+            Bpdu::Tcn => panic!("parsed a TCN"),
+        }
+        assert!(matches!(Bpdu::parse(Bpdu::Tcn.to_buffer()), Ok(Bpdu::Tcn)));
+    }
+
+    #[test]
+    fn truncated_bpdu_is_an_error() {
+        let root = Stp::new(addr(0));
+        let mut bytes = root_config(&root, 0x8001).to_buffer().to_vec();
+        bytes.truncate(20);
+        assert!(Bpdu::parse(Buffer::from_vec(bytes)).is_err());
+        assert!(Bpdu::parse(Buffer::from_vec(vec![0, 0])).is_err());
+    }
+
+    #[test]
+    fn rst_bpdu_reads_as_config() {
+        let root = Stp::new(addr(0));
+        let mut bytes = root_config(&root, 0x8001).to_buffer().to_vec();
+        bytes[2] = VERSION_RSTP;
+        bytes[3] = TYPE_RST;
+        match Bpdu::parse(Buffer::from_vec(bytes)).unwrap() {
+            Bpdu::Config(config) => assert!(config.rstp),
+            Bpdu::Tcn => panic!("parsed a TCN"),
+        }
+    }
+
+    #[test]
+    fn unknown_protocol_is_an_error() {
+        let root = Stp::new(addr(0));
+        let mut bytes = root_config(&root, 0x8001).to_buffer().to_vec();
+        bytes[0] = 0x12;
+        assert!(Bpdu::parse(Buffer::from_vec(bytes)).is_err());
+    }
+
+    // what the root sends on its port `port_id` as an RSTP designated port
+    fn root_rst(root: &Stp, port_id: u16, flags: u8) -> Bpdu {
+        match root_config(root, port_id) {
+            Bpdu::Config(config) => Bpdu::Config(Config {
+                rstp: true,
+                flags: (ROLE_DESIGNATED << FLAG_ROLE_SHIFT) | flags,
+                ..config
+            }),
+            Bpdu::Tcn => unreachable!(),
+        }
+    }
+
+    fn rst_flags(out: &Output, port: usize) -> Vec<u8> {
+        out.iter()
+            .filter_map(|(idx, bpdu)| match bpdu {
+                Bpdu::Config(config) if *idx == port && config.rstp => Some(config.flags),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // RSTP bridges with their ports wired together, BPDUs delivered at once
+    struct Lan {
+        bridges: Vec<Stp>,
+        // (bridge, port) at either end
+        links: Vec<((usize, usize), (usize, usize))>,
+    }
+
+    impl Lan {
+        // bridge n has `ports[n]` ports and the n + 1th lowest id
+        fn new(ports: &[usize]) -> Lan {
+            let bridges = ports
+                .iter()
+                .enumerate()
+                .map(|(n, ports)| {
+                    let mut stp = Stp::new(addr(n as u8 + 1));
+                    for _ in 0..*ports {
+                        stp.add_port();
+                    }
+                    stp
+                })
+                .collect();
+            Lan {
+                bridges,
+                links: vec![],
+            }
+        }
+
+        fn peer(&self, end: (usize, usize)) -> Option<(usize, usize)> {
+            self.links.iter().find_map(|(a, b)| {
+                if *a == end {
+                    Some(*b)
+                } else if *b == end {
+                    Some(*a)
+                } else {
+                    None
+                }
+            })
+        }
+
+        fn deliver(&mut self, mut queue: Vec<(usize, usize, Bpdu)>) {
+            let mut budget = 1000;
+            while !queue.is_empty() {
+                let (from, port, bpdu) = queue.remove(0);
+                budget -= 1;
+                assert!(budget > 0, "BPDUs keep coming");
+                let bpdu = Bpdu::parse(bpdu.to_buffer()).unwrap();
+                if let Some((to, to_port)) = self.peer((from, port)) {
+                    for (idx, bpdu) in self.bridges[to].received(to_port, bpdu) {
+                        queue.push((to, idx, bpdu));
+                    }
+                }
+            }
+        }
+
+        fn enable(&mut self) {
+            let mut queue = vec![];
+            for (n, stp) in self.bridges.iter_mut().enumerate() {
+                queue.extend(stp.enable().into_iter().map(|(idx, bpdu)| (n, idx, bpdu)));
+            }
+            self.deliver(queue);
+        }
+
+        fn ticks(&mut self, seconds: u16) {
+            for _ in 0..seconds {
+                let mut queue = vec![];
+                for (n, stp) in self.bridges.iter_mut().enumerate() {
+                    queue.extend(stp.tick().into_iter().map(|(idx, bpdu)| (n, idx, bpdu)));
+                }
+                self.deliver(queue);
+            }
+        }
+
+        fn states(&self) -> Vec<Vec<(Role, State)>> {
+            self.bridges
+                .iter()
+                .map(|stp| {
+                    (0..stp.ports.len())
+                        .map(|idx| (stp.role(idx), stp.ports[idx].state))
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    // 0 -- 1 -- 2 -- 0, bridge 0 the root
+    fn triangle() -> Lan {
+        let mut lan = Lan::new(&[2, 2, 2]);
+        lan.links = vec![((0, 0), (1, 0)), ((1, 1), (2, 0)), ((2, 1), (0, 1))];
+        lan.enable();
+        lan
+    }
+
+    #[test]
+    fn rst_bpdu_round_trip() {
+        let root = Stp::new(addr(0));
+        let buf = root_rst(&root, 0x8001, FLAG_PROPOSAL | FLAG_TC).to_buffer();
+        let bytes = buf.clone().to_vec();
+        assert_eq!(bytes.len(), 36);
+        assert_eq!((bytes[2], bytes[3], bytes[35]), (VERSION_RSTP, TYPE_RST, 0));
+        match Bpdu::parse(buf).unwrap() {
+            Bpdu::Config(config) => {
+                assert!(config.rstp);
+                assert_eq!(config.role(), Some(Role::Designated));
+                assert_eq!(config.flags & !FLAG_ROLE_MASK, FLAG_PROPOSAL | FLAG_TC);
+                assert_eq!(config.root_id, root.bridge_id);
+            }
+            Bpdu::Tcn => panic!("parsed a TCN"),
+        }
+    }
+
+    #[test]
+    fn stp_ignores_rst_agreements() {
+        let root = Stp::new(addr(0));
+        let mut stp = bridge(1, 1);
+        let agreement = match root_rst(&root, 0x8001, FLAG_AGREEMENT) {
+            Bpdu::Config(config) => Bpdu::Config(Config {
+                flags: (ROLE_ROOT << FLAG_ROLE_SHIFT) | FLAG_AGREEMENT,
+                ..config
+            }),
+            Bpdu::Tcn => unreachable!(),
+        };
+        assert!(stp.received(0, agreement).is_empty());
+        assert!(stp.is_root());
+    }
+
+    #[test]
+    fn agreement_makes_a_link_forward_at_once() {
+        let mut lan = Lan::new(&[1, 1]);
+        lan.links = vec![((0, 0), (1, 0))];
+        lan.enable();
+        assert_eq!(
+            lan.states(),
+            vec![
+                vec![(Role::Designated, State::Forwarding)],
+                vec![(Role::Root, State::Forwarding)],
+            ]
+        );
+        assert_eq!(lan.bridges[1].root_id, lan.bridges[0].bridge_id);
+    }
+
+    #[test]
+    fn a_loop_is_cut_without_waiting() {
+        let mut lan = triangle();
+        let expected = vec![
+            vec![
+                (Role::Designated, State::Forwarding),
+                (Role::Designated, State::Forwarding),
+            ],
+            vec![
+                (Role::Root, State::Forwarding),
+                (Role::Designated, State::Forwarding),
+            ],
+            vec![
+                (Role::Alternate, State::Discarding),
+                (Role::Root, State::Forwarding),
+            ],
+        ];
+        assert_eq!(lan.states(), expected);
+        // and it stays that way
+        lan.ticks(2 * FORWARD_DELAY + MAX_AGE);
+        assert_eq!(lan.states(), expected);
+    }
+
+    #[test]
+    fn the_alternate_port_takes_over_at_once() {
+        let mut lan = triangle();
+        for stp in lan.bridges.iter_mut() {
+            stp.take_flushes();
+        }
+        // the link from bridge 2 to the root goes quiet
+        lan.links.retain(|link| *link != ((2, 1), (0, 1)));
+        lan.ticks(3 * HELLO_TIME - 1);
+        assert_eq!(lan.bridges[2].role(0), Role::Alternate);
+        lan.ticks(1);
+        assert_eq!(lan.bridges[2].role(0), Role::Root);
+        assert_eq!(lan.bridges[2].ports[0].state, State::Forwarding);
+        assert_eq!(lan.bridges[2].root_path_cost, 2 * DEFAULT_PATH_COST);
+        assert_eq!(lan.bridges[2].role(1), Role::Designated);
+        // what was learned behind the other port is stale now
+        assert_eq!(lan.bridges[2].take_flushes(), vec![1]);
+    }
+
+    #[test]
+    fn a_proposal_syncs_and_is_agreed_to() {
+        let root = Stp::new(addr(0));
+        let mut stp = Stp::new(addr(1));
+        for _ in 0..3 {
+            stp.add_port();
+        }
+        stp.enable();
+        // alone, the ports forward after the forward delay twice
+        ticks(&mut stp, 2 * FORWARD_DELAY);
+        assert!((0..3).all(|idx| stp.ports[idx].state == State::Forwarding));
+        stp.take_flushes();
+
+        let out = stp.received(0, root_rst(&root, 0x8001, FLAG_PROPOSAL));
+        assert_eq!(stp.role(0), Role::Root);
+        assert_eq!(stp.ports[0].state, State::Forwarding);
+        for idx in 1..3 {
+            assert_eq!(stp.ports[idx].state, State::Discarding);
+            let flags = rst_flags(&out, idx);
+            assert_eq!(flags.len(), 1);
+            assert_ne!(flags[0] & FLAG_PROPOSAL, 0);
+        }
+        let flags = rst_flags(&out, 0);
+        assert_eq!(flags.len(), 1);
+        assert_eq!(flags[0] & FLAG_ROLE_MASK, ROLE_ROOT << FLAG_ROLE_SHIFT);
+        assert_ne!(flags[0] & FLAG_AGREEMENT, 0);
+
+        // the neighbor on port 1 agrees in turn
+        let mut agreement = match root_rst(&Stp::new(addr(9)), 0x8001, FLAG_AGREEMENT) {
+            Bpdu::Config(config) => config,
+            Bpdu::Tcn => unreachable!(),
+        };
+        agreement.flags = (ROLE_ROOT << FLAG_ROLE_SHIFT) | FLAG_AGREEMENT;
+        agreement.root_id = root.bridge_id;
+        stp.take_flushes();
+        stp.received(1, Bpdu::Config(agreement));
+        assert_eq!(stp.ports[1].state, State::Forwarding);
+        assert_eq!(stp.ports[2].state, State::Discarding);
+        assert_eq!(stp.take_flushes(), vec![0, 2]);
+    }
+
+    #[test]
+    fn edge_ports_forward_until_a_bpdu_comes() {
+        let mut stp = Stp::new(addr(1));
+        stp.add_port();
+        stp.add_port();
+        stp.set_edge(1, true);
+        stp.enable();
+        assert_eq!(stp.ports[0].state, State::Discarding);
+        assert_eq!(stp.ports[1].state, State::Forwarding);
+        assert!(stp.port_info(1).edge);
+        // nor does an edge port make a topology change
+        assert!(stp.take_flushes().is_empty());
+
+        let other = Stp::new(addr(9));
+        stp.received(1, root_rst(&other, 0x8001, 0));
+        assert!(!stp.port_info(1).edge);
+        assert_eq!(stp.ports[1].state, State::Forwarding);
+    }
+
+    #[test]
+    fn stp_neighbors_are_spoken_to_in_stp() {
+        let root = Stp::new(addr(0));
+        let other = Stp::new(addr(9));
+        let mut stp = Stp::new(addr(1));
+        stp.add_port();
+        stp.add_port();
+        stp.enable();
+        ticks(&mut stp, MIGRATE_TIME);
+
+        let out = stp.received(0, root_config(&root, 0x8001));
+        assert!(!stp.port_info(0).rstp);
+        assert_eq!(stp.role(0), Role::Root);
+        assert_eq!(stp.ports[0].state, State::Forwarding);
+        // the change goes to the STP root as a notification
+        assert!(out.iter().any(|(idx, bpdu)| *idx == 0 && matches!(bpdu, Bpdu::Tcn)));
+
+        // a designated port facing an STP bridge proposes nothing and waits
+        let out = stp.received(1, root_config(&other, 0x8001));
+        assert!(!stp.port_info(1).rstp);
+        match out.as_slice() {
+            [(1, Bpdu::Config(config))] => {
+                assert!(!config.rstp);
+                assert_eq!(config.root_id, root.bridge_id);
+            }
+            out => panic!("expected our config on port 1: {:?}", out),
+        }
+        // counting from when the protocol started
+        let mut age = MIGRATE_TIME;
+        while stp.ports[1].state != State::Forwarding {
+            stp.received(0, root_config(&root, 0x8001));
+            stp.tick();
+            age += 1;
+        }
+        assert_eq!(age, 2 * FORWARD_DELAY);
+    }
+
+    #[test]
+    fn tcn_from_an_stp_bridge_is_acknowledged_and_spread() {
+        let mut stp = Stp::new(addr(1));
+        stp.add_port();
+        stp.add_port();
+        stp.enable();
+        ticks(&mut stp, MIGRATE_TIME);
+        stp.take_flushes();
+        let out = stp.received(0, Bpdu::Tcn);
+        match out.iter().find(|(idx, _)| *idx == 0) {
+            Some((_, Bpdu::Config(config))) => {
+                assert!(!config.rstp);
+                assert_eq!(config.flags, FLAG_TCA);
+            }
+            _ => panic!("expected an acknowledgement: {:?}", out),
+        }
+        // and the other port passes the change on
+        assert_ne!(rst_flags(&out, 1)[0] & FLAG_TC, 0);
+        assert_eq!(stp.take_flushes(), vec![1]);
+    }
+}
@@ -0,0 +1,289 @@
+// IEEE 802.1Q VLAN sub-devices, stacked as 802.1ad (QinQ) when given two VIDs
+
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    arp,
+    buffer::Buffer,
+    error::Error,
+    ethernet, ip,
+    link::{self, DeviceFlags, LinkDevice, Resolution},
+    stack::Stack,
+};
+
+pub const TPID_CTAG: u16 = 0x8100;
+pub const TPID_STAG: u16 = 0x88a8;
+pub const TAG_SIZE: usize = 4;
+
+pub const VID_MIN: u16 = 1;
+pub const VID_MAX: u16 = 4094;
+pub const PCP_MAX: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tag {
+    pub tpid: u16,
+    pub pcp: u8,
+    pub dei: bool,
+    pub vid: u16,
+}
+
+impl Tag {
+    pub fn from_tci(tpid: u16, tci: u16) -> Tag {
+        Tag {
+            tpid,
+            pcp: (tci >> 13) as u8,
+            dei: tci & 0x1000 != 0,
+            vid: tci & 0x0fff,
+        }
+    }
+    pub fn tci(&self) -> u16 {
+        ((self.pcp as u16 & 0x07) << 13) | if self.dei { 0x1000 } else { 0 } | (self.vid & 0x0fff)
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x} vid={} pcp={}{}",
+            self.tpid,
+            self.vid,
+            self.pcp,
+            if self.dei { " dei" } else { "" }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct DeviceImpl {
+    pub interface: Option<ip::interface::WeakInterface>,
+    pub name: String,
+    pub parent: ethernet::Device,
+    // outermost first
+    pub vids: Vec<u16>,
+    pub priority: u8,
+    pub flags: DeviceFlags,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device(pub Arc<Mutex<DeviceImpl>>);
+
+impl Device {
+    /// creates `<parent>.<vid>` carrying frames with a single 802.1Q tag.
+    pub fn open(parent: &ethernet::Device, vid: u16) -> Result<Device, Error> {
+        Device::open_stacked(parent, &[vid])
+    }
+
+    /// creates `<parent>.<s_vid>.<c_vid>` carrying an 802.1ad service tag around an 802.1Q one.
+    pub fn open_qinq(
+        parent: &ethernet::Device,
+        s_vid: u16,
+        c_vid: u16,
+    ) -> Result<Device, Error> {
+        Device::open_stacked(parent, &[s_vid, c_vid])
+    }
+
+    fn open_stacked(parent: &ethernet::Device, vids: &[u16]) -> Result<Device, Error> {
+        if let Some(vid) = vids.iter().find(|vid| **vid < VID_MIN || **vid > VID_MAX) {
+            return Err(Error::Invalid(format!("invalid vid: {}", vid)));
+        }
+        let stack = parent.stack();
+        let mut devices = stack.0.vlans.lock().unwrap();
+        if find(&devices, parent, vids).is_some() {
+            return Err(Error::Exists(format!(
+                "vlan {:?} on `{}`",
+                vids,
+                parent.name()
+            )));
+        }
+        let name = vids.iter().fold(parent.name(), |name, vid| format!("{}.{}", name, vid));
+        let device = Device(Arc::new(Mutex::new(DeviceImpl {
+            interface: None,
+            name,
+            parent: parent.clone(),
+            vids: vids.to_vec(),
+            priority: 0,
+            flags: parent.flags(),
+        })));
+        devices.push(device.clone());
+        link::register(&stack, Arc::new(device.clone()));
+        Ok(device)
+    }
+
+    pub fn add_interface(&mut self, interface: ip::interface::Interface) {
+        self.0.lock().unwrap().interface = Some(interface.downgrade());
+    }
+
+    /// sets the PCP of frames whose datagram does not carry an IP precedence of its own.
+    pub fn set_priority(&self, pcp: u8) -> Result<(), Error> {
+        if pcp > PCP_MAX {
+            return Err(Error::Invalid(format!("invalid priority: {}", pcp)));
+        }
+        self.0.lock().unwrap().priority = pcp;
+        Ok(())
+    }
+
+    /// stops demultiplexing frames to this device.
+    pub fn close(self) -> Result<(), Error> {
+        let stack = self.stack();
+        let mut devices = stack.0.vlans.lock().unwrap();
+        devices.retain(|device| !Arc::ptr_eq(&device.0, &self.0));
+        Ok(())
+    }
+
+    pub fn rx(
+        &self,
+        type_: ethernet::Type,
+        payload: Buffer,
+    ) -> Result<(), Error> {
+        match type_ {
+            ethernet::Type::Arp => arp::rx(payload, self),
+            ethernet::Type::Ip => ip::rx(payload, self),
+            ethernet::Type::Lldp | ethernet::Type::Llc => Ok(()),
+        }
+    }
+}
+
+// the TPID of the tag at `idx` of `len`, outermost first: service tags around a customer one
+fn tpid(idx: usize, len: usize) -> u16 {
+    if idx + 1 == len {
+        TPID_CTAG
+    } else {
+        TPID_STAG
+    }
+}
+
+fn find(devices: &[Device], parent: &ethernet::Device, vids: &[u16]) -> Option<Device> {
+    devices
+        .iter()
+        .find(|device| {
+            let device = device.0.lock().unwrap();
+            Arc::ptr_eq(&device.parent.0, &parent.0) && device.vids.as_slice() == vids
+        })
+        .cloned()
+}
+
+/// returns the sub-device of `parent` receiving frames tagged with `tags`, which must
+/// carry the TPIDs it sends them with.
+pub fn lookup(parent: &ethernet::Device, tags: &[Tag]) -> Option<Device> {
+    if tags
+        .iter()
+        .enumerate()
+        .any(|(idx, tag)| tag.tpid != tpid(idx, tags.len()))
+    {
+        return None;
+    }
+    let vids = tags.iter().map(|tag| tag.vid).collect::<Vec<_>>();
+    let stack = parent.stack();
+    let devices = stack.0.vlans.lock().unwrap();
+    find(&devices, parent, &vids)
+}
+
+impl LinkDevice for Device {
+    fn name(&self) -> String {
+        self.0.lock().unwrap().name.clone()
+    }
+    fn flags(&self) -> DeviceFlags {
+        self.0.lock().unwrap().flags
+    }
+    fn mtu(&self) -> usize {
+        let parent = self.0.lock().unwrap().parent.clone();
+        parent.mtu()
+    }
+    fn header_len(&self) -> usize {
+        ethernet::HDR_SIZE + TAG_SIZE * self.0.lock().unwrap().vids.len()
+    }
+    fn resolution(&self) -> Resolution {
+        Resolution::Arp
+    }
+    fn addr(&self) -> ethernet::MacAddr {
+        let parent = self.0.lock().unwrap().parent.clone();
+        parent.addr()
+    }
+    fn broadcast_addr(&self) -> ethernet::MacAddr {
+        ethernet::ADDR_BROADCAST
+    }
+    fn interface(&self) -> Option<ip::interface::Interface> {
+        let interface = self.0.lock().unwrap().interface.clone();
+        interface.and_then(|interface| interface.upgrade())
+    }
+    fn stack(&self) -> Stack {
+        let parent = self.0.lock().unwrap().parent.clone();
+        parent.stack()
+    }
+    fn tx(
+        &self,
+        type_: ethernet::Type,
+        payload: Buffer,
+        dst: ethernet::MacAddr,
+    ) -> Result<(), Error> {
+        let (parent, vids, priority) = {
+            let inner = self.0.lock().unwrap();
+            (inner.parent.clone(), inner.vids.clone(), inner.priority)
+        };
+        // the precedence bits of the TOS field map onto PCP
+        let precedence = match type_ {
+            ethernet::Type::Ip if payload.0.len() > 1 => payload.0[1] >> 5,
+            _ => 0,
+        };
+        let pcp = if precedence != 0 { precedence } else { priority };
+        let tags = vids
+            .iter()
+            .enumerate()
+            .map(|(idx, vid)| Tag {
+                tpid: tpid(idx, vids.len()),
+                pcp,
+                dei: false,
+                vid: *vid,
+            })
+            .collect::<Vec<_>>();
+        parent.tx_tagged(type_, payload, dst, tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{LinkConfig, Simulator};
+
+    fn tag(tpid: u16, vid: u16) -> Tag {
+        Tag {
+            tpid,
+            pcp: 0,
+            dei: false,
+            vid,
+        }
+    }
+
+    #[test]
+    fn lookup_matches_tpids_as_well_as_vids() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (parent, _) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let single = Device::open(&parent, 10).unwrap();
+        let qinq = Device::open_qinq(&parent, 20, 10).unwrap();
+        let is = |found: Option<Device>, device: &Device| {
+            found.is_some_and(|found| Arc::ptr_eq(&found.0, &device.0))
+        };
+
+        assert!(is(lookup(&parent, &[tag(TPID_CTAG, 10)]), &single));
+        assert!(lookup(&parent, &[tag(TPID_STAG, 10)]).is_none());
+        let stacked = [tag(TPID_STAG, 20), tag(TPID_CTAG, 10)];
+        assert!(is(lookup(&parent, &stacked), &qinq));
+        for tpids in [(TPID_CTAG, TPID_CTAG), (TPID_STAG, TPID_STAG), (TPID_CTAG, TPID_STAG)].iter() {
+            assert!(lookup(&parent, &[tag(tpids.0, 20), tag(tpids.1, 10)]).is_none());
+        }
+    }
+
+    #[test]
+    fn the_mtu_is_the_parents() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (parent, _) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let device = Device::open_qinq(&parent, 20, 10).unwrap();
+        assert_eq!(device.mtu(), ethernet::PAYLOAD_SIZE_MAX);
+        parent.0.lock().unwrap().mtu = 9000;
+        assert_eq!(device.mtu(), 9000);
+    }
+}
@@ -0,0 +1,173 @@
+// one thread driving every device of a stack
+//
+// Instead of `run` starting a thread per device, an event loop waits on all of their
+// descriptors at once with epoll and handles each frame on the calling thread, together
+// with the stack's timers. Timers added from other threads wake it through an eventfd. An
+// application can run it on a thread of its own or step it with `poll_once` from its own
+// loop.
+
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use std::sync::Arc;
+
+use nix::sys::epoll::{
+    epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::unistd;
+
+use crate::{clock::Clock, error::Error, link::LinkDevice, stack::Stack, timer};
+
+#[cfg(feature = "tokio")]
+mod tokio_loop;
+#[cfg(feature = "tokio")]
+pub use tokio_loop::TokioEventLoop;
+
+// events taken from epoll per call
+const EVENTS_MAX: usize = 16;
+// the epoll data of the eventfd; devices are registered by their index
+const WAKEUP: u64 = u64::MAX;
+
+pub struct EventLoop {
+    stack: Stack,
+    epoll: RawFd,
+    // written to by the timers when they change
+    wakeup: RawFd,
+    // by the index registered with epoll
+    devices: Vec<Arc<dyn LinkDevice>>,
+}
+
+impl EventLoop {
+    /// takes over the timers of `stack`, which must be on the system clock; a simulator
+    /// drives its own stacks.
+    pub fn new(stack: &Stack) -> Result<EventLoop, Error> {
+        if let Clock::Virtual(_) = stack.clock() {
+            return Err(Error::Invalid(
+                "a simulated stack is driven by its simulator".to_string(),
+            ));
+        }
+        let epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        let wakeup = match eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK) {
+            Ok(wakeup) => wakeup,
+            Err(err) => {
+                unistd::close(epoll)?;
+                return Err(err.into());
+            }
+        };
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, WAKEUP);
+        let result = epoll_ctl(epoll, EpollOp::EpollCtlAdd, wakeup, &mut event)
+            .map_err(Error::from)
+            .and_then(|_| timer::take_over(stack, wakeup));
+        if let Err(err) = result {
+            unistd::close(wakeup)?;
+            unistd::close(epoll)?;
+            return Err(err);
+        }
+        Ok(EventLoop {
+            stack: stack.clone(),
+            epoll,
+            wakeup,
+            devices: vec![],
+        })
+    }
+
+    /// has the loop receive on `device`, which must not be `run` as well.
+    pub fn add<D: LinkDevice + Clone + 'static>(&mut self, device: &D) -> Result<(), Error> {
+        let fd = check_device(&self.stack, &self.devices, device)?;
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, self.devices.len() as u64);
+        epoll_ctl(self.epoll, EpollOp::EpollCtlAdd, fd, &mut event)?;
+        self.devices.push(Arc::new(device.clone()));
+        Ok(())
+    }
+
+    /// Waits for frames or the next timer, at most `timeout` (or for ever with `None`),
+    /// then handles one frame from each ready device and every due timer. Returns how
+    /// many frames and timers were handled.
+    pub fn poll_once(&mut self, timeout: Option<Duration>) -> Result<usize, Error> {
+        let now = self.stack.now();
+        let until_timer = timer::next_deadline(&self.stack).map(|deadline| {
+            (deadline - now)
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0))
+        });
+        let wait = match (timeout, until_timer) {
+            (Some(timeout), Some(until_timer)) => Some(timeout.min(until_timer)),
+            (timeout, until_timer) => timeout.or(until_timer),
+        };
+        let timeout_ms = match wait {
+            // rounded up, so as not to wake just before a timer is due
+            Some(wait) => wait.as_micros().div_ceil(1000) as isize,
+            None => -1,
+        };
+        let mut events = [EpollEvent::empty(); EVENTS_MAX];
+        let ready = match epoll_wait(self.epoll, &mut events, timeout_ms) {
+            Ok(ready) => ready,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => 0,
+            Err(err) => return Err(err.into()),
+        };
+        let mut handled = 0;
+        for event in events[..ready].iter() {
+            if event.data() == WAKEUP {
+                // only there to end the wait; the deadlines are looked at below
+                let mut count = [0; 8];
+                if let Err(err) = unistd::read(self.wakeup, &mut count) {
+                    warn!("reading the eventfd failed: {}", err);
+                }
+                continue;
+            }
+            let device = &self.devices[event.data() as usize];
+            // each rejected frame is counted in `stats` too
+            if let Err(err) = device.receive() {
+                log_rx_error!(device.name(), err);
+            }
+            handled += 1;
+        }
+        handled += timer::run_due(&self.stack);
+        Ok(handled)
+    }
+
+    /// polls for ever, or until an error.
+    pub fn run(&mut self) -> Result<(), Error> {
+        loop {
+            self.poll_once(None)?;
+        }
+    }
+}
+
+// the descriptor to wait on for `device`, if a loop of `stack` already driving `devices`
+// can take it
+fn check_device(
+    stack: &Stack,
+    devices: &[Arc<dyn LinkDevice>],
+    device: &dyn LinkDevice,
+) -> Result<RawFd, Error> {
+    let name = device.name();
+    if device.stack() != *stack {
+        return Err(Error::Invalid(format!(
+            "`{}` belongs to another stack",
+            name
+        )));
+    }
+    if device.is_running() {
+        return Err(Error::Exists(format!("receive thread of `{}`", name)));
+    }
+    if devices.iter().any(|device_| device_.name() == name) {
+        return Err(Error::Exists(format!("`{}` in the event loop", name)));
+    }
+    device
+        .fd()
+        .ok_or_else(|| Error::Invalid(format!("`{}` has no descriptor to poll", name)))
+}
+
+impl Drop for EventLoop {
+    // the devices go back to being unattended, but the timers to their thread
+    fn drop(&mut self) {
+        timer::hand_back(&self.stack);
+        for fd in [self.epoll, self.wakeup].iter() {
+            if let Err(err) = unistd::close(*fd) {
+                warn!("closing the event loop failed: {}", err);
+            }
+        }
+    }
+}
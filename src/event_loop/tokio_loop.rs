@@ -0,0 +1,160 @@
+// the event loop as tokio tasks, for applications that already run a tokio runtime
+//
+// Each device gets a task that waits for its descriptor through the runtime's reactor,
+// and `run` handles the stack's timers, woken through the same eventfd as `EventLoop`.
+// Frames and timers are still handled synchronously, so a task never holds a lock of the
+// stack across an await.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::unistd;
+use tokio::io::unix::AsyncFd;
+use tokio::task::JoinSet;
+
+use super::{check_device, EVENTS_MAX};
+use crate::{clock::Clock, error::Error, link::LinkDevice, stack::Stack, timer};
+
+// a descriptor of a device, which stays the device's to close
+struct DeviceFd(RawFd);
+
+impl AsRawFd for DeviceFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+struct EventFd(RawFd);
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        if let Err(err) = unistd::close(self.0) {
+            warn!("closing the event loop failed: {}", err);
+        }
+    }
+}
+
+pub struct TokioEventLoop {
+    stack: Stack,
+    // written to by the timers when they change
+    wakeup: AsyncFd<EventFd>,
+    devices: Vec<Arc<dyn LinkDevice>>,
+}
+
+impl TokioEventLoop {
+    /// takes over the timers of `stack`, which must be on the system clock; to be called
+    /// from within a tokio runtime with IO and time enabled.
+    pub fn new(stack: &Stack) -> Result<TokioEventLoop, Error> {
+        if let Clock::Virtual(_) = stack.clock() {
+            return Err(Error::Invalid(
+                "a simulated stack is driven by its simulator".to_string(),
+            ));
+        }
+        let wakeup = EventFd(eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?);
+        let wakeup = AsyncFd::new(wakeup)?;
+        timer::take_over(stack, wakeup.as_raw_fd())?;
+        Ok(TokioEventLoop {
+            stack: stack.clone(),
+            wakeup,
+            devices: vec![],
+        })
+    }
+
+    /// has the loop receive on `device`, which must not be `run` as well.
+    pub fn add<D: LinkDevice + Clone + 'static>(&mut self, device: &D) -> Result<(), Error> {
+        check_device(&self.stack, &self.devices, device)?;
+        self.devices.push(Arc::new(device.clone()));
+        Ok(())
+    }
+
+    /// Receives on every device and runs the timers, for ever or until an error. Dropping
+    /// the future stops the device tasks along with it.
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut tasks = JoinSet::new();
+        for device in self.devices.iter() {
+            // checked by `add`
+            let fd = AsyncFd::new(DeviceFd(device.fd().unwrap()))?;
+            tasks.spawn(receive(device.clone(), fd));
+        }
+        loop {
+            timer::run_due(&self.stack);
+            let now = self.stack.now();
+            let until_timer = timer::next_deadline(&self.stack).map(|deadline| {
+                (deadline - now)
+                    .to_std()
+                    .unwrap_or_else(|_| Duration::from_secs(0))
+            });
+            let readable = self.wakeup.readable();
+            let guard = match until_timer {
+                Some(wait) => match tokio::time::timeout(wait, readable).await {
+                    Ok(guard) => Some(guard?),
+                    Err(_) => None,
+                },
+                None => Some(readable.await?),
+            };
+            if let Some(mut guard) = guard {
+                // only there to end the wait; the deadlines are looked at above
+                let mut count = [0; 8];
+                match unistd::read(self.wakeup.as_raw_fd(), &mut count) {
+                    Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {}
+                    Err(err) => warn!("reading the eventfd failed: {}", err),
+                }
+                guard.clear_ready();
+            }
+        }
+    }
+}
+
+impl Drop for TokioEventLoop {
+    // the devices go back to being unattended, but the timers to their thread
+    fn drop(&mut self) {
+        timer::hand_back(&self.stack);
+    }
+}
+
+// the task of one device: every frame it has, each time its descriptor turns readable
+async fn receive(device: Arc<dyn LinkDevice>, fd: AsyncFd<DeviceFd>) {
+    loop {
+        let mut guard = match fd.readable().await {
+            Ok(guard) => guard,
+            Err(err) => {
+                warn!("waiting on `{}` failed: {}", device.name(), err);
+                return;
+            }
+        };
+        let mut handled = 0;
+        loop {
+            // each rejected frame is counted in `stats` too
+            if let Err(err) = device.receive() {
+                log_rx_error!(device.name(), err);
+            }
+            handled += 1;
+            if !is_readable(fd.as_raw_fd()) {
+                break;
+            }
+            // leave the other tasks a turn on a busy link
+            if handled % EVENTS_MAX == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+        // the reactor reports the descriptor only when it turns readable again
+        guard.clear_ready();
+    }
+}
+
+fn is_readable(fd: RawFd) -> bool {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 0) > 0 }
+}
@@ -1,7 +1,16 @@
-use crate::{buffer::Buffer, ip, packet, protocol, util};
-use std::error::Error;
+use crate::{
+    buffer::Buffer,
+    error::{Error, Layer},
+    ip, packet, protocol,
+    stack::Stack,
+    stats, util,
+};
+use chrono::Timelike;
+use std::collections::HashMap;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -17,6 +26,8 @@ pub enum Type {
     TimestampReply = 14,
     InfoRequest = 15,
     InfoReply = 16,
+    AddressMaskRequest = 17,
+    AddressMaskReply = 18,
 }
 
 impl Type {
@@ -43,6 +54,10 @@ impl Type {
             Type::InfoRequest
         } else if n == Type::InfoReply as u8 {
             Type::InfoReply
+        } else if n == Type::AddressMaskRequest as u8 {
+            Type::AddressMaskRequest
+        } else if n == Type::AddressMaskReply as u8 {
+            Type::AddressMaskReply
         } else {
             return None;
         })
@@ -66,6 +81,8 @@ impl fmt::Display for Type {
                 Type::TimestampReply => "Timestamp Reply",
                 Type::InfoRequest => "Information Request",
                 Type::InfoReply => "Information Reply",
+                Type::AddressMaskRequest => "Address Mask Request",
+                Type::AddressMaskReply => "Address Mask Reply",
             }
         )
     }
@@ -194,11 +211,49 @@ impl fmt::Display for CodeExceeded {
     }
 }
 
+// for ParamProblem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CodeParamProblem {
+    Pointer = 0,
+    MissingOption = 1,
+    BadLength = 2,
+}
+
+impl CodeParamProblem {
+    pub fn from_u8(n: u8) -> Option<CodeParamProblem> {
+        Some(if n == CodeParamProblem::Pointer as u8 {
+            CodeParamProblem::Pointer
+        } else if n == CodeParamProblem::MissingOption as u8 {
+            CodeParamProblem::MissingOption
+        } else if n == CodeParamProblem::BadLength as u8 {
+            CodeParamProblem::BadLength
+        } else {
+            return None;
+        })
+    }
+}
+
+impl fmt::Display for CodeParamProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CodeParamProblem::Pointer => "Pointer",
+                CodeParamProblem::MissingOption => "Missing Option",
+                CodeParamProblem::BadLength => "Bad Length",
+            }
+        )
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Code {
     Unreach(CodeUnreach),
     Redirect(CodeRedirect),
     Exceeded(CodeExceeded),
+    ParamProblem(CodeParamProblem),
     Others(u8),
 }
 
@@ -208,16 +263,18 @@ impl Code {
             Type::DestUnreach => Code::Unreach(CodeUnreach::from_u8(n)?),
             Type::Redirect => Code::Redirect(CodeRedirect::from_u8(n)?),
             Type::TimeExceeded => Code::Exceeded(CodeExceeded::from_u8(n)?),
+            Type::ParamProblem => Code::ParamProblem(CodeParamProblem::from_u8(n)?),
             _ => Code::Others(n),
         })
     }
 
-    fn to_u8(&self) -> u8 {
+    fn to_u8(self) -> u8 {
         match self {
-            Code::Unreach(code) => *code as u8,
-            Code::Redirect(code) => *code as u8,
-            Code::Exceeded(code) => *code as u8,
-            Code::Others(n) => *n,
+            Code::Unreach(code) => code as u8,
+            Code::Redirect(code) => code as u8,
+            Code::Exceeded(code) => code as u8,
+            Code::ParamProblem(code) => code as u8,
+            Code::Others(n) => n,
         }
     }
 }
@@ -232,11 +289,14 @@ struct IcmpFrame {
 }
 
 impl IcmpFrame {
-    pub fn dump(&self) {
-        eprintln!("type: {}", self.type_);
-        eprintln!("code: {:?}", self.code);
-        eprintln!("sum: {}", self.sum);
-        eprintln!("{}", self.payload);
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "type: {}", self.type_);
+        let _ = writeln!(out, "code: {:?}", self.code);
+        let _ = writeln!(out, "sum: {}", self.sum);
+        let _ = writeln!(out, "{}", self.payload);
+        out
     }
 
     fn write_checksum(buf: &mut Buffer, sum: u16) {
@@ -244,26 +304,36 @@ impl IcmpFrame {
     }
 }
 
+impl fmt::Display for IcmpFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} code {} values {:08x} {} bytes",
+            self.type_,
+            self.code.to_u8(),
+            self.values,
+            self.payload.0.len()
+        )
+    }
+}
+
 impl packet::Packet<IcmpFrame> for IcmpFrame {
-    fn from_buffer(mut buf: Buffer) -> Result<Self, Box<dyn Error>> {
+    const LAYER: Layer = Layer::Icmp;
+
+    fn from_buffer(mut buf: Buffer) -> Result<Self, Error> {
         let n = buf.pop_u8("type")?;
-        let type_ = Type::from_u8(n).ok_or(util::RuntimeError::new(format!(
-            "{} can not be ICMP type.",
-            n
-        )))?;
+        let type_ = Type::from_u8(n).ok_or_else(|| Error::parse("type", format!("unknown: {}", n)))?;
         let n = buf.pop_u8("code")?;
-        let code = Code::from_u8(n, type_).ok_or(util::RuntimeError::new(format!(
-            "{} can not be ICMP code under {}",
-            n, type_
-        )))?;
+        let code = Code::from_u8(n, type_)
+            .ok_or_else(|| Error::parse("code", format!("unknown under {}: {}", type_, n)))?;
         let sum = buf.pop_u16("sum")?;
         let values = buf.pop_u32("values")?;
 
         Ok(IcmpFrame {
-            type_: type_,
-            code: code,
-            sum: sum,
-            values: values,
+            type_,
+            code,
+            sum,
+            values,
             payload: buf,
         })
     }
@@ -271,40 +341,257 @@ impl packet::Packet<IcmpFrame> for IcmpFrame {
         let mut buffer = Buffer::new(64 + self.payload.0.len());
         buffer.push_u8(self.type_ as u8);
         buffer.push_u8(self.code.to_u8());
-        buffer.push_u32(self.values);
         buffer.push_u16(self.sum);
+        buffer.push_u32(self.values);
         buffer.append(self.payload);
         buffer
     }
 }
 
+const TIMESTAMP_PAYLOAD_LEN: usize = 12;
+const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// milliseconds since midnight UT on the stack's clock, as carried in Timestamp messages (RFC 792)
+pub fn ms_since_midnight(stack: &Stack) -> u32 {
+    let now = stack.now();
+    now.num_seconds_from_midnight() * 1000 + now.timestamp_subsec_millis() % 1000
+}
+
+/// `a - b` in milliseconds, taking the wrap around midnight into account
+fn ms_diff(a: u32, b: u32) -> i64 {
+    let diff = (a as i64 - b as i64).rem_euclid(MS_PER_DAY);
+    if diff > MS_PER_DAY / 2 {
+        diff - MS_PER_DAY
+    } else {
+        diff
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampReply {
+    pub originate: u32,
+    pub receive: u32,
+    pub transmit: u32,
+    pub arrival: u32,
+}
+
+impl TimestampReply {
+    /// estimated offset of the peer's clock from ours in milliseconds
+    pub fn offset(&self) -> i64 {
+        (ms_diff(self.receive, self.originate) + ms_diff(self.transmit, self.arrival)) / 2
+    }
+
+    /// round trip time in milliseconds, excluding the time spent in the peer
+    pub fn round_trip(&self) -> i64 {
+        ms_diff(self.arrival, self.originate) - ms_diff(self.transmit, self.receive)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    // pending Timestamp requests by their identifier and sequence number, with their peer
+    timestamp_replies: Mutex<HashMap<u32, (ip::Addr, Option<TimestampReply>)>>,
+    timestamp_replied: Condvar,
+    timestamp_seq: Mutex<u16>,
+    // whether Address Mask Requests are answered
+    address_mask_agent: AtomicBool,
+}
+
+/// makes the stack answer Address Mask Requests, which only an authoritative agent may do
+/// (RFC 1122 3.2.2.9); off by default.
+pub fn set_address_mask_agent(stack: &Stack, b: bool) {
+    stack.0.icmp.address_mask_agent.store(b, Ordering::Relaxed);
+}
+
+/// whether an error about a datagram from `src` to `dst` may be sent: never about one to a
+/// broadcast or multicast address, nor from an address that is not a single host (RFC 1122
+/// 3.2.2).
+pub fn may_report(interface: &ip::interface::Interface, src: &ip::Addr, dst: &ip::Addr) -> bool {
+    let broadcast = {
+        let interface = interface.0.lock().unwrap();
+        interface.unicast.apply_mask(&interface.netmask) | !interface.netmask
+    };
+    let is_group = |addr: &ip::Addr| {
+        *addr == ip::Addr::full() || *addr == broadcast || addr.is_multicast()
+    };
+    *src != ip::Addr::empty() && !is_group(src) && !is_group(dst)
+}
+
+/// sends a Timestamp request to `dst` and waits for the matching reply.
+pub fn timestamp(
+    interface: &ip::interface::Interface,
+    dst: &ip::Addr,
+    timeout: Duration,
+) -> Result<TimestampReply, Error> {
+    let stack = interface.stack();
+    let state = &stack.0.icmp;
+    let values = {
+        let mut seq = state.timestamp_seq.lock().unwrap();
+        *seq = seq.wrapping_add(1);
+        ((::std::process::id() as u16 as u32) << 16) | *seq as u32
+    };
+    state
+        .timestamp_replies
+        .lock()
+        .unwrap()
+        .insert(values, (*dst, None));
+
+    let mut payload = Buffer::new(TIMESTAMP_PAYLOAD_LEN);
+    payload.push_u32(ms_since_midnight(&stack));
+    payload.push_u32(0);
+    payload.push_u32(0);
+    if let Err(err) = self::tx(
+        interface,
+        Type::Timestamp,
+        Code::Others(0),
+        values,
+        payload,
+        dst,
+    ) {
+        state.timestamp_replies.lock().unwrap().remove(&values);
+        return Err(err);
+    }
+
+    let replies = state.timestamp_replies.lock().unwrap();
+    let (mut replies, _) = stack.clock().wait_timeout_while(
+        &state.timestamp_replied,
+        replies,
+        timeout,
+        |replies| {
+            replies
+                .get(&values)
+                .map(|(_, reply)| reply.is_none())
+                .unwrap_or(false)
+        },
+    );
+    match replies.remove(&values) {
+        Some((_, Some(reply))) => Ok(reply),
+        _ => Err(Error::Timeout),
+    }
+}
+
+fn rx_timestamp_reply(stack: &Stack, frame: IcmpFrame, src: &ip::Addr) -> Result<(), Error> {
+    let arrival = ms_since_midnight(stack);
+    let mut payload = frame.payload;
+    let reply = TimestampReply {
+        originate: payload.pop_u32("originate timestamp")?,
+        receive: payload.pop_u32("receive timestamp")?,
+        transmit: payload.pop_u32("transmit timestamp")?,
+        arrival,
+    };
+    let mut replies = stack.0.icmp.timestamp_replies.lock().unwrap();
+    // another host's reply may carry the same identifier and sequence number
+    match replies.get_mut(&frame.values) {
+        Some((peer, pending)) if peer == src => {
+            *pending = Some(reply);
+            stack.0.icmp.timestamp_replied.notify_all();
+        }
+        _ => stats::dropped(stack, "icmp: unexpected timestamp reply"),
+    }
+    Ok(())
+}
+
 pub fn rx(
     packet: Buffer,
     src: &ip::Addr,
     _dst: &ip::Addr,
     interface: &ip::interface::Interface,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error> {
     use packet::Packet;
-    let frame = IcmpFrame::from_buffer(packet)?;
+    let stack = interface.stack();
+    let type_ = packet.0.front().cloned();
+    stats::update(&stack, |s| {
+        s.icmp.in_msgs += 1;
+        if let Some(type_) = type_ {
+            *s.icmp.in_types.entry(type_).or_insert(0) += 1;
+        }
+    });
+    let frame = match IcmpFrame::parse(packet) {
+        Ok(frame) => frame,
+        Err(err) => {
+            stats::update(&stack, |s| s.icmp.in_errors += 1);
+            stats::dropped(&stack, "icmp: malformed message");
+            return Err(err);
+        }
+    };
 
-    if cfg!(debug_assertions) {
-        eprintln!(">>> icmp rx <<<");
-        frame.dump();
-    }
+    log_packet!("rx", frame);
 
-    if frame.type_ == Type::Echo {
-        self::tx(
+    match frame.type_ {
+        Type::Echo => self::tx(
             interface,
             Type::EchoReply,
             frame.code,
             frame.values,
             frame.payload,
             src,
-        )?;
+        )?,
+        Type::Timestamp => {
+            let receive = ms_since_midnight(&stack);
+            let mut payload = frame.payload;
+            let originate = payload.pop_u32("originate timestamp")?;
+            let mut reply = Buffer::new(TIMESTAMP_PAYLOAD_LEN);
+            reply.push_u32(originate);
+            reply.push_u32(receive);
+            reply.push_u32(ms_since_midnight(&stack));
+            self::tx(
+                interface,
+                Type::TimestampReply,
+                Code::Others(0),
+                frame.values,
+                reply,
+                src,
+            )?
+        }
+        Type::TimestampReply => rx_timestamp_reply(&stack, frame, src)?,
+        Type::AddressMaskRequest if stack.0.icmp.address_mask_agent.load(Ordering::Relaxed) => {
+            let netmask = interface.0.lock().unwrap().netmask;
+            let mut reply = Buffer::new(ip::ADDR_LEN);
+            reply.push_ip_addr(netmask);
+            // a requester without an address yet can only hear a broadcast (RFC 950)
+            let dst = if *src == ip::Addr::empty() {
+                ip::Addr::full()
+            } else {
+                *src
+            };
+            self::tx(
+                interface,
+                Type::AddressMaskReply,
+                Code::Others(0),
+                frame.values,
+                reply,
+                &dst,
+            )?
+        }
+        _ => (),
     }
     Ok(())
 }
 
+/// cuts `dgram` down to its IP header plus the first 64 bits of data for an error message
+pub fn quote(mut dgram: Buffer) -> Buffer {
+    let header_len = ((dgram.0.front().cloned().unwrap_or(0) & 0x0f) as usize) << 2;
+    dgram.0.truncate(header_len.max(ip::dgram::HEADER_MIN_SIZE) + 8);
+    dgram
+}
+
+/// reports the invalid octet at `pointer` in `dgram` back to its sender.
+pub fn param_problem(
+    interface: &ip::interface::Interface,
+    dgram: Buffer,
+    pointer: u8,
+    dst: &ip::Addr,
+) -> Result<(), Error> {
+    self::tx(
+        interface,
+        Type::ParamProblem,
+        Code::ParamProblem(CodeParamProblem::Pointer),
+        (pointer as u32) << 24,
+        quote(dgram),
+        dst,
+    )
+}
+
 pub fn tx(
     interface: &ip::interface::Interface,
     type_: Type,
@@ -312,19 +599,16 @@ pub fn tx(
     values: u32,
     payload: Buffer,
     dst: &ip::Addr,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error> {
     let frame: IcmpFrame = IcmpFrame {
-        type_: type_,
-        code: code,
-        values: values,
+        type_,
+        code,
+        values,
         sum: 0,
-        payload: payload,
+        payload,
     };
 
-    if cfg!(debug_assertions) {
-        eprintln!(">>> icmp tx <<<");
-        frame.dump();
-    }
+    log_packet!("tx", frame);
 
     use packet::Packet;
     let buf_vec = frame.to_buffer().to_vec();
@@ -332,7 +616,16 @@ pub fn tx(
     let mut buf = Buffer::from_vec(buf_vec);
     IcmpFrame::write_checksum(&mut buf, sum);
 
-    interface.tx(protocol::ProtocolType::Icmp, buf, dst)
+    let ret = interface.tx(protocol::ProtocolType::Icmp, buf, dst);
+    let stack = interface.stack();
+    stats::update(&stack, |s| {
+        s.icmp.out_msgs += 1;
+        *s.icmp.out_types.entry(type_ as u8).or_insert(0) += 1;
+        if ret.is_err() {
+            s.icmp.out_errors += 1;
+        }
+    });
+    ret
 }
 
 pub fn length(dgram: &ip::dgram::Dgram) -> usize {
@@ -341,12 +634,6 @@ pub fn length(dgram: &ip::dgram::Dgram) -> usize {
 
 pub struct IcmpProtocol {}
 
-impl IcmpProtocol {
-    pub fn new() -> Arc<dyn protocol::Protocol + Send + Sync> {
-        Arc::new(IcmpProtocol {})
-    }
-}
-
 impl protocol::Protocol for IcmpProtocol {
     fn type_(&self) -> protocol::ProtocolType {
         protocol::ProtocolType::Icmp
@@ -357,7 +644,7 @@ impl protocol::Protocol for IcmpProtocol {
         src: ip::Addr,
         dst: ip::Addr,
         interface: &ip::interface::Interface,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), Error> {
         self::rx(payload, &src, &dst, interface)
     }
 }
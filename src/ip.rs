@@ -1,21 +1,23 @@
-use arrayvec::ArrayVec;
-use std::error::Error;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread::JoinHandle;
+use std::str::FromStr;
+use std::sync::Mutex;
 
 use crate::{
     buffer::Buffer,
-    ethernet, icmp,
+    error::{Error, Layer},
+    icmp,
     ip::interface::Interface,
+    link::LinkDevice,
     packet,
-    protocol::{ProtocolType, PROTOCOLS},
-    util,
+    protocol::ProtocolType,
+    stack::Stack,
+    stats, util,
 };
 
 pub mod dgram;
-mod fragment;
+pub(crate) mod fragment;
 pub mod interface;
-mod route;
+pub mod route;
 
 pub const VERSION: u8 = 4;
 
@@ -23,9 +25,25 @@ pub const ADDR_LEN: usize = 4;
 const ADDR_ANY: Addr = Addr([0; ADDR_LEN]);
 const ADDR_BROADCAST: Addr = Addr([255; ADDR_LEN]);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Addr(pub [u8; ADDR_LEN]);
 
+impl FromStr for Addr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::Invalid(format!("invalid ip address: {}", s));
+        if s.split('.').count() != ADDR_LEN {
+            return Err(invalid());
+        }
+        let mut addr = [0; ADDR_LEN];
+        for (octet, n) in addr.iter_mut().zip(s.split('.')) {
+            *octet = n.parse().map_err(|_| invalid())?;
+        }
+        Ok(Self(addr))
+    }
+}
+
 impl Addr {
     pub fn empty() -> Self {
         Addr([0; ADDR_LEN])
@@ -34,17 +52,14 @@ impl Addr {
         Addr([0xff; ADDR_LEN])
     }
 
-    pub fn from_str(str: &String) -> Result<Self, Box<dyn Error>> {
-        str.split('.')
-            .map(|n| u8::from_str_radix(n, 10))
-            .collect::<Result<ArrayVec<[_; 4]>, _>>()
-            .map(|arr| Self(arr.into_inner().unwrap()))
-            .or_else(|err| Err(util::RuntimeError::new(format!("{}", err))))
-    }
     pub fn as_u32(&self) -> u32 {
         unsafe { ::std::mem::transmute(*self) }
     }
 
+    /// true for class D, 224.0.0.0/4
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xf0 == 0xe0
+    }
     pub fn apply_mask(&self, mask: &Addr) -> Addr {
         Addr([
             self.0[0] & mask.0[0],
@@ -83,146 +98,424 @@ impl fmt::Display for Addr {
     }
 }
 
-lazy_static! {
-    static ref IS_FORWARDING: AtomicBool = AtomicBool::new(false);
+pub(crate) struct State {
+    is_forwarding: AtomicBool,
+    routes: Mutex<Vec<route::Route>>,
+    // every interface made on the stack, which devices only refer to weakly
+    interfaces: Mutex<Vec<Interface>>,
+    id_counter: Mutex<u16>,
+    fragments: fragment::State,
 }
 
-pub fn set_is_forwarding(b: bool) {
-    IS_FORWARDING.store(b, Ordering::Relaxed);
+impl Default for State {
+    fn default() -> State {
+        State {
+            is_forwarding: AtomicBool::new(false),
+            routes: Mutex::new(vec![]),
+            interfaces: Mutex::new(vec![]),
+            id_counter: Mutex::new(128),
+            fragments: fragment::State::default(),
+        }
+    }
 }
 
-fn forward_process(mut dgram: dgram::Dgram, interface: &Interface) -> Result<(), Box<dyn Error>> {
+// drops the routes and interfaces, for `Stack::shutdown`
+pub(crate) fn clear(stack: &Stack) {
+    stack.0.ip.routes.lock().unwrap().clear();
+    stack.0.ip.interfaces.lock().unwrap().clear();
+}
+
+pub fn set_is_forwarding(stack: &Stack, b: bool) {
+    stack.0.ip.is_forwarding.store(b, Ordering::Relaxed);
+}
+
+// tells the sender of a datagram that could not be forwarded why, unless it must not be
+fn report_unforwarded(
+    interface: &Interface,
+    dgram: &dgram::Dgram,
+    type_: icmp::Type,
+    code: icmp::Code,
+    values: u32,
+) -> Result<(), Error> {
     use packet::Packet;
-    if dgram.time_to_live != 1 {
-        let src = dgram.src;
-        icmp::tx(
+    if !icmp::may_report(interface, &dgram.src, &dgram.dst) {
+        return Ok(());
+    }
+    let quoted = icmp::quote(dgram.clone().to_buffer());
+    icmp::tx(interface, type_, code, values, quoted, &dgram.src)
+}
+
+fn forward_process(mut dgram: dgram::Dgram, interface: &Interface) -> Result<(), Error> {
+    use packet::Packet;
+    let stack = interface.stack();
+    if dgram.time_to_live <= 1 {
+        stats::update(&stack, |s| s.ip.in_hdr_errors += 1);
+        stats::dropped(&stack, "ip: time exceeded");
+        report_unforwarded(
             interface,
+            &dgram,
             icmp::Type::TimeExceeded,
             icmp::Code::Exceeded(icmp::CodeExceeded::Ttl),
             0,
-            dgram.to_buffer(),
-            &src,
         )?;
-        return Err(util::RuntimeError::new(format!("time exceeded")));
+        return Err(Error::Dropped("ip: time exceeded"));
     }
-    let route = match route::lookup(Some(interface), dgram.dst) {
+    // the way on may be through any interface, not just the one it came in on
+    let route = match route::lookup(&stack, None, dgram.dst) {
         Some(route) => route,
         None => {
-            let src = dgram.src;
-            icmp::tx(
+            stats::update(&stack, |s| s.ip.out_no_routes += 1);
+            stats::dropped(&stack, "ip: no route");
+            report_unforwarded(
                 interface,
+                &dgram,
                 icmp::Type::DestUnreach,
                 icmp::Code::Unreach(icmp::CodeUnreach::Net),
                 0,
-                dgram.to_buffer(),
-                &src,
             )?;
-            return Err(util::RuntimeError::new(format!("destination unreach")));
+            return Err(Error::NoRoute(dgram.dst));
         }
     };
-    {
+    let (route_device, route_unicast) = {
         let route_interface = route.interface.0.lock().unwrap();
-        let route_device = route_interface.device.clone();
-        if route_interface.unicast == dgram.dst {
-            rx(dgram.to_buffer(), &route_device)?;
-            return Ok(());
-        }
+        (route_interface.device.clone(), route_interface.unicast)
+    };
+    if route_unicast == dgram.dst {
+        rx(dgram.to_buffer(), &*route_device)?;
+        return Ok(());
     }
-    if dgram.offset & 0x4000 != 0 && dgram.payload.0.len() > ethernet::PAYLOAD_SIZE_MAX {
-        let src = dgram.src;
-        return icmp::tx(
+    let mtu = route_device.mtu();
+    if dgram.offset & 0x4000 != 0 && dgram.len as usize > mtu {
+        stats::update(&stack, |s| s.ip.frag_fails += 1);
+        stats::dropped(&stack, "ip: fragmentation needed");
+        // the next-hop MTU goes in the low half (RFC 1191)
+        return report_unforwarded(
             interface,
+            &dgram,
             icmp::Type::DestUnreach,
             icmp::Code::Unreach(icmp::CodeUnreach::FragmentNeeded),
-            0,
-            dgram.to_buffer(),
-            &src,
+            mtu.min(0xffff) as u32,
         );
-        // return Err(util::RuntimeError::new(format!("destination unreach")));
     }
+    let checksum = dgram.checksum;
     dgram.time_to_live -= 1;
-    let sum = dgram.checksum;
+    dgram.checksum = 0;
     let len = ((dgram.version_header_length & 0x0f) as usize) << 2;
     let buf_vec = dgram.clone().to_buffer().to_vec();
-    let sum = util::calc_checksum(buf_vec.as_slice(), len, (u16::max_value() - sum) as u32);
+    let sum = util::calc_checksum(buf_vec.as_slice(), len, 0);
     let mut buf = Buffer::from_vec(buf_vec);
     dgram::Dgram::write_checksum(&mut buf, sum);
     let ret = route.interface.tx_device(
         buf,
         &match route.nexthop {
             Some(next) => Some(next),
-            None => Some(dgram.src),
+            None => Some(dgram.dst),
         },
     );
     match ret {
-        Ok(()) => Ok(()),
+        Ok(()) => {
+            stats::update(&stack, |s| s.ip.forw_datagrams += 1);
+            Ok(())
+        }
         Err(_) => {
-            // restore original IP header
+            stats::update(&stack, |s| s.ip.out_discards += 1);
+            stats::dropped(&stack, "ip: forwarding failed");
+            // quote the header as it came in
             dgram.time_to_live += 1;
-            dgram.checksum = sum;
-
-            let src = dgram.src;
-
-            icmp::tx(
+            dgram.checksum = checksum;
+            report_unforwarded(
                 interface,
+                &dgram,
                 icmp::Type::DestUnreach,
                 match route.nexthop {
                     Some(_) => icmp::Code::Unreach(icmp::CodeUnreach::Net),
                     None => icmp::Code::Unreach(icmp::CodeUnreach::Host),
                 },
                 0,
-                dgram.to_buffer(),
-                &src,
             )
         }
     }
 }
 
 pub fn rx(
-    dgram: Buffer,
-    device: &ethernet::Device,
-) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+    buf: Buffer,
+    device: &dyn LinkDevice,
+) -> Result<(), Error> {
     use packet::Packet;
-    let dgram = dgram::Dgram::from_buffer(dgram)?;
-    let device = device.0.lock().unwrap();
-    let interface = device
-        .interface
-        .as_ref()
-        .ok_or(util::RuntimeError::new(format!(
-            "device `{}` has not ip interface.",
-            device.name
-        )))?;
+    let stack = device.stack();
+    stats::update(&stack, |s| s.ip.in_receives += 1);
+    let interface = match device.interface() {
+        Some(interface) => interface,
+        None => {
+            stats::update(&stack, |s| s.ip.in_discards += 1);
+            stats::dropped(&stack, "ip: no interface");
+            return Err(Error::Dropped("ip: no interface"));
+        }
+    };
+    let interface = &interface;
+    if buf.0.len() < dgram::HEADER_MIN_SIZE {
+        stats::update(&stack, |s| s.ip.in_hdr_errors += 1);
+        stats::dropped(&stack, "ip: too short");
+        return Err(
+            Error::parse("header", format!("too short: {} bytes", buf.0.len())).in_layer(Layer::Ip),
+        );
+    }
+    if buf.0[0] >> 4 != VERSION {
+        stats::update(&stack, |s| s.ip.in_hdr_errors += 1);
+        stats::dropped(&stack, "ip: unsupported version");
+        // e.g. IPv6 on a TUN device, not ours to complain about
+        return Err(Error::Dropped("ip: unsupported version"));
+    }
+    // nothing past the header length can be trusted, not even enough to report on, until
+    // the checksum over it holds
+    let header_len = ((buf.0[0] & 0x0f) as usize) << 2;
+    if header_len < dgram::HEADER_MIN_SIZE || header_len > buf.0.len() {
+        stats::update(&stack, |s| s.ip.in_hdr_errors += 1);
+        stats::dropped(&stack, "ip: bad header length");
+        return Err(
+            Error::parse("header length", format!("{} bytes", header_len)).in_layer(Layer::Ip),
+        );
+    }
+    if !dgram::Dgram::verify_checksum(&buf) {
+        stats::update(&stack, |s| s.ip.in_hdr_errors += 1);
+        stats::dropped(&stack, "ip: bad checksum");
+        return Err(Error::parse("header checksum", "mismatch").in_layer(Layer::Ip));
+    }
+    if let Some(pointer) = dgram::Dgram::problem_pointer(&buf) {
+        stats::update(&stack, |s| s.ip.in_hdr_errors += 1);
+        stats::dropped(&stack, "ip: parameter problem");
+        let src = Addr([buf.0[12], buf.0[13], buf.0[14], buf.0[15]]);
+        let dst = Addr([buf.0[16], buf.0[17], buf.0[18], buf.0[19]]);
+        if icmp::may_report(interface, &src, &dst) {
+            icmp::param_problem(interface, buf, pointer, &src)?;
+        }
+        return Err(
+            Error::parse("header", format!("problem at octet {}", pointer)).in_layer(Layer::Ip),
+        );
+    }
+    // the header is sound by now, so only the protocol field can be rejected
+    let dgram = match dgram::Dgram::parse(buf) {
+        Ok(dgram) => dgram,
+        Err(err) => {
+            stats::update(&stack, |s| s.ip.in_unknown_protos += 1);
+            stats::dropped(&stack, "ip: unknown protocol");
+            return Err(err);
+        }
+    };
     let (unicast, broadcast) = {
         let interface = interface.0.lock().unwrap();
         let network = interface.unicast.apply_mask(&interface.netmask);
         let broadcast = network | !interface.netmask;
-        (interface.unicast.clone(), broadcast)
+        (interface.unicast, broadcast)
     };
     if dgram.dst != unicast && dgram.dst != broadcast && dgram.dst != ADDR_BROADCAST {
         /* forward to other host */
-        if IS_FORWARDING.load(Ordering::SeqCst) {
+        if stack.0.ip.is_forwarding.load(Ordering::SeqCst) {
             forward_process(dgram, interface)?;
+        } else {
+            stats::update(&stack, |s| s.ip.in_addr_errors += 1);
+            stats::dropped(&stack, "ip: not for us");
         }
-        return Ok(None);
-    }
-    if cfg!(debug_assertions) {
-        eprintln!(">>> ip rx <<<");
-        dgram.dump();
+        return Ok(());
     }
+    log_packet!("rx", dgram);
 
     let (src, dst, protocol_type) = (dgram.src, dgram.dst, dgram.protocol);
-    let payload = if dgram.offset & 0x2000 != 0 || dgram.offset & 0x1ff != 0 {
-        let fragment = fragment::process(dgram)?;
-        fragment.data
+    let payload = if dgram.offset & 0x2000 != 0 || dgram.offset & 0x1fff != 0 {
+        stats::update(&stack, |s| s.ip.reasm_reqds += 1);
+        match fragment::process(&stack, dgram)? {
+            Some(fragment) => fragment.data,
+            None => return Ok(()),
+        }
     } else {
         dgram.payload
     };
-    let protocols = PROTOCOLS.lock().unwrap();
-    for protocol in protocols.iter() {
-        if protocol.type_() == protocol_type {
-            protocol.handler(payload, src, dst, interface)?;
-            return Ok(None);
+    let protocol = {
+        let protocols = stack.0.protocols.lock().unwrap();
+        protocols
+            .iter()
+            .find(|protocol| protocol.type_() == protocol_type)
+            .cloned()
+    };
+    if let Some(protocol) = protocol {
+        stats::update(&stack, |s| s.ip.in_delivers += 1);
+        protocol.handler(payload, src, dst, interface)?;
+        return Ok(());
+    }
+    stats::update(&stack, |s| s.ip.in_unknown_protos += 1);
+    stats::dropped(&stack, "ip: unknown protocol");
+    Err(Error::Dropped("ip: unknown protocol"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, VirtualClock},
+        link::loopback,
+    };
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+
+    // a stack with 127.0.0.1/8 on a loopback device that is not running, so that what is
+    // sent stays queued
+    fn stack() -> (Stack, loopback::Device, Interface) {
+        let clock = VirtualClock::new(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0));
+        let stack = Stack::with_clock(Clock::Virtual(Arc::new(clock)), 1);
+        let mut device = loopback::Device::open(&stack);
+        let interface = Interface::new(
+            device.clone(),
+            Addr([127, 0, 0, 1]),
+            Addr([255, 0, 0, 0]),
+            None,
+        );
+        device.add_interface(interface.clone());
+        (stack, device, interface)
+    }
+
+    // a UDP datagram from 127.0.0.2 carrying `payload` bytes, with a valid checksum
+    fn datagram(dst: Addr, ttl: u8, offset: u16, payload: usize) -> Buffer {
+        let len = (dgram::HEADER_MIN_SIZE + payload) as u16;
+        let mut bytes = vec![0x45, 0, 0, 0, 0, 1, 0, 0, ttl, 17, 0, 0];
+        bytes[2..4].copy_from_slice(&len.to_be_bytes());
+        bytes[6..8].copy_from_slice(&offset.to_be_bytes());
+        bytes.extend_from_slice(&[127, 0, 0, 2]);
+        bytes.extend_from_slice(&dst.0);
+        bytes.extend((0..payload).map(|n| n as u8));
+        let mut buf = Buffer::from_vec(bytes);
+        seal(&mut buf);
+        buf
+    }
+
+    fn seal(buf: &mut Buffer) {
+        dgram::Dgram::write_checksum(buf, 0);
+        let header: Vec<u8> = buf.0.iter().take(dgram::HEADER_MIN_SIZE).cloned().collect();
+        let sum = util::calc_checksum(&header, dgram::HEADER_MIN_SIZE, 0);
+        dgram::Dgram::write_checksum(buf, sum);
+    }
+
+    fn local() -> Addr {
+        Addr([127, 0, 0, 1])
+    }
+
+    fn reported(stack: &Stack) -> u64 {
+        let counters = stats::snapshot(stack).counters;
+        let sent = counters.icmp.out_types.get(&(icmp::Type::ParamProblem as u8));
+        sent.cloned().unwrap_or(0)
+    }
+
+    // the ICMP messages sent: type, code, the rest of the header and what they quote
+    fn sent(device: &loopback::Device) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let queue = (device.0).0.lock().unwrap().queue.clone();
+        queue
+            .into_iter()
+            .map(|buf| {
+                let bytes = buf.to_vec();
+                let values = u32::from_be_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]);
+                (bytes[20], bytes[21], values, bytes[28..].to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_bad_length_is_reported() {
+        let (stack, device, _) = stack();
+        let mut buf = datagram(local(), 64, 0, 8);
+        buf.0[3] = 100;
+        seal(&mut buf);
+        assert!(rx(buf, &device).is_err());
+        assert_eq!(reported(&stack), 1);
+    }
+
+    #[test]
+    fn a_corrupt_header_is_not_reported() {
+        let (stack, device, _) = stack();
+        let mut buf = datagram(local(), 64, 0, 8);
+        buf.0[3] = 100;
+        assert!(rx(buf, &device).is_err());
+        assert_eq!(reported(&stack), 0);
+        assert_eq!(stats::snapshot(&stack).counters.ip.in_hdr_errors, 1);
+    }
+
+    #[test]
+    fn a_header_length_past_the_end_is_not_reported() {
+        let (stack, device, _) = stack();
+        let mut buf = datagram(local(), 64, 0, 8);
+        buf.0[0] = 0x4f;
+        assert!(rx(buf, &device).is_err());
+        let mut buf = datagram(local(), 64, 0, 8);
+        buf.0[0] = 0x44;
+        assert!(rx(buf, &device).is_err());
+        assert_eq!(reported(&stack), 0);
+        assert_eq!(stats::snapshot(&stack).counters.ip.in_hdr_errors, 2);
+    }
+
+    #[test]
+    fn an_expired_datagram_is_quoted_back() {
+        let (stack, device, _) = stack();
+        set_is_forwarding(&stack, true);
+        let buf = datagram(Addr([10, 0, 0, 1]), 1, 0, 100);
+        let original = buf.clone().to_vec();
+        assert!(rx(buf, &device).is_err());
+        let sent = sent(&device);
+        assert_eq!(sent.len(), 1);
+        let (type_, code, _, quote) = &sent[0];
+        assert_eq!((*type_, *code), (icmp::Type::TimeExceeded as u8, 0));
+        // the header and the first eight bytes, not the whole datagram
+        assert_eq!(quote, &original[..dgram::HEADER_MIN_SIZE + 8].to_vec());
+    }
+
+    #[test]
+    fn no_route_is_reported() {
+        let (stack, device, _) = stack();
+        set_is_forwarding(&stack, true);
+        assert!(rx(datagram(Addr([10, 0, 0, 1]), 64, 0, 100), &device).is_err());
+        let sent = sent(&device);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1), (icmp::Type::DestUnreach as u8, 0));
+        assert_eq!(sent[0].3.len(), dgram::HEADER_MIN_SIZE + 8);
+    }
+
+    #[test]
+    fn fragmentation_needed_tells_the_mtu() {
+        let (stack, device, interface) = stack();
+        set_is_forwarding(&stack, true);
+        route::add(
+            &stack,
+            route::Route {
+                network: Addr([10, 0, 0, 0]),
+                netmask: Addr([255, 0, 0, 0]),
+                nexthop: None,
+                interface,
+            },
+        );
+        // don't fragment, and one byte too long
+        let buf = datagram(Addr([10, 0, 0, 1]), 64, 0x4000, loopback::MTU - 19);
+        assert!(rx(buf, &device).is_ok());
+        let sent = sent(&device);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1), (icmp::Type::DestUnreach as u8, 4));
+        assert_eq!(sent[0].2, loopback::MTU as u32);
+        assert_eq!(stats::snapshot(&stack).counters.ip.frag_fails, 1);
+    }
+
+    #[test]
+    fn sending_without_a_route_fails() {
+        let (stack, device, interface) = stack();
+        let dst = Addr([10, 0, 0, 1]);
+        match interface.tx(ProtocolType::Udp, Buffer::from_vec(vec![0; 8]), &dst) {
+            Err(Error::NoRoute(addr)) => assert_eq!(addr, dst),
+            other => panic!("expected no route: {:?}", other),
         }
+        assert!(sent(&device).is_empty());
+        assert_eq!(stats::snapshot(&stack).counters.ip.out_no_routes, 1);
+    }
+
+    #[test]
+    fn multicasts_are_not_reported() {
+        let (stack, device, _) = stack();
+        set_is_forwarding(&stack, true);
+        assert!(rx(datagram(Addr([224, 0, 0, 9]), 1, 0, 8), &device).is_err());
+        assert!(sent(&device).is_empty());
     }
-    Err(util::RuntimeError::new(format!("no suitable protocol")))
 }
@@ -1,5 +1,10 @@
-use crate::{buffer, ip, packet, protocol::ProtocolType, util};
-use std::error::Error;
+use crate::{
+    buffer,
+    error::{Error, Layer},
+    ip, packet,
+    protocol::ProtocolType,
+    util,
+};
 
 pub const HEADER_MIN_SIZE: usize = 20;
 pub const HEADER_MAX_SIZE: usize = 60;
@@ -19,31 +24,79 @@ pub struct Dgram {
     pub checksum: u16,
     pub src: ip::Addr,
     pub dst: ip::Addr,
+    // between the fixed header and the payload
+    pub options: buffer::Buffer,
     pub payload: buffer::Buffer,
 }
 
 impl Dgram {
-    pub fn dump(&self) {
-        eprintln!("version, header length: {}", self.version_header_length);
-        eprintln!("type of service: {}", self.type_of_service);
-        eprintln!("len: {}", self.len);
-        eprintln!("id: {}", self.id);
-        eprintln!("offset: {}", self.offset);
-        eprintln!("time_to_live: {}", self.time_to_live);
-        eprintln!("protocol: {}", self.protocol);
-        eprintln!("checksum: {}", self.checksum);
-        eprintln!("src: {}", self.src);
-        eprintln!("dst: {}", self.dst);
-        eprintln!("payload: {}", self.payload);
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "version, header length: {}", self.version_header_length);
+        let _ = writeln!(out, "type of service: {}", self.type_of_service);
+        let _ = writeln!(out, "len: {}", self.len);
+        let _ = writeln!(out, "id: {}", self.id);
+        let _ = writeln!(out, "offset: {}", self.offset);
+        let _ = writeln!(out, "time_to_live: {}", self.time_to_live);
+        let _ = writeln!(out, "protocol: {}", self.protocol);
+        let _ = writeln!(out, "checksum: {}", self.checksum);
+        let _ = writeln!(out, "src: {}", self.src);
+        let _ = writeln!(out, "dst: {}", self.dst);
+        let _ = writeln!(out, "options: {}", self.options);
+        let _ = writeln!(out, "payload: {}", self.payload);
+        out
     }
 
     pub fn write_checksum(buf: &mut buffer::Buffer, sum: u16) {
         buf.write_u16(10, sum);
     }
+
+    /// Returns the offset of the first header octet that makes `buf` an invalid datagram,
+    /// to be reported in an ICMP Parameter Problem message.
+    pub fn problem_pointer(buf: &buffer::Buffer) -> Option<u8> {
+        let header_len = ((buf.0[0] & 0x0f) as usize) << 2;
+        if header_len < HEADER_MIN_SIZE || header_len > buf.0.len() {
+            return Some(0);
+        }
+        let len = u16::from_be_bytes([buf.0[2], buf.0[3]]) as usize;
+        if len < header_len || len > buf.0.len() {
+            return Some(2);
+        }
+        None
+    }
+
+    pub fn verify_checksum(buf: &buffer::Buffer) -> bool {
+        let header_len = ((buf.0[0] & 0x0f) as usize) << 2;
+        let header: Vec<u8> = buf.0.iter().take(header_len).cloned().collect();
+        util::calc_checksum(header.as_slice(), header_len, 0) == 0
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Dgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} > {} {} len {} id {} ttl {}",
+            self.src, self.dst, self.protocol, self.len, self.id, self.time_to_live
+        )?;
+        if self.offset & 0x3fff != 0 {
+            write!(
+                f,
+                " frag {}{}",
+                (self.offset & 0x1fff) << 3,
+                if self.offset & 0x2000 != 0 { "+" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
 }
 
 impl packet::Packet<Dgram> for Dgram {
-    fn from_buffer(mut buf: buffer::Buffer) -> Result<Self, Box<dyn Error>> {
+    const LAYER: Layer = Layer::Ip;
+
+    fn from_buffer(mut buf: buffer::Buffer) -> Result<Self, Error> {
         let version_header_length = buf.pop_u8("vhl")?;
         let type_of_service = buf.pop_u8("tos")?;
         let len = buf.pop_u16("length")?;
@@ -51,27 +104,35 @@ impl packet::Packet<Dgram> for Dgram {
         let offset = buf.pop_u16("flags and fragment offset")?;
         let time_to_live = buf.pop_u8("ttl")?;
         let protocol = buf.pop_u8("protocol")?;
-        let protocol = ProtocolType::from_u8(protocol).ok_or(util::RuntimeError::new(format!(
-            "{} can not be Protocol Family",
-            protocol
-        )))?;
+        let protocol = ProtocolType::from_u8(protocol)
+            .ok_or_else(|| Error::parse("protocol", format!("unknown: {}", protocol)))?;
         let checksum = buf.pop_u16("checksum")?;
         let src = buf.pop_ip_addr("src")?;
         let dst = buf.pop_ip_addr("dst")?;
-        let payload = buf;
+        let header_len = ((version_header_length & 0x0f) as usize) << 2;
+        if header_len < HEADER_MIN_SIZE || (len as usize) < header_len {
+            return Err(Error::parse(
+                "header length",
+                format!("{} bytes of {} in all", header_len, len),
+            ));
+        }
+        let options = buf.pop_buffer(header_len - HEADER_MIN_SIZE, "options")?;
+        // what follows the datagram, e.g. padding up to the smallest frame, is not its own
+        let payload = buf.pop_buffer(len as usize - header_len, "payload")?;
 
         Ok(Dgram {
-            version_header_length: version_header_length,
-            type_of_service: type_of_service,
-            len: len,
-            id: id,
-            offset: offset,
-            time_to_live: time_to_live,
-            protocol: protocol,
-            checksum: checksum,
-            src: src,
-            dst: dst,
-            payload: payload,
+            version_header_length,
+            type_of_service,
+            len,
+            id,
+            offset,
+            time_to_live,
+            protocol,
+            checksum,
+            src,
+            dst,
+            options,
+            payload,
         })
     }
 
@@ -87,8 +148,45 @@ impl packet::Packet<Dgram> for Dgram {
         buf.push_u16(self.checksum);
         buf.push_ip_addr(self.src);
         buf.push_ip_addr(self.dst);
+        buf.append(self.options);
         buf.append(self.payload);
 
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Packet;
+
+    // 24 bytes of header with a no-op option, 4 of payload and 6 of link padding
+    fn padded() -> Vec<u8> {
+        let mut bytes = vec![0x46, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0];
+        bytes.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2]);
+        bytes.extend_from_slice(&[1, 1, 1, 0]);
+        bytes.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd]);
+        bytes.extend_from_slice(&[0; 6]);
+        bytes
+    }
+
+    #[test]
+    fn options_and_padding_are_not_payload() {
+        let dgram = Dgram::parse(buffer::Buffer::from_vec(padded())).unwrap();
+        assert_eq!(dgram.options.clone().to_vec(), vec![1, 1, 1, 0]);
+        assert_eq!(dgram.payload.clone().to_vec(), vec![0xaa, 0xbb, 0xcc, 0xdd]);
+        // and the options go back where they were
+        assert_eq!(dgram.to_buffer().to_vec(), padded()[..28].to_vec());
+    }
+
+    #[test]
+    fn short_datagrams_are_an_error() {
+        let mut bytes = padded();
+        bytes.truncate(26);
+        assert!(Dgram::parse(buffer::Buffer::from_vec(bytes)).is_err());
+        let mut bytes = padded();
+        // a total length shorter than the header
+        bytes[3] = 20;
+        assert!(Dgram::parse(buffer::Buffer::from_vec(bytes)).is_err());
+    }
+}
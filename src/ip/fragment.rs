@@ -1,7 +1,6 @@
-use crate::{buffer::Buffer, ip, util};
+use crate::{buffer::Buffer, error::Error, ip, stack::Stack, stats};
 use chrono::{DateTime, Utc};
-use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 
 #[derive(Debug)]
 pub struct Fragment {
@@ -11,65 +10,78 @@ pub struct Fragment {
     pub protocol: ip::ProtocolType,
     pub data: Buffer,
     pub mask: Vec<u32>,
+    // known once the last fragment arrives
+    pub total_len: Option<usize>,
     pub timestamp: Option<DateTime<Utc>>,
 }
 
-lazy_static! {
-    static ref FRAGMENTS: Arc<Mutex<Vec<Fragment>>> = Arc::new(Mutex::new(vec![]));
+#[derive(Default)]
+pub(crate) struct State {
+    fragments: Mutex<Vec<Fragment>>,
+    count: Mutex<i32>,
 }
 
+// how often reassemblies are checked for the timeout, in seconds
+pub const PATROL_INTERVAL: i64 = 1;
+const TIMEOUT_SEC: i64 = 30;
+
 impl Fragment {
     fn new(dgram: &ip::dgram::Dgram) -> Self {
-        let mut mask = vec![];
-        mask.resize(2048, 0);
+        let mask = vec![0; 2048];
         Fragment {
-            src: dgram.src.clone(),
-            dst: dgram.dst.clone(),
-            id: dgram.id.clone(),
+            src: dgram.src,
+            dst: dgram.dst,
+            id: dgram.id,
             protocol: dgram.protocol,
             data: Buffer::new(65535),
-            mask: mask,
+            mask,
+            total_len: None,
             timestamp: None,
         }
     }
 }
 
-fn patrol() {
-    let now = Utc::now();
-    let mut fragments = FRAGMENTS.lock().unwrap();
-    const TIMEOUT_SEC: i64 = 30;
-
+/// drops the reassemblies that have timed out, run every `PATROL_INTERVAL` by the stack's
+/// timer.
+pub fn patrol(stack: &Stack) {
+    let now = stack.now();
+    let mut fragments = stack.0.ip.fragments.fragments.lock().unwrap();
     fragments.retain(|fragment| {
-        if let Some(timestamp) = fragment.timestamp {
+        let alive = if let Some(timestamp) = fragment.timestamp {
             (now - timestamp).num_seconds() < TIMEOUT_SEC
         } else {
             false
+        };
+        if !alive {
+            *stack.0.ip.fragments.count.lock().unwrap() -= 1;
+            stats::update(stack, |s| s.ip.reasm_fails += 1);
+            stats::dropped(stack, "ip: reassembly timeout");
         }
+        alive
     });
 }
 
-fn lookup<Pred: Fn(&Fragment) -> bool>(pred: Pred) -> Option<Fragment> {
-    let mut fragments = FRAGMENTS.lock().unwrap();
+fn lookup<Pred: Fn(&Fragment) -> bool>(stack: &Stack, pred: Pred) -> Option<Fragment> {
+    let mut fragments = stack.0.ip.fragments.fragments.lock().unwrap();
     fragments
         .iter()
         .position(pred)
         .map(|index| fragments.remove(index))
 }
 
-lazy_static! {
-    static ref PREV_TIME: Arc<Mutex<DateTime<Utc>>> = Arc::new(Mutex::new(Utc::now()));
-    static ref FRAGMENT_COUNT: Arc<Mutex<i32>> = Arc::new(Mutex::new(0));
-}
-
-pub fn process(dgram: ip::dgram::Dgram) -> Result<Fragment, Box<dyn Error>> {
-    let mut prev_time = PREV_TIME.lock().unwrap();
-    let now = Utc::now();
-    if (now - *prev_time as DateTime<Utc>).num_seconds() < 10 {
-        patrol();
+/// adds `dgram` to its reassembly, returning the whole datagram once every part is in.
+pub fn process(stack: &Stack, dgram: ip::dgram::Dgram) -> Result<Option<Fragment>, Error> {
+    let now = stack.now();
+    let off = ((dgram.offset & 0x1fff) << 3) as usize;
+    let payload_len = dgram.payload.0.len();
+    // the whole datagram, header included, has to fit in 65535 bytes
+    let header_len = ((dgram.version_header_length & 0x0f) as usize) << 2;
+    if off + payload_len > 65535 - header_len {
+        stats::update(stack, |s| s.ip.reasm_fails += 1);
+        stats::dropped(stack, "ip: reassembly too long");
+        return Err(Error::Dropped("ip: reassembly too long"));
     }
-    *prev_time = now;
-
-    let mut fragment = match lookup(|fragment| {
+    let mut fragment = match lookup(stack, |fragment| {
         fragment.src == dgram.src
             && fragment.dst == dgram.dst
             && fragment.id == dgram.id
@@ -78,9 +90,11 @@ pub fn process(dgram: ip::dgram::Dgram) -> Result<Fragment, Box<dyn Error>> {
         Some(fragment) => fragment,
         None => {
             const NUM_MAX: i32 = 8;
-            let mut count = FRAGMENT_COUNT.lock().unwrap();
+            let mut count = stack.0.ip.fragments.count.lock().unwrap();
             if *count >= NUM_MAX {
-                return Err(util::RuntimeError::new(format!("too many fragments")));
+                stats::update(stack, |s| s.ip.reasm_fails += 1);
+                stats::dropped(stack, "ip: too many reassemblies");
+                return Err(Error::Dropped("ip: too many reassemblies"));
             }
             let fragment = Fragment::new(&dgram);
             *count += 1;
@@ -88,28 +102,36 @@ pub fn process(dgram: ip::dgram::Dgram) -> Result<Fragment, Box<dyn Error>> {
         }
     };
 
-    let off = ((dgram.offset & 0x1fff) << 3) as usize;
-    let payload_len = dgram.payload.0.len();
+    if fragment.data.0.len() < off {
+        // a later part came first, leave a hole for the ones before it
+        fragment.data.0.resize(off, 0);
+    }
     fragment.data.write(off, dgram.payload);
-    set_mask(&mut fragment.mask, off, payload_len);
-
-    fragment.timestamp = Some(Utc::now());
-    if dgram.offset & 0x2000 != 0 {
-        let mut fragments = FRAGMENTS.lock().unwrap();
-        fragments.push(fragment);
-        return Err(util::RuntimeError::new(format!("more fragments exists")));
+    if payload_len > 0 {
+        set_mask(&mut fragment.mask, off, payload_len);
     }
-    fragment.data.0.resize(off + payload_len, 0);
 
-    if !check_mask(&fragment.mask, 0, fragment.data.0.len()) {
-        return Err(util::RuntimeError::new(format!("imcomplete flagments")));
+    fragment.timestamp = Some(now);
+    if dgram.offset & 0x2000 == 0 {
+        fragment.total_len = Some(off + payload_len);
     }
-    let mut count = FRAGMENT_COUNT.lock().unwrap();
+    let total_len = match fragment.total_len {
+        Some(len) if check_mask(&fragment.mask, 0, len) => len,
+        _ => {
+            // more fragments to come
+            let mut fragments = stack.0.ip.fragments.fragments.lock().unwrap();
+            fragments.push(fragment);
+            return Ok(None);
+        }
+    };
+    fragment.data.0.truncate(total_len);
+    let mut count = stack.0.ip.fragments.count.lock().unwrap();
     *count -= 1;
-    Ok(fragment)
+    stats::update(stack, |s| s.ip.reasm_oks += 1);
+    Ok(Some(fragment))
 }
 
-fn set_mask(mask: &mut Vec<u32>, offset: usize, mut len: usize) {
+fn set_mask(mask: &mut [u32], offset: usize, mut len: usize) {
     let so = offset / 32;
     let sb = offset % 32;
     let bl = if len > 32 - sb { 32 - sb } else { len };
@@ -125,7 +147,7 @@ fn set_mask(mask: &mut Vec<u32>, offset: usize, mut len: usize) {
     }
 }
 
-fn check_mask(mask: &Vec<u32>, offset: usize, mut data_len: usize) -> bool {
+fn check_mask(mask: &[u32], offset: usize, mut data_len: usize) -> bool {
     let so = offset / 32;
     let sb = offset % 32;
     let bl = if data_len > 32 - sb {
@@ -147,9 +169,68 @@ fn check_mask(mask: &Vec<u32>, offset: usize, mut data_len: usize) -> bool {
     let i = so + data_len / 32;
     data_len -= 32 * (data_len / 32);
     if data_len != 0 {
-        if (mask[i + 1] & (0xffffffff >> (32 - data_len))) ^ (0xffffffff >> (32 - data_len)) != 0 {
+        let tail = 0xffffffff >> (32 - data_len);
+        if (mask[i + 1] & tail) ^ tail != 0 {
             return false;
         }
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, VirtualClock},
+        protocol::ProtocolType,
+    };
+    use chrono::TimeZone;
+    use std::sync::Arc;
+
+    fn stack() -> Stack {
+        let clock = VirtualClock::new(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0));
+        Stack::with_clock(Clock::Virtual(Arc::new(clock)), 1)
+    }
+
+    fn dgram(offset: u16, payload: Vec<u8>) -> ip::dgram::Dgram {
+        ip::dgram::Dgram {
+            version_header_length: 0x45,
+            type_of_service: 0,
+            len: (ip::dgram::HEADER_MIN_SIZE + payload.len()) as u16,
+            id: 7,
+            offset,
+            time_to_live: 64,
+            protocol: ProtocolType::Udp,
+            checksum: 0,
+            src: ip::Addr([192, 0, 2, 1]),
+            dst: ip::Addr([192, 0, 2, 2]),
+            options: Buffer::empty(),
+            payload: Buffer::from_vec(payload),
+        }
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let stack = stack();
+        assert!(process(&stack, dgram(0x2001, vec![2; 8])).unwrap().is_none());
+        assert!(process(&stack, dgram(0x2000, vec![1; 8])).unwrap().is_none());
+        let whole = process(&stack, dgram(2, vec![3; 8]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(whole.data.to_vec(), [[1; 8], [2; 8], [3; 8]].concat());
+        assert_eq!(stats::snapshot(&stack).counters.ip.reasm_oks, 1);
+    }
+
+    #[test]
+    fn rejects_parts_past_the_largest_datagram() {
+        let stack = stack();
+        // the last part at the highest offset there is, 65528
+        assert!(process(&stack, dgram(0x1fff, vec![0; 48])).is_err());
+        assert!(process(&stack, dgram(0x1fff, vec![0; 1])).is_err());
+        // ending right at 65535 with the header fits
+        assert!(process(&stack, dgram(0x1ffd, vec![0; 3])).unwrap().is_none());
+        let counters = stats::snapshot(&stack).counters;
+        assert_eq!(counters.ip.reasm_fails, 2);
+        assert_eq!(counters.drops["ip: reassembly too long"], 2);
+    }
+}
@@ -1,17 +1,20 @@
-use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 
 use crate::{
-    arp, buffer, ethernet,
+    arp, buffer,
+    error::Error,
+    ethernet,
     ip::{self, dgram, route},
+    link::{LinkDevice, Resolution},
     packet,
     protocol::ProtocolType,
-    util,
+    stack::Stack,
+    stats, util,
 };
 
 #[derive(Debug)]
 pub struct InterfaceImpl {
-    pub device: ethernet::Device,
+    pub device: Arc<dyn LinkDevice>,
     pub unicast: ip::Addr,
     pub netmask: ip::Addr,
     pub gateway: Option<ip::Addr>,
@@ -20,67 +23,111 @@ pub struct InterfaceImpl {
 #[derive(Debug, Clone)]
 pub struct Interface(pub Arc<Mutex<InterfaceImpl>>);
 
+/// an interface as its device refers to it, which does not keep it alive.
+#[derive(Debug, Clone)]
+pub struct WeakInterface(Weak<Mutex<InterfaceImpl>>);
+
+impl WeakInterface {
+    pub fn upgrade(&self) -> Option<Interface> {
+        self.0.upgrade().map(Interface)
+    }
+}
+
 impl Interface {
-    pub fn new(
-        device: ethernet::Device,
+    pub fn new<D: LinkDevice + 'static>(
+        device: D,
         unicast: ip::Addr,
         netmask: ip::Addr,
         gateway: Option<ip::Addr>,
     ) -> Interface {
+        let stack = device.stack();
         let interface = Interface(Arc::new(Mutex::new(InterfaceImpl {
-            device: device,
-            unicast: unicast,
-            netmask: netmask,
-            gateway: gateway,
+            device: Arc::new(device),
+            unicast,
+            netmask,
+            gateway,
         })));
         let network = unicast.apply_mask(&netmask);
-        route::add(route::Route {
-            network: network,
-            netmask: netmask,
+        route::add(&stack, route::Route {
+            network,
+            netmask,
             nexthop: None,
             interface: interface.clone(),
         });
         if let Some(gateway) = gateway {
-            route::add(route::Route {
+            route::add(&stack, route::Route {
                 network: ip::ADDR_ANY,
                 netmask: ip::ADDR_ANY,
                 nexthop: Some(gateway),
                 interface: interface.clone(),
             });
         }
+        stack.0.ip.interfaces.lock().unwrap().push(interface.clone());
+        arp::probe(&interface);
         interface
     }
+
+    pub fn downgrade(&self) -> WeakInterface {
+        WeakInterface(Arc::downgrade(&self.0))
+    }
+
+    pub fn stack(&self) -> Stack {
+        self.0.lock().unwrap().device.stack()
+    }
+
     pub fn tx(
+        &self,
+        protocol: ProtocolType,
+        packet: buffer::Buffer,
+        dst: &ip::Addr,
+    ) -> Result<(), Error> {
+        self.tx_tos(protocol, packet, dst, 0)
+    }
+
+    /// sends with the given type of service; its precedence bits become the VLAN priority.
+    pub fn tx_tos(
         &self,
         protocol: ProtocolType,
         mut packet: buffer::Buffer,
         dst: &ip::Addr,
-    ) -> Result<(), Box<dyn Error>> {
+        tos: u8,
+    ) -> Result<(), Error> {
+        let stack = self.stack();
+        stats::update(&stack, |s| s.ip.out_requests += 1);
         let (nexthop, interface, src) = if dst == &ip::ADDR_BROADCAST {
             (None, self.clone(), None)
         } else {
-            match route::lookup(None, dst.clone()) {
+            match route::lookup(&stack, None, *dst) {
                 None => {
-                    eprintln!("ip no route to host"); // TODO
-                    return Ok(());
+                    stats::update(&stack, |s| s.ip.out_no_routes += 1);
+                    stats::dropped(&stack, "ip: no route");
+                    return Err(Error::NoRoute(*dst));
                 }
                 Some(route) => {
-                    let nexthop = Some(route.nexthop.unwrap_or(dst.clone()));
+                    let nexthop = Some(route.nexthop.unwrap_or(*dst));
                     let interface = route.interface;
-                    let src = Some(self.0.lock().unwrap().unicast.clone());
+                    let src = Some(self.0.lock().unwrap().unicast);
                     (nexthop, interface, src)
                 }
             }
         };
-        let id = generate_id();
+        let src = src.unwrap_or_else(|| interface.0.lock().unwrap().unicast);
+        let id = generate_id(&stack);
+        let mtu = interface.0.lock().unwrap().device.mtu();
+        // fragment offsets count 8 bytes, so all but the last fragment are multiples of 8
+        let segment_max = (mtu - ip::dgram::HEADER_MIN_SIZE) & !7;
 
+        let segments = packet.0.len().div_ceil(segment_max);
+        if segments > 1 {
+            stats::update(&stack, |s| {
+                s.ip.frag_oks += 1;
+                s.ip.frag_creates += segments as u64;
+            });
+        }
         let mut segment_len: u16;
         let mut done: u16 = 0;
         while !packet.0.is_empty() {
-            segment_len = ::std::cmp::min(
-                packet.0.len() as u16,
-                ethernet::PAYLOAD_SIZE_MAX as u16 - ip::dgram::HEADER_MIN_SIZE as u16,
-            );
+            segment_len = ::std::cmp::min(packet.0.len() as u16, segment_max as u16);
             let flag: u16 = if segment_len < packet.0.len() as u16 {
                 0x2000
             } else {
@@ -88,72 +135,59 @@ impl Interface {
             };
             let offset = flag | (done >> 3) & 0x1fff;
             let segment = packet.pop_buffer(segment_len as usize, "segment")?;
-            interface.tx_core(protocol, segment, src, dst.clone(), nexthop, id, offset)?;
-            done += segment_len as u16;
+            let dgram = dgram::Dgram {
+                version_header_length: (ip::VERSION << 4) | (ip::dgram::HEADER_LEN >> 2),
+                type_of_service: tos,
+                len: ip::dgram::HEADER_LEN as u16 + segment_len,
+                id,
+                offset,
+                time_to_live: 0xff,
+                protocol,
+                checksum: 0,
+                src,
+                dst: *dst,
+                options: buffer::Buffer::empty(),
+                payload: segment,
+            };
+            interface.tx_core(dgram, &nexthop)?;
+            done += segment_len;
         }
         Ok(())
     }
 
-    fn tx_core(
-        &self,
-        type_: ProtocolType,
-        buf: buffer::Buffer,
-        src: Option<ip::Addr>,
-        dst: ip::Addr,
-        nexthop: Option<ip::Addr>,
-        id: u16,
-        offset: u16,
-    ) -> Result<(), Box<dyn Error>> {
-        let dgram = dgram::Dgram {
-            version_header_length: (ip::VERSION << 4) | (ip::dgram::HEADER_LEN >> 2),
-            type_of_service: 0,
-            len: ip::dgram::HEADER_LEN as u16 + buf.0.len() as u16,
-            id: id,
-            offset: offset,
-            time_to_live: 0xff,
-            protocol: type_,
-            checksum: 0,
-            src: match src {
-                Some(src) => src,
-                None => {
-                    let impl_ = self.0.lock().unwrap();
-                    impl_.unicast
-                }
-            },
-            dst: dst,
-            payload: buf,
-        };
+    // checksums `dgram` and sends it to `nexthop`
+    fn tx_core(&self, dgram: dgram::Dgram, nexthop: &Option<ip::Addr>) -> Result<(), Error> {
         use packet::Packet;
         let buf_vec = dgram.to_buffer().to_vec();
         let sum = util::calc_checksum(buf_vec.as_slice(), ip::dgram::HEADER_LEN as usize, 0);
         let mut buf = buffer::Buffer::from_vec(buf_vec);
         dgram::Dgram::write_checksum(&mut buf, sum);
-        self.tx_device(buf, &nexthop)
+        let ret = self.tx_device(buf, nexthop);
+        if ret.is_err() {
+            let stack = self.stack();
+            stats::update(&stack, |s| s.ip.out_discards += 1);
+            stats::dropped(&stack, "ip: transmit failed");
+        }
+        ret
     }
 
     pub fn tx_device(
         &self,
         data: buffer::Buffer,
         dst: &Option<ip::Addr>,
-    ) -> Result<(), Box<dyn Error>> {
-        use ethernet::DeviceFlags;
-        let mac_addr = if DeviceFlags::BROADCAST & DeviceFlags::NOARP == DeviceFlags::EMPTY {
-            match dst {
-                Some(dst) => match arp::resolve(&self, *dst, data.clone())? {
+    ) -> Result<(), Error> {
+        let device = self.0.lock().unwrap().device.clone();
+        let mac_addr = match device.resolution() {
+            Resolution::Arp => match dst {
+                Some(dst) => match arp::resolve(self, *dst, &data)? {
                     Some(addr) => addr,
                     None => return Ok(()),
                 },
-                None => {
-                    let interface = self.0.lock().unwrap();
-                    let device = interface.device.0.lock().unwrap();
-                    device.broadcast_addr
-                }
-            }
-        } else {
-            ethernet::MacAddr::empty()
+                None => device.broadcast_addr(),
+            },
+            Resolution::None | Resolution::PointToPoint => ethernet::MacAddr::empty(),
         };
-        let interface = self.0.lock().unwrap();
-        interface.device.tx(ethernet::Type::Ip, data, mac_addr)
+        device.tx(ethernet::Type::Ip, data, mac_addr)
     }
 
     pub fn reconfigure(
@@ -161,51 +195,48 @@ impl Interface {
         addr: ip::Addr,
         netmask: ip::Addr,
         gateway: Option<ip::Addr>,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), Error> {
+        let stack = self.stack();
         route::delete(self);
         let mut interface = self.0.lock().unwrap();
         interface.unicast = addr;
         let network = interface.unicast.apply_mask(&interface.netmask);
-        route::add(route::Route {
-            network: network,
-            netmask: netmask,
+        route::add(&stack, route::Route {
+            network,
+            netmask,
             nexthop: Some(ip::ADDR_ANY),
             interface: self.clone(),
         });
         if let Some(gateway) = gateway {
-            route::add(route::Route {
+            route::add(&stack, route::Route {
                 network: ip::ADDR_ANY,
                 netmask: ip::ADDR_ANY,
                 nexthop: Some(gateway),
                 interface: self.clone(),
             });
         }
+        drop(interface);
+        arp::probe(self);
         Ok(())
     }
 }
 
-fn generate_id() -> u16 {
-    let mut id_counter = ID_COUNTER.lock().unwrap();
+fn generate_id(stack: &Stack) -> u16 {
+    let mut id_counter = stack.0.ip.id_counter.lock().unwrap();
     let ret = *id_counter;
-    *id_counter += 1;
+    *id_counter = id_counter.wrapping_add(1);
     ret
 }
 
-lazy_static! {
-    static ref ID_COUNTER: Mutex<u16> = Mutex::new(128);
-}
-
-pub fn by_addr(addr: ip::Addr) -> Option<Interface> {
-    let devices = ethernet::DEVICES.lock().unwrap();
-    for device in devices.iter() {
-        let device = device.0.lock().unwrap();
-        if let Some(interface) = &device.interface {
+pub fn by_addr(stack: &Stack, addr: ip::Addr) -> Option<Interface> {
+    for device in stack.devices().iter() {
+        if let Some(interface) = device.interface() {
             let unicast = {
                 let interface = interface.0.lock().unwrap();
                 interface.unicast
             };
             if unicast == addr {
-                return Some(interface.clone());
+                return Some(interface);
             }
         }
     }
@@ -1,6 +1,9 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use crate::ip::{self, interface::Interface};
+use crate::{
+    ip::{self, interface::Interface},
+    stack::Stack,
+};
 
 #[derive(Debug, Clone)]
 pub struct Route {
@@ -10,27 +13,23 @@ pub struct Route {
     pub interface: Interface,
 }
 
-lazy_static! {
-    static ref ROUTE_TABLE: Arc<Mutex<Vec<Route>>> = Arc::new(Mutex::new(vec![]));
-}
-
-pub fn add(route: Route) {
-    let mut route_table = ROUTE_TABLE.lock().unwrap();
+pub fn add(stack: &Stack, route: Route) {
+    let mut route_table = stack.0.ip.routes.lock().unwrap();
     route_table.push(route);
 }
 
 pub fn delete(interface: &Interface) {
-    let mut route_table = ROUTE_TABLE.lock().unwrap();
-    route_table
-        .retain(|route| &route.interface as *const Interface == interface as *const Interface);
+    let stack = interface.stack();
+    let mut route_table = stack.0.ip.routes.lock().unwrap();
+    route_table.retain(|route| !Arc::ptr_eq(&route.interface.0, &interface.0));
 }
 
-pub fn lookup(interface: Option<&Interface>, dst: ip::Addr) -> Option<Route> {
-    let route_table = ROUTE_TABLE.lock().unwrap();
+pub fn lookup(stack: &Stack, interface: Option<&Interface>, dst: ip::Addr) -> Option<Route> {
+    let route_table = stack.0.ip.routes.lock().unwrap();
     let mut candidate = None;
     for route in route_table.iter() {
         let is_same_interface = if let Some(interface) = interface {
-            &route.interface as *const Interface == interface as *const Interface
+            Arc::ptr_eq(&route.interface.0, &interface.0)
         } else {
             true
         };
@@ -1,6 +1,3 @@
-#![feature(wait_timeout_until)]
-#![feature(drain_filter)]
-
 extern crate arrayvec;
 extern crate bitflags;
 extern crate chrono;
@@ -14,15 +11,31 @@ extern crate lazy_static;
 #[macro_use]
 extern crate nix;
 
+// first, so that its macros are visible to every other module
+#[macro_use]
+pub mod log;
+
 pub mod arp;
 pub mod buffer;
+pub mod capi;
+pub mod clock;
+pub mod config;
+pub mod error;
 pub mod ethernet;
+pub mod event_loop;
 pub mod icmp;
 pub mod ip;
+pub mod link;
 pub mod packet;
+#[cfg(feature = "preload")]
+pub mod preload;
 pub mod protocol;
 pub mod raw;
+pub mod sim;
 pub mod slip;
+pub mod stack;
+pub mod stats;
 pub mod tcp;
+pub mod timer;
 pub mod udp;
 pub mod util;
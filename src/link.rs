@@ -0,0 +1,78 @@
+use std::fmt::Debug;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use bitflags::bitflags;
+
+use crate::{buffer::Buffer, error::Error, ethernet, ip, stack::Stack, stats};
+
+pub mod loopback;
+pub mod p2p;
+
+bitflags! {
+    pub struct DeviceFlags: u32 {
+        const EMPTY = 0x00;
+        const BROADCAST = 0x01;
+        const MULTICAST = 0x02;
+        const P2P       = 0x04;
+        const LOOPBACK  = 0x08;
+        const NOARP     = 0x10;
+        const PROMISC   = 0x20;
+        const RUNNING   = 0x40;
+        const UP        = 0x80;
+    }
+}
+
+/// how IP finds the link-layer destination of a next hop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Arp,
+    // the link has no destination address at all, e.g. loopback
+    None,
+    // everything goes to the single peer on the other end
+    PointToPoint,
+}
+
+pub trait LinkDevice: Debug + Send + Sync {
+    fn name(&self) -> String;
+    fn flags(&self) -> DeviceFlags;
+    fn mtu(&self) -> usize;
+    fn header_len(&self) -> usize;
+    fn resolution(&self) -> Resolution;
+    fn addr(&self) -> ethernet::MacAddr;
+    fn broadcast_addr(&self) -> ethernet::MacAddr;
+    fn interface(&self) -> Option<ip::interface::Interface>;
+    /// the stack the device was opened on; panics once it is dropped.
+    fn stack(&self) -> Stack;
+    /// tells the device's receive thread, if it has one, to end soon.
+    fn stop(&self) {}
+    /// a descriptor that is readable whenever `receive` has a frame, for an event loop to
+    /// wait on; none for devices without one.
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// handles the frame waiting on `fd`, and any the device has read along with it.
+    fn receive(&self) -> Result<(), Error> {
+        Err(Error::Invalid(format!("`{}` has nothing to receive", self.name())))
+    }
+    /// whether a receive thread of the device's own is running.
+    fn is_running(&self) -> bool {
+        false
+    }
+    /// link-level counters, for devices that keep them.
+    fn stats(&self) -> Option<stats::Device> {
+        None
+    }
+
+    /// sends `payload` of protocol `type_`; `dst` is ignored by links without addresses.
+    fn tx(
+        &self,
+        type_: ethernet::Type,
+        payload: Buffer,
+        dst: ethernet::MacAddr,
+    ) -> Result<(), Error>;
+}
+
+pub fn register(stack: &Stack, device: Arc<dyn LinkDevice>) {
+    stack.0.devices.lock().unwrap().push(device);
+}
@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::{
+    buffer::Buffer,
+    error::Error,
+    ethernet, ip,
+    link::{self, DeviceFlags, LinkDevice, Resolution},
+    stack::{Stack, WeakStack},
+};
+
+pub const MTU: usize = 16384;
+pub const NAME: &str = "lo";
+
+#[derive(Debug)]
+pub struct DeviceImpl {
+    pub interface: Option<ip::interface::WeakInterface>,
+    pub queue: VecDeque<Buffer>,
+    pub terminate: bool,
+    join_handle: Option<thread::JoinHandle<()>>,
+    pub stack: WeakStack,
+}
+
+/// Loops transmitted datagrams back into `ip::rx` on its own thread.
+#[derive(Debug, Clone)]
+pub struct Device(pub Arc<(Mutex<DeviceImpl>, Condvar)>);
+
+impl Device {
+    pub fn open(stack: &Stack) -> Device {
+        let device = Device(Arc::new((
+            Mutex::new(DeviceImpl {
+                interface: None,
+                queue: VecDeque::new(),
+                terminate: false,
+                join_handle: None,
+                stack: stack.downgrade(),
+            }),
+            Condvar::new(),
+        )));
+        link::register(stack, Arc::new(device.clone()));
+        device
+    }
+
+    pub fn add_interface(&mut self, interface: ip::interface::Interface) {
+        (self.0).0.lock().unwrap().interface = Some(interface.downgrade());
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let device = self.clone();
+        let join_handle = thread::spawn(move || loop {
+            let buf = {
+                let (ref inner, ref cond) = *device.0;
+                let mut inner = cond
+                    .wait_while(inner.lock().unwrap(), |inner| {
+                        inner.queue.is_empty() && !inner.terminate
+                    })
+                    .unwrap();
+                if inner.terminate {
+                    break;
+                }
+                inner.queue.pop_front().unwrap()
+            };
+            // the stack may have been dropped meanwhile
+            let stack = (device.0).0.lock().unwrap().stack.upgrade();
+            if stack.is_none() {
+                break;
+            }
+            // each rejected packet is counted in `stats` too
+            if let Err(err) = ip::rx(buf, &device) {
+                log_rx_error!(NAME, err);
+            }
+        });
+        (self.0).0.lock().unwrap().join_handle = Some(join_handle);
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        let (ref inner, ref cond) = *self.0;
+        let join_handle = {
+            let mut inner = inner.lock().unwrap();
+            inner.terminate = true;
+            inner.join_handle.take()
+        };
+        cond.notify_all();
+        if let Some(join_handle) = join_handle {
+            join_handle.join().unwrap();
+        }
+        Ok(())
+    }
+}
+
+impl LinkDevice for Device {
+    fn name(&self) -> String {
+        NAME.to_string()
+    }
+    fn flags(&self) -> DeviceFlags {
+        DeviceFlags::LOOPBACK | DeviceFlags::NOARP | DeviceFlags::UP | DeviceFlags::RUNNING
+    }
+    fn mtu(&self) -> usize {
+        MTU
+    }
+    fn header_len(&self) -> usize {
+        0
+    }
+    fn resolution(&self) -> Resolution {
+        Resolution::None
+    }
+    fn addr(&self) -> ethernet::MacAddr {
+        ethernet::ADDR_ANY
+    }
+    fn broadcast_addr(&self) -> ethernet::MacAddr {
+        ethernet::ADDR_ANY
+    }
+    fn interface(&self) -> Option<ip::interface::Interface> {
+        let interface = (self.0).0.lock().unwrap().interface.clone();
+        interface.and_then(|interface| interface.upgrade())
+    }
+    fn stack(&self) -> Stack {
+        (self.0).0.lock().unwrap().stack.get()
+    }
+    fn stop(&self) {
+        let (ref inner, ref cond) = *self.0;
+        inner.lock().unwrap().terminate = true;
+        cond.notify_all();
+    }
+    fn tx(
+        &self,
+        type_: ethernet::Type,
+        payload: Buffer,
+        _dst: ethernet::MacAddr,
+    ) -> Result<(), Error> {
+        match type_ {
+            ethernet::Type::Ip => {
+                let (ref inner, ref cond) = *self.0;
+                inner.lock().unwrap().queue.push_back(payload);
+                cond.notify_all();
+                Ok(())
+            }
+            _ => Err(Error::Invalid(format!(
+                "{} can not be sent on `{}`",
+                type_, NAME
+            ))),
+        }
+    }
+}
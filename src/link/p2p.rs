@@ -0,0 +1,175 @@
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::{
+    buffer::Buffer,
+    error::Error,
+    ethernet, ip,
+    link::{self, DeviceFlags, LinkDevice, Resolution},
+    raw,
+    stack::{Stack, WeakStack},
+};
+
+pub const MTU: usize = 1500;
+
+/// A point-to-point link carrying bare IP datagrams, such as TUN or SLIP.
+#[derive(Debug)]
+pub struct DeviceImpl {
+    pub interface: Option<ip::interface::WeakInterface>,
+    pub name: String,
+    pub raw: Arc<dyn raw::RawDevice + Sync + Send>,
+    pub mtu: usize,
+    pub flags: DeviceFlags,
+    pub terminate: bool,
+    join_handle: Option<thread::JoinHandle<()>>,
+    pub stack: WeakStack,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device(pub Arc<Mutex<DeviceImpl>>);
+
+impl Device {
+    pub fn open(
+        stack: &Stack,
+        raw: Arc<dyn raw::RawDevice + Sync + Send>,
+        mtu: usize,
+    ) -> Result<Device, Error> {
+        if raw.type_().is_ethernet() {
+            return Err(Error::Invalid(format!(
+                "`{}` carries ethernet frames",
+                raw.name()
+            )));
+        }
+        let device = Device(Arc::new(Mutex::new(DeviceImpl {
+            interface: None,
+            name: raw.name().clone(),
+            raw,
+            mtu,
+            flags: DeviceFlags::P2P | DeviceFlags::NOARP,
+            terminate: false,
+            join_handle: None,
+            stack: stack.downgrade(),
+        })));
+        link::register(stack, Arc::new(device.clone()));
+        Ok(device)
+    }
+
+    pub fn add_interface(&mut self, interface: ip::interface::Interface) {
+        self.0.lock().unwrap().interface = Some(interface.downgrade());
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let device = self.clone();
+        let join_handle = thread::spawn(move || loop {
+            let device_ = device.clone();
+            let (terminate, raw, stack) = {
+                let device_inner = device.0.lock().unwrap();
+                (
+                    device_inner.terminate,
+                    device_inner.raw.clone(),
+                    device_inner.stack.clone(),
+                )
+            };
+            if terminate || stack.upgrade().is_none_or(|stack| stack.is_shut_down()) {
+                break;
+            }
+            let rx = move |buf: Buffer| {
+                // held while the datagram goes up, in case the stack is dropped meanwhile
+                let stack = device_.0.lock().unwrap().stack.upgrade();
+                match stack {
+                    Some(_) => ip::rx(buf, &device_),
+                    None => Err(Error::Dropped("p2p: stack is gone")),
+                }
+            };
+            // each rejected packet is counted in `stats` too
+            if let Err(err) = raw.rx(Box::new(rx), 1000) {
+                log_rx_error!(device.name(), err);
+            }
+        });
+        self.0.lock().unwrap().join_handle = Some(join_handle);
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        let join_handle = {
+            let mut inner = self.0.lock().unwrap();
+            inner.terminate = true;
+            inner.join_handle.take()
+        };
+        if let Some(join_handle) = join_handle {
+            join_handle.join().unwrap();
+        }
+        self.0.lock().unwrap().raw.close()
+    }
+}
+
+impl LinkDevice for Device {
+    fn name(&self) -> String {
+        self.0.lock().unwrap().name.clone()
+    }
+    fn flags(&self) -> DeviceFlags {
+        self.0.lock().unwrap().flags
+    }
+    fn mtu(&self) -> usize {
+        self.0.lock().unwrap().mtu
+    }
+    fn header_len(&self) -> usize {
+        0
+    }
+    fn resolution(&self) -> Resolution {
+        Resolution::PointToPoint
+    }
+    fn addr(&self) -> ethernet::MacAddr {
+        ethernet::ADDR_ANY
+    }
+    fn broadcast_addr(&self) -> ethernet::MacAddr {
+        ethernet::ADDR_ANY
+    }
+    fn interface(&self) -> Option<ip::interface::Interface> {
+        let interface = self.0.lock().unwrap().interface.clone();
+        interface.and_then(|interface| interface.upgrade())
+    }
+    fn stack(&self) -> Stack {
+        self.0.lock().unwrap().stack.get()
+    }
+    fn stop(&self) {
+        self.0.lock().unwrap().terminate = true;
+    }
+    fn fd(&self) -> Option<RawFd> {
+        self.0.lock().unwrap().raw.fd()
+    }
+    fn receive(&self) -> Result<(), Error> {
+        let raw = self.0.lock().unwrap().raw.clone();
+        loop {
+            let device = self.clone();
+            // the descriptor is readable, so this does not block
+            let result = raw.rx(Box::new(move |buf| ip::rx(buf, &device)), 0);
+            // a stream such as SLIP may have brought more than one datagram
+            if !raw.pending() {
+                return result;
+            }
+            if let Err(err) = result {
+                log_rx_error!(self.name(), err);
+            }
+        }
+    }
+    fn is_running(&self) -> bool {
+        self.0.lock().unwrap().join_handle.is_some()
+    }
+    fn tx(
+        &self,
+        type_: ethernet::Type,
+        payload: Buffer,
+        _dst: ethernet::MacAddr,
+    ) -> Result<(), Error> {
+        let inner = self.0.lock().unwrap();
+        match type_ {
+            ethernet::Type::Ip => inner.raw.tx(payload),
+            _ => Err(Error::Invalid(format!(
+                "{} can not be sent on `{}`",
+                type_, inner.name
+            ))),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+// leveled logging, filtered per module and written to a replaceable sink
+//
+// packets are logged as one-line summaries at debug level and dumped in full, hexdumps
+// included, at trace level. filters read like `info,ethernet=debug,ip::fragment=trace`
+// and are taken from MICROPS_LOG until `configure` replaces them.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use chrono::{DateTime, Utc};
+
+use crate::error::Error;
+
+pub const ENV: &str = "MICROPS_LOG";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Level, Error> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "off" => Level::Off,
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return Err(Error::Invalid(format!("invalid log level: {}", s))),
+        })
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Level::Off => "OFF",
+                Level::Error => "ERROR",
+                Level::Warn => "WARN",
+                Level::Info => "INFO",
+                Level::Debug => "DEBUG",
+                Level::Trace => "TRACE",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    pub level: Level,
+    // e.g. `ethernet::bridge`
+    pub module: String,
+    pub message: String,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:5} {}: {}",
+            self.timestamp.format("%H:%M:%S%.3f"),
+            self.level,
+            self.module,
+            self.message
+        )
+    }
+}
+
+pub trait Sink: Send + Sync {
+    fn write(&self, record: &Record);
+}
+
+pub struct Stderr;
+
+impl Sink for Stderr {
+    fn write(&self, record: &Record) {
+        eprintln!("{}", record);
+    }
+}
+
+pub struct File(Mutex<fs::File>);
+
+impl File {
+    /// appends to `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<File, Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(File(Mutex::new(file)))
+    }
+}
+
+impl Sink for File {
+    fn write(&self, record: &Record) {
+        let _ = writeln!(self.0.lock().unwrap(), "{}", record);
+    }
+}
+
+pub struct Callback(pub Box<dyn Fn(&Record) + Send + Sync>);
+
+impl Sink for Callback {
+    fn write(&self, record: &Record) {
+        (self.0)(record)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Filter {
+    default: Level,
+    // the longest matching module prefix wins
+    modules: Vec<(String, Level)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Filter, Error> {
+        let mut filter = Filter {
+            default: Level::Info,
+            modules: vec![],
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let first = parts.next().unwrap();
+            match parts.next() {
+                Some(level) => {
+                    let level = level.trim().parse()?;
+                    filter.modules.push((first.trim().to_string(), level));
+                }
+                None => filter.default = first.parse()?,
+            }
+        }
+        Ok(filter)
+    }
+
+    fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix
+                    || (module.starts_with(prefix.as_str())
+                        && module[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+}
+
+lazy_static! {
+    static ref FILTER: RwLock<Filter> = RwLock::new(from_env());
+    static ref SINK: RwLock<Arc<dyn Sink>> = RwLock::new(Arc::new(Stderr));
+}
+
+// the filters in MICROPS_LOG, or the default with a warning when they do not parse
+fn from_env() -> Filter {
+    let default = Filter {
+        default: Level::Info,
+        modules: vec![],
+    };
+    match ::std::env::var(ENV) {
+        Ok(spec) => Filter::parse(&spec).unwrap_or_else(|err| {
+            // written past the filters, which are what is being set up
+            let message = format!("ignoring {}={:?}: {}", ENV, spec, err);
+            log(module_path!(), Level::Warn, message);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// reads MICROPS_LOG now rather than at the first message, so that a bad value is reported
+/// right away.
+pub fn init() {
+    lazy_static::initialize(&FILTER);
+}
+
+/// replaces the filters, e.g. with `warn,arp=debug`.
+pub fn configure(spec: &str) -> Result<(), Error> {
+    *FILTER.write().unwrap() = Filter::parse(spec)?;
+    Ok(())
+}
+
+/// sets the level of `module` and everything below it, or the default for an empty `module`.
+pub fn set_level(module: &str, level: Level) {
+    let mut filter = FILTER.write().unwrap();
+    if module.is_empty() {
+        filter.default = level;
+    } else {
+        filter.modules.retain(|(prefix, _)| prefix != module);
+        filter.modules.push((module.to_string(), level));
+    }
+}
+
+pub fn set_sink(sink: Arc<dyn Sink>) {
+    *SINK.write().unwrap() = sink;
+}
+
+/// strips the crate name off a `module_path!()`.
+fn module_name(path: &str) -> &str {
+    match path.find("::") {
+        Some(idx) => &path[idx + 2..],
+        None => path,
+    }
+}
+
+pub fn enabled(path: &str, level: Level) -> bool {
+    level != Level::Off && level <= FILTER.read().unwrap().level(module_name(path))
+}
+
+pub fn log(path: &str, level: Level, message: String) {
+    let record = Record {
+        timestamp: Utc::now(),
+        level,
+        module: module_name(path).to_string(),
+        message,
+    };
+    let sink = SINK.read().unwrap().clone();
+    sink.write(&record);
+}
+
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        if $crate::log::enabled(module_path!(), $crate::log::Level::$level) {
+            $crate::log::log(module_path!(), $crate::log::Level::$level, format!($($arg)+))
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!(Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!(Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!(Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!(Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log!(Trace, $($arg)+) };
+}
+
+/// logs why a device could not take a received frame: at debug level when it was only
+/// not for us, at warn level when it was malformed or reading it failed.
+macro_rules! log_rx_error {
+    ($device:expr, $err:expr) => {
+        match $err {
+            $crate::error::Error::Dropped(_) => debug!("{}: {}", $device, $err),
+            _ => warn!("{}: rx failed: {}", $device, $err),
+        }
+    };
+}
+
+/// logs `packet` by its one-line `Display` at debug level, or by its full `dump` at trace level.
+macro_rules! log_packet {
+    ($what:expr, $packet:expr) => {
+        if $crate::log::enabled(module_path!(), $crate::log::Level::Trace) {
+            trace!("{}\n{}", $what, $packet.dump())
+        } else {
+            debug!("{}: {}", $what, $packet)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_level() {
+        let filter = Filter::parse("").unwrap();
+        assert_eq!(filter.level("ip"), Level::Info);
+        let filter = Filter::parse("DEBUG").unwrap();
+        assert_eq!(filter.level("ip"), Level::Debug);
+    }
+
+    #[test]
+    fn longest_module_prefix_wins() {
+        let filter = Filter::parse("warn, ethernet=debug ,ethernet::stp=trace,").unwrap();
+        assert_eq!(filter.level("ip"), Level::Warn);
+        assert_eq!(filter.level("ethernet"), Level::Debug);
+        assert_eq!(filter.level("ethernet::lldp"), Level::Debug);
+        assert_eq!(filter.level("ethernet::stp"), Level::Trace);
+        // a prefix only matches whole path segments
+        assert_eq!(filter.level("ethernetx"), Level::Warn);
+    }
+
+    #[test]
+    fn bad_levels_are_errors() {
+        assert!(Filter::parse("loud").is_err());
+        assert!(Filter::parse("info,ip=").is_err());
+    }
+
+    #[test]
+    fn crate_name_is_stripped() {
+        assert_eq!(module_name("microps::ip::route"), "ip::route");
+        assert_eq!(module_name("microps"), "microps");
+    }
+}
@@ -1,7 +1,14 @@
 use crate::buffer::Buffer;
-use std::error::Error;
+use crate::error::{Error, Layer};
 
 pub trait Packet<T> {
-    fn from_buffer(buffer: Buffer) -> Result<T, Box<dyn Error>>;
+    const LAYER: Layer;
+
+    fn from_buffer(buffer: Buffer) -> Result<T, Error>;
     fn to_buffer(self) -> Buffer;
+
+    /// parses a received packet, blaming any failure on this layer.
+    fn parse(buffer: Buffer) -> Result<T, Error> {
+        Self::from_buffer(buffer).map_err(|err| err.in_layer(Self::LAYER))
+    }
 }
@@ -0,0 +1,426 @@
+// an LD_PRELOAD shim running unmodified programs' UDP over the stack
+//
+//   cargo build --features preload
+//   MICROPS_DEVICE=tap0 MICROPS_ADDRESS=10.0.0.2 MICROPS_NETMASK=255.255.255.0 \
+//     LD_PRELOAD=$PWD/target/debug/libmicrops_rs.so nc -u 10.0.0.1 7
+//
+// socket(2) for AF_INET datagrams opens a socket of the C API instead, and the calls
+// below service it: socket, bind, connect, sendto, send, write, recvfrom, recv, read,
+// close and poll. Everything else goes to libc. The stack comes up on the first such
+// socket, configured by MICROPS_DEVICE, MICROPS_MAC (optional), MICROPS_ADDRESS,
+// MICROPS_NETMASK and MICROPS_GATEWAY (optional); without MICROPS_DEVICE nothing is
+// intercepted.
+//
+// each socket is backed by an unused kernel socket, whose descriptor the program sees, so
+// that numbers never collide and fcntl(2) and setsockopt(2) keep working on it; O_NONBLOCK
+// set there is honoured. Not covered: sendmsg/recvmsg, select/epoll, dup, and filtering
+// received datagrams by the connected peer.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::os::raw::{c_int, c_void};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libc::{nfds_t, pollfd, sockaddr, sockaddr_in, socklen_t, ssize_t};
+
+use crate::{
+    capi::{self, Errno},
+    ethernet, ip,
+};
+
+// libc's own `$name`, looked up once
+macro_rules! real {
+    ($name:ident: fn($($arg:ty),*) -> $ret:ty) => {{
+        lazy_static! {
+            static ref REAL: usize = unsafe {
+                libc::dlsym(
+                    libc::RTLD_NEXT,
+                    concat!(stringify!($name), "\0").as_ptr() as *const libc::c_char,
+                ) as usize
+            };
+        }
+        unsafe { std::mem::transmute::<usize, unsafe extern "C" fn($($arg),*) -> $ret>(*REAL) }
+    }};
+}
+
+#[derive(Clone, Copy)]
+struct Shim {
+    // the descriptor of the C API
+    fd: c_int,
+    // set by connect(2), for send(2) and write(2)
+    peer: Option<sockaddr_in>,
+}
+
+lazy_static! {
+    // by the kernel descriptor standing in for the socket
+    static ref SHIMS: Mutex<HashMap<c_int, Shim>> = Mutex::new(HashMap::new());
+    static ref STARTED: Mutex<bool> = Mutex::new(false);
+}
+
+// whether any socket has been intercepted, sparing other calls the table lock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// how long a poll mixing kernel descriptors waits on them at a time
+const POLL_SLICE: Duration = Duration::from_millis(10);
+
+thread_local! {
+    // set while the shim runs the stack, whose own calls must reach libc
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+fn busy() -> bool {
+    // a thread being torn down is not running the shim
+    BUSY.try_with(|busy| busy.get()).unwrap_or(true)
+}
+
+fn enter<T, F: FnOnce() -> T>(f: F) -> T {
+    BUSY.with(|busy| busy.set(true));
+    let ret = f();
+    BUSY.with(|busy| busy.set(false));
+    ret
+}
+
+fn shim(fd: c_int) -> Option<Shim> {
+    if !ACTIVE.load(Ordering::Acquire) || busy() {
+        return None;
+    }
+    SHIMS.lock().unwrap().get(&fd).copied()
+}
+
+fn last_errno() -> Errno {
+    Errno(unsafe { *libc::__errno_location() })
+}
+
+fn nonblocking(fd: c_int) -> bool {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    flags != -1 && flags & libc::O_NONBLOCK != 0
+}
+
+fn var(name: &str) -> Result<String, Errno> {
+    env::var(name).map_err(|_| {
+        error!("{} is not set", name);
+        Errno(libc::EINVAL)
+    })
+}
+
+fn start() -> Result<(), Errno> {
+    let mut started = STARTED.lock().unwrap();
+    if *started {
+        return Ok(());
+    }
+    let mac = match env::var("MICROPS_MAC") {
+        Ok(mac) => ethernet::MacAddr::from_str(&mac)?,
+        Err(_) => ethernet::ADDR_ANY,
+    };
+    let gateway = match env::var("MICROPS_GATEWAY") {
+        Ok(gateway) => Some(ip::Addr::from_str(&gateway)?),
+        Err(_) => None,
+    };
+    capi::init(
+        &var("MICROPS_DEVICE")?,
+        mac,
+        ip::Addr::from_str(&var("MICROPS_ADDRESS")?)?,
+        ip::Addr::from_str(&var("MICROPS_NETMASK")?)?,
+        gateway,
+    )?;
+    *started = true;
+    Ok(())
+}
+
+/// # Safety
+/// As socket(2).
+#[no_mangle]
+pub unsafe extern "C" fn socket(domain: c_int, type_: c_int, protocol: c_int) -> c_int {
+    let real = real!(socket: fn(c_int, c_int, c_int) -> c_int);
+    let flags = libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    if domain != libc::AF_INET
+        || type_ & !flags != libc::SOCK_DGRAM
+        || busy()
+        || env::var_os("MICROPS_DEVICE").is_none()
+    {
+        return real(domain, type_, protocol);
+    }
+    enter(|| {
+        capi::call(|| {
+            start()?;
+            // non-blocking is decided per call, by the stand-in's flags
+            let fd = capi::mps_socket(domain, libc::SOCK_DGRAM, protocol);
+            if fd == -1 {
+                return Err(last_errno());
+            }
+            let stand_in = real(libc::AF_INET, libc::SOCK_DGRAM | (type_ & flags), 0);
+            if stand_in == -1 {
+                let err = last_errno();
+                capi::mps_close(fd);
+                return Err(err);
+            }
+            SHIMS
+                .lock()
+                .unwrap()
+                .insert(stand_in, Shim { fd, peer: None });
+            ACTIVE.store(true, Ordering::Release);
+            Ok(stand_in)
+        })
+    })
+}
+
+/// # Safety
+/// As bind(2).
+#[no_mangle]
+pub unsafe extern "C" fn bind(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    match shim(fd) {
+        Some(shim) => enter(|| capi::mps_bind(shim.fd, addr, len)),
+        None => real!(bind: fn(c_int, *const sockaddr, socklen_t) -> c_int)(fd, addr, len),
+    }
+}
+
+/// # Safety
+/// As connect(2).
+#[no_mangle]
+pub unsafe extern "C" fn connect(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    if shim(fd).is_none() {
+        return real!(connect: fn(c_int, *const sockaddr, socklen_t) -> c_int)(fd, addr, len);
+    }
+    capi::call(|| {
+        if addr.is_null() || (len as usize) < std::mem::size_of::<sockaddr_in>() {
+            return Err(Errno(libc::EINVAL));
+        }
+        let peer = *(addr as *const sockaddr_in);
+        if peer.sin_family != libc::AF_INET as libc::sa_family_t {
+            return Err(Errno(libc::EAFNOSUPPORT));
+        }
+        match SHIMS.lock().unwrap().get_mut(&fd) {
+            Some(shim) => shim.peer = Some(peer),
+            None => return Err(Errno(libc::EBADF)),
+        }
+        Ok(0)
+    })
+}
+
+/// # Safety
+/// As sendto(2).
+#[no_mangle]
+pub unsafe extern "C" fn sendto(
+    fd: c_int,
+    buf: *const c_void,
+    len: usize,
+    flags: c_int,
+    dst: *const sockaddr,
+    dst_len: socklen_t,
+) -> ssize_t {
+    match shim(fd) {
+        Some(shim) if dst.is_null() => send_shim(shim, buf, len, flags),
+        Some(shim) => enter(|| capi::mps_sendto(shim.fd, buf, len, flags, dst, dst_len)),
+        None => {
+            real!(sendto: fn(c_int, *const c_void, usize, c_int, *const sockaddr, socklen_t) -> ssize_t)(
+                fd, buf, len, flags, dst, dst_len,
+            )
+        }
+    }
+}
+
+/// # Safety
+/// As send(2).
+#[no_mangle]
+pub unsafe extern "C" fn send(fd: c_int, buf: *const c_void, len: usize, flags: c_int) -> ssize_t {
+    match shim(fd) {
+        Some(shim) => send_shim(shim, buf, len, flags),
+        None => real!(send: fn(c_int, *const c_void, usize, c_int) -> ssize_t)(fd, buf, len, flags),
+    }
+}
+
+/// # Safety
+/// As write(2).
+#[no_mangle]
+pub unsafe extern "C" fn write(fd: c_int, buf: *const c_void, len: usize) -> ssize_t {
+    match shim(fd) {
+        Some(shim) => send_shim(shim, buf, len, 0),
+        None => real!(write: fn(c_int, *const c_void, usize) -> ssize_t)(fd, buf, len),
+    }
+}
+
+// to the connected peer
+unsafe fn send_shim(shim: Shim, buf: *const c_void, len: usize, flags: c_int) -> ssize_t {
+    match shim.peer {
+        Some(peer) => enter(|| {
+            capi::mps_sendto(
+                shim.fd,
+                buf,
+                len,
+                flags,
+                &peer as *const sockaddr_in as *const sockaddr,
+                std::mem::size_of::<sockaddr_in>() as socklen_t,
+            )
+        }),
+        None => capi::call(|| Err(Errno(libc::EDESTADDRREQ))),
+    }
+}
+
+/// # Safety
+/// As recvfrom(2).
+#[no_mangle]
+pub unsafe extern "C" fn recvfrom(
+    fd: c_int,
+    buf: *mut c_void,
+    len: usize,
+    flags: c_int,
+    src: *mut sockaddr,
+    src_len: *mut socklen_t,
+) -> ssize_t {
+    match shim(fd) {
+        Some(shim) => recv_shim(fd, shim, buf, len, flags, src, src_len),
+        None => {
+            real!(recvfrom: fn(c_int, *mut c_void, usize, c_int, *mut sockaddr, *mut socklen_t) -> ssize_t)(
+                fd, buf, len, flags, src, src_len,
+            )
+        }
+    }
+}
+
+/// # Safety
+/// As recv(2).
+#[no_mangle]
+pub unsafe extern "C" fn recv(fd: c_int, buf: *mut c_void, len: usize, flags: c_int) -> ssize_t {
+    match shim(fd) {
+        Some(shim) => recv_shim(
+            fd,
+            shim,
+            buf,
+            len,
+            flags,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        ),
+        None => real!(recv: fn(c_int, *mut c_void, usize, c_int) -> ssize_t)(fd, buf, len, flags),
+    }
+}
+
+/// # Safety
+/// As read(2).
+#[no_mangle]
+pub unsafe extern "C" fn read(fd: c_int, buf: *mut c_void, len: usize) -> ssize_t {
+    match shim(fd) {
+        Some(shim) => recv_shim(
+            fd,
+            shim,
+            buf,
+            len,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        ),
+        None => real!(read: fn(c_int, *mut c_void, usize) -> ssize_t)(fd, buf, len),
+    }
+}
+
+unsafe fn recv_shim(
+    fd: c_int,
+    shim: Shim,
+    buf: *mut c_void,
+    len: usize,
+    mut flags: c_int,
+    src: *mut sockaddr,
+    src_len: *mut socklen_t,
+) -> ssize_t {
+    if nonblocking(fd) {
+        flags |= libc::MSG_DONTWAIT;
+    }
+    enter(|| capi::mps_recvfrom(shim.fd, buf, len, flags, src, src_len))
+}
+
+/// # Safety
+/// As close(2).
+#[no_mangle]
+pub unsafe extern "C" fn close(fd: c_int) -> c_int {
+    let real = real!(close: fn(c_int) -> c_int);
+    let shim = match shim(fd) {
+        Some(_) => SHIMS.lock().unwrap().remove(&fd),
+        None => None,
+    };
+    match shim {
+        Some(shim) => {
+            let ret = enter(|| capi::mps_close(shim.fd));
+            real(fd);
+            ret
+        }
+        None => real(fd),
+    }
+}
+
+/// # Safety
+/// As poll(2).
+#[no_mangle]
+pub unsafe extern "C" fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int {
+    let real = real!(poll: fn(*mut pollfd, nfds_t, c_int) -> c_int);
+    if fds.is_null() || nfds == 0 || !ACTIVE.load(Ordering::Acquire) || busy() {
+        return real(fds, nfds, timeout);
+    }
+    let fds = std::slice::from_raw_parts_mut(fds, nfds as usize);
+    // where each descriptor goes, and as what
+    let mut ours = vec![];
+    let mut theirs = vec![];
+    let mut places = vec![];
+    for fd in fds.iter() {
+        match shim(fd.fd) {
+            Some(shim) => {
+                places.push((true, ours.len()));
+                ours.push(pollfd {
+                    fd: shim.fd,
+                    events: fd.events,
+                    revents: 0,
+                });
+            }
+            None => {
+                places.push((false, theirs.len()));
+                theirs.push(*fd);
+            }
+        }
+    }
+    if ours.is_empty() {
+        return real(fds.as_mut_ptr(), nfds, timeout);
+    }
+    let deadline = if timeout < 0 {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout as u64))
+    };
+    // without kernel descriptors the stack waits alone; with them, both are taken turns at
+    let ready = loop {
+        let ours_timeout = if theirs.is_empty() { timeout } else { 0 };
+        let ready = enter(|| capi::mps_poll(ours.as_mut_ptr(), ours.len() as nfds_t, ours_timeout));
+        if ready == -1 || theirs.is_empty() {
+            break ready;
+        }
+        let slice = match deadline {
+            _ if ready > 0 => Duration::from_millis(0),
+            Some(deadline) => POLL_SLICE.min(deadline.saturating_duration_since(Instant::now())),
+            None => POLL_SLICE,
+        };
+        let their_ready = real(
+            theirs.as_mut_ptr(),
+            theirs.len() as nfds_t,
+            slice.as_millis() as c_int,
+        );
+        if their_ready == -1 {
+            break -1;
+        }
+        if ready + their_ready > 0 || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break ready + their_ready;
+        }
+    };
+    if ready == -1 {
+        return -1;
+    }
+    for (fd, (is_ours, i)) in fds.iter_mut().zip(places) {
+        fd.revents = if is_ours {
+            ours[i].revents
+        } else {
+            theirs[i].revents
+        };
+    }
+    ready
+}
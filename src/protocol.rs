@@ -1,6 +1,4 @@
-use crate::{buffer, icmp, ip, udp};
-use std::error::Error;
-use std::sync::{Arc, Mutex};
+use crate::{buffer, error::Error, ip};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,10 +49,5 @@ pub trait Protocol {
         src: ip::Addr,
         dst: ip::Addr,
         interface: &ip::interface::Interface,
-    ) -> Result<(), Box<dyn Error>>;
-}
-
-lazy_static! {
-    pub static ref PROTOCOLS: Mutex<Vec<Arc<dyn Protocol + Send + Sync>>> =
-        Mutex::new(vec![icmp::IcmpProtocol::new(), udp::UdpProtocol::new(),]);
+    ) -> Result<(), Error>;
 }
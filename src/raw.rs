@@ -1,7 +1,7 @@
-use crate::{buffer::Buffer, ethernet::MacAddr};
-use std::error::Error;
+use crate::{buffer::Buffer, error::Error, ethernet::MacAddr, slip};
+use std::io;
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
-use std::thread::JoinHandle;
 
 pub mod socket;
 pub mod tap;
@@ -11,44 +11,112 @@ pub mod tap;
 pub enum Type {
     Auto,
     Tap,
+    Tun,
     Socket,
+    Slip,
+    // a port of `sim::Simulator`, which carries ethernet frames
+    Sim,
     // Bpf,
 }
 
+impl Type {
+    /// whether frames on this device carry an Ethernet header
+    pub fn is_ethernet(&self) -> bool {
+        !matches!(self, Type::Tun | Type::Slip)
+    }
+}
+
 // assume as HAVE_PF_PACKET
 const DEFAULT_TYPE: Type = Type::Socket;
 
 use std::fmt::Debug;
 pub trait RawDevice: Debug {
-    fn close(&self) -> Result<(), Box<dyn Error>>;
+    fn close(&self) -> Result<(), Error>;
     fn rx(
         &self,
-        callback: Box<dyn FnOnce(Buffer) -> Result<Option<JoinHandle<()>>, Box<dyn Error>>>,
+        callback: Box<dyn FnOnce(Buffer) -> Result<(), Error>>,
         timeout: i32,
-    ) -> Result<Option<JoinHandle<()>>, Box<dyn Error>>;
-    fn tx(&self, buf: Buffer) -> Result<(), Box<dyn Error>>;
+    ) -> Result<(), Error>;
+    fn tx(&self, buf: Buffer) -> Result<(), Error>;
 
     fn type_(&self) -> Type;
     fn name(&self) -> &String;
-    fn addr(&self) -> Result<MacAddr, Box<dyn Error>>;
+    fn addr(&self) -> Result<MacAddr, Error>;
+    /// a descriptor that is readable whenever `rx` has a frame, to wait on with others.
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// whether `rx` has frames read already, which the descriptor does not signal.
+    fn pending(&self) -> bool {
+        false
+    }
+}
+
+// writes one frame to `fd`, which the kernel takes whole or not at all
+fn write_frame(fd: RawFd, buf: &[u8]) -> Result<(), Error> {
+    match unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) } {
+        -1 => Err(io::Error::last_os_error().into()),
+        len if len as usize != buf.len() => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            format!("wrote {} of {} bytes", len, buf.len()),
+        )
+        .into()),
+        _ => Ok(()),
+    }
 }
 
-fn detect_type(name: &str) -> Type {
+/// the type `Type::Auto` stands for with device `name`.
+pub fn detect_type(name: &str) -> Type {
     if name.starts_with("tap") {
         Type::Tap
+    } else if name.starts_with("tun") {
+        Type::Tun
+    } else if name.starts_with("/dev/") {
+        Type::Slip
     } else {
         DEFAULT_TYPE
     }
 }
 
-pub fn open(mut type_: Type, name: &str) -> Arc<dyn RawDevice + Sync + Send> {
+pub fn open(type_: Type, name: &str) -> Arc<dyn RawDevice + Sync + Send> {
+    try_open(type_, name).unwrap()
+}
+
+/// like `open`, returning the error instead of panicking on it.
+pub fn try_open(mut type_: Type, name: &str) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
     if type_ == Type::Auto {
         type_ = detect_type(name);
     }
     match type_ {
         Type::Auto => unreachable!(),
-        Type::Tap => tap::Device::open(name).unwrap(),
-        Type::Socket => socket::Device::open(name).unwrap(),
+        Type::Tap => tap::Device::open(name),
+        Type::Tun => tap::Device::open_tun(name, false),
+        Type::Socket => socket::Device::open(name),
+        Type::Slip => slip::Device::open(name, &slip::Config::default()),
+        Type::Sim => Err(Error::Invalid(
+            "simulated ports are made by `sim::Simulator::connect`".to_string(),
+        )),
         // Type::Bpf => unimplemented!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd;
+
+    #[test]
+    fn failed_writes_are_errors() {
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        write_frame(write_end, b"frame").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(unistd::read(read_end, &mut buf).unwrap(), 5);
+        unistd::close(read_end).unwrap();
+        unistd::close(write_end).unwrap();
+        // what a closed device is left with
+        match write_frame(-1, b"frame") {
+            Err(Error::Io(err)) => assert_eq!(err.raw_os_error(), Some(libc::EBADF)),
+            other => panic!("{:?}", other),
+        }
+    }
+}
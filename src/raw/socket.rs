@@ -1,5 +1,6 @@
 use super::{RawDevice, Type};
 use crate::buffer::Buffer;
+use crate::error::Error;
 use crate::ethernet::{MacAddr, ADDR_LEN};
 use crate::util::*;
 use ifstructs::ifreq;
@@ -10,12 +11,11 @@ use nix::{
     unistd,
 };
 use std::convert::TryInto;
-use std::error::Error;
+use std::io;
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
-use std::thread::JoinHandle;
 
 ioctl_readwrite_bad!(get_iface_index, 0x8933, ifreq);
-ioctl_readwrite_bad!(get_iface_flags, libc::SIOCGIFFLAGS, ifreq);
 ioctl_readwrite_bad!(get_hwaddr, libc::SIOCGIFHWADDR, ifreq);
 
 #[derive(Debug)]
@@ -25,25 +25,27 @@ pub struct Device {
 }
 
 impl Device {
-    pub fn open(name: &str) -> Result<Arc<dyn RawDevice + Sync + Send>, Box<dyn Error>> {
+    pub fn open(name: &str) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
         let device = Device {
-            fd: socket(
-                AddressFamily::Packet,
-                SockType::Raw,
-                SockFlag::empty(),
-                Some(unsafe { ::std::mem::transmute(libc::ETH_P_ALL) }),
-            )?,
+            // `SockProtocol` has no variant for ETH_P_ALL, so call socket(2) directly
+            fd: unsafe {
+                libc::socket(
+                    libc::AF_PACKET,
+                    libc::SOCK_RAW,
+                    htons(ETH_P_ALL.try_into().unwrap()) as i32,
+                )
+            },
             name: name.to_string(),
         };
         if device.fd == -1 {
+            let err = io::Error::last_os_error();
             device.close()?;
-            return Err(RuntimeError::new("socket failed".to_string()));
+            return Err(err.into());
         }
         let mut ifr = ifreq::from_name(name)?;
         if let Err(err) = unsafe { get_iface_index(device.fd, &mut ifr) } {
             device.close()?;
-            return Err(Box::new(err));
-            // return Err(Box::new(RuntimeError::new("ioctl [SIOCGIFINDEX]".to_string())));
+            return Err(err.into());
         }
         let socket_addr = SockAddr::Link(LinkAddr(libc::sockaddr_ll {
             sll_family: libc::AF_PACKET.try_into().unwrap(),
@@ -56,18 +58,28 @@ impl Device {
         }));
         if let Err(err) = bind(device.fd, &socket_addr) {
             device.close()?;
-            return Err(Box::new(err));
+            return Err(err.into());
         }
-        if let Err(err) = unsafe { get_iface_flags(device.fd, &mut ifr) } {
-            device.close()?;
-            return Err(Box::new(err));
-        }
-        unsafe {
-            ifr.ifr_ifru.ifr_flags = ifr.ifr_ifru.ifr_flags | (libc::IFF_PROMISC as i16);
-        }
-        if let Err(err) = unsafe { get_iface_flags(device.fd, &mut ifr) } {
+        // unlike setting IFF_PROMISC, the membership goes away with the socket
+        let mreq = libc::packet_mreq {
+            mr_ifindex: unsafe { ifr.ifr_ifru.ifr_ifindex },
+            mr_type: libc::PACKET_MR_PROMISC as u16,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                device.fd,
+                libc::SOL_PACKET,
+                libc::PACKET_ADD_MEMBERSHIP,
+                &mreq as *const libc::packet_mreq as *const libc::c_void,
+                ::std::mem::size_of::<libc::packet_mreq>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
             device.close()?;
-            return Err(Box::new(err));
+            return Err(err.into());
         }
         Ok(Arc::new(device))
     }
@@ -80,7 +92,10 @@ impl RawDevice for Device {
     fn name(&self) -> &String {
         &self.name
     }
-    fn addr(&self) -> Result<MacAddr, Box<dyn Error>> {
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
+    fn addr(&self) -> Result<MacAddr, Error> {
         let fd = socket(
             AddressFamily::Inet,
             SockType::Datagram,
@@ -91,7 +106,7 @@ impl RawDevice for Device {
         ifr.ifr_ifru.ifr_addr.sa_family = libc::AF_INET.try_into().unwrap();
         if let Err(err) = unsafe { get_hwaddr(fd, &mut ifr) } {
             unistd::close(fd)?;
-            Err(Box::new(err))
+            Err(err.into())
         } else {
             let addr = unsafe { ifr.ifr_ifru.ifr_hwaddr.sa_data };
             let addr =
@@ -102,7 +117,7 @@ impl RawDevice for Device {
             Ok(MacAddr(*addr))
         }
     }
-    fn close(&self) -> Result<(), Box<dyn Error>> {
+    fn close(&self) -> Result<(), Error> {
         if self.fd != -1 {
             unistd::close(self.fd)? // TODO
         }
@@ -112,32 +127,31 @@ impl RawDevice for Device {
 
     fn rx(
         &self,
-        callback: Box<dyn FnOnce(Buffer) -> Result<Option<JoinHandle<()>>, Box<dyn Error>>>,
+        callback: Box<dyn FnOnce(Buffer) -> Result<(), Error>>,
         timeout: i32,
-    ) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+    ) -> Result<(), Error> {
         let mut pfd = pollfd {
             fd: self.fd,
             events: POLLIN,
             revents: 0,
         };
         match unsafe { libc::poll(&mut pfd, 1, timeout) } {
-            0 => return Ok(None), // timeout
+            0 => return Ok(()), // timeout
             -1 => {
                 if errno() != Errno::EINTR as i32 {
-                    return Err(RuntimeError::new("poll error".to_string()));
+                    return Err(io::Error::last_os_error().into());
                 } else {
-                    return Ok(None);
+                    return Ok(());
                 }
             }
             _ => (),
         }
-        let mut buf = vec![];
-        buf.resize(2048, 0);
+        let mut buf = vec![0; 2048];
         let len: usize = match unsafe {
             libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
         } {
-            0 => return Ok(None), // timeout
-            -1 => return Err(RuntimeError::new("read error".to_string())),
+            0 => return Ok(()), // timeout
+            -1 => return Err(io::Error::last_os_error().into()),
             len => len,
         }
         .try_into()
@@ -146,9 +160,8 @@ impl RawDevice for Device {
         callback(Buffer::from_vec(buf))
     }
 
-    fn tx(&self, buf: Buffer) -> Result<(), Box<dyn Error>> {
+    fn tx(&self, buf: Buffer) -> Result<(), Error> {
         let buf = buf.to_vec();
-        unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
-        Ok(())
+        super::write_frame(self.fd, &buf)
     }
 }
@@ -1,59 +1,91 @@
 use super::{RawDevice, Type};
 use crate::buffer::Buffer;
+use crate::error::Error;
+use crate::ethernet;
 use crate::ethernet::{MacAddr, ADDR_LEN};
-use crate::util::RuntimeError;
 use ifstructs::ifreq;
-use libc::{self, pollfd, IFF_NO_PI, IFF_TAP, POLLIN};
+use libc::{self, pollfd, IFF_NO_PI, IFF_TAP, IFF_TUN, POLLIN};
 use nix::{
     errno::{errno, Errno},
     fcntl,
     sys::stat::Mode,
     unistd,
 };
-use std::error::Error;
+use std::io;
 use std::os::unix::io::RawFd;
 use std::sync::Arc;
-use std::thread::JoinHandle;
 
 ioctl_write_ptr!(tun_set_iff, 'T', 202, libc::c_int);
 
+// flags and protocol prepended to each packet unless IFF_NO_PI is set
+const PI_LEN: usize = 4;
+
 #[derive(Debug, Clone)]
 pub struct Device {
     fd: RawFd,
     name: String,
+    type_: Type,
+    packet_info: bool,
 }
 
 impl Device {
-    pub fn open(name: &str) -> Result<Arc<dyn RawDevice + Sync + Send>, Box<dyn Error>> {
+    pub fn open(name: &str) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
+        Device::open_with(name, Type::Tap, false)
+    }
+
+    /// opens a layer-3 TUN device, which carries bare IP packets.
+    /// With `packet_info` each packet is preceded by the 4-byte packet information header.
+    pub fn open_tun(
+        name: &str,
+        packet_info: bool,
+    ) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
+        Device::open_with(name, Type::Tun, packet_info)
+    }
+
+    fn open_with(
+        name: &str,
+        type_: Type,
+        packet_info: bool,
+    ) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
         let device = Device {
-            fd: fcntl::open("/dev/net/tun", fcntl::OFlag::O_RDWR, Mode::empty())
-                .expect("can not open /dev/net/tun"),
+            fd: fcntl::open("/dev/net/tun", fcntl::OFlag::O_RDWR, Mode::empty())?,
             name: name.to_string(),
+            type_,
+            packet_info,
         };
-        if device.fd == -1 {
-            device.close().unwrap();
-            return Err(RuntimeError::new(format!("can not open : {}", name)));
+        let mut flags = if type_ == Type::Tun { IFF_TUN } else { IFF_TAP };
+        if !packet_info {
+            flags |= IFF_NO_PI;
+        }
+        let attached = ifreq::from_name(name).map_err(Error::from).and_then(|mut ifr| {
+            ifr.set_flags(flags as i16);
+            unsafe { tun_set_iff(device.fd, &mut ifr as *mut _ as *mut _) }?;
+            Ok(())
+        });
+        if let Err(err) = attached {
+            device.close()?;
+            return Err(err);
         }
-        let mut ifr = ifreq::from_name(name)?;
-        ifr.set_flags(IFF_TAP as i16 | IFF_NO_PI as i16);
-
-        unsafe { tun_set_iff(device.fd, &mut ifr as *mut _ as *mut _) }?;
-        // device.close().unwrap();
-        //
         Ok(Arc::new(device))
     }
 }
 
 impl RawDevice for Device {
     fn type_(&self) -> Type {
-        Type::Tap
+        self.type_
     }
     fn name(&self) -> &String {
         &self.name
     }
-    fn addr(&self) -> Result<MacAddr, Box<dyn Error>> {
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
+    fn addr(&self) -> Result<MacAddr, Error> {
+        if self.type_ == Type::Tun {
+            return Ok(ethernet::ADDR_ANY);
+        }
         let socket = match unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) } {
-            -1 => return Err(RuntimeError::new("socket".to_string())),
+            -1 => return Err(io::Error::last_os_error().into()),
 
             socket => socket,
         };
@@ -65,10 +97,11 @@ impl RawDevice for Device {
         };
 
         if unsafe { libc::ioctl(socket, libc::SIOCGIFHWADDR, &ifr) } == -1 {
+            let err = io::Error::last_os_error();
             unsafe {
                 libc::close(socket);
             }
-            return Err(RuntimeError::new("ioctl [SIOCGIFHWADDR]".to_string()));
+            return Err(err.into());
         }
         let addr = unsafe { ifr.ifr_ifru.ifr_hwaddr.sa_data };
         let addr = unsafe { &*(addr.as_ptr() as *const [i8; ADDR_LEN] as *const [u8; ADDR_LEN]) };
@@ -78,51 +111,60 @@ impl RawDevice for Device {
         Ok(MacAddr(*addr))
     }
 
-    fn close(&self) -> Result<(), Box<dyn Error>> {
+    fn close(&self) -> Result<(), Error> {
         if self.fd != -1 {
-            unistd::close(self.fd).expect("can not close fd")
+            unistd::close(self.fd)?
         }
         Ok(())
     }
     fn rx(
         &self,
-        callback: Box<dyn FnOnce(Buffer) -> Result<Option<JoinHandle<()>>, Box<dyn Error>>>,
+        callback: Box<dyn FnOnce(Buffer) -> Result<(), Error>>,
         timeout: i32,
-    ) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+    ) -> Result<(), Error> {
         let mut pfd = pollfd {
             fd: self.fd,
             events: POLLIN,
             revents: 0,
         };
         match unsafe { libc::poll(&mut pfd, 1, timeout) } {
-            0 => return Ok(None), // timeout
+            0 => return Ok(()), // timeout
             -1 => {
                 if errno() != Errno::EINTR as i32 {
-                    return Err(RuntimeError::new("poll error".to_string()));
+                    return Err(io::Error::last_os_error().into());
                 } else {
-                    return Ok(None);
+                    return Ok(());
                 }
             }
             _ => (),
         }
-        let mut buf = vec![];
-        buf.resize(2048, 0);
+        let mut buf = vec![0; 2048];
         use std::convert::TryInto;
         let len: usize = match unsafe {
             libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
         } {
-            0 => return Ok(None),
-            -1 => return Err(RuntimeError::new("read error".to_string())),
+            0 => return Ok(()),
+            -1 => return Err(io::Error::last_os_error().into()),
             len => len,
         }
         .try_into()
         .unwrap();
         buf.resize(len, 0);
+        if self.packet_info {
+            if len < PI_LEN || u16::from_be_bytes([buf[2], buf[3]]) != ethernet::Type::Ip as u16 {
+                return Ok(()); // not IPv4
+            }
+            buf.drain(..PI_LEN);
+        }
         callback(Buffer::from_vec(buf))
     }
-    fn tx(&self, buf: Buffer) -> Result<(), Box<dyn Error>> {
-        let buf = buf.to_vec();
-        unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
-        Ok(())
+    fn tx(&self, buf: Buffer) -> Result<(), Error> {
+        let mut buf = buf.to_vec();
+        if self.packet_info {
+            let mut pi = vec![0, 0];
+            pi.extend_from_slice(&(ethernet::Type::Ip as u16).to_be_bytes());
+            buf.splice(0..0, pi);
+        }
+        super::write_frame(self.fd, &buf)
     }
 }
@@ -0,0 +1,363 @@
+// a deterministic network simulator
+//
+// Stacks created by a simulator read its virtual clock, and their devices are joined by
+// simulated links with latency, bandwidth, loss, duplication and reordering. Nothing
+// happens between calls to the simulator: each step delivers the next frame, fires the
+// next timer or wakes the next sleeping thread, then lets the stacks' threads run one at
+// a time. With the same seed, a run repeats exactly.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    buffer::Buffer,
+    clock::{self, Clock, VirtualClock},
+    error::Error,
+    ethernet::{self, MacAddr},
+    link::LinkDevice,
+    raw::{RawDevice, Type},
+    stack::Stack,
+    timer,
+};
+
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    // one way, added to every frame
+    pub latency: Duration,
+    // bits per second in each direction, or 0 for no limit
+    pub bandwidth: u64,
+    // per frame probabilities
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    // a reordered frame is held back by up to this long, letting later ones overtake it
+    pub reorder_delay: Duration,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            latency: Duration::from_millis(1),
+            bandwidth: 0,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub sent: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delivered: u64,
+}
+
+type Outbox = Arc<Mutex<Vec<(usize, Buffer)>>>;
+
+/// one end of a simulated link, as seen by an ethernet device.
+#[derive(Debug)]
+pub struct Port {
+    index: usize,
+    name: String,
+    addr: MacAddr,
+    outbox: Outbox,
+    clock: Arc<VirtualClock>,
+}
+
+impl RawDevice for Port {
+    fn close(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    // frames are handed to the device by the simulator, so there is never one to read;
+    // a receive thread waits out its timeout on the simulated clock
+    fn rx(
+        &self,
+        _callback: Box<dyn FnOnce(Buffer) -> Result<(), Error>>,
+        timeout: i32,
+    ) -> Result<(), Error> {
+        if timeout > 0 {
+            Clock::Virtual(self.clock.clone()).sleep(Duration::from_millis(timeout as u64));
+        }
+        Ok(())
+    }
+    fn tx(&self, buf: Buffer) -> Result<(), Error> {
+        self.outbox.lock().unwrap().push((self.index, buf));
+        Ok(())
+    }
+    fn type_(&self) -> Type {
+        Type::Sim
+    }
+    fn name(&self) -> &String {
+        &self.name
+    }
+    fn addr(&self) -> Result<MacAddr, Error> {
+        Ok(self.addr)
+    }
+}
+
+struct End {
+    device: ethernet::Device,
+    peer: usize,
+    config: LinkConfig,
+    // of `config`, as the clock counts it
+    latency: chrono::Duration,
+    // the transmitter is sending an earlier frame until then
+    busy_until: DateTime<Utc>,
+}
+
+pub struct Simulator {
+    clock: Arc<VirtualClock>,
+    rng: StdRng,
+    // whose timers the simulator runs, in the order they joined
+    stacks: Vec<Stack>,
+    ends: Vec<End>,
+    outbox: Outbox,
+    // frames in flight by arrival time, ties broken by the order they were sent
+    events: BTreeMap<(DateTime<Utc>, u64), (usize, Buffer)>,
+    seq: u64,
+    counters: Counters,
+}
+
+fn is_probability(p: f64) -> bool {
+    (0.0..=1.0).contains(&p)
+}
+
+// whether a frame can be held up this long, counted in nanoseconds as the clock does
+fn is_delay(delay: Duration) -> bool {
+    delay.as_nanos() <= i64::MAX as u128
+}
+
+impl Simulator {
+    pub fn new(seed: u64) -> Simulator {
+        Simulator {
+            clock: Arc::new(VirtualClock::new(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0))),
+            rng: StdRng::seed_from_u64(seed),
+            stacks: vec![],
+            ends: vec![],
+            outbox: Arc::new(Mutex::new(vec![])),
+            events: BTreeMap::new(),
+            seq: 0,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        Clock::Virtual(self.clock.clone())
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.counters.clone()
+    }
+
+    /// creates a stack on the simulated clock, seeded from the simulator.
+    pub fn stack(&mut self) -> Stack {
+        let stack = Stack::with_clock(self.clock(), self.rng.gen());
+        self.stacks.push(stack.clone());
+        stack
+    }
+
+    fn is_driving(&self, stack: &Stack) -> bool {
+        match stack.clock() {
+            Clock::Virtual(clock) => Arc::ptr_eq(clock, &self.clock),
+            Clock::System => false,
+        }
+    }
+
+    fn open_port(&self, stack: &Stack, index: usize) -> Result<ethernet::Device, Error> {
+        // locally administered, numbered in the order the ports were made
+        let n = (index as u32 + 1).to_be_bytes();
+        let addr = MacAddr([0x02, 0x00, n[0], n[1], n[2], n[3]]);
+        let port = Port {
+            index,
+            name: format!("sim{}", index),
+            addr,
+            outbox: self.outbox.clone(),
+            clock: self.clock.clone(),
+        };
+        ethernet::Device::from_raw(stack, Arc::new(port), addr)
+    }
+
+    /// Joins `a` and `b` with a link, returning a new ethernet device on each side. The
+    /// devices are driven by the simulator, so they must not be `run`.
+    pub fn connect(
+        &mut self,
+        a: &Stack,
+        b: &Stack,
+        config: LinkConfig,
+    ) -> Result<(ethernet::Device, ethernet::Device), Error> {
+        if !self.is_driving(a) || !self.is_driving(b) {
+            return Err(Error::Invalid(
+                "stack is not on this simulator's clock".to_string(),
+            ));
+        }
+        if !is_probability(config.loss)
+            || !is_probability(config.duplicate)
+            || !is_probability(config.reorder)
+        {
+            return Err(Error::Invalid(format!(
+                "link probabilities must be within 0..1: {:?}",
+                config
+            )));
+        }
+        if !is_delay(config.latency) || !is_delay(config.reorder_delay) {
+            return Err(Error::Invalid(format!(
+                "link delays are too long: {:?}",
+                config
+            )));
+        }
+        let latency = chrono::Duration::nanoseconds(config.latency.as_nanos() as i64);
+        // also drive the timers of stacks made on our clock without `stack`
+        for stack in [a, b].iter() {
+            if !self.stacks.contains(stack) {
+                self.stacks.push((*stack).clone());
+            }
+        }
+        let index = self.ends.len();
+        let device_a = self.open_port(a, index)?;
+        let device_b = self.open_port(b, index + 1)?;
+        let now = self.now();
+        for (device, peer) in [(device_a.clone(), index + 1), (device_b.clone(), index)]
+            .iter()
+            .cloned()
+        {
+            self.ends.push(End {
+                device,
+                peer,
+                config: config.clone(),
+                latency,
+                busy_until: now,
+            });
+        }
+        Ok((device_a, device_b))
+    }
+
+    // puts a frame sent on `port` on its link
+    fn transmit(&mut self, port: usize, frame: Buffer) {
+        let now = self.clock.now();
+        self.counters.sent += 1;
+        let config = self.ends[port].config.clone();
+        if self.rng.gen_bool(config.loss) {
+            self.counters.lost += 1;
+            return;
+        }
+        let copies = if self.rng.gen_bool(config.duplicate) {
+            self.counters.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let end = &mut self.ends[port];
+            let start = end.busy_until.max(now);
+            let bits = frame.0.len() as u64 * 8;
+            let serialization = match (bits * 1_000_000_000).checked_div(config.bandwidth) {
+                Some(ns) => chrono::Duration::nanoseconds(ns as i64),
+                None => chrono::Duration::zero(),
+            };
+            end.busy_until = start + serialization;
+            let mut at = end.busy_until + end.latency;
+            let peer = end.peer;
+            if self.rng.gen_bool(config.reorder) {
+                self.counters.reordered += 1;
+                let max = config.reorder_delay.as_nanos() as i64;
+                at = at + chrono::Duration::nanoseconds(self.rng.gen_range(0, max + 1));
+            }
+            self.events.insert((at, self.seq), (peer, frame.clone()));
+            self.seq += 1;
+        }
+    }
+
+    // runs the stacks' due timers and threads until they are idle, then picks up what they
+    // sent
+    fn settle(&mut self) {
+        loop {
+            for stack in self.stacks.iter() {
+                timer::run_due(stack);
+            }
+            self.clock.settle();
+            let sent = ::std::mem::take(&mut *self.outbox.lock().unwrap());
+            if sent.is_empty() {
+                return;
+            }
+            for (port, frame) in sent {
+                self.transmit(port, frame);
+            }
+        }
+    }
+
+    fn next_at(&self) -> Option<DateTime<Utc>> {
+        let frame = self.events.keys().next().map(|(at, _)| *at);
+        let timers = self.stacks.iter().filter_map(timer::next_deadline);
+        frame
+            .into_iter()
+            .chain(self.clock.next_wakeup())
+            .chain(timers)
+            .min()
+    }
+
+    /// delivers the next frame or wakes the next timer, whichever is due first; false if
+    /// nothing is left to happen.
+    pub fn step(&mut self) -> bool {
+        self.settle();
+        let at = match self.next_at() {
+            Some(at) => at,
+            None => return false,
+        };
+        self.clock.advance(at);
+        let key = self.events.keys().next().cloned();
+        if let Some(key) = key.filter(|(frame_at, _)| *frame_at == at) {
+            let (port, frame) = self.events.remove(&key).unwrap();
+            self.counters.delivered += 1;
+            let device = &self.ends[port].device;
+            // each rejected frame is counted in `stats` too
+            if let Err(err) = device.rx(frame) {
+                log_rx_error!(device.name(), err);
+            }
+        }
+        self.settle();
+        true
+    }
+
+    /// runs everything due within `duration`, leaving the clock at its end.
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(duration, || false);
+    }
+
+    /// runs until `done` holds, checked after every step, for at most `limit`; whether
+    /// `done` was reached. A limit too long for the clock to count to is no limit at all.
+    pub fn run_until<F>(&mut self, limit: Duration, mut done: F) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        let end = clock::deadline(self.now(), limit);
+        loop {
+            self.settle();
+            if done() {
+                return true;
+            }
+            match self.next_at() {
+                Some(at) if end.is_none_or(|end| at <= end) => {
+                    self.step();
+                }
+                _ => break,
+            }
+        }
+        if let Some(end) = end {
+            self.clock.advance(end);
+        }
+        self.settle();
+        done()
+    }
+}
@@ -1 +1,329 @@
+use crate::buffer::Buffer;
+use crate::error::Error;
+use crate::ethernet::{self, MacAddr};
+use crate::raw::{RawDevice, Type};
+use libc::{self, pollfd, POLLIN};
+use nix::{
+    errno::{errno, Errno},
+    fcntl,
+    sys::{
+        stat::Mode,
+        termios::{self, ControlFlags, FlushArg, SetArg},
+    },
+    unistd,
+};
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
 
+pub use nix::sys::termios::BaudRate;
+
+// RFC 1055
+pub const END: u8 = 0xc0;
+pub const ESC: u8 = 0xdb;
+pub const ESC_END: u8 = 0xdc;
+pub const ESC_ESC: u8 = 0xdd;
+
+// longest datagram accepted from the line
+const FRAME_SIZE_MAX: usize = ethernet::PAYLOAD_SIZE_MAX;
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    // a leading END flushes any line noise received by the peer
+    buf.push(END);
+    for byte in data {
+        match *byte {
+            END => {
+                buf.push(ESC);
+                buf.push(ESC_END);
+            }
+            ESC => {
+                buf.push(ESC);
+                buf.push(ESC_ESC);
+            }
+            byte => buf.push(byte),
+        }
+    }
+    buf.push(END);
+    buf
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    frame: Vec<u8>,
+    escaped: bool,
+    overrun: bool,
+    frames: VecDeque<Buffer>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            frame: vec![],
+            escaped: false,
+            overrun: false,
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn input(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let byte = match (self.escaped, *byte) {
+                (false, END) => {
+                    let frame = std::mem::take(&mut self.frame);
+                    if !frame.is_empty() && !self.overrun {
+                        self.frames.push_back(Buffer::from_vec(frame));
+                    }
+                    self.overrun = false;
+                    continue;
+                }
+                (false, ESC) => {
+                    self.escaped = true;
+                    continue;
+                }
+                (true, ESC_END) => END,
+                (true, ESC_ESC) => ESC,
+                // protocol violation, RFC 1055 keeps the byte as is
+                (_, byte) => byte,
+            };
+            self.escaped = false;
+            if self.frame.len() < FRAME_SIZE_MAX {
+                self.frame.push(byte);
+            } else {
+                self.overrun = true;
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Buffer> {
+        self.frames.pop_front()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub baud_rate: BaudRate,
+    pub hardware_flow_control: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud_rate: BaudRate::B115200,
+            hardware_flow_control: false,
+        }
+    }
+}
+
+pub fn baud_rate(n: u32) -> Option<BaudRate> {
+    Some(match n {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        921600 => BaudRate::B921600,
+        _ => return None,
+    })
+}
+
+#[derive(Debug)]
+pub struct Device {
+    fd: RawFd,
+    name: String,
+    decoder: Mutex<Decoder>,
+}
+
+impl Device {
+    /// opens a serial line or pseudo-terminal, e.g. `/dev/ttyUSB0` or `/dev/pts/3`
+    pub fn open(
+        path: &str,
+        config: &Config,
+    ) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
+        let fd = fcntl::open(
+            path,
+            fcntl::OFlag::O_RDWR | fcntl::OFlag::O_NOCTTY,
+            Mode::empty(),
+        )?;
+        Device::from_fd(fd, path, config)
+    }
+
+    /// takes over an already opened line, e.g. the master side of a pty pair
+    pub fn from_fd(
+        fd: RawFd,
+        name: &str,
+        config: &Config,
+    ) -> Result<Arc<dyn RawDevice + Sync + Send>, Error> {
+        let device = Device {
+            fd,
+            name: name.to_string(),
+            decoder: Mutex::new(Decoder::new()),
+        };
+        if let Err(err) = device.configure(config) {
+            device.close()?;
+            return Err(err);
+        }
+        Ok(Arc::new(device))
+    }
+
+    fn configure(&self, config: &Config) -> Result<(), Error> {
+        let mut termios = termios::tcgetattr(self.fd)?;
+        termios::cfmakeraw(&mut termios);
+        termios::cfsetspeed(&mut termios, config.baud_rate)?;
+        termios.control_flags |= ControlFlags::CLOCAL | ControlFlags::CREAD;
+        termios
+            .control_flags
+            .set(ControlFlags::CRTSCTS, config.hardware_flow_control);
+        termios::tcsetattr(self.fd, SetArg::TCSANOW, &termios)?;
+        termios::tcflush(self.fd, FlushArg::TCIOFLUSH)?;
+        Ok(())
+    }
+}
+
+impl RawDevice for Device {
+    fn type_(&self) -> Type {
+        Type::Slip
+    }
+    fn name(&self) -> &String {
+        &self.name
+    }
+    fn addr(&self) -> Result<MacAddr, Error> {
+        // point-to-point, no hardware address
+        Ok(ethernet::ADDR_ANY)
+    }
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
+    fn pending(&self) -> bool {
+        !self.decoder.lock().unwrap().frames.is_empty()
+    }
+    fn close(&self) -> Result<(), Error> {
+        if self.fd != -1 {
+            unistd::close(self.fd)?
+        }
+        Ok(())
+    }
+    fn rx(
+        &self,
+        callback: Box<dyn FnOnce(Buffer) -> Result<(), Error>>,
+        timeout: i32,
+    ) -> Result<(), Error> {
+        // one read may have completed several frames
+        if let Some(frame) = self.decoder.lock().unwrap().pop() {
+            return callback(frame);
+        }
+        let mut pfd = pollfd {
+            fd: self.fd,
+            events: POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pfd, 1, timeout) } {
+            0 => return Ok(()), // timeout
+            -1 => {
+                if errno() != Errno::EINTR as i32 {
+                    return Err(io::Error::last_os_error().into());
+                } else {
+                    return Ok(());
+                }
+            }
+            _ => (),
+        }
+        let mut buf = vec![0; 2048];
+        let len = match unistd::read(self.fd, buf.as_mut_slice()) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(err) => return Err(err.into()),
+        };
+        let frame = {
+            let mut decoder = self.decoder.lock().unwrap();
+            decoder.input(&buf[..len]);
+            decoder.pop()
+        };
+        match frame {
+            Some(frame) => callback(frame),
+            None => Ok(()),
+        }
+    }
+    fn tx(&self, buf: Buffer) -> Result<(), Error> {
+        let buf = encode(buf.to_vec().as_slice());
+        let mut done = 0;
+        while done < buf.len() {
+            done += unistd::write(self.fd, &buf[done..])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::new();
+        decoder.input(bytes);
+        let mut frames = vec![];
+        while let Some(frame) = decoder.pop() {
+            frames.push(frame.to_vec());
+        }
+        frames
+    }
+
+    #[test]
+    fn escapes_end_and_esc() {
+        let data = [0x01, END, 0x02, ESC, 0x03];
+        assert_eq!(
+            encode(&data),
+            vec![END, 0x01, ESC, ESC_END, 0x02, ESC, ESC_ESC, 0x03, END]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = vec![END, ESC, ESC_END, ESC_ESC, 0x45, END, END, ESC];
+        assert_eq!(decode(&encode(&data)), vec![data]);
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let mut encoded = encode(&[0x11, END, 0x22]);
+        encoded.extend(encode(&[ESC, 0x33]));
+        let mut decoder = Decoder::new();
+        // the first frame breaks off between ESC and ESC_END
+        decoder.input(&encoded[..3]);
+        assert!(decoder.pop().is_none());
+        decoder.input(&encoded[3..]);
+        assert_eq!(decoder.pop().unwrap().to_vec(), vec![0x11, END, 0x22]);
+        assert_eq!(decoder.pop().unwrap().to_vec(), vec![ESC, 0x33]);
+        assert!(decoder.pop().is_none());
+    }
+
+    #[test]
+    fn skips_empty_frames() {
+        assert_eq!(decode(&[END, END, END]), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn keeps_bad_escapes_as_is() {
+        assert_eq!(decode(&[ESC, 0x41, END]), vec![vec![0x41]]);
+    }
+
+    #[test]
+    fn drops_overlong_frames() {
+        let mut bytes = vec![0x55; FRAME_SIZE_MAX + 1];
+        bytes.push(END);
+        bytes.extend(encode(&[0x66]));
+        assert_eq!(decode(&bytes), vec![vec![0x66]]);
+    }
+}
@@ -0,0 +1,248 @@
+// one instance of the network stack: its devices and every table the protocols keep
+//
+// Stacks are independent of each other, so one process can run several, e.g. two hosts
+// and a router joined by veth pairs in a single test. The stack holds its devices and
+// interfaces, which only refer back to it weakly; sockets keep it alive. A stack reads
+// the time from its clock, which a simulator may drive instead of the system.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    arp,
+    clock::Clock,
+    error::Error,
+    ethernet::{self, lldp, vlan},
+    icmp, ip,
+    link::{self, LinkDevice},
+    log,
+    protocol::Protocol,
+    raw, stats, timer, udp,
+};
+
+pub struct StackImpl {
+    pub id: usize,
+    pub clock: Clock,
+    // random choices of the protocols, e.g. ACD probe delays
+    rng: Mutex<StdRng>,
+    pub devices: Mutex<Vec<Arc<dyn LinkDevice>>>,
+    // receive threads of ethernet devices, by device name
+    pub(crate) join_handles: Mutex<HashMap<String, JoinHandle<()>>>,
+    pub(crate) vlans: Mutex<Vec<vlan::Device>>,
+    pub(crate) lldp: lldp::State,
+    pub(crate) arp: arp::Tables,
+    pub(crate) ip: ip::State,
+    pub(crate) protocols: Mutex<Vec<Arc<dyn Protocol + Send + Sync>>>,
+    pub(crate) icmp: icmp::State,
+    pub(crate) udp: udp::State,
+    pub(crate) counters: Mutex<stats::Counters>,
+    pub(crate) timers: timer::Timers,
+    // set by `shutdown`, for the receive threads
+    shut_down: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Stack(pub Arc<StackImpl>);
+
+/// a stack as referred to by what it holds, which does not keep it alive.
+#[derive(Clone)]
+pub struct WeakStack(Weak<StackImpl>);
+
+impl WeakStack {
+    pub fn upgrade(&self) -> Option<Stack> {
+        self.0.upgrade().map(Stack)
+    }
+
+    /// the stack, which must still be alive.
+    pub fn get(&self) -> Stack {
+        self.upgrade().expect("the stack is gone")
+    }
+}
+
+lazy_static! {
+    static ref NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+}
+
+impl Stack {
+    pub fn new() -> Stack {
+        Stack::build(Clock::System, StdRng::from_entropy())
+    }
+
+    /// a stack reading the time from `clock`, whose random choices all follow from `seed`.
+    pub fn with_clock(clock: Clock, seed: u64) -> Stack {
+        Stack::build(clock, StdRng::seed_from_u64(seed))
+    }
+
+    fn build(clock: Clock, rng: StdRng) -> Stack {
+        log::init();
+        let stack = Stack(Arc::new(StackImpl {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            clock,
+            rng: Mutex::new(rng),
+            devices: Mutex::new(vec![]),
+            join_handles: Mutex::new(HashMap::new()),
+            vlans: Mutex::new(vec![]),
+            lldp: lldp::State::default(),
+            arp: arp::Tables::default(),
+            ip: ip::State::default(),
+            protocols: Mutex::new(vec![
+                Arc::new(icmp::IcmpProtocol {}),
+                Arc::new(udp::UdpProtocol {}),
+            ]),
+            icmp: icmp::State::default(),
+            udp: udp::State::default(),
+            counters: Mutex::new(stats::Counters::default()),
+            timers: timer::Timers::default(),
+            shut_down: AtomicBool::new(false),
+        }));
+        // expiry that has to happen even when nothing is received
+        timer::add_periodic(
+            &stack,
+            "arp: aging",
+            chrono::Duration::seconds(arp::AGING_INTERVAL),
+            arp::patrol,
+        )
+        .expect("the aging interval is positive");
+        timer::add_periodic(
+            &stack,
+            "ip: reassembly timeout",
+            chrono::Duration::seconds(ip::fragment::PATROL_INTERVAL),
+            ip::fragment::patrol,
+        )
+        .expect("the patrol interval is positive");
+        stack
+    }
+
+    pub fn id(&self) -> usize {
+        self.0.id
+    }
+
+    pub fn downgrade(&self) -> WeakStack {
+        WeakStack(Arc::downgrade(&self.0))
+    }
+
+    /// stops the timers and the receive threads, then lets go of the devices, interfaces
+    /// and routes. Not to be called from a callback or a receive thread of the stack.
+    pub fn shutdown(&self) {
+        self.0.shut_down.store(true, Ordering::Relaxed);
+        self.0.timers.stop();
+        let devices = ::std::mem::take(&mut *self.0.devices.lock().unwrap());
+        for device in devices.iter() {
+            device.stop();
+        }
+        let handles: Vec<_> = self.0.join_handles.lock().unwrap().drain().collect();
+        for (_, handle) in handles {
+            let _ = handle.join();
+        }
+        self.0.vlans.lock().unwrap().clear();
+        arp::clear(self);
+        ip::clear(self);
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.0.shut_down.load(Ordering::Relaxed)
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.0.clock
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.0.clock.now()
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        self.0.clock.sleep(duration)
+    }
+
+    pub fn spawn<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.clock.spawn(f)
+    }
+
+    /// a random number in `low..high`.
+    pub(crate) fn gen_range(&self, low: u64, high: u64) -> u64 {
+        self.0.rng.lock().unwrap().gen_range(low, high)
+    }
+
+    pub fn devices(&self) -> Vec<Arc<dyn LinkDevice>> {
+        self.0.devices.lock().unwrap().clone()
+    }
+
+    /// opens an ethernet device on `name`, see `ethernet::Device::open`.
+    pub fn open_ethernet(
+        &self,
+        name: &str,
+        addr: ethernet::MacAddr,
+        raw_type: raw::Type,
+    ) -> Result<ethernet::Device, Error> {
+        ethernet::Device::open(self, name, addr, raw_type)
+    }
+
+    pub fn open_loopback(&self) -> link::loopback::Device {
+        link::loopback::Device::open(self)
+    }
+
+    /// assigns an address to `device`, which must belong to this stack.
+    pub fn create_interface<D: LinkDevice + 'static>(
+        &self,
+        device: D,
+        unicast: ip::Addr,
+        netmask: ip::Addr,
+        gateway: Option<ip::Addr>,
+    ) -> ip::interface::Interface {
+        ip::interface::Interface::new(device, unicast, netmask, gateway)
+    }
+
+    pub fn open_udp(&self) -> Result<udp::Socket, Error> {
+        udp::open(self)
+    }
+
+    pub fn interface_by_addr(&self, addr: ip::Addr) -> Option<ip::interface::Interface> {
+        ip::interface::by_addr(self, addr)
+    }
+}
+
+// the threads left are told to stop, as nothing refers to the stack strongly any more
+impl Drop for StackImpl {
+    fn drop(&mut self) {
+        self.timers.stop();
+        for device in self.devices.lock().unwrap().iter() {
+            device.stop();
+        }
+    }
+}
+
+impl Default for Stack {
+    fn default() -> Stack {
+        Stack::new()
+    }
+}
+
+impl PartialEq for Stack {
+    fn eq(&self, other: &Stack) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// devices refer to their stack, so this must not print them back
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stack({})", self.0.id)
+    }
+}
+
+impl fmt::Debug for WeakStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WeakStack")
+    }
+}
@@ -0,0 +1,96 @@
+// counters in the manner of the SNMP MIBs (RFC 1213, RFC 4293)
+
+use std::collections::BTreeMap;
+
+use crate::stack::Stack;
+
+pub mod prometheus;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Device {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    // well-formed frames nobody wanted
+    pub rx_drops: u64,
+    // malformed frames
+    pub rx_errors: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ip {
+    pub in_receives: u64,
+    pub in_hdr_errors: u64,
+    pub in_addr_errors: u64,
+    pub forw_datagrams: u64,
+    pub in_unknown_protos: u64,
+    pub in_discards: u64,
+    pub in_delivers: u64,
+    pub out_requests: u64,
+    pub out_discards: u64,
+    pub out_no_routes: u64,
+    pub reasm_reqds: u64,
+    pub reasm_oks: u64,
+    pub reasm_fails: u64,
+    pub frag_oks: u64,
+    pub frag_fails: u64,
+    pub frag_creates: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Icmp {
+    pub in_msgs: u64,
+    pub in_errors: u64,
+    // keyed by ICMP type
+    pub in_types: BTreeMap<u8, u64>,
+    pub out_msgs: u64,
+    pub out_errors: u64,
+    pub out_types: BTreeMap<u8, u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Udp {
+    pub in_datagrams: u64,
+    pub no_ports: u64,
+    pub in_errors: u64,
+    pub out_datagrams: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Counters {
+    pub ip: Ip,
+    pub icmp: Icmp,
+    pub udp: Udp,
+    // every discarded packet, by reason
+    pub drops: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub devices: Vec<(String, Device)>,
+    pub counters: Counters,
+}
+
+pub fn update<F: FnOnce(&mut Counters)>(stack: &Stack, f: F) {
+    f(&mut stack.0.counters.lock().unwrap())
+}
+
+/// counts a packet discarded for `reason`.
+pub fn dropped(stack: &Stack, reason: &'static str) {
+    *stack.0.counters.lock().unwrap().drops.entry(reason).or_insert(0) += 1;
+}
+
+/// returns every counter of the stack at once.
+pub fn snapshot(stack: &Stack) -> Snapshot {
+    let devices = stack
+        .devices()
+        .iter()
+        .filter_map(|device| device.stats().map(|stats| (device.name(), stats)))
+        .collect();
+    Snapshot {
+        devices,
+        counters: stack.0.counters.lock().unwrap().clone(),
+    }
+}
@@ -0,0 +1,358 @@
+// renders the stack's counters and tables in the Prometheus text exposition format
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use crate::{arp, error::Error, ethernet::lldp, stack::Stack, stats, udp};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const PREFIX: &str = "microps";
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// writes one metric family; `samples` pairs a rendered label set (maybe empty) with a value.
+fn family(out: &mut String, name: &str, type_: &str, help: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, type_);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}_{}{} {}", PREFIX, name, labels, value);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    family(
+        out,
+        &format!("{}_total", name),
+        "counter",
+        help,
+        &[(String::new(), value)],
+    );
+}
+
+fn label(key: &str, value: &str) -> String {
+    format!("{{{}=\"{}\"}}", key, escape(value))
+}
+
+// a metric's name and help, and how to read it from a `T`
+type Family<T> = (&'static str, &'static str, fn(&T) -> u64);
+
+/// renders every counter and table size of the stack.
+pub fn render(stack: &Stack) -> String {
+    let snapshot = stats::snapshot(stack);
+    let mut out = String::new();
+
+    let device_families: [Family<stats::Device>; 7] = [
+        ("device_rx_frames_total", "Frames received.", |s| {
+            s.rx_frames
+        }),
+        ("device_rx_bytes_total", "Bytes received.", |s| s.rx_bytes),
+        (
+            "device_rx_drops_total",
+            "Received frames nobody wanted.",
+            |s| s.rx_drops,
+        ),
+        (
+            "device_rx_errors_total",
+            "Malformed frames received.",
+            |s| s.rx_errors,
+        ),
+        ("device_tx_frames_total", "Frames sent.", |s| s.tx_frames),
+        ("device_tx_bytes_total", "Bytes sent.", |s| s.tx_bytes),
+        (
+            "device_tx_errors_total",
+            "Frames the device failed to send.",
+            |s| s.tx_errors,
+        ),
+    ];
+    for (name, help, f) in device_families.iter() {
+        let samples = snapshot
+            .devices
+            .iter()
+            .map(|(device, stats)| (label("device", device), f(stats)))
+            .collect::<Vec<_>>();
+        family(&mut out, name, "counter", help, &samples);
+    }
+
+    // named after the MIB objects they mirror
+    let ip = &snapshot.counters.ip;
+    let icmp = &snapshot.counters.icmp;
+    let udp = &snapshot.counters.udp;
+    let counters = [
+        ("ip_in_receives", "ipInReceives", ip.in_receives),
+        ("ip_in_hdr_errors", "ipInHdrErrors", ip.in_hdr_errors),
+        ("ip_in_addr_errors", "ipInAddrErrors", ip.in_addr_errors),
+        ("ip_forw_datagrams", "ipForwDatagrams", ip.forw_datagrams),
+        (
+            "ip_in_unknown_protos",
+            "ipInUnknownProtos",
+            ip.in_unknown_protos,
+        ),
+        ("ip_in_discards", "ipInDiscards", ip.in_discards),
+        ("ip_in_delivers", "ipInDelivers", ip.in_delivers),
+        ("ip_out_requests", "ipOutRequests", ip.out_requests),
+        ("ip_out_discards", "ipOutDiscards", ip.out_discards),
+        ("ip_out_no_routes", "ipOutNoRoutes", ip.out_no_routes),
+        ("ip_reasm_reqds", "ipReasmReqds", ip.reasm_reqds),
+        ("ip_reasm_oks", "ipReasmOKs", ip.reasm_oks),
+        ("ip_reasm_fails", "ipReasmFails", ip.reasm_fails),
+        ("ip_frag_oks", "ipFragOKs", ip.frag_oks),
+        ("ip_frag_fails", "ipFragFails", ip.frag_fails),
+        ("ip_frag_creates", "ipFragCreates", ip.frag_creates),
+        ("icmp_in_msgs", "icmpInMsgs", icmp.in_msgs),
+        ("icmp_in_errors", "icmpInErrors", icmp.in_errors),
+        ("icmp_out_msgs", "icmpOutMsgs", icmp.out_msgs),
+        ("icmp_out_errors", "icmpOutErrors", icmp.out_errors),
+        ("udp_in_datagrams", "udpInDatagrams", udp.in_datagrams),
+        ("udp_no_ports", "udpNoPorts", udp.no_ports),
+        ("udp_in_errors", "udpInErrors", udp.in_errors),
+        ("udp_out_datagrams", "udpOutDatagrams", udp.out_datagrams),
+    ];
+    for (name, help, value) in counters.iter() {
+        counter(&mut out, name, help, *value);
+    }
+
+    let per_type = |types: &BTreeMap<u8, u64>| {
+        types
+            .iter()
+            .map(|(type_, count)| (label("type", &type_.to_string()), *count))
+            .collect::<Vec<_>>()
+    };
+    family(
+        &mut out,
+        "icmp_in_types_total",
+        "counter",
+        "ICMP messages received by type.",
+        &per_type(&icmp.in_types),
+    );
+    family(
+        &mut out,
+        "icmp_out_types_total",
+        "counter",
+        "ICMP messages sent by type.",
+        &per_type(&icmp.out_types),
+    );
+
+    let drops = snapshot
+        .counters
+        .drops
+        .iter()
+        .map(|(reason, count)| (label("reason", reason), *count))
+        .collect::<Vec<_>>();
+    family(
+        &mut out,
+        "drops_total",
+        "counter",
+        "Discarded packets by reason.",
+        &drops,
+    );
+
+    let mut arp_states = BTreeMap::new();
+    let mut arp_queued = 0;
+    for entry in arp::entries(stack) {
+        *arp_states.entry(entry.state.to_string()).or_insert(0) += 1;
+        arp_queued += entry.queued as u64;
+    }
+    let arp_states = arp_states
+        .iter()
+        .map(|(state, count)| (label("state", state), *count))
+        .collect::<Vec<_>>();
+    family(
+        &mut out,
+        "arp_entries",
+        "gauge",
+        "ARP table entries by state.",
+        &arp_states,
+    );
+    family(
+        &mut out,
+        "arp_queued_packets",
+        "gauge",
+        "Packets waiting for address resolution.",
+        &[(String::new(), arp_queued)],
+    );
+
+    let sockets = udp::sockets(stack);
+    family(
+        &mut out,
+        "udp_sockets",
+        "gauge",
+        "Open UDP sockets.",
+        &[(String::new(), sockets.len() as u64)],
+    );
+    // summed, as unbound sockets and a port bound on several interfaces share a label
+    let mut queued = BTreeMap::new();
+    for socket in sockets.iter() {
+        *queued.entry(socket.port).or_insert(0) += socket.queued as u64;
+    }
+    let queues = queued
+        .iter()
+        .map(|(port, count)| (label("port", &port.to_string()), *count))
+        .collect::<Vec<_>>();
+    family(
+        &mut out,
+        "udp_queue_depth",
+        "gauge",
+        "Datagrams received but not read, by local port.",
+        &queues,
+    );
+
+    family(
+        &mut out,
+        "lldp_neighbors",
+        "gauge",
+        "LLDP neighbors heard.",
+        &[(String::new(), lldp::neighbors(stack).len() as u64)],
+    );
+    out
+}
+
+fn respond(stack: &Stack, mut stream: TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+        if request.len() > 8192 {
+            return Err(Error::Invalid("request too long".to_string()));
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) | (Some("GET"), Some("/")) => ("200 OK", render(stack)),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// serves `/metrics` over HTTP on a host socket bound to `addr`, e.g. `127.0.0.1:9100`.
+pub fn serve(stack: &Stack, addr: &str) -> Result<thread::JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(addr)?;
+    let stack = stack.clone();
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let ret = stream
+                .map_err(Error::from)
+                .and_then(|stream| respond(&stack, stream));
+            if let Err(err) = ret {
+                warn!("serving metrics failed: {}", err);
+            }
+        }
+    }))
+}
+
+/// writes the metrics to `path`, replacing it at once so readers never see a partial file.
+pub fn write(stack: &Stack, path: &PathBuf) -> Result<(), Error> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, render(stack))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// rewrites `path` every `interval`, e.g. for node_exporter's textfile collector.
+pub fn write_every(stack: &Stack, path: PathBuf, interval: Duration) -> thread::JoinHandle<()> {
+    let stack = stack.clone();
+    thread::spawn(move || loop {
+        if let Err(err) = write(&stack, &path) {
+            warn!("writing {} failed: {}", path.display(), err);
+        }
+        thread::sleep(interval);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::Buffer,
+        ethernet,
+        ip::{self, interface::Interface},
+        sim::{LinkConfig, Simulator},
+    };
+    use std::collections::HashSet;
+
+    // the interface of `addr`/8 on `device`
+    fn configure(device: &mut ethernet::Device, addr: [u8; 4]) -> Interface {
+        let interface = Interface::new(device.clone(), ip::Addr(addr), ip::Addr([255, 0, 0, 0]), None);
+        device.add_interface(interface.clone());
+        interface
+    }
+
+    #[test]
+    fn every_series_is_rendered_once() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer_b, peer_c) = (sim.stack(), sim.stack(), sim.stack());
+        let (mut device_b, mut b) = sim.connect(&stack, &peer_b, LinkConfig::default()).unwrap();
+        let (mut device_c, mut c) = sim.connect(&stack, &peer_c, LinkConfig::default()).unwrap();
+        let interface_b = configure(&mut device_b, [10, 0, 0, 1]);
+        let interface_c = configure(&mut device_c, [11, 0, 0, 1]);
+        let b = configure(&mut b, [10, 0, 0, 2]);
+        let c = configure(&mut c, [11, 0, 0, 2]);
+        sim.run_for(Duration::from_secs(10));
+
+        // port 7 on both interfaces, and two sockets bound to nothing
+        let mut sockets = vec![];
+        for interface in [interface_b, interface_c] {
+            let mut socket = udp::open(&stack).unwrap();
+            socket.bind_interface(interface, 7).unwrap();
+            sockets.push(socket);
+        }
+        sockets.push(udp::open(&stack).unwrap());
+        sockets.push(udp::open(&stack).unwrap());
+        for (interface, dst, count) in [(b, [10, 0, 0, 1], 2), (c, [11, 0, 0, 1], 1)] {
+            let mut sender = udp::open(&interface.stack()).unwrap();
+            sender.bind_interface(interface, 0).unwrap();
+            for _ in 0..count {
+                sender
+                    .send_to(Buffer::from_vec(b"x".to_vec()), ip::Addr(dst), 7)
+                    .unwrap();
+            }
+        }
+        sim.run_for(Duration::from_secs(1));
+
+        let out = render(&stack);
+        let mut seen = HashSet::new();
+        for line in out.lines() {
+            assert!(seen.insert(line), "`{}` rendered twice", line);
+            if !line.starts_with('#') {
+                let series = line.rsplit_once(' ').unwrap().0;
+                assert!(seen.insert(series), "series `{}` rendered twice", series);
+            }
+        }
+        assert!(out.contains("microps_udp_sockets 4\n"));
+        assert!(out.contains("microps_udp_queue_depth{port=\"7\"} 3\n"));
+        assert!(out.contains("microps_udp_queue_depth{port=\"0\"} 0\n"));
+        assert!(out.contains("# TYPE microps_udp_in_datagrams_total counter\n"));
+        assert!(out.contains("microps_udp_in_datagrams_total 3\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label("reason", "a \"b\"\\\n"), "{reason=\"a \\\"b\\\"\\\\\\n\"}");
+    }
+}
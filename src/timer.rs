@@ -0,0 +1,394 @@
+// timers owned by a stack, for everything that has to happen later or again
+//
+// Callbacks run one at a time, in deadline order. On the system clock a thread of the
+// stack's own runs them, unless an event loop has taken them over; on a virtual clock the
+// simulator does, when it gets to their deadline. Either way they fire on an idle stack
+// too. The thread only holds the stack while callbacks run, and ends with it.
+
+use std::collections::BTreeMap;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+use nix::unistd;
+
+use crate::{
+    clock::Clock,
+    error::Error,
+    stack::{Stack, WeakStack},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+/// a pending timer, as returned by `timers`.
+#[derive(Debug, Clone)]
+pub struct TimerInfo {
+    pub id: TimerId,
+    pub name: String,
+    pub deadline: DateTime<Utc>,
+    // for a periodic timer
+    pub interval: Option<Duration>,
+}
+
+type Callback = Arc<dyn Fn(&Stack) + Send + Sync>;
+
+struct Timer {
+    name: String,
+    interval: Option<Duration>,
+    callback: Callback,
+}
+
+#[derive(Default)]
+struct Queue {
+    // by deadline, then by id, i.e. the order they were added
+    timers: BTreeMap<(DateTime<Utc>, u64), Timer>,
+    next_id: u64,
+    // the timer whose callback is running, and whether it was cancelled meanwhile
+    running: Option<(u64, bool)>,
+    has_thread: bool,
+    // run by an event loop instead of the thread
+    driven: bool,
+    // an eventfd the event loop waits on, written when the timers change
+    wakeup: Option<RawFd>,
+    // the stack was shut down or dropped
+    stopped: bool,
+}
+
+// shared with the timer thread, which has to wait on them without holding the stack
+#[derive(Default)]
+pub(crate) struct Timers {
+    queue: Arc<Mutex<Queue>>,
+    // tells the timer thread about a new earliest deadline
+    changed: Arc<Condvar>,
+}
+
+impl Timers {
+    // drops every timer and ends the thread
+    pub(crate) fn stop(&self) {
+        let timers = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.stopped = true;
+            ::std::mem::take(&mut queue.timers)
+        };
+        self.changed.notify_all();
+        // outside the lock, as the callbacks may hold anything
+        drop(timers);
+    }
+}
+
+fn add_timer(
+    stack: &Stack,
+    name: &str,
+    delay: Duration,
+    interval: Option<Duration>,
+    callback: Callback,
+) -> TimerId {
+    let deadline = stack.now() + delay;
+    let mut queue = stack.0.timers.queue.lock().unwrap();
+    let id = queue.next_id;
+    queue.next_id += 1;
+    if queue.stopped {
+        return TimerId(id);
+    }
+    queue.timers.insert(
+        (deadline, id),
+        Timer {
+            name: name.to_string(),
+            interval,
+            callback,
+        },
+    );
+    start_thread(stack, &mut queue);
+    stack.0.timers.changed.notify_all();
+    wake(&queue);
+    TimerId(id)
+}
+
+// has the event loop look at the deadlines again
+fn wake(queue: &Queue) {
+    if let Some(fd) = queue.wakeup {
+        if let Err(err) = unistd::write(fd, &1u64.to_ne_bytes()) {
+            warn!("waking the event loop failed: {}", err);
+        }
+    }
+}
+
+fn start_thread(stack: &Stack, queue: &mut Queue) {
+    if !queue.has_thread && !queue.driven && !queue.stopped {
+        if let Clock::System = stack.clock() {
+            queue.has_thread = true;
+            let weak = stack.downgrade();
+            let queue = stack.0.timers.queue.clone();
+            let changed = stack.0.timers.changed.clone();
+            thread::spawn(move || run(weak, queue, changed));
+        }
+    }
+}
+
+// stops the timer thread, leaving the caller to `run_due` whenever `wakeup` is written
+pub(crate) fn take_over(stack: &Stack, wakeup: RawFd) -> Result<(), Error> {
+    let mut queue = stack.0.timers.queue.lock().unwrap();
+    if queue.driven {
+        return Err(Error::Exists("event loop of the stack".to_string()));
+    }
+    queue.driven = true;
+    queue.wakeup = Some(wakeup);
+    stack.0.timers.changed.notify_all();
+    // so that no callback is left running on it
+    while queue.has_thread {
+        queue = stack.0.timers.changed.wait(queue).unwrap();
+    }
+    Ok(())
+}
+
+pub(crate) fn hand_back(stack: &Stack) {
+    let mut queue = stack.0.timers.queue.lock().unwrap();
+    queue.driven = false;
+    queue.wakeup = None;
+    start_thread(stack, &mut queue);
+}
+
+/// calls `f` once, `delay` from now.
+pub fn add<F>(stack: &Stack, name: &str, delay: Duration, f: F) -> TimerId
+where
+    F: Fn(&Stack) + Send + Sync + 'static,
+{
+    add_timer(stack, name, delay, None, Arc::new(f))
+}
+
+/// calls `f` every `interval`, starting `interval` from now; the interval must be positive.
+pub fn add_periodic<F>(
+    stack: &Stack,
+    name: &str,
+    interval: Duration,
+    f: F,
+) -> Result<TimerId, Error>
+where
+    F: Fn(&Stack) + Send + Sync + 'static,
+{
+    if interval <= Duration::zero() {
+        return Err(Error::Invalid(format!(
+            "{}: interval {} is not positive",
+            name, interval
+        )));
+    }
+    Ok(add_timer(stack, name, interval, Some(interval), Arc::new(f)))
+}
+
+/// stops a timer from firing (again); false if it is already gone.
+pub fn cancel(stack: &Stack, id: TimerId) -> bool {
+    let mut queue = stack.0.timers.queue.lock().unwrap();
+    if let Some((running, ref mut cancelled)) = queue.running {
+        if running == id.0 {
+            *cancelled = true;
+            return true;
+        }
+    }
+    let key = queue.timers.keys().find(|(_, id_)| *id_ == id.0).cloned();
+    match key {
+        Some(key) => {
+            queue.timers.remove(&key);
+            wake(&queue);
+            true
+        }
+        None => false,
+    }
+}
+
+/// returns the pending timers, soonest first.
+pub fn timers(stack: &Stack) -> Vec<TimerInfo> {
+    let queue = stack.0.timers.queue.lock().unwrap();
+    queue
+        .timers
+        .iter()
+        .map(|((deadline, id), timer)| TimerInfo {
+            id: TimerId(*id),
+            name: timer.name.clone(),
+            deadline: *deadline,
+            interval: timer.interval,
+        })
+        .collect()
+}
+
+pub fn next_deadline(stack: &Stack) -> Option<DateTime<Utc>> {
+    let queue = stack.0.timers.queue.lock().unwrap();
+    queue.timers.keys().next().map(|(deadline, _)| *deadline)
+}
+
+/// runs the callbacks of every timer that is due, returning how many fired.
+pub fn run_due(stack: &Stack) -> usize {
+    let mut fired = 0;
+    loop {
+        let now = stack.now();
+        let (deadline, id, timer) = {
+            let mut queue = stack.0.timers.queue.lock().unwrap();
+            let key = match queue.timers.keys().next() {
+                Some(&(deadline, id)) if deadline <= now => (deadline, id),
+                _ => return fired,
+            };
+            let timer = queue.timers.remove(&key).unwrap();
+            queue.running = Some((key.1, false));
+            (key.0, key.1, timer)
+        };
+        (timer.callback)(stack);
+        fired += 1;
+        let mut queue = stack.0.timers.queue.lock().unwrap();
+        let cancelled = match queue.running.take() {
+            Some((_, cancelled)) => cancelled,
+            None => false,
+        };
+        if let (Some(interval), false) = (timer.interval, cancelled) {
+            // skip the periods missed while the stack was busy
+            let mut next = deadline + interval;
+            while next <= now {
+                next = next + interval;
+            }
+            queue.timers.insert((next, id), timer);
+        }
+    }
+}
+
+// the timer thread of a stack on the system clock
+fn run(weak: WeakStack, queue: Arc<Mutex<Queue>>, changed: Arc<Condvar>) {
+    loop {
+        // dropped before waiting, which may be what frees the stack
+        match weak.upgrade() {
+            Some(stack) => run_due(&stack),
+            None => return,
+        };
+        let mut queue = queue.lock().unwrap();
+        if queue.driven || queue.stopped {
+            queue.has_thread = false;
+            changed.notify_all();
+            return;
+        }
+        let now = Utc::now();
+        match queue.timers.keys().next().map(|(deadline, _)| *deadline) {
+            Some(deadline) if deadline <= now => continue,
+            Some(deadline) => {
+                let timeout = (deadline - now).to_std().unwrap();
+                drop(changed.wait_timeout(queue, timeout).unwrap());
+            }
+            None => drop(changed.wait(queue).unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use chrono::TimeZone;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn stack() -> (Stack, Arc<VirtualClock>) {
+        let clock = Arc::new(VirtualClock::new(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0)));
+        let stack = Stack::with_clock(Clock::Virtual(clock.clone()), 1);
+        (stack, clock)
+    }
+
+    // the timers this test added, leaving out those of the stack itself
+    fn named(stack: &Stack, prefix: &str) -> Vec<TimerInfo> {
+        timers(stack)
+            .into_iter()
+            .filter(|info| info.name.starts_with(prefix))
+            .collect()
+    }
+
+    fn counter() -> (Arc<AtomicUsize>, impl Fn(&Stack) + Send + Sync + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_ = count.clone();
+        (count, move |_: &Stack| {
+            count_.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn fires_once_at_the_deadline() {
+        let (stack, clock) = stack();
+        let (count, f) = counter();
+        add(&stack, "test: once", Duration::seconds(2), f);
+        let start = clock.now();
+        assert_eq!(named(&stack, "test")[0].deadline, start + Duration::seconds(2));
+        clock.advance(start + Duration::milliseconds(1999));
+        run_due(&stack);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        clock.advance(start + Duration::seconds(2));
+        run_due(&stack);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(named(&stack, "test").is_empty());
+    }
+
+    #[test]
+    fn periodic_skips_missed_periods() {
+        let (stack, clock) = stack();
+        let (count, f) = counter();
+        let start = clock.now();
+        add_periodic(&stack, "test: tick", Duration::seconds(3), f).unwrap();
+        clock.advance(start + Duration::seconds(10));
+        run_due(&stack);
+        // late, it fires once and goes on from the next period still ahead
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        let info = &named(&stack, "test")[0];
+        assert_eq!(info.deadline, start + Duration::seconds(12));
+        assert_eq!(info.interval, Some(Duration::seconds(3)));
+    }
+
+    #[test]
+    fn non_positive_intervals_are_rejected() {
+        let (stack, _) = stack();
+        for secs in [0, -1].iter() {
+            let ret = add_periodic(&stack, "test: bad", Duration::seconds(*secs), |_| {});
+            assert!(matches!(ret, Err(Error::Invalid(_))));
+        }
+        assert!(named(&stack, "test").is_empty());
+    }
+
+    #[test]
+    fn cancelled_timers_do_not_fire() {
+        let (stack, clock) = stack();
+        let (count, f) = counter();
+        let id = add(&stack, "test: cancelled", Duration::seconds(1), f);
+        assert!(cancel(&stack, id));
+        assert!(!cancel(&stack, id));
+        clock.advance(clock.now() + Duration::seconds(5));
+        run_due(&stack);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn a_periodic_timer_can_cancel_itself() {
+        let (stack, clock) = stack();
+        let id = Arc::new(Mutex::new(None));
+        let (count, f) = counter();
+        let id_ = id.clone();
+        let timer = add_periodic(&stack, "test: self", Duration::seconds(1), move |stack| {
+            f(stack);
+            cancel(stack, id_.lock().unwrap().unwrap());
+        })
+        .unwrap();
+        *id.lock().unwrap() = Some(timer);
+        clock.advance(clock.now() + Duration::seconds(1));
+        run_due(&stack);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(named(&stack, "test").is_empty());
+    }
+
+    #[test]
+    fn fire_in_deadline_order() {
+        let (stack, clock) = stack();
+        let order = Arc::new(Mutex::new(vec![]));
+        for (name, secs) in [("b", 2), ("a", 1), ("c", 2)].iter() {
+            let order = order.clone();
+            let name = name.to_string();
+            add(&stack, "test", Duration::seconds(*secs), move |_| {
+                order.lock().unwrap().push(name.clone())
+            });
+        }
+        clock.advance(clock.now() + Duration::seconds(2));
+        run_due(&stack);
+        assert_eq!(*order.lock().unwrap(), vec!["a", "b", "c"]);
+    }
+}
@@ -1,92 +1,238 @@
 use crate::{
     buffer,
+    error::{Error, Layer},
     ip::{self, interface::Interface},
-    protocol, util,
+    protocol,
+    stack::Stack,
+    stats, timer, util,
 };
-use nix::errno::{errno, Errno};
+use chrono::Duration;
 use std::collections::HashMap;
-use std::error::Error;
-use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::task::Waker;
 use uuid::Uuid;
 
+mod future;
 mod packet;
+mod poll;
 mod queue;
 
+pub use future::{AsyncSocket, RecvFrom};
+pub use poll::{poll, Events, PollFd};
+
 const SOURCE_PORT_MIN: u16 = 49152;
 const SOURCE_PORT_MAX: u16 = 65535;
 
 struct Cb {
     interface: Option<Interface>,
     port: u16,
+    priority: u8,
+    nonblocking: bool,
     queue: queue::Queue,
+    // tasks waiting in `AsyncSocket::recv_from`, by the key of their future
+    wakers: Vec<(u64, Waker)>,
+}
+
+// whether a socket other than `id` already has `port` on an interface that overlaps `interface`
+fn is_in_use(
+    cb_table: &HashMap<Uuid, Cb>,
+    id: &Uuid,
+    interface: Option<&Interface>,
+    port: u16,
+) -> bool {
+    cb_table.iter().any(|(id_, cb)| {
+        id_ != id
+            && cb.port == port
+            && match (interface, cb.interface.as_ref()) {
+                (Some(interface), Some(interface_)) => Arc::ptr_eq(&interface.0, &interface_.0),
+                _ => true,
+            }
+    })
 }
 
-lazy_static! {
-    static ref CB_TABLE: Arc<Mutex<HashMap<Uuid, Cb>>> = Arc::new(Mutex::new(HashMap::new()));
-    static ref CONDS_PUSHED: Arc<RwLock<HashMap<Uuid, Condvar>>> =
-        Arc::new(RwLock::new(HashMap::new()));
+type CbTable<'a> = MutexGuard<'a, HashMap<Uuid, Cb>>;
+
+// Waits on `cond` until `ready` holds, for at most `timeout` on the stack's clock, which
+// may be a simulated one. Returns whether it became ready.
+// `timeout` from now must still be a time, or a timer for it would overflow
+pub(crate) fn timeout_from_std(
+    stack: &Stack,
+    timeout: ::std::time::Duration,
+) -> Result<Duration, Error> {
+    Duration::from_std(timeout)
+        .ok()
+        .filter(|timeout| stack.now().checked_add_signed(*timeout).is_some())
+        .ok_or_else(|| Error::Invalid(format!("timeout too long: {:?}", timeout)))
+}
+
+fn wait_until<'a, F>(
+    stack: &Stack,
+    mut cb_table: CbTable<'a>,
+    cond: &Arc<Condvar>,
+    timeout: Option<Duration>,
+    name: &str,
+    mut ready: F,
+) -> (CbTable<'a>, bool)
+where
+    F: FnMut(&HashMap<Uuid, Cb>) -> bool,
+{
+    if ready(&cb_table) {
+        return (cb_table, true);
+    }
+    if timeout == Some(Duration::zero()) {
+        return (cb_table, false);
+    }
+    let expired = Arc::new(AtomicBool::new(false));
+    let timer_id = timeout.map(|timeout| {
+        let (expired, cond) = (expired.clone(), cond.clone());
+        timer::add(stack, name, timeout, move |stack| {
+            // under the lock, so that the wakeup cannot be missed
+            let _cb_table = stack.0.udp.cbs.lock().unwrap();
+            expired.store(true, Ordering::SeqCst);
+            cond.notify_all();
+        })
+    });
+    cb_table = cond
+        .wait_while(cb_table, |cb_table| {
+            !ready(cb_table) && !expired.load(Ordering::SeqCst)
+        })
+        .unwrap();
+    if let Some(timer_id) = timer_id {
+        timer::cancel(stack, timer_id);
+    }
+    let is_ready = ready(&cb_table);
+    (cb_table, is_ready)
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    cbs: Mutex<HashMap<Uuid, Cb>>,
+    // signalled when a datagram is queued for the socket
+    conds: RwLock<HashMap<Uuid, Arc<Condvar>>>,
+    // signalled when any socket gets a datagram or is closed, for `poll`
+    polled: Arc<Condvar>,
 }
 
 pub struct Socket {
     id: Uuid,
+    stack: Stack,
 }
 
 impl Socket {
-    pub fn bind(&mut self, peer_addr: ip::Addr, peer_port: u16) -> Result<(), Box<dyn Error>> {
-        let mut cb_table = CB_TABLE.lock().unwrap();
-        let ref mut cb = cb_table.get_mut(&self.id).unwrap();
-        let interface = match ip::interface::by_addr(peer_addr) {
+    // another handle on the same socket, for the C API to use without holding its table
+    pub(crate) fn share(&self) -> Socket {
+        Socket {
+            id: self.id,
+            stack: self.stack.clone(),
+        }
+    }
+
+    pub fn bind(&mut self, peer_addr: ip::Addr, peer_port: u16) -> Result<(), Error> {
+        let interface = match ip::interface::by_addr(&self.stack, peer_addr) {
             Some(interface) => interface,
-            None => {
-                return Err(util::RuntimeError::new(format!(
-                    "invalid addr: {}",
-                    peer_addr
-                )))
-            }
+            None => return Err(Error::NotFound(format!("interface with {}", peer_addr))),
         };
-        cb.interface = Some(interface);
-        cb.port = peer_port;
-        Ok(())
+        self.bind_interface(interface, peer_port)
     }
     pub fn bind_interface(
         &mut self,
         interface: Interface,
         peer_port: u16,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut cb_table = CB_TABLE.lock().unwrap();
-        let ref mut cb = cb_table.get_mut(&self.id).unwrap();
+    ) -> Result<(), Error> {
+        let mut cb_table = self.stack.0.udp.cbs.lock().unwrap();
+        if !cb_table.contains_key(&self.id) {
+            return Err(closed());
+        }
+        if peer_port != 0 && is_in_use(&cb_table, &self.id, Some(&interface), peer_port) {
+            return Err(Error::PortInUse(peer_port));
+        }
+        let cb = cb_table.get_mut(&self.id).ok_or_else(closed)?;
         cb.interface = Some(interface);
         cb.port = peer_port;
         Ok(())
     }
 
+    /// sets the priority (0-7) of datagrams sent from this socket, carried as IP precedence.
+    pub fn set_priority(&mut self, priority: u8) -> Result<(), Error> {
+        if priority > 7 {
+            return Err(Error::Invalid(format!("invalid priority: {}", priority)));
+        }
+        let mut cb_table = self.stack.0.udp.cbs.lock().unwrap();
+        cb_table.get_mut(&self.id).ok_or_else(closed)?.priority = priority;
+        Ok(())
+    }
+
+    /// makes receiving return `Error::WouldBlock` instead of waiting for a datagram.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Error> {
+        let mut cb_table = self.stack.0.udp.cbs.lock().unwrap();
+        cb_table.get_mut(&self.id).ok_or_else(closed)?.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// receives a datagram, waiting at most `timeout` seconds, or for ever with -1.
     pub fn recv_from(
         &mut self,
         timeout: i32,
-    ) -> Result<(ip::Addr, u16, buffer::Buffer), Box<dyn Error>> {
-        loop {
-            if timeout != -1 {
-                let conds_pushed = CONDS_PUSHED.read().unwrap();
-                let ref cond_pushed = conds_pushed.get(&self.id).unwrap();
-                let cb_table = CB_TABLE.lock().unwrap();
-                cond_pushed
-                    .wait_timeout(cb_table, ::std::time::Duration::from_secs(timeout as u64))?;
-            } else {
-                let conds_pushed = CONDS_PUSHED.read().unwrap();
-                let ref cond_pushed = conds_pushed.get(&self.id).unwrap();
-                let cb_table = CB_TABLE.lock().unwrap();
-                cond_pushed.wait(cb_table);
-            };
-
-            if errno() == Errno::ETIMEDOUT as i32 {
-                return Err(util::RuntimeError::new(format!("timeout")));
+    ) -> Result<(ip::Addr, u16, buffer::Buffer), Error> {
+        self.recv(match timeout {
+            -1 => None,
+            timeout if timeout < 0 => {
+                return Err(Error::Invalid(format!("invalid timeout: {}", timeout)))
             }
+            timeout => Some(Duration::seconds(timeout as i64)),
+        })
+    }
+
+    /// fails with `Error::Invalid` for a timeout past what the clock can count to.
+    pub fn recv_from_timeout(
+        &mut self,
+        timeout: ::std::time::Duration,
+    ) -> Result<(ip::Addr, u16, buffer::Buffer), Error> {
+        let timeout = timeout_from_std(&self.stack, timeout)?;
+        self.recv(Some(timeout))
+    }
 
-            let mut cb_table = CB_TABLE.lock().unwrap();
-            let ref mut cb = cb_table.get_mut(&self.id).unwrap();
-            if let Some(entry) = cb.queue.pop() {
-                return Ok((entry.addr, entry.port, entry.data));
+    fn recv(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<(ip::Addr, u16, buffer::Buffer), Error> {
+        let id = self.id;
+        let cond_pushed = match self.stack.0.udp.conds.read().unwrap().get(&id) {
+            Some(cond) => cond.clone(),
+            None => return Err(closed()),
+        };
+        let cb_table = self.stack.0.udp.cbs.lock().unwrap();
+        let nonblocking = cb_table.get(&id).ok_or_else(closed)?.nonblocking;
+        let (mut cb_table, is_ready) = wait_until(
+            &self.stack,
+            cb_table,
+            &cond_pushed,
+            if nonblocking {
+                Some(Duration::zero())
+            } else {
+                timeout
+            },
+            "udp: receive timeout",
+            |cb_table| {
+                cb_table
+                    .get(&id)
+                    .is_none_or(|cb| !cb.queue.data.is_empty())
+            },
+        );
+        if !is_ready {
+            return Err(if nonblocking {
+                Error::WouldBlock
+            } else {
+                Error::Timeout
+            });
+        }
+        match cb_table.get_mut(&id) {
+            Some(cb) => {
+                let entry = cb.queue.pop().unwrap();
+                Ok((entry.addr, entry.port, entry.data))
             }
+            None => Err(closed()),
         }
     }
 
@@ -95,66 +241,92 @@ impl Socket {
         buf: buffer::Buffer,
         peer_addr: ip::Addr,
         peer_port: u16,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut cb_table = CB_TABLE.lock().unwrap();
-        let ref cb = cb_table.get_mut(&self.id).unwrap();
-        let interface = cb
-            .interface
-            .clone()
-            .or_else(|| ip::interface::by_addr(peer_addr))
-            .unwrap();
-
-        let port = if cb.port == 0 {
-            let mut result = None;
-            for port in { SOURCE_PORT_MIN..SOURCE_PORT_MAX } {
-                let is_found = cb_table.iter().any(|(_, ref cb)| {
-                    cb.port == port
-                        && cb
-                            .interface
-                            .as_ref()
-                            .map(|interface_| Arc::ptr_eq(&interface.0, &interface_.0))
-                            .unwrap_or(true)
-                });
-                if is_found {
-                    result = Some(port);
-                    break;
-                }
-            }
-            result
-        } else {
-            Some(cb.port)
-        };
-
-        let ref mut cb = cb_table.get_mut(&self.id).unwrap();
-        if let Some(port) = port {
+    ) -> Result<(), Error> {
+        let (interface, port, priority) = {
+            let mut cb_table = self.stack.0.udp.cbs.lock().unwrap();
+            let cb = cb_table.get(&self.id).ok_or_else(closed)?;
+            // an unbound socket sends from the interface the route goes through
+            let interface = cb
+                .interface
+                .clone()
+                .or_else(|| {
+                    ip::route::lookup(&self.stack, None, peer_addr).map(|route| route.interface)
+                })
+                .ok_or(Error::NoRoute(peer_addr))?;
+            let port = if cb.port == 0 {
+                (SOURCE_PORT_MIN..SOURCE_PORT_MAX)
+                    .find(|port| !is_in_use(&cb_table, &self.id, Some(&interface), *port))
+                    .ok_or(Error::PortsExhausted)?
+            } else {
+                cb.port
+            };
+            let cb = cb_table.get_mut(&self.id).ok_or_else(closed)?;
             cb.port = port;
-        } else {
-            return Err(util::RuntimeError::new(format!("not found : valid port")));
-        }
-        tx(&interface, cb.port, buf, peer_addr, peer_port)
+            (interface, port, cb.priority)
+        };
+        // without the table, which receiving on the same stack needs
+        tx(&interface, port, buf, peer_addr, peer_port, priority)
     }
 
-    pub fn close(&self) -> Result<(), Box<dyn Error>> {
-        let mut cb_table = CB_TABLE.lock().unwrap();
-        cb_table.remove(&self.id).unwrap();
+    /// closes the socket, after which every call on it, a second `close` included, fails
+    /// with `Error::NotFound`.
+    pub fn close(&self) -> Result<(), Error> {
+        let mut cb_table = self.stack.0.udp.cbs.lock().unwrap();
+        let cb = cb_table.remove(&self.id).ok_or_else(closed)?;
+        // pending futures find the socket gone
+        for (_, waker) in cb.wakers {
+            waker.wake();
+        }
+        self.stack.0.udp.polled.notify_all();
+        self.stack.0.udp.conds.write().unwrap().remove(&self.id);
         Ok(())
     }
 }
 
-pub fn open() -> Result<Socket, Box<dyn Error>> {
-    let mut cb_table = CB_TABLE.lock().unwrap();
+// what a socket that has been closed reports
+fn closed() -> Error {
+    Error::NotFound("udp socket".to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct SocketInfo {
+    pub port: u16,
+    // datagrams received but not read yet
+    pub queued: usize,
+}
+
+/// returns a snapshot of the open sockets.
+pub fn sockets(stack: &Stack) -> Vec<SocketInfo> {
+    let cb_table = stack.0.udp.cbs.lock().unwrap();
+    cb_table
+        .values()
+        .map(|cb| SocketInfo {
+            port: cb.port,
+            queued: cb.queue.data.len(),
+        })
+        .collect()
+}
+
+pub fn open(stack: &Stack) -> Result<Socket, Error> {
+    let mut cb_table = stack.0.udp.cbs.lock().unwrap();
     let uuid = Uuid::new_v4();
     let cb = Cb {
         interface: None,
         port: 0,
+        priority: 0,
+        nonblocking: false,
         queue: queue::Queue::new(),
+        wakers: vec![],
     };
     cb_table.insert(uuid, cb);
 
-    let mut conds_pushed = CONDS_PUSHED.write().unwrap();
-    conds_pushed.insert(uuid, Condvar::new());
+    let mut conds_pushed = stack.0.udp.conds.write().unwrap();
+    conds_pushed.insert(uuid, Arc::new(Condvar::new()));
 
-    Ok(Socket { id: uuid })
+    Ok(Socket {
+        id: uuid,
+        stack: stack.clone(),
+    })
 }
 
 pub fn rx(
@@ -162,7 +334,8 @@ pub fn rx(
     src: &ip::Addr,
     dst: &ip::Addr,
     interface: &Interface,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error> {
+    let stack = interface.stack();
     let mut pseudo: u32 = 0;
     let src_u32 = src.as_u32();
     let dst_u32 = dst.as_u32();
@@ -174,20 +347,28 @@ pub fn rx(
     pseudo += (buf.0.len() as u32).to_be();
 
     let buf_vec = buf.to_vec();
-    if util::calc_checksum(buf_vec.as_slice(), buf_vec.len(), pseudo) != 0 && false {
-        return Err(util::RuntimeError::new(format!("incorrect checksum")));
+    // a sum of zero means the sender did not compute one
+    let has_sum = buf_vec.len() >= 8 && (buf_vec[6] != 0 || buf_vec[7] != 0);
+    if has_sum && util::calc_checksum(buf_vec.as_slice(), buf_vec.len(), pseudo) != 0 {
+        stats::update(&stack, |s| s.udp.in_errors += 1);
+        stats::dropped(&stack, "udp: bad checksum");
+        return Err(Error::parse("sum", "mismatch").in_layer(Layer::Udp));
     }
 
     use crate::packet::Packet;
-    let packet = packet::Packet::from_buffer(buffer::Buffer::from_vec(buf_vec))?;
+    let packet = match packet::Packet::parse(buffer::Buffer::from_vec(buf_vec)) {
+        Ok(packet) => packet,
+        Err(err) => {
+            stats::update(&stack, |s| s.udp.in_errors += 1);
+            stats::dropped(&stack, "udp: malformed datagram");
+            return Err(err);
+        }
+    };
 
-    if cfg!(debug_assertions) {
-        eprintln!(">>> udp_rx <<<");
-        packet.dump();
-    }
+    log_packet!("rx", packet);
 
-    let mut cb_table = CB_TABLE.lock().unwrap();
-    for (ref id, ref mut cb) in cb_table.iter_mut() {
+    let mut cb_table = stack.0.udp.cbs.lock().unwrap();
+    for (id, cb) in cb_table.iter_mut() {
         let is_same_interface = cb
             .interface
             .as_ref()
@@ -200,13 +381,20 @@ pub fn rx(
                 data: packet.payload,
             };
             cb.queue.push(queue_header);
+            for (_, waker) in cb.wakers.drain(..) {
+                waker.wake();
+            }
 
-            let conds_pushed = CONDS_PUSHED.read().unwrap();
-            let cond = conds_pushed.get(id).unwrap();
-            cond.notify_all();
-            break;
+            if let Some(cond) = stack.0.udp.conds.read().unwrap().get(id) {
+                cond.notify_all();
+            }
+            stack.0.udp.polled.notify_all();
+            stats::update(&stack, |s| s.udp.in_datagrams += 1);
+            return Ok(());
         }
     }
+    stats::update(&stack, |s| s.udp.no_ports += 1);
+    stats::dropped(&stack, "udp: no port");
     Ok(())
 }
 
@@ -216,17 +404,15 @@ pub fn tx(
     buf: buffer::Buffer,
     peer_addr: ip::Addr,
     peer_port: u16,
-) -> Result<(), Box<dyn Error>> {
+    priority: u8,
+) -> Result<(), Error> {
     let packet = packet::Packet {
-        src_port: src_port,
+        src_port,
         dst_port: peer_port,
         sum: 0,
         payload: buf,
     };
-    if cfg!(debug_assertions) {
-        eprintln!(">> udp tx <<");
-        packet.dump();
-    }
+    log_packet!("tx", packet);
     use crate::packet::Packet;
     let packet = packet.to_buffer();
 
@@ -243,21 +429,26 @@ pub fn tx(
     pseudo += (protocol::ProtocolType::Udp as u32).to_be();
     pseudo += (packet.0.len() as u32).to_be();
     let packet_vec = packet.to_vec();
-    let sum = util::calc_checksum(packet_vec.as_slice(), packet_vec.len(), pseudo);
+    let sum = match util::calc_checksum(packet_vec.as_slice(), packet_vec.len(), pseudo) {
+        // zero would read as no sum at all
+        0 => 0xffff,
+        sum => sum,
+    };
     let mut packet = buffer::Buffer::from_vec(packet_vec);
     packet::Packet::write_checksum(&mut packet, sum);
 
-    interface.tx(protocol::ProtocolType::Udp, packet, &peer_addr)
+    let stack = interface.stack();
+    stats::update(&stack, |s| s.udp.out_datagrams += 1);
+    interface.tx_tos(
+        protocol::ProtocolType::Udp,
+        packet,
+        &peer_addr,
+        priority << 5,
+    )
 }
 
 pub struct UdpProtocol {}
 
-impl UdpProtocol {
-    pub fn new() -> Arc<dyn protocol::Protocol + Send + Sync> {
-        Arc::new(UdpProtocol {})
-    }
-}
-
 impl protocol::Protocol for UdpProtocol {
     fn type_(&self) -> protocol::ProtocolType {
         protocol::ProtocolType::Udp
@@ -268,7 +459,91 @@ impl protocol::Protocol for UdpProtocol {
         src: ip::Addr,
         dst: ip::Addr,
         interface: &Interface,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), Error> {
         self::rx(payload, &src, &dst, interface)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{LinkConfig, Simulator};
+
+    #[test]
+    fn a_closed_socket_fails_without_poisoning_the_table() {
+        let mut sim = Simulator::new(1);
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (mut device, _) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let addr = ip::Addr([10, 0, 0, 1]);
+        let interface = Interface::new(device.clone(), addr, ip::Addr([255, 0, 0, 0]), None);
+        device.add_interface(interface.clone());
+
+        let socket = open(&stack).unwrap();
+        let mut shared = socket.share();
+        socket.close().unwrap();
+        let is_closed = |ret: Result<(), Error>| matches!(ret, Err(Error::NotFound(_)));
+        assert!(is_closed(socket.close()));
+        assert!(is_closed(shared.bind_interface(interface.clone(), 7)));
+        assert!(is_closed(shared.set_priority(1)));
+        assert!(is_closed(shared.set_nonblocking(true)));
+        let buf = buffer::Buffer::from_vec(vec![1]);
+        assert!(is_closed(shared.send_to(buf, ip::Addr([10, 0, 0, 2]), 7)));
+        assert!(matches!(shared.recv_from(0), Err(Error::NotFound(_))));
+
+        // the rest of the stack goes on as before
+        let mut socket = open(&stack).unwrap();
+        socket.bind_interface(interface, 7).unwrap();
+        assert_eq!(sockets(&stack).len(), 1);
+    }
+
+    // a socket on 10.0.0.1:7 and another on 10.0.0.2 to send to it from
+    fn pair(sim: &mut Simulator) -> (Socket, Socket) {
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (mut device, mut peer_device) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let mask = ip::Addr([255, 0, 0, 0]);
+        let interface = Interface::new(device.clone(), ip::Addr([10, 0, 0, 1]), mask, None);
+        device.add_interface(interface.clone());
+        let peer_interface = Interface::new(peer_device.clone(), ip::Addr([10, 0, 0, 2]), mask, None);
+        peer_device.add_interface(peer_interface.clone());
+        // past conflict detection
+        sim.run_for(::std::time::Duration::from_secs(10));
+        let mut socket = open(&stack).unwrap();
+        socket.bind_interface(interface, 7).unwrap();
+        let mut sender = open(&peer).unwrap();
+        sender.bind_interface(peer_interface, 0).unwrap();
+        (socket, sender)
+    }
+
+    #[test]
+    fn nonblocking_receives_do_not_wait() {
+        let mut sim = Simulator::new(1);
+        let (mut socket, mut sender) = pair(&mut sim);
+        socket.set_nonblocking(true).unwrap();
+        // not even when asked to wait for ever
+        assert!(matches!(socket.recv_from(-1), Err(Error::WouldBlock)));
+
+        let buf = buffer::Buffer::from_vec(b"x".to_vec());
+        sender.send_to(buf, ip::Addr([10, 0, 0, 1]), 7).unwrap();
+        sim.run_for(::std::time::Duration::from_secs(1));
+        let (addr, _, data) = socket.recv_from(-1).unwrap();
+        assert_eq!((addr, data.to_vec()), (ip::Addr([10, 0, 0, 2]), b"x".to_vec()));
+
+        // blocking again, an empty queue times out rather than would block
+        socket.set_nonblocking(false).unwrap();
+        assert!(matches!(socket.recv_from(0), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn overlong_timeouts_are_invalid() {
+        let mut sim = Simulator::new(1);
+        let stack = sim.stack();
+        let mut socket = open(&stack).unwrap();
+        let invalid = |ret: Result<_, Error>| matches!(ret, Err(Error::Invalid(_)));
+        let forever = ::std::time::Duration::from_secs(u64::MAX);
+        assert!(invalid(socket.recv_from_timeout(forever)));
+        // representable, but not as a time that far ahead
+        let ages = ::std::time::Duration::from_secs(1 << 50);
+        assert!(invalid(socket.recv_from_timeout(ages)));
+        socket.close().unwrap();
+    }
+}
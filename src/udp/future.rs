@@ -0,0 +1,189 @@
+// sockets for async code, with no runtime of their own
+//
+// A pending `recv_from` leaves the task's waker with the socket, and the datagram that
+// arrives for it wakes the task; a timeout is a timer of the stack. Nothing else is
+// needed from an executor, so the futures run on any of them.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use super::Socket;
+use crate::{
+    buffer::Buffer,
+    error::Error,
+    ip,
+    timer::{self, TimerId},
+};
+
+// keys the wakers a socket holds to the futures that left them
+static NEXT_WAKER_KEY: AtomicU64 = AtomicU64::new(0);
+
+pub struct AsyncSocket {
+    socket: Socket,
+}
+
+impl Socket {
+    pub fn into_async(self) -> AsyncSocket {
+        AsyncSocket { socket: self }
+    }
+}
+
+impl AsyncSocket {
+    /// the blocking socket underneath, e.g. to bind it.
+    pub fn socket(&mut self) -> &mut Socket {
+        &mut self.socket
+    }
+
+    pub fn into_inner(self) -> Socket {
+        self.socket
+    }
+
+    /// receives the next datagram, waiting for as long as it takes.
+    pub fn recv_from(&self) -> RecvFrom<'_> {
+        RecvFrom {
+            socket: &self.socket,
+            timeout: None,
+            key: NEXT_WAKER_KEY.fetch_add(1, Ordering::Relaxed),
+            timer: None,
+        }
+    }
+
+    /// receives the next datagram, or `Error::Timeout` once `timeout` has passed.
+    pub fn recv_from_timeout(&self, timeout: Duration) -> RecvFrom<'_> {
+        RecvFrom {
+            socket: &self.socket,
+            timeout: Some(timeout),
+            key: NEXT_WAKER_KEY.fetch_add(1, Ordering::Relaxed),
+            timer: None,
+        }
+    }
+
+    /// sends a datagram; this never has to wait, as the stack queues it while the peer
+    /// is being resolved.
+    pub async fn send_to(
+        &mut self,
+        buf: Buffer,
+        peer_addr: ip::Addr,
+        peer_port: u16,
+    ) -> Result<(), Error> {
+        self.socket.send_to(buf, peer_addr, peer_port)
+    }
+
+    pub fn close(&self) -> Result<(), Error> {
+        self.socket.close()
+    }
+}
+
+/// the future of `AsyncSocket::recv_from`.
+pub struct RecvFrom<'a> {
+    socket: &'a Socket,
+    timeout: Option<Duration>,
+    // of the waker this future leaves with the socket
+    key: u64,
+    // started on the first poll, along with whether it has fired
+    timer: Option<(TimerId, Arc<AtomicBool>)>,
+}
+
+impl<'a> Future for RecvFrom<'a> {
+    type Output = Result<(ip::Addr, u16, Buffer), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stack = self.socket.stack.clone();
+        let id = self.socket.id;
+        let mut cb_table = stack.0.udp.cbs.lock().unwrap();
+        let cb = match cb_table.get_mut(&id) {
+            Some(cb) => cb,
+            None => return Poll::Ready(Err(Error::NotFound("udp socket".to_string()))),
+        };
+        if let Some(entry) = cb.queue.pop() {
+            return Poll::Ready(Ok((entry.addr, entry.port, entry.data)));
+        }
+        if let Some((_, ref expired)) = self.timer {
+            if expired.load(Ordering::SeqCst) {
+                return Poll::Ready(Err(Error::Timeout));
+            }
+        }
+        let key = self.key;
+        match cb.wakers.iter_mut().find(|(key_, _)| *key_ == key) {
+            Some((_, waker)) if waker.will_wake(cx.waker()) => {}
+            Some((_, waker)) => *waker = cx.waker().clone(),
+            None => cb.wakers.push((key, cx.waker().clone())),
+        }
+        drop(cb_table);
+        if let (Some(timeout), None) = (self.timeout, &self.timer) {
+            let interval = match super::timeout_from_std(&stack, timeout) {
+                Ok(interval) => interval,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            let expired = Arc::new(AtomicBool::new(false));
+            let expired_ = expired.clone();
+            let timer_id = timer::add(
+                &stack,
+                "udp: receive timeout",
+                interval,
+                move |stack| {
+                    expired_.store(true, Ordering::SeqCst);
+                    let mut cb_table = stack.0.udp.cbs.lock().unwrap();
+                    if let Some(cb) = cb_table.get_mut(&id) {
+                        for (_, waker) in cb.wakers.drain(..) {
+                            waker.wake();
+                        }
+                    }
+                },
+            );
+            self.timer = Some((timer_id, expired));
+        }
+        Poll::Pending
+    }
+}
+
+impl<'a> Drop for RecvFrom<'a> {
+    fn drop(&mut self) {
+        if let Some((timer_id, _)) = self.timer.take() {
+            timer::cancel(&self.socket.stack, timer_id);
+        }
+        // a future dropped before its datagram came must not leave its waker behind
+        let mut cb_table = self.socket.stack.0.udp.cbs.lock().unwrap();
+        if let Some(cb) = cb_table.get_mut(&self.socket.id) {
+            cb.wakers.retain(|(key, _)| *key != self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::{Clock, VirtualClock}, stack::Stack, udp};
+    use chrono::{TimeZone, Utc};
+    use std::task::{Wake, Waker};
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn overlong_timeouts_are_invalid() {
+        let clock = VirtualClock::new(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0));
+        let stack = Stack::with_clock(Clock::Virtual(Arc::new(clock)), 1);
+        let socket = udp::open(&stack).unwrap().into_async();
+        let waker = Waker::from(Arc::new(Noop));
+        let mut cx = Context::from_waker(&waker);
+        let timers = timer::timers(&stack).len();
+        for secs in &[u64::MAX, 1 << 50] {
+            let mut future = socket.recv_from_timeout(Duration::from_secs(*secs));
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(Err(Error::Invalid(_))) => {}
+                _ => panic!("a timeout of {} s was taken", secs),
+            }
+            drop(future);
+            assert_eq!(timer::timers(&stack).len(), timers);
+            assert!(stack.0.udp.cbs.lock().unwrap()[&socket.socket.id].wakers.is_empty());
+        }
+    }
+}
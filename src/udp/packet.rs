@@ -1,5 +1,8 @@
-use crate::{buffer::Buffer, packet, util};
-use std::error::Error;
+use crate::{
+    buffer::Buffer,
+    error::{Error, Layer},
+    packet,
+};
 
 pub struct Packet {
     pub src_port: u16,
@@ -11,31 +14,52 @@ pub struct Packet {
 const HEADER_LEN: usize = 8;
 
 impl Packet {
-    pub fn dump(&self) {
-        eprintln!("src port: {}", self.src_port);
-        eprintln!("dst port: {}", self.dst_port);
-        eprintln!("sum: {}", self.sum);
-        eprintln!("{}", self.payload);
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "src port: {}", self.src_port);
+        let _ = writeln!(out, "dst port: {}", self.dst_port);
+        let _ = writeln!(out, "sum: {}", self.sum);
+        let _ = writeln!(out, "{}", self.payload);
+        out
     }
 
     pub fn write_checksum(buf: &mut Buffer, sum: u16) {
-        buf.write_u16(4, sum);
+        buf.write_u16(6, sum);
+    }
+}
+
+use std::fmt;
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} > {} {} bytes",
+            self.src_port,
+            self.dst_port,
+            self.payload.0.len()
+        )
     }
 }
 
 impl packet::Packet<Packet> for Packet {
-    fn from_buffer(mut buf: Buffer) -> Result<Self, Box<dyn Error>> {
+    const LAYER: Layer = Layer::Udp;
+
+    fn from_buffer(mut buf: Buffer) -> Result<Self, Error> {
         let src_port = buf.pop_u16("src port")?;
         let dst_port = buf.pop_u16("dst port")?;
         let len = buf.pop_u16("len")?;
         let sum = buf.pop_u16("sum")?;
         if len as usize != HEADER_LEN + buf.0.len() {
-            return Err(util::RuntimeError::new(format!("invalid len: {}", len)));
+            return Err(Error::parse(
+                "len",
+                format!("{}, but {} bytes received", len, HEADER_LEN + buf.0.len()),
+            ));
         }
         Ok(Packet {
-            src_port: src_port,
-            dst_port: dst_port,
-            sum: sum,
+            src_port,
+            dst_port,
+            sum,
             payload: buf,
         })
     }
@@ -0,0 +1,199 @@
+// waiting on several sockets at once, like poll(2)
+
+use std::collections::HashMap;
+
+use bitflags::bitflags;
+use chrono::Duration;
+use uuid::Uuid;
+
+use super::{wait_until, Cb, Socket};
+use crate::error::Error;
+
+bitflags! {
+    pub struct Events: u8 {
+        const READABLE = 0x01;
+        // always, as sending never has to wait
+        const WRITABLE = 0x02;
+        // the socket has been closed; reported whether asked for or not
+        const ERROR = 0x04;
+    }
+}
+
+pub struct PollFd<'a> {
+    pub socket: &'a Socket,
+    pub events: Events,
+    pub revents: Events,
+}
+
+impl<'a> PollFd<'a> {
+    pub fn new(socket: &'a Socket, events: Events) -> PollFd<'a> {
+        PollFd {
+            socket,
+            events,
+            revents: Events::empty(),
+        }
+    }
+}
+
+fn revents(cb_table: &HashMap<Uuid, Cb>, fd: &PollFd) -> Events {
+    match cb_table.get(&fd.socket.id) {
+        Some(cb) => {
+            let mut revents = Events::WRITABLE;
+            if !cb.queue.data.is_empty() {
+                revents |= Events::READABLE;
+            }
+            revents & fd.events
+        }
+        None => Events::ERROR,
+    }
+}
+
+/// Waits until one of `fds` has an event it asks for, at most `timeout` milliseconds (-1
+/// for ever, 0 not at all), and fills in their `revents`. Returns how many have any. The
+/// sockets must all belong to one stack.
+pub fn poll(fds: &mut [PollFd], timeout: i32) -> Result<usize, Error> {
+    let stack = match fds.first() {
+        Some(fd) => fd.socket.stack.clone(),
+        None => return Ok(0),
+    };
+    if fds.iter().any(|fd| fd.socket.stack != stack) {
+        return Err(Error::Invalid(
+            "polled sockets belong to different stacks".to_string(),
+        ));
+    }
+    let timeout = if timeout < 0 {
+        None
+    } else {
+        Some(Duration::milliseconds(timeout as i64))
+    };
+    let cb_table = stack.0.udp.cbs.lock().unwrap();
+    let (cb_table, _) = wait_until(
+        &stack,
+        cb_table,
+        &stack.0.udp.polled,
+        timeout,
+        "udp: poll timeout",
+        |cb_table| fds.iter().any(|fd| !revents(cb_table, fd).is_empty()),
+    );
+    let mut count = 0;
+    for fd in fds.iter_mut() {
+        fd.revents = revents(&cb_table, fd);
+        if !fd.revents.is_empty() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::Buffer,
+        ip::{self, interface::Interface},
+        sim::{LinkConfig, Simulator},
+        stack::Stack,
+        timer, udp,
+    };
+    use std::{sync::mpsc, thread, time};
+
+    // a socket on 10.0.0.1:7 and another on 10.0.0.2 to send to it from
+    fn pair(sim: &mut Simulator) -> (Socket, Socket) {
+        let (stack, peer) = (sim.stack(), sim.stack());
+        let (mut device, mut peer_device) = sim.connect(&stack, &peer, LinkConfig::default()).unwrap();
+        let mask = ip::Addr([255, 0, 0, 0]);
+        let interface = Interface::new(device.clone(), ip::Addr([10, 0, 0, 1]), mask, None);
+        device.add_interface(interface.clone());
+        let peer_interface = Interface::new(peer_device.clone(), ip::Addr([10, 0, 0, 2]), mask, None);
+        peer_device.add_interface(peer_interface.clone());
+        // past conflict detection
+        sim.run_for(time::Duration::from_secs(10));
+        let mut socket = udp::open(&stack).unwrap();
+        socket.bind_interface(interface, 7).unwrap();
+        let mut sender = udp::open(&peer).unwrap();
+        sender.bind_interface(peer_interface, 0).unwrap();
+        (socket, sender)
+    }
+
+    fn send(sender: &mut Socket) {
+        let buf = Buffer::from_vec(b"x".to_vec());
+        sender.send_to(buf, ip::Addr([10, 0, 0, 1]), 7).unwrap();
+    }
+
+    // polls `socket` for `events` on a thread of its own, as the simulator must go on
+    fn poll_on_thread(socket: &Socket, events: Events, timeout: i32) -> mpsc::Receiver<(usize, Events)> {
+        let socket = socket.share();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut fds = [PollFd::new(&socket, events)];
+            let count = poll(&mut fds, timeout).unwrap();
+            tx.send((count, fds[0].revents)).unwrap();
+        });
+        rx
+    }
+
+    fn poll_timer(stack: &Stack) -> Option<timer::TimerInfo> {
+        timer::timers(stack)
+            .into_iter()
+            .find(|timer| timer.name == "udp: poll timeout")
+    }
+
+    #[test]
+    fn sockets_are_always_writable() {
+        let mut sim = Simulator::new(1);
+        let (socket, _) = pair(&mut sim);
+        let mut fds = [PollFd::new(&socket, Events::READABLE | Events::WRITABLE)];
+        assert_eq!(poll(&mut fds, 0).unwrap(), 1);
+        assert_eq!(fds[0].revents, Events::WRITABLE);
+        // nothing asked for that is there
+        let mut fds = [PollFd::new(&socket, Events::READABLE)];
+        assert_eq!(poll(&mut fds, 0).unwrap(), 0);
+        assert!(fds[0].revents.is_empty());
+    }
+
+    #[test]
+    fn a_datagram_makes_a_socket_readable() {
+        let mut sim = Simulator::new(1);
+        let (socket, mut sender) = pair(&mut sim);
+        let polled = poll_on_thread(&socket, Events::READABLE, -1);
+        send(&mut sender);
+        sim.run_for(time::Duration::from_millis(10));
+        let (count, revents) = polled.recv().unwrap();
+        assert_eq!((count, revents), (1, Events::READABLE));
+    }
+
+    #[test]
+    fn the_timeout_is_in_virtual_milliseconds() {
+        let mut sim = Simulator::new(1);
+        let (socket, _) = pair(&mut sim);
+        let stack = socket.stack.clone();
+        let start = sim.now();
+        let polled = poll_on_thread(&socket, Events::READABLE, 250);
+        let timer = loop {
+            match poll_timer(&stack) {
+                Some(timer) => break timer,
+                None => thread::sleep(time::Duration::from_millis(1)),
+            }
+        };
+        assert_eq!(timer.deadline - start, chrono::Duration::milliseconds(250));
+        sim.run_for(time::Duration::from_millis(249));
+        assert!(polled.try_recv().is_err());
+        sim.run_for(time::Duration::from_millis(1));
+        assert_eq!(polled.recv().unwrap(), (0, Events::empty()));
+        assert!(poll_timer(&stack).is_none());
+    }
+
+    #[test]
+    fn closing_a_socket_is_an_error_event() {
+        let mut sim = Simulator::new(1);
+        let (socket, _) = pair(&mut sim);
+        let polled = poll_on_thread(&socket, Events::READABLE, -1);
+        socket.close().unwrap();
+        let (count, revents) = polled.recv().unwrap();
+        assert_eq!((count, revents), (1, Events::ERROR));
+        // and reported whether asked for or not
+        let mut fds = [PollFd::new(&socket, Events::empty())];
+        assert_eq!(poll(&mut fds, 0).unwrap(), 1);
+        assert_eq!(fds[0].revents, Events::ERROR);
+    }
+}
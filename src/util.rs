@@ -1,32 +1,3 @@
-use std::error::Error;
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct RuntimeError {
-    pub message: String,
-}
-
-impl RuntimeError {
-    pub fn new(message: String) -> Box<dyn Error> {
-        Box::new(RuntimeError { message: message })
-    }
-}
-
-use std::fmt;
-impl fmt::Display for RuntimeError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "runtime error : {}", self.message)
-    }
-}
-
-impl Error for RuntimeError {
-    fn description(&self) -> &str {
-        self.message.as_str()
-    }
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
-    }
-}
-
 pub fn htons(n: u16) -> u16 {
     n.to_be()
 }
@@ -44,10 +15,10 @@ pub fn calc_checksum(data: &[u8], len: usize, init: u32) -> u16 {
         sum += data_u16[index / 2] as u32;
         index += 2;
     }
-    if index + 1 != len {
-        sum += data[index - 1] as u32;
+    if index < len {
+        sum += data[index] as u32;
     }
     sum = (sum & 0xffff) + (sum >> 16);
     sum = (sum & 0xffff) + (sum >> 16);
-    return !(sum as u16);
+    !(sum as u16)
 }
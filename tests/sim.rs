@@ -0,0 +1,470 @@
+extern crate microps_rs;
+
+use chrono::{DateTime, Utc};
+use microps_rs::{
+    arp,
+    buffer::Buffer,
+    clock::Clock,
+    error::Error,
+    ethernet, icmp,
+    ip::{self, interface::Interface},
+    link::LinkDevice,
+    sim::{self, LinkConfig, Simulator},
+    stack::Stack,
+    stats, udp,
+};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn addr(s: &str) -> ip::Addr {
+    ip::Addr::from_str(s).unwrap()
+}
+
+// the interface of `addr`/24 on `device`, routed through `gateway` if any
+fn configure(device: &mut ethernet::Device, ip_addr: &str, gateway: Option<&str>) -> Interface {
+    let interface = Interface::new(
+        device.clone(),
+        addr(ip_addr),
+        addr("255.255.255.0"),
+        gateway.map(addr),
+    );
+    device.add_interface(interface.clone());
+    interface
+}
+
+fn received(stack: &Stack, type_: icmp::Type) -> u64 {
+    let counters = stats::snapshot(stack).counters;
+    counters
+        .icmp
+        .in_types
+        .get(&(type_ as u8))
+        .cloned()
+        .unwrap_or(0)
+}
+
+// a (10.1.0.2) -- router (10.1.0.1, 10.2.0.1) -- b (10.2.0.2)
+#[test]
+fn two_hosts_through_a_router() {
+    let mut sim = Simulator::new(1);
+    let host_a = sim.stack();
+    let router = sim.stack();
+    let host_b = sim.stack();
+    let (mut device_a, mut router_a) = sim
+        .connect(&host_a, &router, LinkConfig::default())
+        .unwrap();
+    let (mut router_b, mut device_b) = sim
+        .connect(&router, &host_b, LinkConfig::default())
+        .unwrap();
+    let interface_a = configure(&mut device_a, "10.1.0.2", Some("10.1.0.1"));
+    configure(&mut router_a, "10.1.0.1", None);
+    configure(&mut router_b, "10.2.0.1", None);
+    let interface_b = configure(&mut device_b, "10.2.0.2", Some("10.2.0.1"));
+    ip::set_is_forwarding(&router, true);
+    // conflict detection holds every address back for a few seconds
+    sim.run_for(Duration::from_secs(10));
+
+    // ARP resolves the gateway on each side, then ICMP echoes cross the router
+    icmp::tx(
+        &interface_a,
+        icmp::Type::Echo,
+        icmp::Code::Others(0),
+        0x1234_0001,
+        Buffer::from_vec(b"ping".to_vec()),
+        &addr("10.2.0.2"),
+    )
+    .unwrap();
+    assert!(sim.run_until(Duration::from_secs(5), || received(
+        &host_a,
+        icmp::Type::EchoReply
+    ) == 1));
+    assert_eq!(received(&host_b, icmp::Type::Echo), 1);
+    assert_eq!(stats::snapshot(&router).counters.ip.forw_datagrams, 2);
+
+    let gateway = arp::entries(&host_a)
+        .into_iter()
+        .find(|entry| entry.ip_addr == addr("10.1.0.1"))
+        .unwrap();
+    assert_eq!(gateway.state, arp::State::Reachable);
+    assert_eq!(gateway.mac_addr, router_a.addr());
+    let known: Vec<ip::Addr> = arp::entries(&router)
+        .iter()
+        .map(|entry| entry.ip_addr)
+        .collect();
+    assert!(known.contains(&addr("10.1.0.2")) && known.contains(&addr("10.2.0.2")));
+
+    // UDP there and back, the reply addressed from what the request came from
+    let mut server = udp::open(&host_b).unwrap();
+    server.bind_interface(interface_b, 7).unwrap();
+    let mut client = udp::open(&host_a).unwrap();
+    client.bind_interface(interface_a, 0).unwrap();
+    client
+        .send_to(Buffer::from_vec(b"hello".to_vec()), addr("10.2.0.2"), 7)
+        .unwrap();
+    sim.run_for(Duration::from_secs(1));
+    let (peer_addr, peer_port, data) = server.recv_from(0).unwrap();
+    assert_eq!(peer_addr, addr("10.1.0.2"));
+    assert_eq!(data.clone().to_vec(), b"hello".to_vec());
+    server.send_to(data, peer_addr, peer_port).unwrap();
+    sim.run_for(Duration::from_secs(1));
+    let (peer_addr, peer_port, data) = client.recv_from(0).unwrap();
+    assert_eq!((peer_addr, peer_port), (addr("10.2.0.2"), 7));
+    assert_eq!(data.to_vec(), b"hello".to_vec());
+
+    let counters = stats::snapshot(&router).counters;
+    assert_eq!(counters.ip.forw_datagrams, 4);
+    assert_eq!(counters.udp.in_datagrams, 0);
+    assert_eq!(stats::snapshot(&host_b).counters.udp.in_errors, 0);
+}
+
+#[test]
+fn a_taken_address_stays_conflicted() {
+    let mut sim = Simulator::new(2);
+    let host_a = sim.stack();
+    let host_b = sim.stack();
+    let (mut device_a, mut device_b) = sim
+        .connect(&host_a, &host_b, LinkConfig::default())
+        .unwrap();
+    let interface_a = configure(&mut device_a, "10.1.0.2", None);
+    sim.run_for(Duration::from_secs(10));
+    assert!(!arp::is_tentative(&interface_a) && !arp::is_conflicted(&interface_a));
+
+    // b probes for the address a already defends
+    let reports = Arc::new(AtomicUsize::new(0));
+    let counter = reports.clone();
+    arp::set_conflict_handler(&host_b, move |conflict| {
+        assert_eq!(conflict.state, arp::ConflictState::Probing);
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    let interface_b = configure(&mut device_b, "10.1.0.2", None);
+    sim.run_for(Duration::from_secs(10));
+    assert!(arp::is_conflicted(&interface_b));
+    assert!(!arp::is_tentative(&interface_b));
+    assert!(!arp::is_conflicted(&interface_a));
+
+    // a keeps announcing, which b does not take for news
+    arp::announce(&interface_a).unwrap();
+    sim.run_for(Duration::from_secs(1));
+    assert_eq!(reports.load(Ordering::SeqCst), 1);
+    assert!(arp::is_conflicted(&interface_b));
+}
+
+// a (10.1.0.2) and b (10.1.0.3) on one link, past conflict detection, with a socket on
+// b:7 and one on a to send to it from
+struct Pair {
+    sim: Simulator,
+    a: Stack,
+    b: Stack,
+    device_a: ethernet::Device,
+    device_b: ethernet::Device,
+    sender: udp::Socket,
+    receiver: udp::Socket,
+}
+
+fn pair(seed: u64, config: LinkConfig) -> Pair {
+    let mut sim = Simulator::new(seed);
+    let a = sim.stack();
+    let b = sim.stack();
+    let (mut device_a, mut device_b) = sim.connect(&a, &b, config).unwrap();
+    let interface_a = configure(&mut device_a, "10.1.0.2", None);
+    let interface_b = configure(&mut device_b, "10.1.0.3", None);
+    sim.run_for(Duration::from_secs(10));
+    let mut sender = udp::open(&a).unwrap();
+    sender.bind_interface(interface_a, 0).unwrap();
+    let mut receiver = udp::open(&b).unwrap();
+    receiver.bind_interface(interface_b, 7).unwrap();
+    Pair {
+        sim,
+        a,
+        b,
+        device_a,
+        device_b,
+        sender,
+        receiver,
+    }
+}
+
+// every datagram waiting on `socket`, by its first byte
+fn drain(socket: &mut udp::Socket) -> Vec<u8> {
+    let mut received = vec![];
+    while let Ok((_, _, data)) = socket.recv_from(0) {
+        received.push(data.to_vec()[0]);
+    }
+    received
+}
+
+// sends datagrams 0..n from a to b over a link with `config`, resolved up front so that
+// only they cross it; what the link did to them and what b got
+fn send_over(seed: u64, config: LinkConfig, n: u8) -> (sim::Counters, Vec<u8>) {
+    let mut pair = pair(seed, config);
+    let interface_a = pair.device_a.interface().unwrap();
+    arp::add_static(&interface_a, addr("10.1.0.3"), pair.device_b.addr()).unwrap();
+    let before = pair.sim.counters();
+    for i in 0..n {
+        pair.sender
+            .send_to(Buffer::from_vec(vec![i]), addr("10.1.0.3"), 7)
+            .unwrap();
+    }
+    pair.sim.run_for(Duration::from_secs(1));
+    let after = pair.sim.counters();
+    let delta = sim::Counters {
+        sent: after.sent - before.sent,
+        lost: after.lost - before.lost,
+        duplicated: after.duplicated - before.duplicated,
+        reordered: after.reordered - before.reordered,
+        delivered: after.delivered - before.delivered,
+    };
+    (delta, drain(&mut pair.receiver))
+}
+
+fn faulty() -> LinkConfig {
+    LinkConfig {
+        loss: 0.2,
+        duplicate: 0.2,
+        reorder: 0.5,
+        ..LinkConfig::default()
+    }
+}
+
+// what happened when over a second of faulty traffic, and what came of it
+fn trace(seed: u64) -> (Vec<(DateTime<Utc>, sim::Counters)>, Vec<u8>) {
+    let mut pair = pair(seed, faulty());
+    let interface_a = pair.device_a.interface().unwrap();
+    arp::add_static(&interface_a, addr("10.1.0.3"), pair.device_b.addr()).unwrap();
+    let end = pair.sim.now() + chrono::Duration::seconds(1);
+    for i in 0..40 {
+        pair.sender
+            .send_to(Buffer::from_vec(vec![i]), addr("10.1.0.3"), 7)
+            .unwrap();
+    }
+    let mut trace = vec![];
+    while pair.sim.now() < end && pair.sim.step() {
+        trace.push((pair.sim.now(), pair.sim.counters()));
+    }
+    (trace, drain(&mut pair.receiver))
+}
+
+#[test]
+fn a_seed_replays_the_same_run() {
+    let (trace, received) = trace(7);
+    // a run worth replaying: every fault happened in it
+    let (_, counters) = trace.last().unwrap();
+    assert!(counters.lost > 0 && counters.duplicated > 0 && counters.reordered > 0);
+    assert_eq!((trace.clone(), received.clone()), self::trace(7));
+    assert_ne!((trace, received), self::trace(8));
+}
+
+#[test]
+fn link_faults_happen_as_configured() {
+    // a clean link keeps every datagram, in order
+    let (counters, received) = send_over(1, LinkConfig::default(), 100);
+    assert_eq!((counters.sent, counters.delivered), (100, 100));
+    assert_eq!(received, (0..100).collect::<Vec<u8>>());
+
+    let lossy = LinkConfig {
+        loss: 0.25,
+        ..LinkConfig::default()
+    };
+    let (counters, received) = send_over(1, lossy, 200);
+    assert!(
+        (30..=70).contains(&counters.lost),
+        "lost {} of 200",
+        counters.lost
+    );
+    assert_eq!(received.len() as u64, 200 - counters.lost);
+    assert!(received.windows(2).all(|w| w[0] < w[1]));
+
+    let dead = LinkConfig {
+        loss: 1.0,
+        ..LinkConfig::default()
+    };
+    let (counters, received) = send_over(1, dead, 20);
+    assert_eq!((counters.lost, counters.delivered), (20, 0));
+    assert!(received.is_empty());
+
+    let doubling = LinkConfig {
+        duplicate: 1.0,
+        ..LinkConfig::default()
+    };
+    let (counters, mut received) = send_over(1, doubling, 20);
+    assert_eq!((counters.duplicated, counters.delivered), (20, 40));
+    received.sort_unstable();
+    assert_eq!(received, (0..20).flat_map(|i| vec![i, i]).collect::<Vec<u8>>());
+
+    let shuffling = LinkConfig {
+        reorder: 0.5,
+        ..LinkConfig::default()
+    };
+    let (counters, received) = send_over(1, shuffling, 100);
+    assert!(
+        (30..=70).contains(&counters.reordered),
+        "reordered {} of 100",
+        counters.reordered
+    );
+    assert!(received.windows(2).any(|w| w[0] > w[1]));
+    let mut sorted = received.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, (0..100).collect::<Vec<u8>>());
+}
+
+#[test]
+fn arp_retries_then_gives_up_on_virtual_time() {
+    let mut pair = pair(1, LinkConfig::default());
+    let nobody = addr("10.1.0.9");
+    let requests = |sim: &Simulator, start: u64| sim.counters().sent - start;
+    let start = pair.sim.counters().sent;
+    pair.sender
+        .send_to(Buffer::from_vec(b"anyone?".to_vec()), nobody, 7)
+        .unwrap();
+    let queued = |stack: &Stack| {
+        arp::entries(stack)
+            .into_iter()
+            .find(|entry| entry.ip_addr == nobody)
+            .map(|entry| (entry.state, entry.queued))
+    };
+    assert_eq!(queued(&pair.a), Some((arp::State::Incomplete, 1)));
+
+    // asked again after 1s and 2s more, each on the dot
+    for (wait, count) in [(999, 1), (1, 2), (1999, 2), (1, 3)].iter() {
+        pair.sim.run_for(Duration::from_millis(*wait));
+        assert_eq!(requests(&pair.sim, start), *count, "at +{}ms", wait);
+    }
+    // then given up on 4s after the last, the datagram with it
+    pair.sim.run_for(Duration::from_millis(3999));
+    assert_eq!(queued(&pair.a), Some((arp::State::Incomplete, 1)));
+    pair.sim.run_for(Duration::from_millis(1));
+    assert_eq!(queued(&pair.a), None);
+    assert_eq!(requests(&pair.sim, start), 3);
+    // nobody else was bothered into answering
+    assert!(arp::entries(&pair.b).iter().all(|entry| entry.ip_addr != nobody));
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// a fragment from a to b of datagram `id`, starting `offset` bytes into its payload
+fn fragment(id: u16, offset: usize, more: bool, payload: &[u8]) -> Buffer {
+    let len = (20 + payload.len()) as u16;
+    let flags = (offset / 8) as u16 | if more { 0x2000 } else { 0 };
+    let mut header = vec![0x45, 0];
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&id.to_be_bytes());
+    header.extend_from_slice(&flags.to_be_bytes());
+    header.extend_from_slice(&[64, 17, 0, 0, 10, 1, 0, 2, 10, 1, 0, 3]);
+    let sum = checksum(&header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+    header.extend_from_slice(payload);
+    Buffer::from_vec(header)
+}
+
+// a datagram to b:7 of 2400 bytes, UDP header included, in three fragments
+fn fragments(id: u16) -> Vec<Buffer> {
+    let data: Vec<u8> = (0..2392).map(|i| (i % 251) as u8).collect();
+    let mut payload = vec![0, 9, 0, 7];
+    payload.extend_from_slice(&2400u16.to_be_bytes());
+    // no checksum
+    payload.extend_from_slice(&[0, 0]);
+    payload.extend_from_slice(&data);
+    vec![
+        fragment(id, 0, true, &payload[..1000]),
+        fragment(id, 1000, true, &payload[1000..2000]),
+        fragment(id, 2000, false, &payload[2000..]),
+    ]
+}
+
+#[test]
+fn fragments_are_reassembled_in_any_order() {
+    let mut pair = pair(1, LinkConfig::default());
+    let parts = fragments(1);
+    for i in [2, 0, 1].iter() {
+        pair.device_a
+            .tx(ethernet::Type::Ip, parts[*i].clone(), pair.device_b.addr())
+            .unwrap();
+    }
+    pair.sim.run_for(Duration::from_secs(1));
+    let (_, port, data) = pair.receiver.recv_from(0).unwrap();
+    assert_eq!(port, 9);
+    let expected: Vec<u8> = (0..2392).map(|i| (i % 251) as u8).collect();
+    assert_eq!(data.to_vec(), expected);
+    let counters = stats::snapshot(&pair.b).counters.ip;
+    assert_eq!((counters.reasm_oks, counters.reasm_fails), (1, 0));
+}
+
+#[test]
+fn an_incomplete_reassembly_times_out() {
+    let mut pair = pair(1, LinkConfig::default());
+    let parts = fragments(2);
+    for i in [0, 2].iter() {
+        pair.device_a
+            .tx(ethernet::Type::Ip, parts[*i].clone(), pair.device_b.addr())
+            .unwrap();
+    }
+    let fails = |stack: &Stack| stats::snapshot(stack).counters.ip.reasm_fails;
+    pair.sim.run_for(Duration::from_secs(29));
+    assert_eq!(fails(&pair.b), 0);
+    pair.sim.run_for(Duration::from_secs(2));
+    assert_eq!(fails(&pair.b), 1);
+    assert_eq!(
+        stats::snapshot(&pair.b).counters.drops.get("ip: reassembly timeout"),
+        Some(&1)
+    );
+
+    // the missing part alone, too late, makes nothing whole
+    pair.device_a
+        .tx(ethernet::Type::Ip, parts[1].clone(), pair.device_b.addr())
+        .unwrap();
+    pair.sim.run_for(Duration::from_secs(1));
+    assert!(matches!(pair.receiver.recv_from(0), Err(Error::Timeout)));
+    assert_eq!(stats::snapshot(&pair.b).counters.ip.reasm_oks, 0);
+}
+
+#[test]
+fn overlong_delays_and_limits_do_not_panic() {
+    let mut sim = Simulator::new(1);
+    let a = sim.stack();
+    let b = sim.stack();
+    let forever = Duration::from_secs(u64::MAX);
+    for config in [
+        LinkConfig {
+            latency: forever,
+            ..LinkConfig::default()
+        },
+        LinkConfig {
+            reorder_delay: forever,
+            ..LinkConfig::default()
+        },
+    ]
+    .iter()
+    {
+        assert!(matches!(
+            sim.connect(&a, &b, config.clone()),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    // a sleep too long to count to never ends
+    let clock = match sim.clock() {
+        Clock::Virtual(clock) => clock,
+        Clock::System => unreachable!(),
+    };
+    let sleeper = sim.clock();
+    sim.clock().spawn(move || sleeper.sleep(forever));
+    sim.run_for(Duration::from_secs(1));
+    let wakeup = clock.next_wakeup().unwrap();
+    assert!(wakeup - sim.now() > chrono::Duration::days(365 * 100_000));
+
+    // and a run that long lasts until it is done
+    let start = sim.now();
+    let later = |sim_now: DateTime<Utc>| sim_now - start >= chrono::Duration::seconds(5);
+    let now = sim.clock();
+    assert!(sim.run_until(forever, || later(now.now())));
+}